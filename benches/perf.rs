@@ -133,6 +133,7 @@ fn tcp_mem_insert_flood(b: &mut Bencher) {
     max_latency_ms: 250,
     report_connect_errors: true,
     name: "Test Server".to_string(),
+    tls_pin: None,
   };
   test_config.servers = vec![localhost_server];
   test_config.server_port = port;
@@ -199,6 +200,7 @@ fn tcp_mem_query_over_1k(b: &mut Bencher) {
     max_latency_ms: 250,
     report_connect_errors: true,
     name: "Test Server".to_string(),
+    tls_pin: None,
   };
   test_config.servers = vec![localhost_server];
   test_config.server_port = port;
@@ -269,6 +271,7 @@ fn tcp_mem_query_over_100(b: &mut Bencher) {
     max_latency_ms: 250,
     report_connect_errors: true,
     name: "Test Server".to_string(),
+    tls_pin: None,
   };
   test_config.servers = vec![localhost_server];
   test_config.server_port = port;
@@ -339,6 +342,7 @@ fn tcp_mem_query_over_10(b: &mut Bencher) {
     max_latency_ms: 250,
     report_connect_errors: true,
     name: "Test Server".to_string(),
+    tls_pin: None,
   };
   test_config.servers = vec![localhost_server];
   test_config.server_port = port;
@@ -392,11 +396,102 @@ fn tcp_mem_query_over_10(b: &mut Bencher) {
   }).unwrap();
 }
 
+// Webpage-like records with words from a 5000 word vocabulary,
+// so a single word query matches a small fraction of records.
+fn gen_webpage_record(i: usize) -> dindex::record::Record {
+  let mut rng = rand::thread_rng();
+  let mut rec = dindex::record::Record::empty();
+  let title: Vec<String> = (0..6).map(|_| format!("word{}", rng.gen_range(0, 5000))).collect();
+  rec.p.insert("title".to_string(), title.join(" "));
+  rec.p.insert("url".to_string(), format!("http://host{}.example.org/page/{}", i % 1000, i));
+  rec.p.insert("description".to_string(), thread_rng().sample_iter(&Alphanumeric).take(200).collect());
+  rec
+}
+
+fn mem_data_100k() -> (dindex::config::Config, dindex::data::Data) {
+  let mut test_config = dindex::config::get_config_detail(
+    // this is the method that reads from env, but we specify no env in the arguments
+    false, false, false, false,
+    Err(std::env::VarError::NotPresent),
+    &dindex::args::Args::empty()
+  );
+  test_config.server_extra_quiet = true;
+  test_config.server_datastore_uri = "memory://".to_string();
+  test_config.server_max_records = 200_000;
+  let data = dindex::data::Data::new(&test_config);
+  for i in 0..100_000 {
+    data.insert(gen_webpage_record(i));
+  }
+  (test_config, data)
+}
+
+fn webpage_query(key: &str, pattern: &str) -> dindex::query::Query {
+  let mut query = dindex::record::Record::empty();
+  query.p.insert(key.to_string(), pattern.to_string());
+  query.create_query()
+}
+
+// What Data::search did before record pools were indexed: every regex against every record
+fn full_scan(data: &dindex::data::Data, query: &dindex::query::Query) -> Vec<dindex::record::Record> {
+  let mut results = vec![];
+  for pool in data.record_pools.iter() {
+    for stored in pool.read().unwrap().iter() {
+      if query.matches(&stored.rec) {
+        results.push(stored.rec.clone());
+      }
+    }
+  }
+  results
+}
+
+/**
+ * These tests query 100k in-memory records for a single word in the title,
+ * first by scanning every record and then using the index.
+ */
+fn mem_term_query_over_100k_full_scan(b: &mut Bencher) {
+  let (_config, data) = mem_data_100k();
+  b.iter(|| {
+    let query = webpage_query("title", &format!(".*word{}.*", rand::thread_rng().gen_range(0, 5000)));
+    full_scan(&data, &query)
+  });
+}
+
+fn mem_term_query_over_100k_indexed(b: &mut Bencher) {
+  let (_config, data) = mem_data_100k();
+  b.iter(|| {
+    let query = webpage_query("title", &format!(".*word{}.*", rand::thread_rng().gen_range(0, 5000)));
+    data.search(&query)
+  });
+}
+
+/**
+ * As above, for an anchored url prefix matching 100 of the 100k records.
+ */
+fn mem_prefix_query_over_100k_full_scan(b: &mut Bencher) {
+  let (_config, data) = mem_data_100k();
+  b.iter(|| {
+    let query = webpage_query("url", &format!("^http://host{}\\.example", rand::thread_rng().gen_range(0, 1000)));
+    full_scan(&data, &query)
+  });
+}
+
+fn mem_prefix_query_over_100k_indexed(b: &mut Bencher) {
+  let (_config, data) = mem_data_100k();
+  b.iter(|| {
+    let query = webpage_query("url", &format!("^http://host{}\\.example", rand::thread_rng().gen_range(0, 1000)));
+    data.search(&query)
+  });
+}
+
 benchmark_group!(benches,
   single_rand_record_gen,
   tcp_mem_insert_flood,
   tcp_mem_query_over_1k,
   tcp_mem_query_over_100,
   tcp_mem_query_over_10,
+  mem_term_query_over_100k_full_scan,
+  mem_term_query_over_100k_indexed,
+  mem_prefix_query_over_100k_full_scan,
+  mem_prefix_query_over_100k_indexed,
 );
 benchmark_main!(benches);
@@ -42,6 +42,41 @@ arg_enum! {
       // the client data it did not ask for is a reasonable action.
       unsolicited_msg = 5,
       
+      // Sent by either side to exchange Capabilities, see wire.rs
+      hello = 6,
+      // Sent server -> client when a request cannot be handled.
+      // The record holds "error-code" and "error-message" keys.
+      error = 7,
+      // Sent client -> server to stop the request with the same id,
+      // usually a listen on a multiplexed connection.
+      cancel = 8,
+      // Sent server -> client once a published record is stored.
+      // The record holds the "record-id" the server assigned.
+      ack = 9,
+      // Sent client -> server to remove or replace the records selected
+      // by WireData.target, see server::modify_records
+      delete = 10,
+      update = 11,
+      // Sent server -> listener when a matching record is deleted or replaced
+      removed = 12,
+      // Sent client -> server to count the records matching a query,
+      // answered by one result holding wire::Counts
+      count = 13,
+      // Sent server -> listener which asked for a replay, between the records
+      // which already matched and those published since
+      end_of_replay = 14,
+      // Sent server -> server to compare and copy records, the step is
+      // chosen by WireData.sync, see replication.rs
+      sync = 15,
+      // Sent by servers to server_multicast_group every
+      // server_announce_interval_ms and in reply to discover, carrying
+      // WireData.announce
+      announce = 16,
+      // Sent client -> server (usually to the multicast group) to ask who
+      // is there, answered by announces then end_of_results. Also the CLI
+      // action which lists what was found, see discovery.rs
+      discover = 17,
+      
       // The remaining arguments are NOT designed to be sent over the wire,
       // but instead are used by the CLI tool.
       run_server,
@@ -64,6 +99,17 @@ pub fn action_from_str(s: &str) -> Action {
     "listen" => Action::listen,
     "result" => Action::result,
     "end_of_results" => Action::end_of_results,
+    "hello" => Action::hello,
+    "cancel" => Action::cancel,
+    "ack" => Action::ack,
+    "delete" => Action::delete,
+    "update" => Action::update,
+    "removed" => Action::removed,
+    "count" => Action::count,
+    "end_of_replay" => Action::end_of_replay,
+    "sync" => Action::sync,
+    "announce" => Action::announce,
+    "discover" => Action::discover,
     "run_server" => Action::run_server,
     "double_fork_server" => Action::double_fork_server,
     "run_http_client" => Action::run_http_client,
@@ -74,3 +120,30 @@ pub fn action_from_str(s: &str) -> Action {
     _ => Action::no_action,
   }
 }
+
+// Maps a number received over the wire to an Action.
+// Numbers we do not know and CLI-only actions become no_action so
+// servers can reply with an error instead of dropping the message.
+pub fn action_from_u8(n: u8) -> Action {
+  match n {
+    0 => Action::query,
+    1 => Action::publish,
+    2 => Action::listen,
+    3 => Action::result,
+    4 => Action::end_of_results,
+    5 => Action::unsolicited_msg,
+    6 => Action::hello,
+    7 => Action::error,
+    8 => Action::cancel,
+    9 => Action::ack,
+    10 => Action::delete,
+    11 => Action::update,
+    12 => Action::removed,
+    13 => Action::count,
+    14 => Action::end_of_replay,
+    15 => Action::sync,
+    16 => Action::announce,
+    17 => Action::discover,
+    _ => Action::no_action,
+  }
+}
@@ -20,6 +20,7 @@ use structopt;
 use structopt::StructOpt;
 
 use serde_json;
+use base64;
 //use clap::arg_enum;
 
 //use serde;
@@ -27,8 +28,11 @@ use serde_json;
 
 use crate::actions::Action;
 use crate::config::Config;
-use crate::record::Record;
+use crate::record::{Record, TTL_SECONDS_KEY};
+use crate::query::{QueryExpr, MatchMode, QUERY_TEXT_KEY, QUERY_MATCH_KEY};
+use crate::wire::{Target, QueryOptions};
 use crate::signing;
+use crate::encryption;
 
 #[derive(StructOpt, Debug, Clone)]
 #[structopt(name = "dindex", about = "A distributed index for anything and everything")]
@@ -53,19 +57,151 @@ pub struct Args {
   #[structopt(short = "S", long = "signed")]
   pub signed: bool,
   
-  /// CType or JSON data used for publishing and querying payloads (eg :website '.*keyword.*' or '{"key": "value"}')
+  /// Key type gen_identity creates: rsa (the default) or ed25519
+  #[structopt(long = "key-type")]
+  pub key_type: Option<String>,
+  
+  /// Comma separated keys to encrypt when publishing (eg title,url), readable only by --recipients and us
+  #[structopt(long = "encrypt")]
+  pub encrypt: Option<String>,
+  
+  /// Comma separated public key files, or base64 public keys from print_identity, to encrypt for
+  #[structopt(long = "recipients")]
+  pub recipients: Option<String>,
+  
+  /// Record id to delete or update
+  #[structopt(long = "id")]
+  pub target_id: Option<String>,
+  
+  /// JSON query selecting records to update (delete uses the record arguments)
+  #[structopt(long = "target-query")]
+  pub target_query: Option<String>,
+  
+  /// Seconds servers should keep published records for
+  #[structopt(long = "ttl")]
+  pub ttl: Option<f64>,
+  
+  /// Most query results to return from each server
+  #[structopt(long = "limit")]
+  pub limit: Option<usize>,
+  
+  /// Query results to skip on each server
+  #[structopt(long = "offset")]
+  pub offset: Option<usize>,
+  
+  /// Continue a query from the cursor printed after the previous page, only the server which made it is asked
+  #[structopt(long = "cursor")]
+  pub cursor: Option<String>,
+  
+  /// Order query results by the value of this key
+  #[structopt(long = "sort-by")]
+  pub sort_by: Option<String>,
+  
+  /// Order query results from highest to lowest
+  #[structopt(long = "desc")]
+  pub descending: bool,
+  
+  /// Comma separated keys to return from each query result (eg title,url)
+  #[structopt(long = "fields")]
+  pub fields: Option<String>,
+  
+  /// Count records per value of this key (eg host) when invoking count
+  #[structopt(long = "group-by")]
+  pub group_by: Option<String>,
+  
+  /// Search for the record arguments as words anywhere in records, best matches first
+  #[structopt(long = "text")]
+  pub text: bool,
+  
+  /// Listen for the records servers already have before new ones
+  #[structopt(long = "replay")]
+  pub replay: bool,
+  
+  /// How query patterns are read: regex, literal, glob, fuzzy or fuzzy=N, optionally with ,ignore-case (eg glob,ignore-case)
+  #[structopt(long = "match")]
+  pub match_mode: Option<String>,
+  
+  /// CType, JSON or query expression used for publishing and querying payloads (eg :website '.*keyword.*' or '{"key": "value"}' or "title ~ rust or not exists(url)")
   pub rec_args: Vec<String>,
 }
 
 impl Args {
   // Parses a record from rec_args, applying ctypes along the way.
   // If no known types match, concatinates rec_args and parses as JSON.
-  // Failing that, returns an empty record
+  // Failing that, returns an empty record (or a full-text query when querying)
   pub fn get_record(&self, config: &Config) -> Record {
-    let mut rec = parse_record(&self.rec_args, self.verbose, config);
+    let mut rec = if self.text {
+      text_query_record(&self.rec_args.join(" "))
+    }
+    else if self.action == Action::query || self.action == Action::listen || self.action == Action::count {
+      let mut rec = parse_query_record(&self.rec_args, self.verbose, config);
+      set_match_mode(&mut rec, &self.get_match_mode(config));
+      rec
+    }
+    else {
+      parse_record(&self.rec_args, self.verbose, config)
+    };
+    if let Some(ttl) = self.ttl {
+      if !rec.is_empty() && (self.action == Action::publish || self.action == Action::update) {
+        rec.p.insert(TTL_SECONDS_KEY.to_string(), format!("{}", ttl));
+      }
+    }
+    if self.action == Action::publish || self.action == Action::update {
+      // Never publish in the clear what was asked to be encrypted
+      if let Err(e) = encryption::maybe_encrypt_record(config, &mut rec) {
+        println!("Error encrypting record: {}", e);
+        rec.p.clear();
+        return rec;
+      }
+    }
     signing::maybe_sign_record(config, &mut rec);
     return rec;
   }
+  // The records a delete or update applies to. Deletes without --id
+  // or --target-query select records matching the record arguments.
+  pub fn get_target(&self, config: &Config) -> Target {
+    let mut target = Target::default();
+    target.id = self.target_id.clone();
+    if let Some(target_query) = &self.target_query {
+      target.query = Some(parse_record(&vec![target_query.clone()], self.verbose, config));
+    }
+    else if target.id.is_none() && self.action == Action::delete {
+      target.query = Some(parse_record(&self.rec_args, self.verbose, config));
+    }
+    if let Some(query) = &mut target.query {
+      set_match_mode(query, &self.get_match_mode(config));
+    }
+    return target;
+  }
+  // --match, or config.client_match_mode without it
+  pub fn get_match_mode(&self, config: &Config) -> MatchMode {
+    match &self.match_mode {
+      Some(spec) => MatchMode::parse(spec).unwrap_or_else(|e| {
+        println!("{}, using regex", e);
+        MatchMode::default()
+      }),
+      None => config.client_match_mode,
+    }
+  }
+  // The name of the server which made --cursor and the cursor itself,
+  // None if there is no --cursor or it is not one cli_cursor made
+  pub fn get_cursor(&self) -> Option<(String, String)> {
+    let (server_name, cursor) = self.cursor.as_ref()?.split_once('.')?;
+    let server_name = base64::decode_config(server_name, base64::URL_SAFE_NO_PAD).ok()?;
+    return Some((String::from_utf8(server_name).ok()?, cursor.to_string()));
+  }
+  pub fn get_query_options(&self) -> QueryOptions {
+    QueryOptions {
+      limit: self.limit,
+      offset: self.offset,
+      cursor: self.get_cursor().map(|(_server_name, cursor)| cursor),
+      sort_by: self.sort_by.clone(),
+      descending: self.descending,
+      fields: self.fields.as_ref().map(|fields| split_list(fields)),
+      group_by: self.group_by.clone(),
+      replay: self.replay,
+    }
+  }
   pub fn empty() -> Args {
     Args {
       config_file: None,
@@ -73,11 +209,38 @@ impl Args {
       verbose: 0,
       action: Action::no_action,
       signed: false,
+      key_type: None,
+      encrypt: None,
+      recipients: None,
+      target_id: None,
+      target_query: None,
+      ttl: None,
+      limit: None,
+      offset: None,
+      cursor: None,
+      sort_by: None,
+      descending: false,
+      fields: None,
+      group_by: None,
+      text: false,
+      replay: false,
+      match_mode: None,
       rec_args: vec![]
     }
   }
 }
 
+// Splits comma separated arguments like --fields, dropping empty entries
+// The --cursor value which continues from cursor on the server named server_name.
+// Server cursors are URL-safe base64 so never hold the '.'
+pub fn cli_cursor(server_name: &str, cursor: &str) -> String {
+  return format!("{}.{}", base64::encode_config(server_name, base64::URL_SAFE_NO_PAD), cursor);
+}
+
+pub fn split_list(list: &str) -> Vec<String> {
+  list.split(',').map(|f| f.trim().to_string()).filter(|f| !f.is_empty()).collect()
+}
+
 pub fn parse_record(args: &Vec<String>, verbose: u8, config: &Config) -> Record {
   if let Some(ctype_name) = args.get(0) {
     for ctype in &config.ctypes {
@@ -99,8 +262,18 @@ pub fn parse_record(args: &Vec<String>, verbose: u8, config: &Config) -> Record
   }
   // No ctypes matched in rec_args[0], concatinate all + parse as JSON
   let joined = args.join(" ");
-  let joined = format!("{{\"p\":{} }}", joined); // Wrap it so we can use serde directly
-  if let Ok(rec) = serde_json::from_str(&joined) {
+  let wrapped = format!("{{\"p\":{} }}", joined); // Wrap it so we can use serde directly
+  if let Ok(rec) = serde_json::from_str(&wrapped) {
+    if verbose > 0 {
+      println!("arg record = {:?}", &rec);
+    }
+    return rec;
+  }
+  
+  // Failing that, try a query expression as JSON or in the compact text syntax
+  let expr = QueryExpr::from_json(&joined).or_else(|_| QueryExpr::from_text(&joined));
+  if let Ok(expr) = expr {
+    let rec = expr.to_record();
     if verbose > 0 {
       println!("arg record = {:?}", &rec);
     }
@@ -113,3 +286,40 @@ pub fn parse_record(args: &Vec<String>, verbose: u8, config: &Config) -> Record
   }
   return rec;
 }
+
+// Like parse_record, but words which are not a record or query expression
+// become a full-text query instead of an empty record, and patterns are
+// read in config.client_match_mode.
+pub fn parse_query_record(args: &Vec<String>, verbose: u8, config: &Config) -> Record {
+  let mut rec = parse_record(args, verbose, config);
+  let joined = args.join(" ");
+  if rec.is_empty() && !joined.trim().is_empty() {
+    rec = text_query_record(&joined);
+    if verbose > 0 {
+      println!("arg record = {:?}", &rec);
+    }
+  }
+  set_match_mode(&mut rec, &config.client_match_mode);
+  return rec;
+}
+
+// Tells servers to read the patterns in the query rec in mode. Regex is
+// what servers assume, so it is only written to replace another mode.
+pub fn set_match_mode(rec: &mut Record, mode: &MatchMode) {
+  if rec.is_empty() || (rec.p.contains_key(QUERY_TEXT_KEY) && rec.p.len() == 1) {
+    return; // No patterns to read
+  }
+  if mode.is_default() {
+    rec.p.remove(QUERY_MATCH_KEY);
+  }
+  else {
+    rec.p.insert(QUERY_MATCH_KEY.to_string(), mode.to_spec());
+  }
+}
+
+// A query for records holding any of the words in text, see query::QUERY_TEXT_KEY
+pub fn text_query_record(text: &str) -> Record {
+  let mut rec = Record::empty();
+  rec.p.insert(QUERY_TEXT_KEY.to_string(), text.to_string());
+  return rec;
+}
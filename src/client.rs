@@ -19,6 +19,7 @@
 use crossbeam_utils::thread;
 
 use std::sync::{Arc, Mutex};
+use std::collections::BTreeMap;
 use std::time::{Duration};
 use std::io::prelude::*;
 
@@ -27,13 +28,29 @@ use crate::config::Server;
 use crate::config::ServerProtocol;
 use crate::record::Record;
 use crate::actions::Action;
-use crate::wire::WireData;
+use crate::wire::{WireData, Capabilities, Target, QueryOptions, Counts, NEXT_CURSOR_KEY};
+use crate::query::{cmp_sort_values, TEXT_SCORE_KEY};
+use crate::signing;
+use crate::tls;
+
+use crate::framing::{Framing, FrameDecoder, encode, read_frame, write_frame};
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum ListenAction {
   Continue, EndListen
 }
 
+// What listeners are told about records matching their query
+#[derive(Debug, Clone)]
+pub enum ListenEvent {
+  Published(Record),
+  // The record was deleted, or replaced by an update
+  Removed(Record),
+  // Every record which matched when listening began has been Published,
+  // only sent to listeners asking for QueryOptions::replay
+  EndOfReplay,
+}
+
 impl ListenAction {
   pub fn parse(s: &str) -> ListenAction {
     if s == "Continue" {
@@ -48,184 +65,411 @@ impl ListenAction {
   }
 }
 
-pub fn publish_sync(config: &Config, query: &Record) {
-  thread::scope(|s| {
-    let mut handlers = vec![];
-    
-    for server in &config.servers {
-      let t_server = server.clone();
-      handlers.push(s.spawn(move |_| {
-        publish_server_sync(config, &t_server, query);
-      }));
-    }
-    
-    for h in handlers {
-      h.join().unwrap();
-    }
-  }).unwrap();
-  
-}
-
-pub fn publish_server_sync(config: &Config, server: &Server, rec: &Record) {
+// Sends wire_data to server and passes each reply to on_reply until it returns false,
+// the server sends end_of_results or an error, or the connection closes.
+// When a read times out the request ends unless on_timeout returns true,
+// which is how listeners keep waiting.
+pub fn request_server_sync<T, F>(config: &Config, server: &Server, wire_data: &WireData, on_timeout: T, on_reply: F) -> std::io::Result<()>
+  where T: FnMut() -> bool, F: FnMut(WireData) -> bool
+{
+  let is_stream = server.protocol == ServerProtocol::TCP || server.protocol == ServerProtocol::TLS || server.protocol == ServerProtocol::UNIX;
+  if is_stream && wire_data.action != Action::hello && negotiated_framing(server).is_none() {
+    // The first request to a server asks which framing it prefers,
+    // connection errors are reported by the request itself
+    let mut quiet_server = server.clone();
+    quiet_server.report_connect_errors = false;
+    hello_server_sync(config, &quiet_server);
+  }
   match server.protocol {
     ServerProtocol::TCP => {
-      publish_tcp_server_sync(config, server, rec);
+      stream_request_sync(server, || tcp_connect(server), wire_data, on_timeout, on_reply)
     }
-    ServerProtocol::UDP => {
-      publish_udp_server_sync(config, server, rec);
+    ServerProtocol::UDP | ServerProtocol::MULTICAST => {
+      udp_request_sync(server, wire_data, on_timeout, on_reply)
     }
     ServerProtocol::UNIX => {
-      publish_unix_server_sync(config, server, rec);
+      unix_request_sync(server, wire_data, on_timeout, on_reply)
     }
-    ServerProtocol::WEBSOCKET => {
-      publish_websocket_server_sync(config, server, rec);
+    ServerProtocol::WEBSOCKET | ServerProtocol::WSS => {
+      websocket_request_sync(config, server, wire_data, on_timeout, on_reply)
     }
-    ServerProtocol::MULTICAST => {
-      publish_udp_server_sync(config, server, rec);
+    ServerProtocol::TLS => {
+      stream_request_sync(server, || tls::connect(config, server, Some(Duration::from_millis(256))), wire_data, on_timeout, on_reply)
     }
   }
 }
 
-pub fn publish_tcp_server_sync(_config: &Config, server: &Server, rec: &Record) {
-  use std::net::TcpStream;
-  
-  let wire_data = WireData {
-    action: Action::publish,
-    record: rec.clone(),
+// Passes wire_res to on_reply unless it only marks the end of results
+// (an end_of_results with a next-cursor is passed on).
+// Returns false once the request is over.
+fn deliver<F: FnMut(WireData) -> bool>(wire_res: WireData, on_reply: &mut F) -> bool {
+  if wire_res.action == Action::end_of_results {
+    if ! wire_res.record.is_empty() {
+      on_reply(wire_res);
+    }
+    return false;
+  }
+  let is_last = wire_res.ends_request();
+  return on_reply(wire_res) && ! is_last;
+}
+
+// Framing negotiated by hello (or found by trying both) per server,
+// for the life of the process
+static KNOWN_FRAMINGS: Mutex<BTreeMap<String, Framing>> = Mutex::new(BTreeMap::new());
+
+fn framing_key(server: &Server) -> String {
+  format!("{:?} {}:{} {}", server.protocol, server.host, server.port, server.path)
+}
+
+pub fn negotiated_framing(server: &Server) -> Option<Framing> {
+  return KNOWN_FRAMINGS.lock().ok().and_then(|known| known.get(&framing_key(server)).copied());
+}
+
+fn remember_framing(server: &Server, framing: Framing) {
+  if let Ok(mut known) = KNOWN_FRAMINGS.lock() {
+    known.insert(framing_key(server), framing);
+  }
+}
+
+// Uses the framing a hello negotiated with server. Until there is one we try
+// length framing first; servers which predate it close the connection without
+// replying to a framed request, so when that happens we retry with 0xff framing.
+fn stream_request_sync<S, C, T, F>(server: &Server, connect: C, wire_data: &WireData, mut on_timeout: T, mut on_reply: F) -> std::io::Result<()>
+  where S: Read + Write, C: Fn() -> std::io::Result<S>, T: FnMut() -> bool, F: FnMut(WireData) -> bool
+{
+  let framings = match negotiated_framing(server) {
+    Some(framing) => vec![framing],
+    None => vec![Framing::Length, Framing::Legacy],
   };
-  
-  let ip_and_port = format!("{}:{}", server.host, server.port);
-  match TcpStream::connect(&ip_and_port) {
-    Ok(mut stream) => {
-      if let Err(e) = stream.set_read_timeout(Some(Duration::from_millis(256))) {
-        println!("Error setting TCP read timeout: {}", e);
-      }
-      if let Err(e) = stream.set_write_timeout(Some(Duration::from_millis(256))) {
-        println!("Error setting TCP write timeout: {}", e);
-      }
-      
-      if let Ok(bytes) = serde_cbor::to_vec(&wire_data) {
-        if let Err(e) = stream.write(&bytes) {
-          println!("Error sending WireData to server in publish_tcp_server_sync: {}", e);
+  for &framing in &framings {
+    let mut stream = connect()?;
+    if let Err(e) = write_frame(&mut stream, wire_data, framing) {
+      println!("Error sending WireData to server: {}", e);
+      return Ok(());
+    }
+    
+    let mut decoder = FrameDecoder::with_framing(framing);
+    let mut num_replies = 0;
+    loop {
+      match read_frame(&mut stream, &mut decoder) {
+        Ok(Some(wire_res)) => {
+          num_replies += 1;
+          if num_replies == 1 && negotiated_framing(server).is_none() {
+            remember_framing(server, framing);
+          }
+          if ! deliver(wire_res, &mut on_reply) {
+            return Ok(());
+          }
         }
-        // Write the terminal 0xff byte
-        if let Err(e) = stream.write(&[0xff]) {
-          println!("Error sending WireData to server in publish_tcp_server_sync: {}", e);
+        Ok(None) => {
+          break; // Server closed the connection
+        }
+        Err(ref e) if e.is_timeout() => {
+          if ! on_timeout() {
+            return Ok(());
+          }
+        }
+        Err(e) => {
+          println!("Error reading from server: {}", e);
+          return Ok(());
         }
       }
-      // At the moment we don't expect data back from the server
     }
-    Err(e) => {
-      println!("Error in publish_tcp_server_sync: {}", e);
+    
+    if num_replies > 0 {
+      break;
     }
   }
+  return Ok(());
+}
+
+fn tcp_connect(server: &Server) -> std::io::Result<std::net::TcpStream> {
+  use std::net::TcpStream;
   
+  let ip_and_port = format!("{}:{}", server.host, server.port);
+  let stream = TcpStream::connect(&ip_and_port)?;
+  if let Err(e) = stream.set_read_timeout(Some(Duration::from_millis(256))) {
+    println!("Error setting TCP read timeout: {}", e);
+  }
+  if let Err(e) = stream.set_write_timeout(Some(Duration::from_millis(256))) {
+    println!("Error setting TCP write timeout: {}", e);
+  }
+  return Ok(stream);
 }
 
-pub fn publish_udp_server_sync(_config: &Config, server: &Server, rec: &Record) {
-  use std::net::UdpSocket;
+#[cfg(not(unix))]
+fn unix_request_sync<T, F>(_server: &Server, _wire_data: &WireData, _on_timeout: T, _on_reply: F) -> std::io::Result<()>
+  where T: FnMut() -> bool, F: FnMut(WireData) -> bool
+{
+  println!("Warning: Cannot use unix sockets because architecture is not unix.");
+  return Ok(());
+}
+
+#[cfg(unix)]
+fn unix_request_sync<T, F>(server: &Server, wire_data: &WireData, on_timeout: T, on_reply: F) -> std::io::Result<()>
+  where T: FnMut() -> bool, F: FnMut(WireData) -> bool
+{
+  use std::os::unix::net::UnixStream;
   
-  let wire_data = WireData {
-    action: Action::publish,
-    record: rec.clone(),
+  let connect = || {
+    let stream = UnixStream::connect(&server.path)?;
+    if let Err(e) = stream.set_read_timeout(Some(Duration::from_millis(256))) {
+      println!("Error setting Unix read timeout: {}", e);
+    }
+    if let Err(e) = stream.set_write_timeout(Some(Duration::from_millis(256))) {
+      println!("Error setting Unix write timeout: {}", e);
+    }
+    return Ok(stream);
   };
+  return stream_request_sync(server, connect, wire_data, on_timeout, on_reply);
+}
+
+fn udp_request_sync<T, F>(server: &Server, wire_data: &WireData, mut on_timeout: T, mut on_reply: F) -> std::io::Result<()>
+  where T: FnMut() -> bool, F: FnMut(WireData) -> bool
+{
+  use std::net::UdpSocket;
   
   let server_ip_and_port = format!("{}:{}", server.host, server.port);
   
-  match UdpSocket::bind("0.0.0.0:0") {
-    Ok(socket) => {
-      if let Err(e) = socket.set_read_timeout(Some(Duration::from_millis(256))) {
-        println!("Error setting UDP read timeout: {}", e);
-      }
-      if let Err(e) = socket.set_write_timeout(Some(Duration::from_millis(256))) {
-        println!("Error setting UDP write timeout: {}", e);
-      }
-      
-      if let Ok(bytes) = serde_cbor::to_vec(&wire_data) {
-        if let Err(e) = socket.send_to(&bytes, &server_ip_and_port) {
-          println!("Error sending WireData to server in publish_udp_server_sync: {}", e);
+  let socket = UdpSocket::bind("0.0.0.0:0")?;
+  if let Err(e) = socket.set_read_timeout(Some(Duration::from_millis(256))) {
+    println!("Error setting UDP read timeout: {}", e);
+  }
+  if let Err(e) = socket.set_write_timeout(Some(Duration::from_millis(256))) {
+    println!("Error setting UDP write timeout: {}", e);
+  }
+  
+  // UDP requests are always a single datagram ending in 0xff, which every
+  // server version understands.
+  match encode(wire_data, Framing::Legacy) {
+    Ok(bytes) => {
+      socket.send_to(&bytes, &server_ip_and_port)?;
+    }
+    Err(e) => {
+      println!("Error sending WireData to server: {}", e);
+      return Ok(());
+    }
+  }
+  
+  //let start = Instant::now(); // TODO add timeout on all these queries
+  let mut buff = [0; 64 * 1024];
+  let mut decoder = FrameDecoder::with_framing(Framing::Legacy);
+  loop {
+    match socket.recv_from(&mut buff) {
+      Ok((num_read, _src_socket)) => {
+        decoder.push(&buff[0..num_read]);
+        loop {
+          match decoder.next_frame() {
+            Ok(Some(wire_res)) => {
+              if ! deliver(wire_res, &mut on_reply) {
+                return Ok(());
+              }
+            }
+            Ok(None) => {
+              break; // Wait for more datagrams
+            }
+            Err(e) => {
+              println!("Error reading from UDP: {}", e);
+              break;
+            }
+          }
         }
-        // Write the terminal 0xff byte
-        if let Err(e) = socket.send_to(&[0xff], &server_ip_and_port) {
-          println!("Error sending WireData to server in publish_udp_server_sync: {}", e);
+      }
+      Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => {
+        // Servers may never reply to UDP, this is also our
+        // reliable server-dropped-conn signal.
+        if ! on_timeout() {
+          return Ok(());
         }
       }
-      // At the moment we don't expect data back from the server
+      Err(e) => {
+        return Err(e);
+      }
     }
-    Err(e) => {
-      println!("Error in publish_udp_server_sync: {}", e);
+  }
+}
+
+fn websocket_request_sync<T, F>(config: &Config, server: &Server, wire_data: &WireData, on_timeout: T, on_reply: F) -> std::io::Result<()>
+  where T: FnMut() -> bool, F: FnMut(WireData) -> bool
+{
+  use websocket::client::ClientBuilder;
+  
+  let to_io_err = |e: websocket::WebSocketError| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e));
+  
+  if server.protocol == ServerProtocol::WSS {
+    let ip_and_port = format!("wss://{}:{}", server.host, server.port);
+    let mut unconnected_client = ClientBuilder::new(&ip_and_port).expect("Cannot construct websocket client");
+    let stream = tls::connect(config, server, None)?;
+    let client = unconnected_client.connect_on(stream).map_err(to_io_err)?;
+    if let Err(e) = client.stream_ref().set_read_timeout(Some(Duration::from_millis(256))) {
+      println!("Error setting websocket read timeout: {}", e);
     }
+    return websocket_exchange_sync(client, wire_data, on_timeout, on_reply);
   }
   
+  let ip_and_port = format!("ws://{}:{}", server.host, server.port);
+  let mut unconnected_client = ClientBuilder::new(&ip_and_port).expect("Cannot construct websocket client");
+  let client = unconnected_client.connect_insecure().map_err(to_io_err)?;
+  if let Err(e) = client.stream_ref().set_read_timeout(Some(Duration::from_millis(256))) {
+    println!("Error setting websocket read timeout: {}", e);
+  }
+  return websocket_exchange_sync(client, wire_data, on_timeout, on_reply);
 }
 
-pub fn publish_unix_server_sync(_config: &Config, server: &Server, rec: &Record) {
-  use std::os::unix::net::UnixStream;
+fn websocket_exchange_sync<S, T, F>(client: websocket::sync::Client<S>, wire_data: &WireData, mut on_timeout: T, mut on_reply: F) -> std::io::Result<()>
+  where S: websocket::sync::Stream + websocket::sync::stream::Splittable, T: FnMut() -> bool, F: FnMut(WireData) -> bool
+{
+  use websocket::{OwnedMessage, WebSocketError};
   
-  let wire_data = WireData {
-    action: Action::publish,
-    record: rec.clone(),
-  };
+  let to_io_err = |e: websocket::WebSocketError| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e));
+  let (mut receiver, mut sender) = client.split()?;
   
-  match UnixStream::connect(&server.path) {
-    Ok(mut stream) => {
-      if let Err(e) = stream.set_read_timeout(Some(Duration::from_millis(256))) {
-        println!("Error setting TCP read timeout: {}", e);
+  if let Ok(bytes) = serde_cbor::to_vec(wire_data) {
+    sender.send_message(&OwnedMessage::Binary(bytes)).map_err(to_io_err)?;
+  }
+  
+  // Read results until connection is closed
+  // We read one CBOR WireData object per websocket packet
+  for resp in receiver.incoming_messages() {
+    match resp {
+      Ok(OwnedMessage::Binary(buff)) => {
+        if let Ok(wire_res) = serde_cbor::from_slice::<WireData>(&buff[..]) {
+          if ! deliver(wire_res, &mut on_reply) {
+            break;
+          }
+        }
       }
-      if let Err(e) = stream.set_write_timeout(Some(Duration::from_millis(256))) {
-        println!("Error setting TCP write timeout: {}", e);
+      Ok(OwnedMessage::Close(_)) => {
+        break;
       }
-      
-      if let Ok(bytes) = serde_cbor::to_vec(&wire_data) {
-        if let Err(e) = stream.write(&bytes) {
-          println!("Error sending WireData to server in publish_unix_server_sync: {}", e);
-        }
-        // Write the terminal 0xff byte
-        if let Err(e) = stream.write(&[0xff]) {
-          println!("Error sending WireData to server in publish_unix_server_sync: {}", e);
+      Ok(unk) => {
+        println!("Unsupported websocket msg: {:?}", unk);
+      }
+      Err(WebSocketError::IoError(ref e)) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => {
+        if ! on_timeout() {
+          break;
         }
       }
-      // At the moment we don't expect data back from the server
+      Err(WebSocketError::NoDataAvailable) => {
+        break; // Server closed the connection
+      }
+      Err(e) => {
+        println!("Error reading from server: {}", e);
+        break;
+      }
     }
-    Err(e) => {
-      println!("Error in publish_unix_server_sync: {}", e);
+  }
+  return Ok(());
+}
+
+fn print_unexpected(wire_res: &WireData) {
+  if wire_res.action == Action::error {
+    println!("Error from server: {}", error_message(wire_res));
+  }
+  else {
+    println!("Unexpected action from server, ignoring packet: {}", wire_res.action);
+  }
+}
+
+fn error_message(wire_res: &WireData) -> String {
+  match wire_res.record.p.get("error-message") {
+    Some(msg) => msg.to_string(),
+    None => "unknown error".to_string(),
+  }
+}
+
+// Why a server did not store a published record,
+// or did not apply a delete or update.
+#[derive(Debug)]
+pub enum PublishError {
+  // The server refused the record, eg "bad-signature" or "empty-record"
+  Rejected { code: String, message: String },
+  // The connection ended without an ack; the server may predate acks
+  // or the record was lost along the way.
+  NoAck,
+  Io(std::io::Error),
+}
+
+impl std::fmt::Display for PublishError {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      PublishError::Rejected { code, message } => write!(f, "{} ({})", message, code),
+      PublishError::NoAck => write!(f, "server did not acknowledge the record"),
+      PublishError::Io(e) => write!(f, "{}", e),
     }
   }
-  
 }
 
+impl PublishError {
+  pub fn from_reply(wire_res: &WireData) -> PublishError {
+    PublishError::Rejected {
+      code: wire_res.record.p.get("error-code").cloned().unwrap_or_default(),
+      message: error_message(wire_res),
+    }
+  }
+}
 
-pub fn publish_websocket_server_sync(_config: &Config, server: &Server, rec: &Record) {
-  use websocket::client::ClientBuilder;
-  use websocket::{OwnedMessage};
+// Asks every server in config for its capabilities.
+// None means the server predates hello (or could not be reached).
+pub fn hello_sync(config: &Config) -> Vec<(Server, Option<Capabilities>)> {
+  let results: Arc<Mutex<Vec<(Server, Option<Capabilities>)>>> = Arc::new(Mutex::new(vec![]));
   
-  let wire_data = WireData {
-    action: Action::publish,
-    record: rec.clone(),
-  };
+  thread::scope(|s| {
+    let mut handlers = vec![];
+    
+    for server in &config.servers {
+      let t_results = results.clone();
+      let t_server = server.clone();
+      handlers.push(s.spawn(move |_| {
+        let caps = hello_server_sync(config, &t_server);
+        if let Ok(mut t_results) = t_results.lock() {
+          t_results.push((t_server, caps));
+        }
+      }));
+    }
+    
+    for h in handlers {
+      h.join().unwrap();
+    }
+  }).unwrap();
   
-  let ip_and_port = format!("ws://{}:{}", server.host, server.port);
-  let mut unconnected_client = ClientBuilder::new(&ip_and_port).expect("Cannot construct websocket client");
-  match unconnected_client.connect_insecure() {
-    Ok(client) => {
-      let (mut _receiver, mut sender) = client.split().unwrap();
-      
-      if let Ok(bytes) = serde_cbor::to_vec(&wire_data) {
-        if let Err(e) = sender.send_message(&OwnedMessage::Binary(bytes)) {
-          println!("Error sending WireData to server in publish_websocket_server_sync: {}", e);
+  return Arc::try_unwrap(results).unwrap().into_inner().unwrap();
+}
+
+// Returns the server's capabilities, negotiate them with Capabilities::ours()
+// to get what both sides can use. Later requests to server use the negotiated framing.
+pub fn hello_server_sync(config: &Config, server: &Server) -> Option<Capabilities> {
+  let mut server_caps = None;
+  let wire_data = WireData::hello(Capabilities::ours());
+  let res = request_server_sync(config, server, &wire_data, || false, |wire_res| {
+    match wire_res.action {
+      Action::hello => {
+        if let Some(caps) = &wire_res.caps {
+          let negotiated = Capabilities::ours().negotiate(caps);
+          if let Some(framing) = negotiated.framing.iter().filter_map(|name| Framing::from_name(name)).next() {
+            remember_framing(server, framing);
+          }
         }
+        server_caps = wire_res.caps;
+      }
+      _ => {
+        print_unexpected(&wire_res);
       }
-      // At the moment we don't expect data back from the server
     }
-    Err(e) => {
-      println!("Error in publish_websocket_server_sync: {}", e);
+    return true;
+  });
+  if let Err(e) = res {
+    if server.report_connect_errors {
+      println!("Error in hello_server_sync: {}", e);
     }
   }
+  return server_caps;
 }
 
-
-pub fn query_sync(config: &Config, query: &Record) -> Vec<Record> {
-  let results: Arc<Mutex<Vec<Record>>> = Arc::new(Mutex::new(vec![]));
+// Publishes to every server in config, returning the record id each
+// server assigned or why it did not store the record.
+pub fn publish_sync(config: &Config, query: &Record) -> Vec<(Server, Result<String, PublishError>)> {
+  let results: Arc<Mutex<Vec<(Server, Result<String, PublishError>)>>> = Arc::new(Mutex::new(vec![]));
   
   thread::scope(|s| {
     let mut handlers = vec![];
@@ -234,9 +478,9 @@ pub fn query_sync(config: &Config, query: &Record) -> Vec<Record> {
       let t_results = results.clone();
       let t_server = server.clone();
       handlers.push(s.spawn(move |_| {
-        let res_v = query_server_sync(config, &t_server, query);
+        let res = publish_server_sync(config, &t_server, query);
         if let Ok(mut t_results) = t_results.lock() {
-          t_results.extend_from_slice(&res_v[..]);
+          t_results.push((t_server, res));
         }
       }));
     }
@@ -249,405 +493,320 @@ pub fn query_sync(config: &Config, query: &Record) -> Vec<Record> {
   return Arc::try_unwrap(results).unwrap().into_inner().unwrap();
 }
 
-pub fn query_server_sync(config: &Config, server: &Server, query: &Record) -> Vec<Record> {
-  let mut results = match server.protocol {
-    ServerProtocol::TCP => {
-      query_tcp_server_sync(config, server, query)
-    }
-    ServerProtocol::UDP => {
-      query_udp_server_sync(config, server, query)
-    }
-    ServerProtocol::UNIX => {
-      query_unix_server_sync(config, server, query)
-    }
-    ServerProtocol::WEBSOCKET => {
-      query_websocket_server_sync(config, server, query)
-    }
-    ServerProtocol::MULTICAST => {
-      query_udp_server_sync(config, server, query)
+pub fn publish_server_sync(config: &Config, server: &Server, rec: &Record) -> Result<String, PublishError> {
+  let mut outcome = Err(PublishError::NoAck);
+  let wire_data = WireData::new(Action::publish, rec.clone());
+  let res = request_server_sync(config, server, &wire_data, || false, |wire_res| {
+    match wire_res.action {
+      Action::ack => {
+        if let Some(record_id) = wire_res.record.p.get("record-id") {
+          outcome = Ok(record_id.to_string());
+        }
+      }
+      Action::error => {
+        outcome = Err(PublishError::from_reply(&wire_res));
+      }
+      _ => {
+        print_unexpected(&wire_res);
+      }
     }
-  };
-  
-  // Now write record.src_server for all records
-  for i in 0..results.len() {
-    results[i].src_server = Some(server.clone());
+    return true;
+  });
+  if let Err(e) = res {
+    return Err(PublishError::Io(e));
   }
-  
-  return results;
+  return outcome;
 }
 
-pub fn query_tcp_server_sync(_config: &Config, server: &Server, query: &Record) -> Vec<Record> {
-  use std::net::TcpStream;
-  
-  let mut results = vec![];
-  
-  let wire_data = WireData {
-    action: Action::query,
-    record: query.clone(),
-  };
-  
-  let ip_and_port = format!("{}:{}", server.host, server.port);
-  match TcpStream::connect(&ip_and_port) {
-    Ok(mut stream) => {
-      if let Err(e) = stream.set_read_timeout(Some(Duration::from_millis(256))) {
-        println!("Error setting TCP read timeout: {}", e);
+pub fn publish_tcp_server_sync(config: &Config, server: &Server, rec: &Record) -> Result<String, PublishError> {
+  publish_server_sync(config, &Server { protocol: ServerProtocol::TCP, ..server.clone() }, rec)
+}
+
+pub fn publish_udp_server_sync(config: &Config, server: &Server, rec: &Record) -> Result<String, PublishError> {
+  publish_server_sync(config, &Server { protocol: ServerProtocol::UDP, ..server.clone() }, rec)
+}
+
+pub fn publish_unix_server_sync(config: &Config, server: &Server, rec: &Record) -> Result<String, PublishError> {
+  publish_server_sync(config, &Server { protocol: ServerProtocol::UNIX, ..server.clone() }, rec)
+}
+
+pub fn publish_websocket_server_sync(config: &Config, server: &Server, rec: &Record) -> Result<String, PublishError> {
+  publish_server_sync(config, &Server { protocol: ServerProtocol::WEBSOCKET, ..server.clone() }, rec)
+}
+
+// Removes the records selected by target from every server, returning how
+// many each removed. Signed records need config to hold the same identity.
+pub fn delete_sync(config: &Config, target: &Target) -> Vec<(Server, Result<usize, PublishError>)> {
+  return for_each_server(config, |server| delete_server_sync(config, server, target));
+}
+
+pub fn delete_server_sync(config: &Config, server: &Server, target: &Target) -> Result<usize, PublishError> {
+  let wire_data = maybe_authorize(config, WireData::new(Action::delete, Record::empty()).with_target(target.clone()));
+  let ack = modify_server_sync(config, server, &wire_data)?;
+  return Ok(ack.p.get("removed").and_then(|n| n.parse().ok()).unwrap_or(0));
+}
+
+// Replaces the records selected by target with rec on every server,
+// returning the id each server assigned to rec.
+pub fn update_sync(config: &Config, target: &Target, rec: &Record) -> Vec<(Server, Result<String, PublishError>)> {
+  return for_each_server(config, |server| update_server_sync(config, server, target, rec));
+}
+
+pub fn update_server_sync(config: &Config, server: &Server, target: &Target, rec: &Record) -> Result<String, PublishError> {
+  let wire_data = maybe_authorize(config, WireData::new(Action::update, rec.clone()).with_target(target.clone()));
+  let ack = modify_server_sync(config, server, &wire_data)?;
+  match ack.p.get("record-id") {
+    Some(record_id) => Ok(record_id.to_string()),
+    None => Err(PublishError::NoAck),
+  }
+}
+
+// Signing an authorization proves we own the records a delete or update
+// changes, see server::modify_records. Only done when signing is enabled.
+pub fn maybe_authorize(config: &Config, wire_data: WireData) -> WireData {
+  if ! config.client_use_sig {
+    return wire_data;
+  }
+  let target = wire_data.target.clone().unwrap_or_default();
+  let replacement = if wire_data.action == Action::update { Some(&wire_data.record) } else { None };
+  let mut authorization = target.authorization(wire_data.action, replacement);
+  signing::maybe_sign_record(config, &mut authorization);
+  return wire_data.with_authorization(authorization);
+}
+
+// Returns the record of the server's ack
+fn modify_server_sync(config: &Config, server: &Server, wire_data: &WireData) -> Result<Record, PublishError> {
+  let mut outcome = Err(PublishError::NoAck);
+  let res = request_server_sync(config, server, wire_data, || false, |wire_res| {
+    match wire_res.action {
+      Action::ack => {
+        outcome = Ok(wire_res.record);
       }
-      if let Err(e) = stream.set_write_timeout(Some(Duration::from_millis(256))) {
-        println!("Error setting TCP write timeout: {}", e);
+      Action::error => {
+        outcome = Err(PublishError::from_reply(&wire_res));
       }
-      
-      if let Ok(bytes) = serde_cbor::to_vec(&wire_data) {
-        if let Err(e) = stream.write(&bytes) {
-          println!("Error sending WireData to server in query_tcp_server_sync: {}", e);
-          return vec![];
-        }
-        // Write the terminal 0xff byte
-        if let Err(e) = stream.write(&[0xff]) {
-          println!("Error sending WireData to server in query_tcp_server_sync: {}", e);
-          return vec![];
-        }
+      _ => {
+        print_unexpected(&wire_res);
       }
-      
-      // Read results until connection is closed
-      // We use 0xff ("break" stop code in the CBOR spec (rfc 7049))
-      // as a deliminator because it is least likely to interfere with CBOR stuff.
-      
-      let mut buff = [0; 16 * 1024];
-      let mut overflow_buff = vec![]; // Unused but read-in bytes are appended here
-      loop {
-        match stream.read(&mut buff) {
-          Ok(num_read) => {
-            let new_bytes_read = &buff[0..num_read];
-            let mut parse_buff = overflow_buff.clone();
-            parse_buff.extend_from_slice(new_bytes_read);
-            let parse_buff = parse_buff; // All unparsed bytes
-            // Jump to the next 0xff byte and parse data from 0..ff_i as a WireData object
-            if let Some(ff_i) = parse_buff.iter().position(|&r| r == 0xff) {
-              // There exists a 0xff at ff_i, use it to get the CBOR bytes
-              let mut cbor_slice = vec![];
-              cbor_slice.extend_from_slice(&parse_buff[0..ff_i]);
-              // Remove last 0xff byte from cbor_slice
-              if cbor_slice.last().eq(&Some(&0xff)) {
-                cbor_slice.pop();
-              }
-              if let Ok(wire_res) = serde_cbor::from_slice::<WireData>(&cbor_slice) {
-                //println!("wire_res={:?}", wire_res);
-                match wire_res.action {
-                  Action::end_of_results => {
-                    break;
-                  }
-                  Action::result => {
-                    results.push(wire_res.record);
-                  }
-                  unexpected => {
-                    println!("Unexpected action from server, ignoring packet: {}", unexpected);
-                  }
-                }
-              }
-              // TODO improve below
-              // For the moment we always throw out bytes reguardless of if they were a valid WireData or not
-              overflow_buff = vec![];
-              overflow_buff.extend_from_slice(&parse_buff[ff_i+1..]);
-            }
-            else {
-              // There is no 0xff, we must read more data.
-              // For the moment store all data in overflow_buff
-              overflow_buff = parse_buff.clone();
-            }
-            
-          }
-          Err(e) => {
-            println!("Error reading from TCP: {}", e);
-            break;
-          }
+    }
+    return true;
+  });
+  if let Err(e) = res {
+    return Err(PublishError::Io(e));
+  }
+  return outcome;
+}
+
+// Runs f against every server in config at once
+fn for_each_server<T: Send, F: Fn(&Server) -> T + Sync>(config: &Config, f: F) -> Vec<(Server, T)> {
+  let results: Arc<Mutex<Vec<(Server, T)>>> = Arc::new(Mutex::new(vec![]));
+  
+  thread::scope(|s| {
+    let mut handlers = vec![];
+    
+    for server in &config.servers {
+      let t_results = results.clone();
+      let f = &f;
+      handlers.push(s.spawn(move |_| {
+        let res = f(server);
+        if let Ok(mut t_results) = t_results.lock() {
+          t_results.push((server.clone(), res));
         }
-      }
-      
+      }));
     }
-    Err(e) => {
-      if server.report_connect_errors {
-        println!("Error in query_tcp_server_sync: {}", e);
-      }
-      return vec![];
+    
+    for h in handlers {
+      h.join().unwrap();
     }
-  }
+  }).unwrap();
   
-  return results;
+  return Arc::try_unwrap(results).ok().unwrap().into_inner().unwrap();
 }
 
-pub fn query_udp_server_sync(_config: &Config, server: &Server, query: &Record) -> Vec<Record> {
-  use std::net::UdpSocket;
-  
-  let mut results = vec![];
+pub fn query_sync(config: &Config, query: &Record) -> Vec<Record> {
+  let results: Arc<Mutex<Vec<Record>>> = Arc::new(Mutex::new(vec![]));
   
-  let wire_data = WireData {
-    action: Action::query,
-    record: query.clone(),
-  };
+  thread::scope(|s| {
+    let mut handlers = vec![];
+    
+    for server in &config.servers {
+      let t_results = results.clone();
+      let t_server = server.clone();
+      handlers.push(s.spawn(move |_| {
+        let res_v = query_server_sync(config, &t_server, query);
+        if let Ok(mut t_results) = t_results.lock() {
+          t_results.extend_from_slice(&res_v[..]);
+        }
+      }));
+    }
+    
+    for h in handlers {
+      h.join().unwrap();
+    }
+  }).unwrap();
   
-  let server_ip_and_port = format!("{}:{}", server.host, server.port);
+  return Arc::try_unwrap(results).unwrap().into_inner().unwrap();
+}
+
+pub fn query_server_sync(config: &Config, server: &Server, query: &Record) -> Vec<Record> {
+  return query_server_page_sync(config, server, query, &QueryOptions::default()).records;
+}
+
+// The results of a query with QueryOptions from one server
+#[derive(Debug, Clone, Default)]
+pub struct QueryPage {
+  pub records: Vec<Record>,
+  // Set QueryOptions::cursor to this to get the next page from the same server
+  pub next_cursor: Option<String>,
+}
+
+// Queries every server with options. Each server applies them to its own records.
+pub fn query_pages_sync(config: &Config, query: &Record, options: &QueryOptions) -> Vec<(Server, QueryPage)> {
+  let pages: Arc<Mutex<Vec<(Server, QueryPage)>>> = Arc::new(Mutex::new(vec![]));
   
-  match UdpSocket::bind("0.0.0.0:0") {
-    Ok(socket) => {
-      if let Err(e) = socket.set_read_timeout(Some(Duration::from_millis(256))) {
-        println!("Error setting UDP read timeout: {}", e);
-      }
-      if let Err(e) = socket.set_write_timeout(Some(Duration::from_millis(256))) {
-        println!("Error setting UDP write timeout: {}", e);
-      }
-      
-      if let Ok(bytes) = serde_cbor::to_vec(&wire_data) {
-        if let Err(e) = socket.send_to(&bytes, &server_ip_and_port) {
-          println!("Error sending WireData to server in query_udp_server_sync: {}", e);
-          return vec![];
-        }
-        // Write the terminal 0xff byte
-        if let Err(e) = socket.send_to(&[0xff], &server_ip_and_port) {
-          println!("Error sending WireData to server in query_udp_server_sync: {}", e);
-          return vec![];
-        }
-      }
-      
-      // Read results until connection is closed
-      // We use 0xff ("break" stop code in the CBOR spec (rfc 7049))
-      // as a deliminator because it is least likely to interfere with CBOR stuff.
-      
-      //let start = Instant::now(); // TODO add timeout on all these queries
-      let mut buff = [0; 16 * 1024];
-      let mut overflow_buff = vec![]; // Unused but read-in bytes are appended here
-      loop {
-        match socket.recv_from(&mut buff) {
-          Ok((num_read, _src_socket)) => {
-            let new_bytes_read = &buff[0..num_read];
-            let mut parse_buff = overflow_buff.clone();
-            parse_buff.extend_from_slice(new_bytes_read);
-            let parse_buff = parse_buff; // All unparsed bytes
-            // Jump to the next 0xff byte and parse data from 0..ff_i as a WireData object
-            if let Some(ff_i) = parse_buff.iter().position(|&r| r == 0xff) {
-              // There exists a 0xff at ff_i, use it to get the CBOR bytes
-              let mut cbor_slice = vec![];
-              cbor_slice.extend_from_slice(&parse_buff[0..ff_i]);
-              // Remove last 0xff byte from cbor_slice
-              if cbor_slice.last().eq(&Some(&0xff)) {
-                cbor_slice.pop();
-              }
-              if let Ok(wire_res) = serde_cbor::from_slice::<WireData>(&cbor_slice) {
-                //println!("wire_res={:?}", wire_res);
-                match wire_res.action {
-                  Action::end_of_results => {
-                    break;
-                  }
-                  Action::result => {
-                    results.push(wire_res.record);
-                  }
-                  unexpected => {
-                    println!("Unexpected action from server, ignoring packet: {}", unexpected);
-                  }
-                }
-              }
-              // TODO improve below
-              // For the moment we always throw out bytes reguardless of if they were a valid WireData or not
-              overflow_buff = vec![];
-              overflow_buff.extend_from_slice(&parse_buff[ff_i+1..]);
-            }
-            else {
-              // There is no 0xff, we must read more data.
-              // For the moment store all data in overflow_buff
-              overflow_buff = parse_buff.clone();
-            }
-            
-          }
-          Err(e) => {
-            if e.kind() == std::io::ErrorKind::WouldBlock {
-              // "fatal" error; we actually probably want to handle
-              // this somehow, but for now this is a reliable server-dropped-conn
-              // signal.
-              break;
-            }
-            println!("Error reading from UDP: {} (kind={:?})", &e, &e.kind());
-            break;
-          }
+  thread::scope(|s| {
+    let mut handlers = vec![];
+    
+    for server in &config.servers {
+      let t_pages = pages.clone();
+      let t_server = server.clone();
+      handlers.push(s.spawn(move |_| {
+        let page = query_server_page_sync(config, &t_server, query, options);
+        if let Ok(mut t_pages) = t_pages.lock() {
+          t_pages.push((t_server, page));
         }
-      }
-      
+      }));
     }
-    Err(e) => {
-      if server.report_connect_errors {
-        println!("Error in query_udp_server_sync: {}", e);
-      }
-      return vec![];
+    
+    for h in handlers {
+      h.join().unwrap();
     }
-  }
+  }).unwrap();
   
-  return results;
+  return Arc::try_unwrap(pages).unwrap().into_inner().unwrap();
 }
 
-#[cfg(not(unix))]
-pub fn query_unix_server_sync(config: &Config, server: &Server, query: &Record) -> Vec<Record> {
-  println!("Warning: Cannot query_unix_server_sync because architecture is not unix.");
-  return vec![];
+// Like query_sync, with sort_by and limit also applied to the combined results.
+// offset and cursor can only be applied by each server (see query_pages_sync).
+pub fn query_sync_with_options(config: &Config, query: &Record, options: &QueryOptions) -> Vec<Record> {
+  return merge_pages(options, query_pages_sync(config, query, options));
 }
 
-#[cfg(unix)]
-pub fn query_unix_server_sync(_config: &Config, server: &Server, query: &Record) -> Vec<Record> {
-  use std::os::unix::net::UnixStream;
-  
+// Full-text results are ranked by their score when there is no sort_by.
+pub fn merge_pages(options: &QueryOptions, pages: Vec<(Server, QueryPage)>) -> Vec<Record> {
   let mut results = vec![];
+  for (_server, page) in pages {
+    results.extend(page.records);
+  }
+  return merge_records(options, results);
+}
+
+// Orders results from several servers by options.sort_by and applies options.limit
+pub fn merge_records(options: &QueryOptions, mut results: Vec<Record>) -> Vec<Record> {
+  let ranked = options.sort_by.is_none() && results.iter().any(|r| r.p.contains_key(TEXT_SCORE_KEY));
+  let sort_by = if ranked { Some(TEXT_SCORE_KEY.to_string()) } else { options.sort_by.clone() };
+  let descending = ranked || options.descending;
+  if let Some(sort_by) = &sort_by {
+    results.sort_by(|a, b| cmp_sort_values(
+      a.p.get(sort_by).map(|v| v.as_str()), b.p.get(sort_by).map(|v| v.as_str()), descending
+    ));
+  }
+  if let Some(limit) = options.limit {
+    results.truncate(limit);
+  }
+  return results;
+}
+
+pub fn query_server_page_sync(config: &Config, server: &Server, query: &Record, options: &QueryOptions) -> QueryPage {
+  let mut page = QueryPage::default();
   
-  let wire_data = WireData {
-    action: Action::query,
-    record: query.clone(),
-  };
-  
-  match UnixStream::connect(&server.path) {
-    Ok(mut stream) => {
-      if let Err(e) = stream.set_read_timeout(Some(Duration::from_millis(256))) {
-        println!("Error setting Unix read timeout: {}", e);
+  let mut wire_data = WireData::new(Action::query, query.clone());
+  if ! options.is_empty() {
+    wire_data = wire_data.with_options(options.clone());
+  }
+  let res = request_server_sync(config, server, &wire_data, || false, |wire_res| {
+    match wire_res.action {
+      Action::result => {
+        page.records.push(wire_res.record);
       }
-      if let Err(e) = stream.set_write_timeout(Some(Duration::from_millis(256))) {
-        println!("Error setting Unix write timeout: {}", e);
+      Action::end_of_results => {
+        page.next_cursor = wire_res.record.p.get(NEXT_CURSOR_KEY).cloned();
       }
-      
-      if let Ok(bytes) = serde_cbor::to_vec(&wire_data) {
-        if let Err(e) = stream.write(&bytes) {
-          println!("Error sending WireData to server in query_unix_server_sync: {}", e);
-          return vec![];
-        }
-        // Write the terminal 0xff byte
-        if let Err(e) = stream.write(&[0xff]) {
-          println!("Error sending WireData to server in query_unix_server_sync: {}", e);
-          return vec![];
-        }
+      _ => {
+        print_unexpected(&wire_res);
       }
-      
-      // Read results until connection is closed
-      // We use 0xff ("break" stop code in the CBOR spec (rfc 7049))
-      // as a deliminator because it is least likely to interfere with CBOR stuff.
-      
-      let mut buff = [0; 16 * 1024];
-      let mut overflow_buff = vec![]; // Unused but read-in bytes are appended here
-      loop {
-        match stream.read(&mut buff) {
-          Ok(num_read) => {
-            let new_bytes_read = &buff[0..num_read];
-            let mut parse_buff = overflow_buff.clone();
-            parse_buff.extend_from_slice(new_bytes_read);
-            let parse_buff = parse_buff; // All unparsed bytes
-            // Jump to the next 0xff byte and parse data from 0..ff_i as a WireData object
-            if let Some(ff_i) = parse_buff.iter().position(|&r| r == 0xff) {
-              // There exists a 0xff at ff_i, use it to get the CBOR bytes
-              let mut cbor_slice = vec![];
-              cbor_slice.extend_from_slice(&parse_buff[0..ff_i]);
-              // Remove last 0xff byte from cbor_slice
-              if cbor_slice.last().eq(&Some(&0xff)) {
-                cbor_slice.pop();
-              }
-              if let Ok(wire_res) = serde_cbor::from_slice::<WireData>(&cbor_slice) {
-                //println!("wire_res={:?}", wire_res);
-                match wire_res.action {
-                  Action::end_of_results => {
-                    break;
-                  }
-                  Action::result => {
-                    results.push(wire_res.record);
-                  }
-                  unexpected => {
-                    println!("Unexpected action from server, ignoring packet: {}", unexpected);
-                  }
-                }
-              }
-              // TODO improve below
-              // For the moment we always throw out bytes reguardless of if they were a valid WireData or not
-              overflow_buff = vec![];
-              overflow_buff.extend_from_slice(&parse_buff[ff_i+1..]);
-            }
-            else {
-              // There is no 0xff, we must read more data.
-              // For the moment store all data in overflow_buff
-              overflow_buff = parse_buff.clone();
-            }
-            
-          }
-          Err(e) => {
-            println!("Error reading from Unix: {}", e);
-            break;
-          }
-        }
-      }
-      
     }
-    Err(e) => {
-      if server.report_connect_errors {
-        println!("Error in query_unix_server_sync: {}", e);
-      }
-      return vec![];
+    return true;
+  });
+  if let Err(e) = res {
+    if server.report_connect_errors {
+      println!("Error in query_server_sync: {}", e);
     }
+    return QueryPage::default();
   }
   
-  return results;
-}
-
-pub fn query_websocket_server_sync(_config: &Config, server: &Server, query: &Record) -> Vec<Record> {
-  use websocket::client::ClientBuilder;
-  use websocket::{OwnedMessage};
-  
-  let mut results = vec![];
+  // Servers which predate query options send everything
+  if let Some(limit) = options.limit {
+    page.records.truncate(limit);
+  }
+  if options.fields.is_some() {
+    page.records = page.records.iter().map(|rec| options.project(rec)).collect();
+  }
   
-  let wire_data = WireData {
-    action: Action::query,
-    record: query.clone(),
-  };
+  // Now write record.src_server for all records
+  for i in 0..page.records.len() {
+    page.records[i].src_server = Some(server.clone());
+  }
   
-  let ip_and_port = format!("ws://{}:{}", server.host, server.port);
-  let mut unconnected_client = ClientBuilder::new(&ip_and_port).expect("Cannot construct websocket client");
-  match unconnected_client.connect_insecure() {
-    Ok(client) => {
-      let (mut receiver, mut sender) = client.split().unwrap();
-      
-      if let Ok(bytes) = serde_cbor::to_vec(&wire_data) {
-        if let Err(e) = sender.send_message(&OwnedMessage::Binary(bytes)) {
-          println!("Error sending WireData to server in query_websocket_server_sync: {}", e);
-          return vec![];
-        }
+  return page;
+}
+
+// Counts the records matching query on every server, grouped by the value of
+// group_by. Err holds why a server did not answer, eg it predates count.
+pub fn count_sync(config: &Config, query: &Record, group_by: Option<&str>) -> Vec<(Server, Result<Counts, String>)> {
+  return for_each_server(config, |server| count_server_sync(config, server, query, group_by));
+}
+
+pub fn count_server_sync(config: &Config, server: &Server, query: &Record, group_by: Option<&str>) -> Result<Counts, String> {
+  let mut wire_data = WireData::new(Action::count, query.clone());
+  if let Some(group_by) = group_by {
+    wire_data = wire_data.with_options(QueryOptions { group_by: Some(group_by.to_string()), ..QueryOptions::default() });
+  }
+  let mut outcome = Err("server did not send counts".to_string());
+  let res = request_server_sync(config, server, &wire_data, || false, |wire_res| {
+    match wire_res.action {
+      Action::result => {
+        outcome = Ok(Counts::from_record(&wire_res.record));
       }
-      
-      // Read results until connection is closed
-      // We read one CBOR WireData object per websocket packet
-      for resp in receiver.incoming_messages() {
-        if let Ok(resp) = resp {
-          match resp {
-            OwnedMessage::Binary(buff) => {
-              if let Ok(wire_res) = serde_cbor::from_slice::<WireData>(&buff[..]) {
-                match wire_res.action {
-                  Action::end_of_results => {
-                    break;
-                  }
-                  Action::result => {
-                    results.push(wire_res.record);
-                  }
-                  unexpected => {
-                    println!("Unexpected action from server, ignoring packet: {}", unexpected);
-                  }
-                }
-              }
-            }
-            unk => {
-              println!("Unsupported websocket msg: {:?}", unk);
-            }
-          }
-        }
+      Action::error => {
+        outcome = Err(error_message(&wire_res));
       }
-      
-    }
-    Err(e) => {
-      if server.report_connect_errors {
-        println!("Error in query_websocket_server_sync: {}", e);
+      _ => {
+        print_unexpected(&wire_res);
       }
-      return vec![];
     }
+    return true;
+  });
+  if let Err(e) = res {
+    return Err(format!("{}", e));
   }
-  
-  return results;
+  return outcome;
+}
+
+pub fn query_tcp_server_sync(config: &Config, server: &Server, query: &Record) -> Vec<Record> {
+  query_server_sync(config, &Server { protocol: ServerProtocol::TCP, ..server.clone() }, query)
+}
+
+pub fn query_udp_server_sync(config: &Config, server: &Server, query: &Record) -> Vec<Record> {
+  query_server_sync(config, &Server { protocol: ServerProtocol::UDP, ..server.clone() }, query)
+}
+
+pub fn query_unix_server_sync(config: &Config, server: &Server, query: &Record) -> Vec<Record> {
+  query_server_sync(config, &Server { protocol: ServerProtocol::UNIX, ..server.clone() }, query)
+}
+
+pub fn query_websocket_server_sync(config: &Config, server: &Server, query: &Record) -> Vec<Record> {
+  query_server_sync(config, &Server { protocol: ServerProtocol::WEBSOCKET, ..server.clone() }, query)
 }
 
 pub fn listen_sync<F: Fn(Record) -> ListenAction + Send + Copy>(config: &Config, query: &Record, callback: F) {
@@ -667,6 +826,29 @@ pub fn listen_sync<F: Fn(Record) -> ListenAction + Send + Copy>(config: &Config,
   }).unwrap();
 }
 
+// Like listen_sync but also reports records which are removed
+pub fn listen_events_sync<F: Fn(ListenEvent) -> ListenAction + Send + Copy>(config: &Config, query: &Record, callback: F) {
+  listen_events_sync_with_options(config, query, &QueryOptions::default(), callback);
+}
+
+// options.replay first Publishes the records each server already has
+pub fn listen_events_sync_with_options<F: Fn(ListenEvent) -> ListenAction + Send + Copy>(config: &Config, query: &Record, options: &QueryOptions, callback: F) {
+  thread::scope(|s| {
+    let mut handlers = vec![];
+    
+    for server in &config.servers {
+      let t_server = server.clone();
+      handlers.push(s.spawn(move |_| {
+        listen_server_events_sync_with_options(config, &t_server, query, options, callback);
+      }));
+    }
+    
+    for h in handlers {
+      h.join().unwrap();
+    }
+  }).unwrap();
+}
+
 pub fn listen_sync_with_timeout<F: Fn(Record) -> ListenAction + Send + Copy>(config: &Config, query: &Record, timeout_ms: usize, callback: F) {
   thread::scope(|s| {
     let mut handlers = vec![];
@@ -685,22 +867,52 @@ pub fn listen_sync_with_timeout<F: Fn(Record) -> ListenAction + Send + Copy>(con
 }
 
 pub fn listen_server_sync<F: Fn(Record) -> ListenAction>(config: &Config, server: &Server, query: &Record, callback: F) {
-  match server.protocol {
-    ServerProtocol::TCP => {
-      listen_tcp_server_sync(config, server, query, callback);
-    }
-    ServerProtocol::UDP => {
-      listen_udp_server_sync(config, server, query, callback);
+  listen_server_events_sync(config, server, query, |event| {
+    match event {
+      ListenEvent::Published(rec) => callback(rec),
+      ListenEvent::Removed(_rec) => ListenAction::Continue,
+      ListenEvent::EndOfReplay => ListenAction::Continue,
     }
-    ServerProtocol::UNIX => {
-      listen_unix_server_sync(config, server, query, callback);
-    }
-    ServerProtocol::WEBSOCKET => {
-      listen_websocket_server_sync(config, server, query, callback);
-    }
-    ServerProtocol::MULTICAST => {
-      listen_udp_server_sync(config, server, query, callback);
+  });
+}
+
+pub fn listen_server_events_sync<F: Fn(ListenEvent) -> ListenAction>(config: &Config, server: &Server, query: &Record, callback: F) {
+  listen_server_events_sync_with_options(config, server, query, &QueryOptions::default(), callback);
+}
+
+// Servers without the "listen-replay" feature ignore options.replay and
+// never send EndOfReplay
+pub fn listen_server_events_sync_with_options<F: Fn(ListenEvent) -> ListenAction>(config: &Config, server: &Server, query: &Record, options: &QueryOptions, callback: F) {
+  let mut wire_data = WireData::new(Action::listen, query.clone());
+  if !options.is_empty() {
+    wire_data = wire_data.with_options(options.clone());
+  }
+  
+  // Read timeouts are expected and we don't disconnect when listening,
+  // except over UDP where a timeout is the only sign the server has gone.
+  let keep_waiting = match server.protocol {
+    ServerProtocol::UDP | ServerProtocol::MULTICAST => false,
+    _ => true,
+  };
+  let res = request_server_sync(config, server, &wire_data, || keep_waiting, |wire_res| {
+    match wire_res.action {
+      Action::result => {
+        return callback(ListenEvent::Published(wire_res.record)) != ListenAction::EndListen;
+      }
+      Action::removed => {
+        return callback(ListenEvent::Removed(wire_res.record)) != ListenAction::EndListen;
+      }
+      Action::end_of_replay => {
+        return callback(ListenEvent::EndOfReplay) != ListenAction::EndListen;
+      }
+      _ => {
+        print_unexpected(&wire_res);
+        return true;
+      }
     }
+  });
+  if let Err(e) = res {
+    println!("Error in listen_server_sync: {}", e);
   }
 }
 
@@ -721,470 +933,77 @@ pub fn listen_server_sync_with_timeout<F: Fn(Record) -> ListenAction>(config: &C
     ServerProtocol::MULTICAST => {
       std::unimplemented!() // listen_udp_server_sync_with_timeout(config, server, query, timeout_ms, callback);
     }
+    ServerProtocol::TLS | ServerProtocol::WSS => {
+      listen_with_timeout(config, server, query, timeout_ms, callback);
+    }
   }
 }
 
-pub fn listen_tcp_server_sync<F: Fn(Record) -> ListenAction>(_config: &Config, server: &Server, query: &Record, callback: F) {
-  use std::net::TcpStream;
-  
-  let wire_data = WireData {
-    action: Action::listen,
-    record: query.clone(),
-  };
-  
-  let ip_and_port = format!("{}:{}", server.host, server.port);
-  match TcpStream::connect(&ip_and_port) {
-    Ok(mut stream) => {
-      if let Err(e) = stream.set_read_timeout(Some(Duration::from_millis(256))) {
-        println!("Error setting TCP read timeout: {}", e);
-      }
-      if let Err(e) = stream.set_write_timeout(Some(Duration::from_millis(256))) {
-        println!("Error setting TCP write timeout: {}", e);
-      }
-      
-      if let Ok(bytes) = serde_cbor::to_vec(&wire_data) {
-        if let Err(e) = stream.write(&bytes) {
-          println!("Error sending WireData to server in listen_tcp_server_sync: {}", e);
-          return;
-        }
-        // Write the terminal 0xff byte
-        if let Err(e) = stream.write(&[0xff]) {
-          println!("Error sending WireData to server in listen_tcp_server_sync: {}", e);
-          return;
-        }
-      }
-      
-      // Read results until connection is closed
-      // We use 0xff ("break" stop code in the CBOR spec (rfc 7049))
-      // as a deliminator because it is least likely to interfere with CBOR stuff.
-      
-      let mut buff = [0; 16 * 1024];
-      let mut overflow_buff = vec![]; // Unused but read-in bytes are appended here
-      loop {
-        match stream.read(&mut buff) {
-          Ok(num_read) => {
-            let new_bytes_read = &buff[0..num_read];
-            let mut parse_buff = overflow_buff.clone();
-            parse_buff.extend_from_slice(new_bytes_read);
-            let parse_buff = parse_buff; // All unparsed bytes
-            // Jump to the next 0xff byte and parse data from 0..ff_i as a WireData object
-            if let Some(ff_i) = parse_buff.iter().position(|&r| r == 0xff) {
-              // There exists a 0xff at ff_i, use it to get the CBOR bytes
-              let mut cbor_slice = vec![];
-              cbor_slice.extend_from_slice(&parse_buff[0..ff_i]);
-              // Remove last 0xff byte from cbor_slice
-              if cbor_slice.last().eq(&Some(&0xff)) {
-                cbor_slice.pop();
-              }
-              if let Ok(wire_res) = serde_cbor::from_slice::<WireData>(&cbor_slice) {
-                //println!("wire_res={:?}", wire_res);
-                match wire_res.action {
-                  Action::end_of_results => {
-                    break;
-                  }
-                  Action::result => {
-                    if callback(wire_res.record) == ListenAction::EndListen {
-                      break;
-                    }
-                  }
-                  unexpected => {
-                    println!("Unexpected action from server, ignoring packet: {}", unexpected);
-                  }
-                }
-              }
-              // TODO improve below
-              // For the moment we always throw out bytes reguardless of if they were a valid WireData or not
-              overflow_buff = vec![];
-              overflow_buff.extend_from_slice(&parse_buff[ff_i+1..]);
-            }
-            else {
-              // There is no 0xff, we must read more data.
-              // For the moment store all data in overflow_buff
-              overflow_buff = parse_buff.clone();
-            }
-            
-          }
-          Err(_e) => {
-            // This is usually a timeout and we don't disconnect when listening
-            //println!("Error reading from TCP: {}", e);
-            //break;
-          }
-        }
-      }
-      
-    }
-    Err(e) => {
-      println!("Error in listen_tcp_server_sync: {}", e);
-    }
-  }
+pub fn listen_tcp_server_sync<F: Fn(Record) -> ListenAction>(config: &Config, server: &Server, query: &Record, callback: F) {
+  listen_server_sync(config, &Server { protocol: ServerProtocol::TCP, ..server.clone() }, query, callback);
 }
 
-pub fn listen_tcp_server_sync_with_timeout<F: Fn(Record) -> ListenAction>(_config: &Config, server: &Server, query: &Record, timeout_ms: usize, callback: F) {
-  use std::net::TcpStream;
-  use std::time::{SystemTime, UNIX_EPOCH};
-  
-  let wire_data = WireData {
-    action: Action::listen,
-    record: query.clone(),
-  };
-  
-  let ip_and_port = format!("{}:{}", server.host, server.port);
-  match TcpStream::connect(&ip_and_port) {
-    Ok(mut stream) => {
-      if let Err(e) = stream.set_read_timeout(Some(Duration::from_millis(256))) {
-        println!("Error setting TCP read timeout: {}", e);
-      }
-      if let Err(e) = stream.set_write_timeout(Some(Duration::from_millis(256))) {
-        println!("Error setting TCP write timeout: {}", e);
-      }
-      
-      if let Ok(bytes) = serde_cbor::to_vec(&wire_data) {
-        if let Err(e) = stream.write(&bytes) {
-          println!("Error sending WireData to server in listen_tcp_server_sync_with_timeout: {}", e);
-          return;
-        }
-        // Write the terminal 0xff byte
-        if let Err(e) = stream.write(&[0xff]) {
-          println!("Error sending WireData to server in listen_tcp_server_sync_with_timeout: {}", e);
-          return;
-        }
-      }
-      
-      // Read results until connection is closed
-      // We use 0xff ("break" stop code in the CBOR spec (rfc 7049))
-      // as a deliminator because it is least likely to interfere with CBOR stuff.
-      
-      let mut last_timeout_call_time = SystemTime::now();
-      let mut buff = [0; 16 * 1024];
-      let mut overflow_buff = vec![]; // Unused but read-in bytes are appended here
-      loop {
-        match stream.read(&mut buff) {
-          Ok(num_read) => {
-            let new_bytes_read = &buff[0..num_read];
-            let mut parse_buff = overflow_buff.clone();
-            parse_buff.extend_from_slice(new_bytes_read);
-            let parse_buff = parse_buff; // All unparsed bytes
-            // Jump to the next 0xff byte and parse data from 0..ff_i as a WireData object
-            if let Some(ff_i) = parse_buff.iter().position(|&r| r == 0xff) {
-              // There exists a 0xff at ff_i, use it to get the CBOR bytes
-              let mut cbor_slice = vec![];
-              cbor_slice.extend_from_slice(&parse_buff[0..ff_i]);
-              // Remove last 0xff byte from cbor_slice
-              if cbor_slice.last().eq(&Some(&0xff)) {
-                cbor_slice.pop();
-              }
-              if let Ok(wire_res) = serde_cbor::from_slice::<WireData>(&cbor_slice) {
-                //println!("wire_res={:?}", wire_res);
-                match wire_res.action {
-                  Action::end_of_results => {
-                    break;
-                  }
-                  Action::result => {
-                    if callback(wire_res.record) == ListenAction::EndListen {
-                      break;
-                    }
-                    last_timeout_call_time = SystemTime::now();
-                  }
-                  unexpected => {
-                    println!("Unexpected action from server, ignoring packet: {}", unexpected);
-                  }
-                }
-              }
-              // TODO improve below
-              // For the moment we always throw out bytes reguardless of if they were a valid WireData or not
-              overflow_buff = vec![];
-              overflow_buff.extend_from_slice(&parse_buff[ff_i+1..]);
-            }
-            else {
-              // There is no 0xff, we must read more data.
-              // For the moment store all data in overflow_buff
-              overflow_buff = parse_buff.clone();
-            }
-            
-          }
-          Err(_e) => {
-            // This is usually a timeout and we don't disconnect when listening
-            // We instead compute if timeout_ms has elapsed and if so we send
-            // an empty record to the listener to ensure they get called at least once every timeout_ms
-            match last_timeout_call_time.elapsed() {
-              Ok(elapsed) => {
-                if elapsed.as_millis() as usize > timeout_ms {
-                  if callback(Record::empty()) == ListenAction::EndListen {
-                    break;
-                  }
-                  last_timeout_call_time = SystemTime::now();
-                }
-              }
-              Err(e) => {
-                println!("Error getting elapsed time: {}", e);
-              }
-            }
-          }
-        }
-      }
-      
-    }
-    Err(e) => {
-      println!("Error in listen_tcp_server_sync_with_timeout: {}", e);
-    }
-  }
+pub fn listen_tcp_server_sync_with_timeout<F: Fn(Record) -> ListenAction>(config: &Config, server: &Server, query: &Record, timeout_ms: usize, callback: F) {
+  listen_with_timeout(config, &Server { protocol: ServerProtocol::TCP, ..server.clone() }, query, timeout_ms, callback);
 }
 
-pub fn listen_udp_server_sync<F: Fn(Record) -> ListenAction>(_config: &Config, server: &Server, query: &Record, callback: F) {
-  use std::net::UdpSocket;
-  
-  let wire_data = WireData {
-    action: Action::listen,
-    record: query.clone(),
-  };
-  
-  let server_ip_and_port = format!("{}:{}", server.host, server.port);
-  
-  match UdpSocket::bind("0.0.0.0:0") {
-    Ok(socket) => {
-      if let Err(e) = socket.set_read_timeout(Some(Duration::from_millis(256))) {
-        println!("Error setting UDP read timeout: {}", e);
-      }
-      if let Err(e) = socket.set_write_timeout(Some(Duration::from_millis(256))) {
-        println!("Error setting UDP write timeout: {}", e);
-      }
-      
-      if let Ok(bytes) = serde_cbor::to_vec(&wire_data) {
-        if let Err(e) = socket.send_to(&bytes, &server_ip_and_port) {
-          println!("Error sending WireData to server in publish_udp_server_sync: {}", e);
+// Listens using server's own protocol, which must call on_timeout while idle
+fn listen_with_timeout<F: Fn(Record) -> ListenAction>(config: &Config, server: &Server, query: &Record, timeout_ms: usize, callback: F) {
+  use std::cell::Cell;
+  use std::time::SystemTime;
+  
+  let wire_data = WireData::new(Action::listen, query.clone());
+  
+  let last_timeout_call_time = Cell::new(SystemTime::now());
+  let res = request_server_sync(config, server, &wire_data,
+    || {
+      // We don't disconnect when listening, instead we compute if timeout_ms
+      // has elapsed and if so we send an empty record to the listener to
+      // ensure they get called at least once every timeout_ms
+      match last_timeout_call_time.get().elapsed() {
+        Ok(elapsed) => {
+          if elapsed.as_millis() as usize > timeout_ms {
+            if callback(Record::empty()) == ListenAction::EndListen {
+              return false;
+            }
+            last_timeout_call_time.set(SystemTime::now());
+          }
         }
-        // Write the terminal 0xff byte
-        if let Err(e) = socket.send_to(&[0xff], &server_ip_and_port) {
-          println!("Error sending WireData to server in publish_udp_server_sync: {}", e);
+        Err(e) => {
+          println!("Error getting elapsed time: {}", e);
         }
       }
-      
-      // Read results until connection is closed
-      // We use 0xff ("break" stop code in the CBOR spec (rfc 7049))
-      // as a deliminator because it is least likely to interfere with CBOR stuff.
-      
-      //let start = Instant::now(); // TODO add timeout on all these queries
-      let mut buff = [0; 16 * 1024];
-      let mut overflow_buff = vec![]; // Unused but read-in bytes are appended here
-      loop {
-        match socket.recv_from(&mut buff) {
-          Ok((num_read, _src_socket)) => {
-            let new_bytes_read = &buff[0..num_read];
-            let mut parse_buff = overflow_buff.clone();
-            parse_buff.extend_from_slice(new_bytes_read);
-            let parse_buff = parse_buff; // All unparsed bytes
-            // Jump to the next 0xff byte and parse data from 0..ff_i as a WireData object
-            if let Some(ff_i) = parse_buff.iter().position(|&r| r == 0xff) {
-              // There exists a 0xff at ff_i, use it to get the CBOR bytes
-              let mut cbor_slice = vec![];
-              cbor_slice.extend_from_slice(&parse_buff[0..ff_i]);
-              // Remove last 0xff byte from cbor_slice
-              if cbor_slice.last().eq(&Some(&0xff)) {
-                cbor_slice.pop();
-              }
-              if let Ok(wire_res) = serde_cbor::from_slice::<WireData>(&cbor_slice) {
-                //println!("wire_res={:?}", wire_res);
-                match wire_res.action {
-                  Action::end_of_results => {
-                    break;
-                  }
-                  Action::result => {
-                    if callback(wire_res.record) == ListenAction::EndListen {
-                      break;
-                    }
-                  }
-                  unexpected => {
-                    println!("Unexpected action from server, ignoring packet: {}", unexpected);
-                  }
-                }
-              }
-              // TODO improve below
-              // For the moment we always throw out bytes reguardless of if they were a valid WireData or not
-              overflow_buff = vec![];
-              overflow_buff.extend_from_slice(&parse_buff[ff_i+1..]);
-            }
-            else {
-              // There is no 0xff, we must read more data.
-              // For the moment store all data in overflow_buff
-              overflow_buff = parse_buff.clone();
-            }
-            
-          }
-          Err(e) => {
-            if e.kind() == std::io::ErrorKind::WouldBlock {
-              // "fatal" error; we actually probably want to handle
-              // this somehow, but for now this is a reliable server-dropped-conn
-              // signal.
-              break;
-            }
-            println!("Error reading from UDP: {} (kind={:?})", &e, &e.kind());
-            break;
+      return true;
+    },
+    |wire_res| {
+      match wire_res.action {
+        Action::result => {
+          if callback(wire_res.record) == ListenAction::EndListen {
+            return false;
           }
+          last_timeout_call_time.set(SystemTime::now());
+          return true;
+        }
+        _ => {
+          print_unexpected(&wire_res);
+          return true;
         }
       }
-      
-    }
-    Err(e) => {
-      println!("Error in publish_udp_server_sync: {}", e);
     }
+  );
+  if let Err(e) = res {
+    println!("Error in listen_server_sync_with_timeout: {}", e);
   }
 }
 
-#[cfg(not(unix))]
-pub fn listen_unix_server_sync<F: Fn(Record) -> ListenAction>(_config: &Config, server: &Server, query: &Record, callback: F) {
-  println!("Warning: Cannot listen_unix_server_sync because architecture is not unix.");
-  return vec![];
+pub fn listen_udp_server_sync<F: Fn(Record) -> ListenAction>(config: &Config, server: &Server, query: &Record, callback: F) {
+  listen_server_sync(config, &Server { protocol: ServerProtocol::UDP, ..server.clone() }, query, callback);
 }
 
-#[cfg(unix)]
-pub fn listen_unix_server_sync<F: Fn(Record) -> ListenAction>(_config: &Config, server: &Server, query: &Record, callback: F) {
-  use std::os::unix::net::UnixStream;
-  
-  let wire_data = WireData {
-    action: Action::listen,
-    record: query.clone(),
-  };
-  
-  match UnixStream::connect(&server.path) {
-    Ok(mut stream) => {
-      if let Err(e) = stream.set_read_timeout(Some(Duration::from_millis(256))) {
-        println!("Error setting Unix read timeout: {}", e);
-      }
-      if let Err(e) = stream.set_write_timeout(Some(Duration::from_millis(256))) {
-        println!("Error setting Unix write timeout: {}", e);
-      }
-      
-      if let Ok(bytes) = serde_cbor::to_vec(&wire_data) {
-        if let Err(e) = stream.write(&bytes) {
-          println!("Error sending WireData to server in listen_unix_server_sync: {}", e);
-          return;
-        }
-        // Write the terminal 0xff byte
-        if let Err(e) = stream.write(&[0xff]) {
-          println!("Error sending WireData to server in listen_unix_server_sync: {}", e);
-          return;
-        }
-      }
-      
-      // Read results until connection is closed
-      // We use 0xff ("break" stop code in the CBOR spec (rfc 7049))
-      // as a deliminator because it is least likely to interfere with CBOR stuff.
-      
-      let mut buff = [0; 16 * 1024];
-      let mut overflow_buff = vec![]; // Unused but read-in bytes are appended here
-      loop {
-        match stream.read(&mut buff) {
-          Ok(num_read) => {
-            let new_bytes_read = &buff[0..num_read];
-            let mut parse_buff = overflow_buff.clone();
-            parse_buff.extend_from_slice(new_bytes_read);
-            let parse_buff = parse_buff; // All unparsed bytes
-            // Jump to the next 0xff byte and parse data from 0..ff_i as a WireData object
-            if let Some(ff_i) = parse_buff.iter().position(|&r| r == 0xff) {
-              // There exists a 0xff at ff_i, use it to get the CBOR bytes
-              let mut cbor_slice = vec![];
-              cbor_slice.extend_from_slice(&parse_buff[0..ff_i]);
-              // Remove last 0xff byte from cbor_slice
-              if cbor_slice.last().eq(&Some(&0xff)) {
-                cbor_slice.pop();
-              }
-              if let Ok(wire_res) = serde_cbor::from_slice::<WireData>(&cbor_slice) {
-                //println!("wire_res={:?}", wire_res);
-                match wire_res.action {
-                  Action::end_of_results => {
-                    break;
-                  }
-                  Action::result => {
-                    if callback(wire_res.record) == ListenAction::EndListen {
-                      break;
-                    }
-                  }
-                  unexpected => {
-                    println!("Unexpected action from server, ignoring packet: {}", unexpected);
-                  }
-                }
-              }
-              // TODO improve below
-              // For the moment we always throw out bytes reguardless of if they were a valid WireData or not
-              overflow_buff = vec![];
-              overflow_buff.extend_from_slice(&parse_buff[ff_i+1..]);
-            }
-            else {
-              // There is no 0xff, we must read more data.
-              // For the moment store all data in overflow_buff
-              overflow_buff = parse_buff.clone();
-            }
-            
-          }
-          Err(_e) => {
-            // This is usually a timeout and we don't disconnect when listening
-            //println!("Error reading from TCP: {}", e);
-            //break;
-          }
-        }
-      }
-      
-    }
-    Err(e) => {
-      println!("Error in listen_unix_server_sync: {}", e);
-    }
-  }
+pub fn listen_unix_server_sync<F: Fn(Record) -> ListenAction>(config: &Config, server: &Server, query: &Record, callback: F) {
+  listen_server_sync(config, &Server { protocol: ServerProtocol::UNIX, ..server.clone() }, query, callback);
 }
 
-pub fn listen_websocket_server_sync<F: Fn(Record) -> ListenAction>(_config: &Config, server: &Server, query: &Record, callback: F) {
-  use websocket::client::ClientBuilder;
-  use websocket::{OwnedMessage};
-  
-  let wire_data = WireData {
-    action: Action::listen,
-    record: query.clone(),
-  };
-  
-  let ip_and_port = format!("ws://{}:{}", server.host, server.port);
-  let mut unconnected_client = ClientBuilder::new(&ip_and_port).expect("Cannot construct websocket client");
-  match unconnected_client.connect_insecure() {
-    Ok(client) => {
-      let (mut receiver, mut sender) = client.split().unwrap();
-          
-      if let Ok(bytes) = serde_cbor::to_vec(&wire_data) {
-        if let Err(e) = sender.send_message(&OwnedMessage::Binary(bytes)) {
-          println!("Error sending WireData to server in listen_websocket_server_sync: {}", e);
-          return;
-        }
-      }
-      
-      // Read results until connection is closed
-      // We read one CBOR WireData object per websocket packet
-      for resp in receiver.incoming_messages() {
-        if let Ok(resp) = resp {
-          match resp {
-            OwnedMessage::Binary(buff) => {
-              if let Ok(wire_res) = serde_cbor::from_slice::<WireData>(&buff[..]) {
-                match wire_res.action {
-                  Action::end_of_results => {
-                    break;
-                  }
-                  Action::result => {
-                    if callback(wire_res.record) == ListenAction::EndListen {
-                      break;
-                    }
-                  }
-                  unexpected => {
-                    println!("Unexpected action from server, ignoring packet: {}", unexpected);
-                  }
-                }
-              }
-            }
-            unk => {
-              println!("Unsupported websocket msg: {:?}", unk);
-            }
-          }
-        }
-      }
-      
-    }
-    Err(e) => {
-      println!("Error in listen_websocket_server_sync: {}", e);
-    }
-  }
+pub fn listen_websocket_server_sync<F: Fn(Record) -> ListenAction>(config: &Config, server: &Server, query: &Record, callback: F) {
+  listen_server_sync(config, &Server { protocol: ServerProtocol::WEBSOCKET, ..server.clone() }, query, callback);
 }
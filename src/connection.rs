@@ -0,0 +1,454 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *  
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; version 2 of the License only.
+ * 
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ * 
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::actions::Action;
+use crate::client::{self, ListenAction, ListenEvent, PublishError};
+use crate::config::{Config, Server, ServerProtocol};
+use crate::framing::{Framing, FrameDecoder, FrameError, read_frame, write_frame};
+use crate::record::Record;
+use crate::wire::{WireData, Capabilities, Target, QueryOptions};
+use crate::tls;
+
+type FrameReader = Box<dyn FnMut() -> Result<Option<WireData>, FrameError> + Send>;
+type FrameWriter = Box<dyn FnMut(&WireData) -> std::io::Result<()> + Send>;
+type Shutdown = Box<dyn Fn() + Send + Sync>;
+
+// A long-lived connection to one server. Every request carries an id and
+// replies are routed back to the caller that made it, so any number of
+// threads can query, publish and listen over the same socket at once.
+//
+// Only servers which advertise the "multiplex" feature in hello are supported.
+pub struct Connection {
+  pub server: Server,
+  // What both we and the server support
+  pub caps: Capabilities,
+  next_id: AtomicU64,
+  writer: Arc<Mutex<FrameWriter>>,
+  pending: Arc<Mutex<HashMap<u64, Sender<WireData>>>>,
+  shutdown: Shutdown,
+}
+
+impl Connection {
+  pub fn open(config: &Config, server: &Server) -> std::io::Result<Connection> {
+    let (reader, writer, shutdown) = match server.protocol {
+      ServerProtocol::TCP => connect_tcp(server)?,
+      ServerProtocol::UNIX => connect_unix(server)?,
+      ServerProtocol::WEBSOCKET => connect_websocket(server)?,
+      ServerProtocol::TLS => connect_tls(config, server)?,
+      ServerProtocol::WSS => connect_wss(config, server)?,
+      ServerProtocol::UDP | ServerProtocol::MULTICAST => {
+        return Err(Error::new(ErrorKind::InvalidInput, "UDP servers do not support persistent connections"));
+      }
+    };
+    
+    let pending: Arc<Mutex<HashMap<u64, Sender<WireData>>>> = Arc::new(Mutex::new(HashMap::new()));
+    spawn_reader(reader, pending.clone());
+    
+    let mut conn = Connection {
+      server: server.clone(),
+      caps: Capabilities::legacy(),
+      next_id: AtomicU64::new(1),
+      writer: Arc::new(Mutex::new(writer)),
+      pending: pending,
+      shutdown: shutdown,
+    };
+    
+    let (_id, replies) = conn.request(WireData::hello(Capabilities::ours()))?;
+    // Old servers close the connection or answer without our id
+    let server_caps = match replies.recv_timeout(Duration::from_millis(1024)) {
+      Ok(ref wire_res) if wire_res.action == Action::hello && wire_res.caps.is_some() => {
+        wire_res.caps.clone().unwrap()
+      }
+      _ => {
+        return Err(Error::new(ErrorKind::Other, "server did not answer hello"));
+      }
+    };
+    conn.caps = Capabilities::ours().negotiate(&server_caps);
+    if ! conn.caps.supports_feature("multiplex") {
+      return Err(Error::new(ErrorKind::Other, "server does not support multiplexed connections"));
+    }
+    return Ok(conn);
+  }
+  
+  // Sends wire_data with a fresh id and returns a channel of every reply to it.
+  // The channel closes after end_of_results or error, or if the connection drops.
+  pub fn request(&self, mut wire_data: WireData) -> std::io::Result<(u64, Receiver<WireData>)> {
+    let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+    wire_data.id = Some(id);
+    
+    let (tx, rx) = mpsc::channel();
+    if let Ok(mut pending) = self.pending.lock() {
+      pending.insert(id, tx);
+    }
+    if let Err(e) = send(&self.writer, &wire_data) {
+      if let Ok(mut pending) = self.pending.lock() {
+        pending.remove(&id);
+      }
+      return Err(e);
+    }
+    return Ok((id, rx));
+  }
+  
+  pub fn query(&self, query: &Record) -> std::io::Result<Vec<Record>> {
+    let (_id, replies) = self.request(WireData::new(Action::query, query.clone()))?;
+    let mut results = vec![];
+    for wire_res in collect_replies(replies)? {
+      let mut rec = wire_res.record;
+      rec.src_server = Some(self.server.clone());
+      results.push(rec);
+    }
+    return Ok(results);
+  }
+  
+  // Returns the id the server assigned to rec
+  pub fn publish(&self, rec: &Record) -> Result<String, PublishError> {
+    let (_id, replies) = self.request(WireData::new(Action::publish, rec.clone())).map_err(PublishError::Io)?;
+    for wire_res in replies.iter() {
+      match wire_res.action {
+        Action::ack => {
+          if let Some(record_id) = wire_res.record.p.get("record-id") {
+            return Ok(record_id.to_string());
+          }
+          return Err(PublishError::NoAck);
+        }
+        Action::error => {
+          return Err(PublishError::from_reply(&wire_res));
+        }
+        _ => {
+          println!("Unexpected action from server, ignoring packet: {}", wire_res.action);
+        }
+      }
+    }
+    return Err(PublishError::NoAck);
+  }
+  
+  // Calls callback on a background thread for every new record matching query
+  // until it returns EndListen or cancel() is called with the returned id.
+  pub fn listen<F>(&self, query: &Record, mut callback: F) -> std::io::Result<u64>
+    where F: FnMut(Record) -> ListenAction + Send + 'static
+  {
+    self.listen_events(query, move |event| {
+      match event {
+        ListenEvent::Published(rec) => callback(rec),
+        ListenEvent::Removed(_rec) => ListenAction::Continue,
+        ListenEvent::EndOfReplay => ListenAction::Continue,
+      }
+    })
+  }
+  
+  // Like listen but also reports records which are removed
+  pub fn listen_events<F>(&self, query: &Record, callback: F) -> std::io::Result<u64>
+    where F: FnMut(ListenEvent) -> ListenAction + Send + 'static
+  {
+    self.listen_events_with_options(query, &QueryOptions::default(), callback)
+  }
+  
+  // options.replay first sends the records the server already has, then EndOfReplay
+  pub fn listen_events_with_options<F>(&self, query: &Record, options: &QueryOptions, mut callback: F) -> std::io::Result<u64>
+    where F: FnMut(ListenEvent) -> ListenAction + Send + 'static
+  {
+    let mut wire_data = WireData::new(Action::listen, query.clone());
+    if !options.is_empty() {
+      wire_data = wire_data.with_options(options.clone());
+    }
+    let (id, replies) = self.request(wire_data)?;
+    let writer = self.writer.clone();
+    let server = self.server.clone();
+    std::thread::spawn(move || {
+      let mut ended = false;
+      for mut wire_res in replies.iter() {
+        wire_res.record.src_server = Some(server.clone());
+        let event = match wire_res.action {
+          Action::result => ListenEvent::Published(wire_res.record),
+          Action::removed => ListenEvent::Removed(wire_res.record),
+          Action::end_of_replay => ListenEvent::EndOfReplay,
+          Action::end_of_results => {
+            break;
+          }
+          Action::error => {
+            print_error(&wire_res);
+            break;
+          }
+          _ => {
+            println!("Unexpected action from server, ignoring packet: {}", wire_res.action);
+            continue;
+          }
+        };
+        if ended {
+          continue;
+        }
+        if callback(event) == ListenAction::EndListen {
+          ended = true;
+          // Keep draining until the server confirms with end_of_results
+          if let Err(e) = send(&writer, &WireData::new(Action::cancel, Record::empty()).with_id(Some(id))) {
+            println!("Error cancelling listen: {}", e);
+            break;
+          }
+        }
+      }
+    });
+    return Ok(id);
+  }
+  
+  // Removes the records selected by target, returning how many were removed
+  pub fn delete(&self, config: &Config, target: &Target) -> Result<usize, PublishError> {
+    let ack = self.modify(client::maybe_authorize(config, WireData::new(Action::delete, Record::empty()).with_target(target.clone())))?;
+    return Ok(ack.p.get("removed").and_then(|n| n.parse().ok()).unwrap_or(0));
+  }
+  
+  // Replaces the records selected by target with rec, returning rec's new id
+  pub fn update(&self, config: &Config, target: &Target, rec: &Record) -> Result<String, PublishError> {
+    let ack = self.modify(client::maybe_authorize(config, WireData::new(Action::update, rec.clone()).with_target(target.clone())))?;
+    match ack.p.get("record-id") {
+      Some(record_id) => Ok(record_id.to_string()),
+      None => Err(PublishError::NoAck),
+    }
+  }
+  
+  fn modify(&self, wire_data: WireData) -> Result<Record, PublishError> {
+    let (_id, replies) = self.request(wire_data).map_err(PublishError::Io)?;
+    for wire_res in replies.iter() {
+      match wire_res.action {
+        Action::ack => {
+          return Ok(wire_res.record);
+        }
+        Action::error => {
+          return Err(PublishError::from_reply(&wire_res));
+        }
+        _ => {
+          println!("Unexpected action from server, ignoring packet: {}", wire_res.action);
+        }
+      }
+    }
+    return Err(PublishError::NoAck);
+  }
+  
+  // Stops a listen started on this connection
+  pub fn cancel(&self, id: u64) -> std::io::Result<()> {
+    send(&self.writer, &WireData::new(Action::cancel, Record::empty()).with_id(Some(id)))
+  }
+}
+
+impl Drop for Connection {
+  fn drop(&mut self) {
+    // Ends the reader thread, which closes every outstanding reply channel
+    (self.shutdown)();
+  }
+}
+
+fn send(writer: &Arc<Mutex<FrameWriter>>, wire_data: &WireData) -> std::io::Result<()> {
+  match writer.lock() {
+    Ok(mut writer) => (writer)(wire_data),
+    Err(_e) => Err(Error::new(ErrorKind::Other, "connection writer poisoned")),
+  }
+}
+
+// Waits for the end of a request, returning every reply before it
+fn collect_replies(replies: Receiver<WireData>) -> std::io::Result<Vec<WireData>> {
+  let mut results = vec![];
+  for wire_res in replies.iter() {
+    match wire_res.action {
+      Action::end_of_results | Action::ack => {
+        return Ok(results);
+      }
+      Action::error => {
+        return Err(Error::new(ErrorKind::Other, error_message(&wire_res)));
+      }
+      _ => {
+        results.push(wire_res);
+      }
+    }
+  }
+  return Err(Error::new(ErrorKind::ConnectionAborted, "connection closed before the request finished"));
+}
+
+fn error_message(wire_res: &WireData) -> String {
+  match wire_res.record.p.get("error-message") {
+    Some(msg) => msg.to_string(),
+    None => "unknown error".to_string(),
+  }
+}
+
+fn print_error(wire_res: &WireData) {
+  println!("Error from server: {}", error_message(wire_res));
+}
+
+// Routes replies to the channel registered for their id
+fn spawn_reader(mut reader: FrameReader, pending: Arc<Mutex<HashMap<u64, Sender<WireData>>>>) {
+  std::thread::spawn(move || {
+    loop {
+      match reader() {
+        Ok(Some(wire_res)) => {
+          let id = match wire_res.id {
+            Some(id) => id,
+            None => {
+              println!("Unexpected reply without request id, ignoring packet: {}", wire_res.action);
+              continue;
+            }
+          };
+          let is_last = wire_res.ends_request();
+          if let Ok(mut pending) = pending.lock() {
+            let delivered = match pending.get(&id) {
+              Some(tx) => tx.send(wire_res).is_ok(),
+              None => false,
+            };
+            if is_last || ! delivered {
+              pending.remove(&id);
+            }
+          }
+        }
+        Ok(None) => {
+          break;
+        }
+        Err(ref e) if e.is_timeout() => {
+          continue;
+        }
+        Err(e) => {
+          println!("Error reading from server: {}", e);
+          break;
+        }
+      }
+    }
+    // Dropping the senders wakes everyone waiting on a reply
+    if let Ok(mut pending) = pending.lock() {
+      pending.clear();
+    }
+  });
+}
+
+fn connect_tcp(server: &Server) -> std::io::Result<(FrameReader, FrameWriter, Shutdown)> {
+  use std::net::{TcpStream, Shutdown as NetShutdown};
+  
+  let ip_and_port = format!("{}:{}", server.host, server.port);
+  let mut read_stream = TcpStream::connect(&ip_and_port)?;
+  read_stream.set_read_timeout(Some(Duration::from_millis(256)))?;
+  let mut write_stream = read_stream.try_clone()?;
+  let shutdown_stream = read_stream.try_clone()?;
+  
+  let mut decoder = FrameDecoder::with_framing(Framing::Length);
+  return Ok((
+    Box::new(move || read_frame(&mut read_stream, &mut decoder)),
+    Box::new(move |wire_data| write_frame(&mut write_stream, wire_data, Framing::Length).map_err(|e| Error::new(ErrorKind::Other, format!("{}", e)))),
+    Box::new(move || { let _ = shutdown_stream.shutdown(NetShutdown::Both); }),
+  ));
+}
+
+fn connect_tls(config: &Config, server: &Server) -> std::io::Result<(FrameReader, FrameWriter, Shutdown)> {
+  let mut read_stream = tls::connect(config, server, Some(Duration::from_millis(256)))?;
+  let mut write_stream = read_stream.clone();
+  let shutdown_stream = read_stream.clone();
+  
+  let mut decoder = FrameDecoder::with_framing(Framing::Length);
+  return Ok((
+    Box::new(move || read_frame(&mut read_stream, &mut decoder)),
+    Box::new(move |wire_data| write_frame(&mut write_stream, wire_data, Framing::Length).map_err(|e| Error::new(ErrorKind::Other, format!("{}", e)))),
+    Box::new(move || { let _ = shutdown_stream.shutdown(); }),
+  ));
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_server: &Server) -> std::io::Result<(FrameReader, FrameWriter, Shutdown)> {
+  return Err(Error::new(ErrorKind::InvalidInput, "Cannot use unix sockets because architecture is not unix"));
+}
+
+#[cfg(unix)]
+fn connect_unix(server: &Server) -> std::io::Result<(FrameReader, FrameWriter, Shutdown)> {
+  use std::os::unix::net::UnixStream;
+  use std::net::Shutdown as NetShutdown;
+  
+  let mut read_stream = UnixStream::connect(&server.path)?;
+  read_stream.set_read_timeout(Some(Duration::from_millis(256)))?;
+  let mut write_stream = read_stream.try_clone()?;
+  let shutdown_stream = read_stream.try_clone()?;
+  
+  let mut decoder = FrameDecoder::with_framing(Framing::Length);
+  return Ok((
+    Box::new(move || read_frame(&mut read_stream, &mut decoder)),
+    Box::new(move |wire_data| write_frame(&mut write_stream, wire_data, Framing::Length).map_err(|e| Error::new(ErrorKind::Other, format!("{}", e)))),
+    Box::new(move || { let _ = shutdown_stream.shutdown(NetShutdown::Both); }),
+  ));
+}
+
+fn connect_websocket(server: &Server) -> std::io::Result<(FrameReader, FrameWriter, Shutdown)> {
+  use websocket::client::ClientBuilder;
+  use std::net::Shutdown as NetShutdown;
+  
+  let to_io_err = |e: websocket::WebSocketError| Error::new(ErrorKind::Other, format!("{}", e));
+  
+  let ip_and_port = format!("ws://{}:{}", server.host, server.port);
+  let mut unconnected_client = ClientBuilder::new(&ip_and_port).map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{}", e)))?;
+  let client = unconnected_client.connect_insecure().map_err(to_io_err)?;
+  let shutdown_stream = client.stream_ref().try_clone()?;
+  return websocket_parts(client, Box::new(move || { let _ = shutdown_stream.shutdown(NetShutdown::Both); }));
+}
+
+fn connect_wss(config: &Config, server: &Server) -> std::io::Result<(FrameReader, FrameWriter, Shutdown)> {
+  use websocket::client::ClientBuilder;
+  
+  let to_io_err = |e: websocket::WebSocketError| Error::new(ErrorKind::Other, format!("{}", e));
+  
+  let ip_and_port = format!("wss://{}:{}", server.host, server.port);
+  let mut unconnected_client = ClientBuilder::new(&ip_and_port).map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{}", e)))?;
+  // The reader waits for replies without a timeout, TlsStream polls the
+  // socket so it never holds up the writers while waiting
+  let stream = tls::connect(config, server, None)?;
+  let shutdown_stream = stream.clone();
+  let client = unconnected_client.connect_on(stream).map_err(to_io_err)?;
+  return websocket_parts(client, Box::new(move || { let _ = shutdown_stream.shutdown(); }));
+}
+
+// One CBOR WireData per binary websocket message
+fn websocket_parts<S>(client: websocket::sync::Client<S>, shutdown: Shutdown) -> std::io::Result<(FrameReader, FrameWriter, Shutdown)>
+  where S: websocket::sync::Stream + websocket::sync::stream::Splittable,
+        S::Reader: Send + 'static, S::Writer: Send + 'static
+{
+  use websocket::OwnedMessage;
+  
+  let to_io_err = |e: websocket::WebSocketError| Error::new(ErrorKind::Other, format!("{}", e));
+  let (mut receiver, mut sender) = client.split()?;
+  
+  return Ok((
+    Box::new(move || {
+      loop {
+        match receiver.recv_message() {
+          Ok(OwnedMessage::Binary(buff)) => {
+            return serde_cbor::from_slice::<WireData>(&buff[..]).map(Some).map_err(FrameError::Cbor);
+          }
+          Ok(OwnedMessage::Close(_)) | Err(_) => {
+            return Ok(None);
+          }
+          Ok(_) => {
+            continue;
+          }
+        }
+      }
+    }),
+    Box::new(move |wire_data| {
+      let bytes = serde_cbor::to_vec(wire_data).map_err(|e| Error::new(ErrorKind::Other, format!("{}", e)))?;
+      sender.send_message(&OwnedMessage::Binary(bytes)).map_err(to_io_err)
+    }),
+    shutdown,
+  ));
+}
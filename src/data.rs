@@ -16,28 +16,78 @@
  *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
  */
 
-use regex::Regex;
 use num_cpus;
 use crossbeam_utils::thread;
 
-use std::sync::{Arc, RwLock, Mutex};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::collections::HashMap;
+use std::sync::{Arc, RwLock, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::collections::{HashSet, BTreeMap};
+use std::time::{Duration, Instant};
+use std::cmp::Ordering as CmpOrdering;
 use std::sync::mpsc::{Sender};
 
-use crate::record::Record;
-use crate::config::Config;
-use crate::wire::WireData;
+use crate::record::{Record, now_ms, TTL_EXPIRES_AT_KEY};
+use crate::query::{Query, QueryCache, RegexLimits, TextStats, Cursor, cmp_sort_values, TEXT_SCORE_KEY};
+use crate::config::{Config, EvictionPolicy};
+use crate::wire::{WireData, QueryOptions, Counts, Announcement, Target, AUTH_MAX_AGE_MS};
+use crate::actions::Action;
+use crate::signing;
+use crate::server_data_io::RecordStore;
+use crate::record_index::{RecordPool, QueryPlan};
+use crate::federation::{self, RecentIds, ReplayGate, FORWARDED_REQUESTS_REMEMBERED};
+use crate::replication::{self, Tombstones, FORGOTTEN_IDS_REMEMBERED};
+use crate::discovery::{self, HEARD_ANNOUNCEMENTS_REMEMBERED};
 
 /**
  * This represents data the server will use
  */
 pub struct Data {
-  pub record_pools: Arc<Vec<Arc<RwLock<Vec<Record>>>>>,
+  pub record_pools: Arc<Vec<Arc<RwLock<RecordPool>>>>,
   // When set to true server threads should exit (they may be blocked on IO however)
   pub exit_flag: Arc<AtomicBool>,
   pub listeners: Arc<Mutex<Vec<Listener>>>,
   pub max_listeners: usize,
+  pub max_records: usize,
+  pub eviction_policy: EvictionPolicy,
+  // Read when eviction_policy is PreferTrusted and to check tombstones
+  pub trusted_keys_file: String,
+  // Applied to inserted records without a TTL:seconds key, 0 to disable
+  pub default_ttl_s: f64,
+  // How long the tombstones of deleted records are kept and synced
+  pub tombstone_ttl_s: usize,
+  // Print a line to stdout every time records are evicted
+  pub report_evictions: bool,
+  // Records currently held across all pools
+  num_records: AtomicUsize,
+  // Records evicted since the server started
+  num_evicted: AtomicUsize,
+  // Incremented by every insert and search, orders StoredRecord::seq and last_matched
+  clock: AtomicU64,
+  // Held while choosing and removing eviction victims so concurrent inserts don't over-evict
+  evicting: Mutex<()>,
+  // Read by inserts until listeners are told about the record, written by
+  // listen_with_replay so no insert lands between its replay and listening
+  inserting: RwLock<()>,
+  // Set by read_stored_records for datastores which are written incrementally,
+  // every insert and removal is then passed to it.
+  pub store: Option<Mutex<Box<dyn RecordStore>>>,
+  // Queries from clients, compiled within the configured regex limits
+  query_cache: Mutex<QueryCache>,
+  // Forwarded::request_id of requests answered lately, see federation::route
+  forwarded_requests: Mutex<RecentIds>,
+  // Ids of records evicted lately, which replication must not copy back
+  forgotten: Mutex<RecentIds>,
+  // Left by deletes and updates for replication to pass on
+  tombstones: Mutex<Tombstones>,
+  // Nonces of delete and update authorizations by when we saw them,
+  // kept until the authorizations are too old to be accepted anyway
+  used_nonces: Mutex<BTreeMap<String, u64>>,
+  // Sent in our announcements, see discovery.rs
+  pub instance: String,
+  // Announcements other servers sent lately by Announcement::instance,
+  // passed on to clients which ask us to discover
+  heard: Mutex<BTreeMap<String, (Announcement, Instant)>>,
+  heard_max_age: Duration,
 }
 
 impl Data {
@@ -47,30 +97,361 @@ impl Data {
         exit_flag: Arc::new(AtomicBool::new(false)),
         listeners: Arc::new(Mutex::new(vec![])),
         max_listeners: config.server_max_listeners,
+        max_records: config.server_max_records,
+        eviction_policy: config.server_eviction_policy,
+        trusted_keys_file: config.server_trusted_keys_file.clone(),
+        default_ttl_s: config.server_default_ttl_s,
+        tombstone_ttl_s: config.server_tombstone_ttl_s,
+        report_evictions: config.is_debug() && !config.server_extra_quiet,
+        num_records: AtomicUsize::new(0),
+        num_evicted: AtomicUsize::new(0),
+        clock: AtomicU64::new(0),
+        evicting: Mutex::new(()),
+        inserting: RwLock::new(()),
+        store: None,
+        query_cache: Mutex::new(QueryCache::new(config.server_query_cache_size, RegexLimits::from_config(config))),
+        forwarded_requests: Mutex::new(RecentIds::new(FORWARDED_REQUESTS_REMEMBERED)),
+        forgotten: Mutex::new(RecentIds::new(FORGOTTEN_IDS_REMEMBERED)),
+        tombstones: Mutex::new(Tombstones::new()),
+        used_nonces: Mutex::new(BTreeMap::new()),
+        instance: federation::new_request_id(),
+        heard: Mutex::new(BTreeMap::new()),
+        heard_max_age: discovery::heard_max_age(config),
       };
       let record_pools = Arc::get_mut(&mut data.record_pools).unwrap();
       // Create memory pools
       for _ in 0..config.server_num_record_pools {
         record_pools.push(
-          Arc::new(RwLock::new(vec![]))
+          Arc::new(RwLock::new(RecordPool::new()))
         );
       }
       return data;
   }
-  pub fn insert(&self, rec: Record) {
+  // Compiles a query record from a client, see query::Query::compile
+  pub fn compile_query(&self, query: &Record) -> Result<Query, String> {
+    match self.query_cache.lock() {
+      Ok(mut cache) => cache.compile(query),
+      Err(e) => Err(format!("Error: the query cache is poisoned: {}", e)),
+    }
+  }
+  // True the first time an authorization nonce is used
+  pub fn first_use_of_nonce(&self, nonce: &str) -> bool {
+    let now = now_ms();
+    match self.used_nonces.lock() {
+      Ok(mut used) => {
+        // Authorizations issued more than AUTH_MAX_AGE_MS either side of now are refused
+        used.retain(|_, seen_at| now.saturating_sub(*seen_at) <= 2 * AUTH_MAX_AGE_MS);
+        if used.contains_key(nonce) {
+          return false;
+        }
+        used.insert(nonce.to_string(), now);
+        return true;
+      }
+      Err(e) => {
+        println!("Error locking used nonces: {}", e);
+        return false;
+      }
+    }
+  }
+  // True the first time a forwarded request_id is seen, false when the
+  // request has come back around a loop of federated servers
+  pub fn first_sight(&self, request_id: &str) -> bool {
+    match self.forwarded_requests.lock() {
+      Ok(mut seen) => seen.insert(request_id),
+      Err(e) => {
+        println!("Error locking forwarded requests: {}", e);
+        return false;
+      }
+    }
+  }
+  // Returns false if the record was not stored
+  pub fn insert(&self, mut rec: Record) -> bool {
+    let now = now_ms();
+    if !rec.stamp_expiry(now, self.default_ttl_s) {
+      return false; // Eg TTL:seconds is inf or NaN
+    }
+    if rec.is_expired(now) {
+      return false; // Eg read back from storage after its TTL passed
+    }
+    let trusted = self.eviction_policy == EvictionPolicy::PreferTrusted && self.is_trusted(&rec);
+    // Published again, so peers may copy it back if it is removed here
+    if let Ok(mut forgotten) = self.forgotten.lock() {
+      if forgotten.len() > 0 {
+        forgotten.remove(&rec.id());
+      }
+    }
+    let inserting = self.inserting.read();
+    let mut stored = Some(StoredRecord::new(rec.clone(), self.clock.fetch_add(1, Ordering::SeqCst), trusted));
+    // The store is locked before pools so it sees changes in the order they happen
+    let mut store = self.lock_store();
     for pool in self.record_pools.iter() {
       if let Ok(mut pool) = pool.try_write() {
-        pool.push(rec.clone());
+        pool.push(stored.take().unwrap());
         break;
       }
     }
+    if stored.is_some() && !self.record_pools.is_empty() {
+      // Every pool is busy, wait for one instead of dropping the record
+      let pool = &self.record_pools[stored.as_ref().unwrap().seq as usize % self.record_pools.len()];
+      match pool.write() {
+        Ok(mut pool) => {
+          pool.push(stored.take().unwrap());
+        }
+        Err(e) => {
+          println!("Error locking record pool: {}", e);
+        }
+      }
+    }
+    if stored.is_some() {
+      return false;
+    }
+    self.num_records.fetch_add(1, Ordering::SeqCst);
+    if let Some(store) = &mut store {
+      store.insert(&rec);
+    }
+    drop(store);
     // We must also inform listeners
     match self.listeners.lock() {
       Ok(listeners) => {
         for listener in listeners.iter() {
-          if rec.matches(&listener.query) {
-            if let Err(e) = listener.tx.send(WireData::result(rec.clone())) {
-              println!("Error sending data to listener: {}", e);
+          if listener.query.matches(&rec) && listener.is_unsent(&rec) {
+            if ! listener.send_live(WireData::result(rec.clone()).with_id(listener.id)) {
+              println!("Error sending data to listener: disconnected");
+            }
+          }
+        }
+      }
+      Err(e) => {
+        println!("Error informing listeners in Data: {}", e);
+      }
+    }
+    drop(inserting);
+    self.enforce_max_records();
+    return true;
+  }
+  // Removes every record for which should_remove returns true and
+  // tells listeners whose query matched them. Returns the removed records.
+  pub fn remove<F: FnMut(&Record) -> bool>(&self, mut should_remove: F) -> Vec<Record> {
+    return self.remove_stored(|stored| should_remove(&stored.rec));
+  }
+  // Forgets every record whose TTL has passed, returning them.
+  // Searches already skip expired records, this reclaims their memory.
+  pub fn remove_expired(&self) -> Vec<Record> {
+    let now = now_ms();
+    if let Ok(mut tombstones) = self.tombstones.lock() {
+      tombstones.remove_expired(now);
+    }
+    return self.remove_stored(|stored| stored.is_expired(now));
+  }
+  // Leaves a tombstone for each record a delete or update for target
+  // removed, so replication removes them from our peers as well
+  pub fn remember_deletes(&self, removed: &[Record], target: &Target, authorization: Option<&Record>) {
+    let expires_at = now_ms().saturating_add(self.tombstone_ttl_s as u64 * 1000);
+    if let Ok(mut tombstones) = self.tombstones.lock() {
+      for rec in removed {
+        tombstones.insert(replication::tombstone(rec, target, authorization, expires_at));
+      }
+    }
+  }
+  // Keeps a tombstone a peer synced to us and removes the records it
+  // covers. Returns false if it was expired or already held.
+  pub fn add_tombstone(&self, mut tombstone: Record) -> bool {
+    let now = now_ms();
+    // Peers may not keep records deleted for longer than we would
+    let max_expires_at = now.saturating_add(self.tombstone_ttl_s as u64 * 1000);
+    let expires_at = tombstone.expires_at_ms().unwrap_or(max_expires_at).min(max_expires_at);
+    if expires_at <= now {
+      return false;
+    }
+    tombstone.p.insert(TTL_EXPIRES_AT_KEY.to_string(), format!("{}", expires_at));
+    let is_new = match self.tombstones.lock() {
+      Ok(mut tombstones) => tombstones.insert(tombstone.clone()),
+      Err(e) => {
+        println!("Error locking tombstones: {}", e);
+        return false;
+      }
+    };
+    if is_new {
+      self.remove(|rec| replication::covers(&tombstone, rec, self));
+    }
+    return is_new;
+  }
+  // True if we hold a tombstone which may remove rec
+  pub fn is_tombstoned(&self, rec: &Record) -> bool {
+    let tombstones = match self.tombstones.lock() {
+      Ok(tombstones) => tombstones.for_record(&rec.id(), now_ms()),
+      Err(_) => vec![],
+    };
+    return tombstones.iter().any(|tombstone| replication::covers(tombstone, rec, self));
+  }
+  // Number of tombstones held, expired or not
+  pub fn num_tombstones(&self) -> usize {
+    return self.tombstones.lock().map(|tombstones| tombstones.len()).unwrap_or(0);
+  }
+  fn lock_store(&self) -> Option<MutexGuard<Box<dyn RecordStore>>> {
+    match &self.store {
+      Some(store) => match store.lock() {
+        Ok(store) => Some(store),
+        Err(e) => {
+          println!("Error locking record store: {}", e);
+          None
+        }
+      },
+      None => None,
+    }
+  }
+  // Rewrites the record store (if any) to hold only live records
+  pub fn compact_store(&self) {
+    if let Some(mut store) = self.lock_store() {
+      let now = now_ms();
+      let mut records = vec![];
+      for pool in self.record_pools.iter() {
+        match pool.read() {
+          Ok(pool) => {
+            for stored in pool.iter() {
+              if !stored.is_expired(now) {
+                records.push(stored.rec.clone());
+              }
+            }
+          }
+          Err(e) => {
+            println!("Error locking record pool: {}", e);
+            return; // Compacting without this pool would lose its records
+          }
+        }
+      }
+      if let Err(e) = store.compact(&records) {
+        println!("Error compacting {}: {}", store.describe(), e);
+      }
+    }
+  }
+  // Compacts the record store once it is mostly dead entries
+  pub fn maybe_compact_store(&self) {
+    let should_compact = match self.lock_store() {
+      Some(store) => store.should_compact(self.len()),
+      None => false,
+    };
+    if should_compact {
+      self.compact_store();
+    }
+  }
+  // Number of records held across all pools
+  pub fn len(&self) -> usize {
+    return self.num_records.load(Ordering::SeqCst);
+  }
+  // Number of records evicted to stay under max_records since startup
+  pub fn num_evicted(&self) -> usize {
+    return self.num_evicted.load(Ordering::SeqCst);
+  }
+  fn is_trusted(&self, rec: &Record) -> bool {
+    if !rec.is_signed() {
+      return false;
+    }
+    return self.trusts_key(&rec.p[signing::SIGNING_PUB_KEY_KEY]);
+  }
+  // True if pub_key is listed in trusted_keys_file, see signing::is_trusted_key
+  pub fn trusts_key(&self, pub_key: &str) -> bool {
+    return signing::is_trusted_key(pub_key, &self.trusted_keys_file);
+  }
+  // Called after every insert, removes records chosen by eviction_policy
+  // until no more than max_records remain.
+  fn enforce_max_records(&self) {
+    if self.len() <= self.max_records {
+      return;
+    }
+    let _evicting = match self.evicting.lock() {
+      Ok(lock) => lock,
+      Err(e) => {
+        println!("Error locking eviction state: {}", e);
+        return;
+      }
+    };
+    // Another insert may have evicted while we waited
+    let num_records = self.len();
+    if num_records <= self.max_records {
+      return;
+    }
+    // Evicting a small batch at a time means we only scan every pool
+    // once per max_records/64 inserts instead of on every insert.
+    let num_to_evict = std::cmp::min(
+      num_records,
+      std::cmp::max(num_records - self.max_records, self.max_records / 64)
+    );
+    
+    // (rank, age, seq) of every record, lowest are evicted first
+    let mut candidates: Vec<(u8, u64, u64)> = vec![];
+    for pool in self.record_pools.iter() {
+      match pool.read() {
+        Ok(pool) => {
+          for stored in pool.iter() {
+            let candidate = match self.eviction_policy {
+              EvictionPolicy::Oldest => (0, stored.seq, stored.seq),
+              EvictionPolicy::LeastRecentlyMatched => (0, stored.last_matched.load(Ordering::Relaxed), stored.seq),
+              EvictionPolicy::PreferTrusted => (stored.trusted as u8, stored.seq, stored.seq),
+            };
+            candidates.push(candidate);
+          }
+        }
+        Err(e) => {
+          println!("Error locking record pool: {}", e);
+        }
+      }
+    }
+    candidates.sort_unstable();
+    let victims: HashSet<u64> = candidates.iter().take(num_to_evict).map(|c| c.2).collect();
+    
+    let evicted = self.remove_stored(|stored| victims.contains(&stored.seq));
+    if let Ok(mut forgotten) = self.forgotten.lock() {
+      for rec in &evicted {
+        forgotten.insert(&rec.id());
+      }
+    }
+    let total_evicted = self.num_evicted.fetch_add(evicted.len(), Ordering::SeqCst) + evicted.len();
+    if self.report_evictions {
+      println!(
+        "Evicted {} records ({:?} policy), {} of max {} remain, {} evicted since startup",
+        evicted.len(), self.eviction_policy, self.len(), self.max_records, total_evicted
+      );
+    }
+  }
+  fn remove_stored<F: FnMut(&StoredRecord) -> bool>(&self, mut should_remove: F) -> Vec<Record> {
+    let mut removed = vec![];
+    let mut store = self.lock_store();
+    for pool in self.record_pools.iter() {
+      // Unlike insert we must visit every pool, so wait for busy ones
+      match pool.write() {
+        Ok(mut pool) => {
+          pool.retain(|stored| {
+            if should_remove(stored) {
+              removed.push(stored.rec.clone());
+              return false;
+            }
+            return true;
+          });
+        }
+        Err(e) => {
+          println!("Error locking record pool: {}", e);
+        }
+      }
+    }
+    if let Some(store) = &mut store {
+      for rec in &removed {
+        store.remove(rec);
+      }
+    }
+    drop(store);
+    if removed.is_empty() {
+      return removed;
+    }
+    self.num_records.fetch_sub(removed.len(), Ordering::SeqCst);
+    match self.listeners.lock() {
+      Ok(listeners) => {
+        for listener in listeners.iter() {
+          for rec in &removed {
+            if listener.query.matches(&rec) {
+              if ! listener.send_live(WireData::new(Action::removed, rec.clone()).with_id(listener.id)) {
+                println!("Error sending data to listener: disconnected");
+              }
             }
           }
         }
@@ -79,6 +460,7 @@ impl Data {
         println!("Error informing listeners in Data: {}", e);
       }
     }
+    return removed;
   }
   pub fn listen(&self, listener: Listener) {
     match self.listeners.lock() {
@@ -90,6 +472,39 @@ impl Data {
       }
     }
   }
+  // Sends listener every record already matching its query, then an
+  // end_of_replay, then registers it for new records. Inserts wait until
+  // it is registered, so each record is sent once and none are missed.
+  // A federated listener's end_of_replay is up to its replay_gate.
+  pub fn listen_with_replay(&self, listener: Listener) {
+    let _inserting = self.inserting.write();
+    {
+      let tx = Mutex::new(listener.tx.clone());
+      let tx = &tx;
+      let id = listener.id;
+      let listener = &listener;
+      self.search_stored(&listener.query, true, move |stored| {
+        if ! listener.is_unsent(&stored.rec) {
+          return true; // Already sent by a federated server
+        }
+        if let Ok(tx) = tx.lock() {
+          if let Err(e) = tx.send(WireData::result(stored.rec.clone()).with_id(id)) {
+            println!("Error sending data to listener: {}", e);
+            return false;
+          }
+        }
+        return true;
+      });
+    }
+    if let Some(gate) = &listener.replay_gate {
+      gate.finish();
+    }
+    else if let Err(e) = listener.tx.send(WireData::end_of_replay().with_id(listener.id)) {
+      println!("Error sending data to listener: {}", e);
+      return;
+    }
+    self.listen(listener);
+  }
   pub fn trim_invalid_listeners(&self) {
     match self.listeners.lock() {
       Ok(mut listeners) => {
@@ -107,7 +522,7 @@ impl Data {
         if listeners.len() > self.max_listeners {
           let num_over = listeners.len() - self.max_listeners;
           for to_be_drained_listener in &listeners[0..num_over] {
-            if let Err(e) = to_be_drained_listener.tx.send(WireData::end_of_results()) {
+            if let Err(e) = to_be_drained_listener.tx.send(WireData::end_of_results().with_id(to_be_drained_listener.id)) {
               println!("Error sending data to listener: {}", e);
             }
           }
@@ -120,13 +535,36 @@ impl Data {
       }
     }
   }
+  // Stops the listener with request id on the connection owning conn_is_valid.
+  // Returns false if no such listener exists.
+  pub fn cancel_listener(&self, conn_is_valid: &Arc<Mutex<AtomicBool>>, id: u64) -> bool {
+    match self.listeners.lock() {
+      Ok(mut listeners) => {
+        let num_before = listeners.len();
+        listeners.retain(|l| {
+          if Arc::ptr_eq(&l.conn_is_valid, conn_is_valid) && l.id == Some(id) {
+            if let Err(e) = l.tx.send(WireData::end_of_results().with_id(l.id)) {
+              println!("Error sending data to listener: {}", e);
+            }
+            return false;
+          }
+          return true;
+        });
+        return listeners.len() < num_before;
+      }
+      Err(e) => {
+        println!("Error cancelling listener: {}", e);
+        return false;
+      }
+    }
+  }
   pub fn trim_all_listeners(&self) {
     //println!("trim_all_listeners before .lock()");
     match self.listeners.lock() {
       Ok(mut listeners) => {
         for listener in listeners.iter() {
           //println!("Sending WireData::end_of_results to listener.tx");
-          if let Err(e) = listener.tx.send(WireData::end_of_results()) {
+          if let Err(e) = listener.tx.send(WireData::end_of_results().with_id(listener.id)) {
             println!("Error sending data to listener: {}", e);
           }
         }
@@ -138,71 +576,222 @@ impl Data {
       }
     }
   }
-  pub fn search(&self, query: &HashMap<String, Regex>) -> Vec<Record> {
-    let cpus = num_cpus::get();
-    let results = Arc::new(Mutex::new(vec![]));
-    
-    thread::scope(|s| {
-      let mut handlers = vec![];
-      
-      let pools_per_thread = self.record_pools.len() / cpus;
-      let pools_remainder = self.record_pools.len() % cpus;
-      
-      for thread_num in 0..cpus {
-        // search from (thread_num*pools_per_thread) to ((thread_num+1)*pools_per_thread)
-        let mut pool_refs = vec![];
-        for pool in &self.record_pools[(thread_num*pools_per_thread)..((thread_num+1)*pools_per_thread)] {
-          pool_refs.push(pool);
+  pub fn search(&self, query: &Query) -> Vec<Record> {
+    let results = Mutex::new(vec![]);
+    {
+      let results = &results;
+      self.search_stored(query, false, move |stored| {
+        if let Ok(mut results) = results.lock() {
+          results.push(stored.rec.clone());
         }
-        // Spawn thread to search all pool refs
-        let thread_results = results.clone();
-        handlers.push(s.spawn(move |_| {
-          for p in pool_refs {
-            if let Ok(p) = p.try_read() {
-              for rec in p.iter() {
-                if rec.matches(query) {
-                  if let Ok(mut lock) = thread_results.lock() {
-                    lock.push(rec.clone());
-                  }
-                }
-              }
-            }
+        return true;
+      });
+    }
+    return results.into_inner().unwrap_or_default();
+  }
+  // Calls on_result with every record matching query until it returns false.
+  // Copies of on_result run on several threads at once.
+  pub fn search_callback<F: FnMut(&Record) -> bool>(&self, query: &Query, mut on_result: F)
+    where F: Send + Copy,
+  {
+    self.search_stored(query, false, move |stored| on_result(&stored.rec));
+  }
+  // The results options selects, ordered as described on QueryOptions, along
+  // with a cursor for the next page when there are more. Err if options.cursor
+  // was not made by this instance for the same ordering. Full-text results hold
+  // their TEXT_SCORE_KEY and without a sort_by are ranked by it, best first.
+  pub fn search_page(&self, query: &Query, options: &QueryOptions) -> Result<(Vec<Record>, Option<String>), String> {
+    let stats = query.text.as_ref().map(|terms| self.text_stats(terms));
+    let ranked = stats.is_some() && options.sort_by.is_none();
+    let sort_by = if ranked { Some(TEXT_SCORE_KEY.to_string()) } else { options.sort_by.clone() };
+    let descending = if ranked { true } else { options.descending };
+    let after = match &options.cursor {
+      Some(cursor) => match Cursor::decode(cursor) {
+        Some(cursor) if cursor.instance == self.instance && cursor.sort_by == sort_by && cursor.descending == descending => Some(cursor),
+        _ => return Err("Error: the cursor is not one this server made for this ordering".to_string()),
+      },
+      None => None,
+    };
+    // (sort_by value, seq, record) of every match
+    let matches = Mutex::new(vec![]);
+    {
+      let matches = &matches;
+      let sort_by = sort_by.as_ref();
+      let stats = stats.as_ref();
+      // Skipping a busy pool would make pages and cursors miss records
+      self.search_stored(query, true, move |stored| {
+        let mut rec = stored.rec.clone();
+        if let Some(stats) = stats {
+          rec.p.insert(TEXT_SCORE_KEY.to_string(), format!("{:.4}", query.text_score(&rec, stats)));
+        }
+        let value = sort_by.and_then(|key| rec.p.get(key)).cloned();
+        if let Ok(mut matches) = matches.lock() {
+          matches.push((value, stored.seq, rec));
+        }
+        return true;
+      });
+    }
+    let mut matches: Vec<(Option<String>, u64, Record)> = matches.into_inner().unwrap_or(vec![]);
+    let cmp = |a_value: &Option<String>, a_seq: u64, b_value: &Option<String>, b_seq: u64| -> CmpOrdering {
+      cmp_sort_values(a_value.as_ref().map(|v| v.as_str()), b_value.as_ref().map(|v| v.as_str()), descending)
+        .then(a_seq.cmp(&b_seq))
+    };
+    matches.sort_by(|a, b| cmp(&a.0, a.1, &b.0, b.1));
+    if let Some(after) = &after {
+      matches.retain(|m| cmp(&m.0, m.1, &after.value, after.seq) == CmpOrdering::Greater);
+    }
+    let remaining = matches.len().saturating_sub(options.offset.unwrap_or(0));
+    let page: Vec<(Option<String>, u64, Record)> = matches.into_iter()
+      .skip(options.offset.unwrap_or(0))
+      .take(options.limit.unwrap_or(usize::max_value()))
+      .collect();
+    let mut next_cursor = None;
+    if remaining > page.len() {
+      if let Some((value, seq, _)) = page.last() {
+        next_cursor = Some(Cursor {
+          instance: self.instance.clone(),
+          sort_by: sort_by.clone(),
+          descending: descending,
+          value: value.clone(),
+          seq: *seq,
+        }.encode());
+      }
+    }
+    return Ok((page.into_iter().map(|m| m.2).collect(), next_cursor));
+  }
+  // Ids of unexpired records and tombstones grouped by
+  // replication::bucket_of, only those in buckets when it is given
+  pub fn ids_by_bucket(&self, buckets: Option<&HashSet<String>>) -> BTreeMap<String, Vec<String>> {
+    let now = now_ms();
+    let mut ids: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for pool in self.record_pools.iter() {
+      if let Ok(pool) = pool.read() {
+        for stored in pool.iter() {
+          if stored.is_expired(now) {
+            continue;
           }
-        }));
+          let id = stored.rec.id();
+          let bucket = replication::bucket_of(&id).to_string();
+          if buckets.map(|buckets| buckets.contains(&bucket)).unwrap_or(true) {
+            ids.entry(bucket).or_insert(vec![]).push(id);
+          }
+        }
       }
-      // last thread needs to search (cpus*pools_per_thread) to (cpus*pools_per_thread)+pools_remainder
-      {
-        let mut pool_refs = vec![];
-        for pool in &self.record_pools[(cpus*pools_per_thread)..(cpus*pools_per_thread)+pools_remainder] {
-          pool_refs.push(pool);
+    }
+    if let Ok(tombstones) = self.tombstones.lock() {
+      for (id, _tombstone) in tombstones.iter(now) {
+        let bucket = replication::bucket_of(id).to_string();
+        if buckets.map(|buckets| buckets.contains(&bucket)).unwrap_or(true) {
+          ids.entry(bucket).or_insert(vec![]).push(id.clone());
         }
-        // Spawn thread to search all pool refs
-        let thread_results = results.clone();
-        handlers.push(s.spawn(move |_| {
-          for p in pool_refs {
-            if let Ok(p) = p.try_read() {
-              for rec in p.iter() {
-                if rec.matches(query) {
-                  if let Ok(mut lock) = thread_results.lock() {
-                    lock.push(rec.clone());
-                  }
-                }
-              }
-            }
+      }
+    }
+    // A record published twice is stored twice but synced once
+    for bucket_ids in ids.values_mut() {
+      bucket_ids.sort();
+      bucket_ids.dedup();
+    }
+    return ids;
+  }
+  // Unexpired records and tombstones whose Record::id is in ids
+  pub fn records_with_ids(&self, ids: &HashSet<String>) -> Vec<Record> {
+    let now = now_ms();
+    let mut records = vec![];
+    for pool in self.record_pools.iter() {
+      if let Ok(pool) = pool.read() {
+        for stored in pool.iter() {
+          if !stored.is_expired(now) && ids.contains(&stored.rec.id()) {
+            records.push(stored.rec.clone());
           }
-        }));
+        }
       }
-      
-      for h in handlers {
-        h.join().unwrap();
+    }
+    if let Ok(tombstones) = self.tombstones.lock() {
+      for (id, tombstone) in tombstones.iter(now) {
+        if ids.contains(id) {
+          records.push(tombstone.clone());
+        }
       }
-    }).unwrap();
-    return Arc::try_unwrap(results).unwrap().into_inner().unwrap();
+    }
+    return records;
+  }
+  // True if a record with this id was evicted lately, see replication.rs
+  pub fn was_forgotten(&self, id: &str) -> bool {
+    return self.forgotten.lock().map(|forgotten| forgotten.contains(id)).unwrap_or(false);
+  }
+  // Remembers an announcement another server sent, forgetting those older
+  // than heard_max_age and the oldest beyond HEARD_ANNOUNCEMENTS_REMEMBERED
+  pub fn hear(&self, announcement: Announcement) {
+    if announcement.instance == self.instance {
+      return; // Our own beacon looped back
+    }
+    if let Ok(mut heard) = self.heard.lock() {
+      heard.insert(announcement.instance.clone(), (announcement, Instant::now()));
+      let max_age = self.heard_max_age;
+      heard.retain(|_instance, (_announcement, heard_at)| heard_at.elapsed() < max_age);
+      while heard.len() > HEARD_ANNOUNCEMENTS_REMEMBERED {
+        let oldest = heard.iter().min_by_key(|(_instance, (_announcement, heard_at))| *heard_at).map(|(instance, _)| instance.clone());
+        if let Some(oldest) = oldest {
+          heard.remove(&oldest);
+        }
+      }
+    }
+  }
+  // Announcements heard within heard_max_age
+  pub fn heard(&self) -> Vec<Announcement> {
+    match self.heard.lock() {
+      Ok(heard) => heard.values()
+        .filter(|(_announcement, heard_at)| heard_at.elapsed() < self.heard_max_age)
+        .map(|(announcement, _heard_at)| announcement.clone())
+        .collect(),
+      Err(e) => {
+        println!("Error locking heard announcements: {}", e);
+        vec![]
+      }
+    }
+  }
+  // BM25 statistics for terms over every pool
+  pub fn text_stats(&self, terms: &[String]) -> TextStats {
+    let mut stats = TextStats { doc_freqs: vec![0; terms.len()], ..TextStats::default() };
+    for pool in self.record_pools.iter() {
+      if let Ok(pool) = pool.read() {
+        stats.merge(&pool.text_stats(terms));
+      }
+    }
+    return stats;
+  }
+  // How many records match query, grouped by the value of group_by.
+  // Nothing is copied out of the pools.
+  pub fn count(&self, query: &Query, group_by: Option<&str>) -> Counts {
+    let counts = Mutex::new(Counts { group_by: group_by.map(|key| key.to_string()), ..Counts::default() });
+    {
+      let counts = &counts;
+      // Skipping a busy pool would undercount
+      self.search_stored(query, true, move |stored| {
+        if let Ok(mut counts) = counts.lock() {
+          counts.total += 1;
+          if let Some(value) = group_by.and_then(|key| stored.rec.p.get(key)) {
+            *counts.groups.entry(value.clone()).or_insert(0) += 1;
+          }
+        }
+        return true;
+      });
+    }
+    return counts.into_inner().unwrap_or_default();
   }
-  pub fn search_callback<F: FnMut(&Record) -> bool>(&self, query: &HashMap<String, Regex>, mut on_result: F)
+  // Calls on_result with every stored record matching query until it
+  // returns false, which stops the search in every pool. Pools busy being
+  // written to are skipped unless wait_for_pools is set.
+  fn search_stored<F: FnMut(&StoredRecord) -> bool>(&self, query: &Query, wait_for_pools: bool, on_result: F)
     where F: Send + Copy,
   {
     let cpus = num_cpus::get();
+    let tick = self.clock.fetch_add(1, Ordering::SeqCst);
+    let now = now_ms();
+    let plan = QueryPlan::new(query);
+    let plan = &plan;
+    let stop = AtomicBool::new(false);
+    let stop = &stop;
     
     thread::scope(|s| {
       let mut handlers = vec![];
@@ -218,17 +807,7 @@ impl Data {
         }
         // Spawn thread to search all pool refs
         handlers.push(s.spawn(move |_| {
-          for p in pool_refs {
-            if let Ok(p) = p.try_read() {
-              for rec in p.iter() {
-                if rec.matches(query) {
-                  if ! on_result(rec) {
-                    break; // Caller says we have hit limit of records to search
-                  }
-                }
-              }
-            }
-          }
+          search_pools(pool_refs, plan, query, now, tick, stop, wait_for_pools, on_result);
         }));
       }
       // last thread needs to search (cpus*pools_per_thread) to (cpus*pools_per_thread)+pools_remainder
@@ -239,17 +818,7 @@ impl Data {
         }
         // Spawn thread to search all pool refs
         handlers.push(s.spawn(move |_| {
-          for p in pool_refs {
-            if let Ok(p) = p.try_read() {
-              for rec in p.iter() {
-                if rec.matches(query) {
-                  if ! on_result(rec) {
-                    break; // Caller says we have hit limit of records to search
-                  }
-                }
-              }
-            }
-          }
+          search_pools(pool_refs, plan, query, now, tick, stop, wait_for_pools, on_result);
         }));
       }
       
@@ -260,18 +829,112 @@ impl Data {
   }
 }
 
+// One search_stored thread's share of the work
+fn search_pools<F>(pool_refs: Vec<&Arc<RwLock<RecordPool>>>, plan: &QueryPlan, query: &Query, now: u64, tick: u64, stop: &AtomicBool, wait_for_pools: bool, mut on_result: F)
+  where F: FnMut(&StoredRecord) -> bool,
+{
+  for p in pool_refs {
+    let p = if wait_for_pools { p.read().ok() } else { p.try_read().ok() };
+    if let Some(p) = p {
+      for stored in p.candidates(plan) {
+        if stop.load(Ordering::Relaxed) {
+          return; // Another thread's caller has hit its limit
+        }
+        if !stored.is_expired(now) && query.matches(&stored.rec) {
+          stored.last_matched.store(tick, Ordering::Relaxed);
+          if ! on_result(stored) {
+            stop.store(true, Ordering::Relaxed);
+            return; // Caller says we have hit limit of records to search
+          }
+        }
+      }
+    }
+  }
+}
+
+/**
+ * A record as held in Data::record_pools, with the bookkeeping
+ * used to decide which records to evict.
+ */
+pub struct StoredRecord {
+  pub rec: Record,
+  // Data's clock when the record was inserted, unique per record
+  pub seq: u64,
+  // Data's clock at the last search this record matched (or seq)
+  pub last_matched: AtomicU64,
+  // Signed by a key in server_trusted_keys_file, only tracked for PreferTrusted
+  pub trusted: bool,
+  // Copy of the record's TTL:expires-at so searches don't re-parse it
+  pub expires_at_ms: Option<u64>,
+}
+
+impl StoredRecord {
+  pub fn new(rec: Record, seq: u64, trusted: bool) -> StoredRecord {
+    StoredRecord {
+      seq: seq,
+      expires_at_ms: rec.expires_at_ms(),
+      last_matched: AtomicU64::new(seq),
+      trusted: trusted,
+      rec: rec,
+    }
+  }
+  pub fn is_expired(&self, now_ms: u64) -> bool {
+    match self.expires_at_ms {
+      Some(expires_at) => expires_at <= now_ms,
+      None => false,
+    }
+  }
+}
+
 pub struct Listener {
-  pub query: HashMap<String, Regex>,
+  pub query: Query,
+  // Request id from the client, results are tagged with it
+  pub id: Option<u64>,
   pub tx: Sender<WireData>,
   pub conn_is_valid: Arc<Mutex<AtomicBool>>,
+  // Set once the listener is dropped, eg by a cancel
+  pub ended: Arc<AtomicBool>,
+  // Ids of records already sent to tx, when records also reach it from
+  // federated servers (see federation::listen)
+  pub sent: Option<Arc<Mutex<RecentIds>>>,
+  // Set on federated listens with replay, see federation::ReplayGate
+  pub replay_gate: Option<Arc<ReplayGate>>,
 }
 
 impl Listener {
-  pub fn new(query: &Record, tx: Sender<WireData>, valid_flag: Arc<Mutex<AtomicBool>>) -> Listener {
+  pub fn new(query: Query, id: Option<u64>, tx: Sender<WireData>, valid_flag: Arc<Mutex<AtomicBool>>) -> Listener {
     Listener {
-      query: query.create_regex_map(),
+      query: query,
+      id: id,
       tx: tx,
-      conn_is_valid: valid_flag
+      conn_is_valid: valid_flag,
+      ended: Arc::new(AtomicBool::new(false)),
+      sent: None,
+      replay_gate: None,
     }
   }
+  // Sends a new or removed record, held back while a federated replay
+  // is still going. False if the client is gone.
+  pub fn send_live(&self, wire_data: WireData) -> bool {
+    match &self.replay_gate {
+      Some(gate) => gate.send_live(wire_data),
+      None => self.tx.send(wire_data).is_ok(),
+    }
+  }
+  // False if rec has been sent already, otherwise remembers it as sent
+  pub fn is_unsent(&self, rec: &Record) -> bool {
+    match &self.sent {
+      Some(sent) => match sent.lock() {
+        Ok(mut sent) => sent.insert(&rec.id()),
+        Err(_) => true,
+      },
+      None => true,
+    }
+  }
+}
+
+impl Drop for Listener {
+  fn drop(&mut self) {
+    self.ended.store(true, Ordering::SeqCst);
+  }
 }
@@ -0,0 +1,288 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *  
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; version 2 of the License only.
+ * 
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ * 
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+/**
+ * Discovery: servers listening on server_multicast_group send an announce
+ * beacon there every server_announce_interval_ms and answer discover
+ * requests with their own Announcement and those they heard lately.
+ * Clients send discover to every server in config.servers, usually the
+ * default MULTICAST entry, and turn the announcements into config::Server
+ * entries. `dindex discover` only prints them, callers of discover_sync
+ * may add them to their own config.servers; nothing is written out.
+ */
+
+use crossbeam_utils::thread;
+
+use std::collections::HashSet;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+use crate::actions::Action;
+use crate::client;
+use crate::config::{Config, Server, ServerProtocol};
+use crate::data::Data;
+use crate::framing::{Framing, FrameDecoder, encode};
+use crate::record::Record;
+use crate::wire::{WireData, Announcement, Capabilities};
+
+// Announcements from other servers a server passes on
+pub const HEARD_ANNOUNCEMENTS_REMEMBERED: usize = 256;
+// Beacons a server may miss before it is no longer passed on
+const MISSED_BEACONS: u32 = 3;
+const DEFAULT_ANNOUNCE_INTERVAL_MS: u64 = 60000;
+
+// A server which answered a discover
+#[derive(Debug, Clone)]
+pub struct Discovered {
+  pub server: Server,
+  pub caps: Capabilities,
+}
+
+// How long a server passes on announcements it heard
+pub fn heard_max_age(config: &Config) -> Duration {
+  let interval_ms = if config.server_announce_interval_ms > 0 {
+    config.server_announce_interval_ms as u64
+  } else {
+    DEFAULT_ANNOUNCE_INTERVAL_MS
+  };
+  return Duration::from_millis(interval_ms) * MISSED_BEACONS;
+}
+
+// This server's listeners, without a host
+pub fn ours(config: &Config, data: &Data) -> Announcement {
+  let port_if = |listening: bool, port: u16| if listening { Some(port) } else { None };
+  return Announcement {
+    instance: data.instance.clone(),
+    name: config.server_name.clone(),
+    host: None,
+    tcp_port: port_if(config.server_listen_tcp, config.server_port),
+    udp_port: port_if(config.server_listen_udp, config.server_port),
+    websocket_port: port_if(config.server_listen_websocket, config.server_websocket_port),
+    tls_port: port_if(config.server_listen_tls, config.server_tls_port),
+    wss_port: port_if(config.server_listen_wss, config.server_wss_port),
+    caps: Capabilities::ours(),
+  };
+}
+
+// Remembers a beacon another server sent from src. The host is always
+// src, a beacon naming some other host could send clients anywhere.
+pub fn hear(wire_data: WireData, src: SocketAddr, data: &Data) {
+  if let Some(mut announcement) = wire_data.announce {
+    announcement.host = Some(src.ip().to_string());
+    data.hear(announcement);
+  }
+}
+
+// Replies to a discover request
+pub fn answer(wire_data: &WireData, to_client: &Sender<WireData>, config: &Config, data: &Data) {
+  let id = wire_data.id;
+  let mut replies = vec![WireData::announce(ours(config, data))];
+  for announcement in data.heard() {
+    replies.push(WireData::announce(announcement));
+  }
+  replies.push(WireData::end_of_results());
+  for reply in replies {
+    if let Err(e) = to_client.send(reply.with_id(id)) {
+      println!("e = {}", e);
+      return;
+    }
+  }
+}
+
+// Sends our announcement to server_multicast_group every
+// server_announce_interval_ms until data.exit_flag is set
+pub fn run_announce_sync(config: &Config, data: &Data) {
+  let socket = match UdpSocket::bind("0.0.0.0:0") {
+    Ok(socket) => socket,
+    Err(e) => {
+      println!("Error binding announce socket: {}", e);
+      return;
+    }
+  };
+  let group_and_port = multicast_addr(&config.server_multicast_group, config.server_port);
+  let announce_every = Duration::from_millis(config.server_announce_interval_ms as u64);
+  let check_exit_every = Duration::from_millis(100);
+  let mut last_announce: Option<Instant> = None;
+  while !data.exit_flag.load(Ordering::Relaxed) {
+    if last_announce.map(|last_announce| last_announce.elapsed() < announce_every).unwrap_or(false) {
+      std::thread::sleep(check_exit_every);
+      continue;
+    }
+    last_announce = Some(Instant::now());
+    match encode(&WireData::announce(ours(config, data)), Framing::Legacy) {
+      Ok(bytes) => {
+        if let Err(e) = socket.send_to(&bytes, &group_and_port) {
+          if config.is_debug() && !config.server_extra_quiet {
+            println!("Error announcing to {}: {}", group_and_port, e);
+          }
+        }
+      }
+      Err(e) => {
+        println!("Error encoding announcement: {}", e);
+      }
+    }
+  }
+}
+
+// Asks every server in config who is there, each server found once
+pub fn discover_sync(config: &Config) -> Vec<Discovered> {
+  let results: Arc<Mutex<Vec<Announcement>>> = Arc::new(Mutex::new(vec![]));
+  
+  thread::scope(|s| {
+    let mut handlers = vec![];
+    
+    for server in &config.servers {
+      let t_results = results.clone();
+      let t_server = server.clone();
+      handlers.push(s.spawn(move |_| {
+        let announcements = discover_server_sync(config, &t_server);
+        if let Ok(mut t_results) = t_results.lock() {
+          t_results.extend(announcements);
+        }
+      }));
+    }
+    
+    for h in handlers {
+      h.join().unwrap();
+    }
+  }).unwrap();
+  
+  let announcements = Arc::try_unwrap(results).unwrap().into_inner().unwrap();
+  let mut seen = HashSet::new();
+  let mut discovered = vec![];
+  for announcement in announcements {
+    if !seen.insert(announcement.instance.clone()) {
+      continue;
+    }
+    if let Some(found) = to_discovered(&announcement) {
+      discovered.push(found);
+    }
+  }
+  return discovered;
+}
+
+// Announcements from everyone who answered a discover sent to server,
+// with their hosts filled in
+pub fn discover_server_sync(config: &Config, server: &Server) -> Vec<Announcement> {
+  if server.protocol == ServerProtocol::MULTICAST {
+    return discover_multicast_sync(server);
+  }
+  let mut announcements = vec![];
+  let wire_data = WireData::new(Action::discover, Record::empty());
+  let res = client::request_server_sync(config, server, &wire_data, || false, |wire_res| {
+    if let Some(mut announcement) = wire_res.announce {
+      if announcement.host.is_none() {
+        announcement.host = Some(server.host.clone());
+      }
+      announcements.push(announcement);
+    }
+    return true;
+  });
+  if let Err(e) = res {
+    if server.report_connect_errors {
+      println!("Error discovering with {}: {}", server.name, e);
+    }
+  }
+  return announcements;
+}
+
+// Every server in the group may answer, so unlike client::request_server_sync
+// we keep reading until server.max_latency_ms has passed
+fn discover_multicast_sync(server: &Server) -> Vec<Announcement> {
+  let mut announcements = vec![];
+  let socket = match UdpSocket::bind("0.0.0.0:0") {
+    Ok(socket) => socket,
+    Err(e) => {
+      println!("Error binding discover socket: {}", e);
+      return announcements;
+    }
+  };
+  if let Err(e) = socket.set_read_timeout(Some(Duration::from_millis(50))) {
+    println!("Error setting UDP read timeout: {}", e);
+  }
+  let sent = encode(&WireData::new(Action::discover, Record::empty()), Framing::Legacy)
+    .map_err(|e| e.to_string())
+    .and_then(|bytes| socket.send_to(&bytes, multicast_addr(&server.host, server.port)).map_err(|e| e.to_string()));
+  if let Err(e) = sent {
+    if server.report_connect_errors {
+      println!("Error discovering with {}: {}", server.name, e);
+    }
+    return announcements;
+  }
+  
+  let deadline = Instant::now() + Duration::from_millis(server.max_latency_ms as u64);
+  let mut buff = [0; 64 * 1024];
+  while Instant::now() < deadline {
+    if let Ok((num_read, src)) = socket.recv_from(&mut buff) {
+      // Each reply is its own datagram
+      let mut decoder = FrameDecoder::new();
+      decoder.push(&buff[0..num_read]);
+      while let Ok(Some(wire_res)) = decoder.next_frame() {
+        if let Some(mut announcement) = wire_res.announce {
+          if announcement.host.is_none() {
+            announcement.host = Some(src.ip().to_string());
+          }
+          announcements.push(announcement);
+        }
+      }
+    }
+  }
+  return announcements;
+}
+
+// The server entry clients would use for an announcement, preferring TCP
+pub fn to_discovered(announcement: &Announcement) -> Option<Discovered> {
+  let host = announcement.host.clone()?;
+  let (protocol, port) = if let Some(port) = announcement.tcp_port {
+    (ServerProtocol::TCP, port)
+  } else if let Some(port) = announcement.tls_port {
+    (ServerProtocol::TLS, port)
+  } else if let Some(port) = announcement.websocket_port {
+    (ServerProtocol::WEBSOCKET, port)
+  } else if let Some(port) = announcement.wss_port {
+    (ServerProtocol::WSS, port)
+  } else if let Some(port) = announcement.udp_port {
+    (ServerProtocol::UDP, port)
+  } else {
+    return None;
+  };
+  return Some(Discovered {
+    server: Server {
+      protocol: protocol,
+      host: host,
+      port: port,
+      path: String::new(),
+      report_connect_errors: true,
+      max_latency_ms: 600,
+      name: announcement.name.clone(),
+      tls_pin: None,
+    },
+    caps: announcement.caps.clone(),
+  });
+}
+
+// IPv6 groups need brackets before the port
+fn multicast_addr(group: &str, port: u16) -> String {
+  if group.contains(':') {
+    return format!("[{}]:{}", group, port);
+  }
+  return format!("{}:{}", group, port);
+}
@@ -18,6 +18,7 @@
 
 use crate::config;
 use crate::record;
+use crate::wire::Counts;
 
 pub fn print_results(_config: &config::Config, results: &Vec<record::Record>) {
   // Sort by server name
@@ -76,4 +77,36 @@ pub fn print_results_ref(_config: &config::Config, results: &Vec<&record::Record
   
 }
 
+// Prints the counts from each server followed by their sum
+pub fn print_counts(_config: &config::Config, counts: &Vec<(config::Server, Result<Counts, String>)>) {
+  let mut counts = counts.iter().collect::<Vec<_>>();
+  counts.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+  
+  let mut all = Counts::default();
+  for (server, res) in &counts {
+    println!("=== {} ===", server.name);
+    match res {
+      Ok(server_counts) => {
+        print_count_lines(server_counts);
+        all.merge(server_counts);
+      }
+      Err(e) => {
+        println!("Error counting: {}", e);
+      }
+    }
+  }
+  if counts.len() > 1 {
+    println!("=== all servers ===");
+    print_count_lines(&all);
+  }
+}
 
+fn print_count_lines(counts: &Counts) {
+  println!("total = {}", counts.total);
+  // Largest groups first
+  let mut groups = counts.groups.iter().collect::<Vec<_>>();
+  groups.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+  for (value, n) in groups {
+    println!("{} = {:?}: {}", counts.group_by.as_ref().map(|k| k.as_str()).unwrap_or(""), value, n);
+  }
+}
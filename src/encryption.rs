@@ -0,0 +1,233 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *  
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; version 2 of the License only.
+ * 
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ * 
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+/**
+ * Encrypted records. Publishers seal the values of chosen keys to one or
+ * more recipient RSA public keys (the default gen_identity key type), the
+ * rest of the record stays plaintext and queryable. Each record gets a fresh
+ * AES-256-GCM key, which is wrapped with RSA-OAEP once per recipient.
+ * Records are encrypted before they are signed, so signatures cover the
+ * ciphertext and can be checked by anyone; decrypted records no longer
+ * carry a valid signature.
+ */
+
+use openssl::rsa::{Rsa, Padding};
+use openssl::pkey::{HasPublic, Private, Public};
+use openssl::symm::{Cipher, encrypt_aead, decrypt_aead};
+use openssl::rand::rand_bytes;
+
+use base64;
+
+use std::path::Path;
+
+use crate::config::Config;
+use crate::record::{Record, TTL_SECONDS_KEY, TTL_EXPIRES_AT_KEY};
+use crate::query::QUERY_KEY_PREFIX;
+use crate::signing;
+
+// Reserved key prefix, followed by the plaintext key name. Holds base64 of
+// the nonce, GCM tag and ciphertext of that key's value.
+pub const ENCRYPTED_VALUE_PREFIX: &str = "ENCRYPTED:value:";
+// Reserved key prefix, followed by a recipient's key_fingerprint(). Holds
+// base64 of the record's content key encrypted to that recipient.
+pub const ENCRYPTED_RECIPIENT_PREFIX: &str = "ENCRYPTED:recipient:";
+
+const CONTENT_KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+// Hex SHA-256 of the DER public key, names a recipient in records
+pub fn key_fingerprint<T: HasPublic>(rsa: &Rsa<T>) -> Result<String, String> {
+  let der = rsa.public_key_to_der().map_err(|e| format!("{}", e))?;
+  let digest = openssl::sha::sha256(&der);
+  return Ok(digest.iter().map(|b| format!("{:02x}", b)).collect());
+}
+
+// True if the record holds sealed values
+pub fn is_encrypted(rec: &Record) -> bool {
+  rec.p.keys().any(|k| k.starts_with(ENCRYPTED_VALUE_PREFIX))
+}
+
+// Only RSA identities can open sealed values, Ed25519 ones only sign
+pub fn read_identity(identity_file_path: &str) -> Result<Rsa<Private>, String> {
+  let bytes = std::fs::read(identity_file_path).map_err(|e| format!("{}: {}", identity_file_path, e))?;
+  if let Ok(rsa) = signing::try_parse_rsa(&bytes) {
+    return Ok(rsa);
+  }
+  if let Ok(key) = signing::try_parse_private(&bytes) {
+    if let Some(key_type) = signing::key_type_of(&key) {
+      return Err(format!("{}: is an {} identity, encryption needs an RSA one", identity_file_path, key_type.name()));
+    }
+  }
+  return Err(format!("{}: not an RSA private key", identity_file_path));
+}
+
+// A recipient is a public or private key file, or the base64
+// public key print_identity shows (and SIGNING:public-key holds).
+pub fn read_recipient(spec: &str) -> Result<Rsa<Public>, String> {
+  let bytes = if Path::new(spec).exists() {
+    std::fs::read(spec).map_err(|e| format!("{}: {}", spec, e))?
+  }
+  else {
+    base64::decode(spec.trim()).map_err(|_| format!("{}: not a key file or base64 public key", spec))?
+  };
+  if let Ok(rsa) = signing::try_parse_rsa_pub(&bytes) {
+    return Ok(rsa);
+  }
+  if let Ok(rsa) = signing::try_parse_rsa(&bytes) {
+    return public_half(&rsa);
+  }
+  return Err(format!("{}: not an RSA key", spec));
+}
+
+fn public_half(rsa: &Rsa<Private>) -> Result<Rsa<Public>, String> {
+  let pem = rsa.public_key_to_pem().map_err(|e| format!("{}", e))?;
+  return Rsa::public_key_from_pem(&pem).map_err(|e| format!("{}", e));
+}
+
+// Replaces the values of keys with ciphertext only recipients can read.
+// Keys missing from rec are ignored, reserved keys are never encrypted.
+pub fn seal_record(rec: &mut Record, keys: &[String], recipients: &[Rsa<Public>]) -> Result<(), String> {
+  if is_encrypted(rec) {
+    return Err("record is already encrypted".to_string());
+  }
+  let keys: Vec<&String> = keys.iter()
+    .filter(|k| rec.p.contains_key(k.as_str()) && !is_reserved_key(k))
+    .collect();
+  if keys.is_empty() {
+    return Ok(());
+  }
+  if recipients.is_empty() {
+    return Err("no recipients to encrypt for".to_string());
+  }
+  
+  let mut content_key = [0u8; CONTENT_KEY_LEN];
+  rand_bytes(&mut content_key).map_err(|e| format!("{}", e))?;
+  
+  let mut sealed: Vec<(String, String)> = vec![];
+  for recipient in recipients {
+    let mut wrapped = vec![0u8; recipient.size() as usize];
+    let len = recipient.public_encrypt(&content_key, &mut wrapped, Padding::PKCS1_OAEP).map_err(|e| format!("{}", e))?;
+    wrapped.truncate(len);
+    sealed.push((format!("{}{}", ENCRYPTED_RECIPIENT_PREFIX, key_fingerprint(recipient)?), base64::encode(&wrapped)));
+  }
+  for key in keys {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand_bytes(&mut nonce).map_err(|e| format!("{}", e))?;
+    let mut tag = [0u8; TAG_LEN];
+    // The key name is authenticated so a value cannot be moved to another key
+    let ciphertext = encrypt_aead(Cipher::aes_256_gcm(), &content_key, Some(&nonce), key.as_bytes(), rec.p[key.as_str()].as_bytes(), &mut tag)
+      .map_err(|e| format!("{}", e))?;
+    let mut bytes = nonce.to_vec();
+    bytes.extend(&tag);
+    bytes.extend(ciphertext);
+    sealed.push((format!("{}{}", ENCRYPTED_VALUE_PREFIX, key), base64::encode(&bytes)));
+  }
+  
+  for (key, _) in sealed.iter().filter(|(k, _)| k.starts_with(ENCRYPTED_VALUE_PREFIX)) {
+    rec.p.remove(&key[ENCRYPTED_VALUE_PREFIX.len()..]);
+  }
+  rec.p.extend(sealed);
+  return Ok(());
+}
+
+// Puts back the plaintext of every sealed value and drops the ENCRYPTED:* keys.
+// Leaves rec untouched on error, including when identity is not a recipient.
+pub fn open_record(rec: &mut Record, identity: &Rsa<Private>) -> Result<(), String> {
+  let recipient_key = format!("{}{}", ENCRYPTED_RECIPIENT_PREFIX, key_fingerprint(identity)?);
+  let wrapped = match rec.p.get(&recipient_key) {
+    Some(wrapped) => base64::decode(wrapped).map_err(|e| format!("{}", e))?,
+    None => return Err("not a recipient of this record".to_string()),
+  };
+  let mut content_key = vec![0u8; identity.size() as usize];
+  let len = identity.private_decrypt(&wrapped, &mut content_key, Padding::PKCS1_OAEP).map_err(|e| format!("{}", e))?;
+  content_key.truncate(len);
+  if content_key.len() != CONTENT_KEY_LEN {
+    return Err("bad content key".to_string());
+  }
+  
+  let mut opened: Vec<(String, String)> = vec![];
+  for (key, val) in rec.p.iter().filter(|(k, _)| k.starts_with(ENCRYPTED_VALUE_PREFIX)) {
+    let plain_key = &key[ENCRYPTED_VALUE_PREFIX.len()..];
+    let bytes = base64::decode(val).map_err(|e| format!("{}: {}", plain_key, e))?;
+    if bytes.len() < NONCE_LEN + TAG_LEN {
+      return Err(format!("{}: ciphertext too short", plain_key));
+    }
+    let (nonce, rest) = bytes.split_at(NONCE_LEN);
+    let (tag, ciphertext) = rest.split_at(TAG_LEN);
+    let plaintext = decrypt_aead(Cipher::aes_256_gcm(), &content_key, Some(nonce), plain_key.as_bytes(), ciphertext, tag)
+      .map_err(|_| format!("{}: could not decrypt", plain_key))?;
+    let plaintext = String::from_utf8(plaintext).map_err(|e| format!("{}: {}", plain_key, e))?;
+    opened.push((plain_key.to_string(), plaintext));
+  }
+  
+  rec.p.retain(|k, _| !k.starts_with("ENCRYPTED:"));
+  rec.p.extend(opened);
+  return Ok(());
+}
+
+// Seals config.client_encrypt_keys to config.client_encrypt_recipients,
+// and to our own identity when we have one so we can read our records back.
+pub fn maybe_encrypt_record(config: &Config, rec: &mut Record) -> Result<(), String> {
+  if config.client_encrypt_keys.is_empty() {
+    return Ok(());
+  }
+  let mut recipients = vec![];
+  for spec in &config.client_encrypt_recipients {
+    recipients.push(read_recipient(spec)?);
+  }
+  // The publisher can always read what they sealed, so an identity we
+  // cannot seal to is an error rather than something to leave out
+  if Path::new(&config.client_private_key_file).exists() {
+    let identity = read_identity(&config.client_private_key_file)?;
+    let ours = key_fingerprint(&identity)?;
+    if !recipients.iter().any(|r| key_fingerprint(r).map(|f| f == ours).unwrap_or(false)) {
+      recipients.push(public_half(&identity)?);
+    }
+  }
+  return seal_record(rec, &config.client_encrypt_keys, &recipients);
+}
+
+// Opens every result sealed to client_private_key_file. Results we
+// cannot open are left as they came from the server.
+pub fn decrypt_results(config: &Config, results: &mut [Record]) {
+  if !results.iter().any(is_encrypted) {
+    return;
+  }
+  let identity = match read_identity(&config.client_private_key_file) {
+    Ok(identity) => identity,
+    Err(e) => {
+      println!("Cannot decrypt results: {}", e);
+      return;
+    }
+  };
+  for rec in results.iter_mut().filter(|rec| is_encrypted(rec)) {
+    if let Err(e) = open_record(rec, &identity) {
+      if config.is_debug() {
+        println!("Cannot decrypt result: {}", e);
+      }
+    }
+  }
+}
+
+// Keys servers read, which must stay in the clear
+fn is_reserved_key(key: &str) -> bool {
+  key.starts_with("ENCRYPTED:") || signing::key_is_used_in_signing(key)
+    || key == TTL_SECONDS_KEY || key == TTL_EXPIRES_AT_KEY
+    || key.starts_with(QUERY_KEY_PREFIX)
+}
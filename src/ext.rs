@@ -27,6 +27,7 @@ use crate::config::Config;
 use crate::args::Args;
 use crate::disp;
 use crate::client;
+use crate::wire::QueryOptions;
 
 #[no_mangle]
 pub extern fn dindex_args() -> *mut Args {
@@ -151,6 +152,26 @@ pub extern fn dindex_client_query_sync(config: *mut Config, rec_ptr: *mut Record
   }
 }
 
+// Negative limit or offset and NULL sort_by mean none, see QueryOptions
+#[no_mangle]
+pub extern fn dindex_client_query_sync_with_options(config: *mut Config, rec_ptr: *mut Record, limit: i64, offset: i64, sort_by: *const c_char, descending: bool) -> *mut RecordVec {
+  if config.is_null() || rec_ptr.is_null() {
+    return std::ptr::null_mut();
+  }
+  let options = QueryOptions {
+    limit: if limit < 0 { None } else { Some(limit as usize) },
+    offset: if offset < 0 { None } else { Some(offset as usize) },
+    cursor: None,
+    sort_by: if sort_by.is_null() { None } else { Some(unsafe { CStr::from_ptr(sort_by) }.to_string_lossy().to_string()) },
+    descending: descending,
+    ..QueryOptions::default()
+  };
+  unsafe {
+    let results = client::query_sync_with_options(&(*config), &(*rec_ptr), &options);
+    Box::into_raw(Box::new( results ))
+  }
+}
+
 #[no_mangle]
 pub extern fn dindex_client_publish_sync(config: *mut Config, rec_ptr: *mut Record) {
   if config.is_null() || rec_ptr.is_null() {
@@ -30,6 +30,8 @@ use crate::args::Args;
 use crate::disp;
 use crate::client;
 use crate::actions;
+use crate::wire::QueryOptions;
+use crate::query::MatchMode;
 
 use crate::py_attr_map_dict;
 use crate::attr_from_py_dict;
@@ -52,6 +54,10 @@ dIndex python bindings.
     py_fn!(py, record_display_vec(config: Config, record: Vec<Record> = vec![] ) ))?;
   m.add(py, "client_query_sync",
     py_fn!(py, client_query_sync(config: Config, record: Record ) ))?;
+  m.add(py, "client_query_sync_with_options",
+    py_fn!(py, client_query_sync_with_options(config: Config, record: Record, limit: Option<usize> = None, offset: Option<usize> = None, sort_by: Option<String> = None, descending: bool = false, fields: Option<Vec<String>> = None ) ))?;
+  m.add(py, "client_count_sync",
+    py_fn!(py, client_count_sync(config: Config, record: Record, group_by: Option<String> = None ) ))?;
   m.add(py, "client_publish_sync",
     py_fn!(py, client_publish_sync(config: Config, record: Record ) ))?;
   m.add(py, "client_listen_sync",
@@ -75,6 +81,22 @@ impl cpython::ToPyObject for Args {
     py_attr_map_dict!(py, py_dict, "verbose", self.verbose);
     py_attr_map_dict!(py, py_dict, "action", format!("{}", self.action));
     py_attr_map_dict!(py, py_dict, "signed", self.signed);
+    py_attr_map_dict!(py, py_dict, "key_type", self.key_type.clone());
+    py_attr_map_dict!(py, py_dict, "encrypt", self.encrypt.clone());
+    py_attr_map_dict!(py, py_dict, "recipients", self.recipients.clone());
+    py_attr_map_dict!(py, py_dict, "target_id", self.target_id.clone());
+    py_attr_map_dict!(py, py_dict, "target_query", self.target_query.clone());
+    py_attr_map_dict!(py, py_dict, "ttl", self.ttl);
+    py_attr_map_dict!(py, py_dict, "limit", self.limit);
+    py_attr_map_dict!(py, py_dict, "offset", self.offset);
+    py_attr_map_dict!(py, py_dict, "cursor", self.cursor.clone());
+    py_attr_map_dict!(py, py_dict, "sort_by", self.sort_by.clone());
+    py_attr_map_dict!(py, py_dict, "descending", self.descending);
+    py_attr_map_dict!(py, py_dict, "fields", self.fields.clone());
+    py_attr_map_dict!(py, py_dict, "group_by", self.group_by.clone());
+    py_attr_map_dict!(py, py_dict, "text", self.text);
+    py_attr_map_dict!(py, py_dict, "replay", self.replay);
+    py_attr_map_dict!(py, py_dict, "match_mode", self.match_mode.clone());
     py_attr_map_dict!(py, py_dict, "rec_args", self.rec_args.clone());
     
     return py_dict;
@@ -149,6 +171,8 @@ impl cpython::ToPyObject for config::Server {
     py_attr_map_dict!(py, py_dict, "report_connect_errors", self.report_connect_errors);
     py_attr_map_dict!(py, py_dict, "max_latency_ms", self.max_latency_ms);
     py_attr_map_dict!(py, py_dict, "name", self.name.clone());
+    // Read back with an empty pin meaning none
+    py_attr_map_dict!(py, py_dict, "tls_pin", self.tls_pin.clone().unwrap_or_default());
     
     return py_dict;
   }
@@ -175,6 +199,22 @@ impl <'source> cpython::FromPyObject<'source> for Args {
     let verbose = attr_from_py_dict!(py, py_dict, "verbose", 0, u8 );
     let action = attr_from_py_dict!(py, py_dict, "action", actions::Action::no_action, actions::Action );
     let signed = attr_from_py_dict!(py, py_dict, "signed", false, bool );
+    let key_type = attr_from_py_dict!(py, py_dict, "key_type", None, Option<String> );
+    let encrypt = attr_from_py_dict!(py, py_dict, "encrypt", None, Option<String> );
+    let recipients = attr_from_py_dict!(py, py_dict, "recipients", None, Option<String> );
+    let target_id = attr_from_py_dict!(py, py_dict, "target_id", None, Option<String> );
+    let target_query = attr_from_py_dict!(py, py_dict, "target_query", None, Option<String> );
+    let ttl = attr_from_py_dict!(py, py_dict, "ttl", None, Option<f64> );
+    let limit = attr_from_py_dict!(py, py_dict, "limit", None, Option<usize> );
+    let offset = attr_from_py_dict!(py, py_dict, "offset", None, Option<usize> );
+    let cursor = attr_from_py_dict!(py, py_dict, "cursor", None, Option<String> );
+    let sort_by = attr_from_py_dict!(py, py_dict, "sort_by", None, Option<String> );
+    let descending = attr_from_py_dict!(py, py_dict, "descending", false, bool );
+    let fields = attr_from_py_dict!(py, py_dict, "fields", None, Option<String> );
+    let group_by = attr_from_py_dict!(py, py_dict, "group_by", None, Option<String> );
+    let text = attr_from_py_dict!(py, py_dict, "text", false, bool );
+    let replay = attr_from_py_dict!(py, py_dict, "replay", false, bool );
+    let match_mode = attr_from_py_dict!(py, py_dict, "match_mode", None, Option<String> );
     let rec_args = attr_from_py_dict!(py, py_dict, "rec_args", vec![], Vec<String> );
     
     Ok(Args {
@@ -183,6 +223,22 @@ impl <'source> cpython::FromPyObject<'source> for Args {
       verbose: verbose,
       action: action,
       signed: signed,
+      key_type: key_type,
+      encrypt: encrypt,
+      recipients: recipients,
+      target_id: target_id,
+      target_query: target_query,
+      ttl: ttl,
+      limit: limit,
+      offset: offset,
+      cursor: cursor,
+      sort_by: sort_by,
+      descending: descending,
+      fields: fields,
+      group_by: group_by,
+      text: text,
+      replay: replay,
+      match_mode: match_mode,
       rec_args: rec_args,
     })
   }
@@ -213,6 +269,7 @@ impl <'source> cpython::FromPyObject<'source> for config::Server {
     let report_connect_errors = attr_from_py_dict!(py, py_dict, "report_connect_errors", true, bool );
     let max_latency_ms = attr_from_py_dict!(py, py_dict, "max_latency_ms", 600, usize );
     let name = attr_from_py_dict!(py, py_dict, "name", String::new(), String );
+    let tls_pin = attr_from_py_dict!(py, py_dict, "tls_pin", String::new(), String );
     
     Ok(config::Server {
       protocol: protocol,
@@ -222,6 +279,7 @@ impl <'source> cpython::FromPyObject<'source> for config::Server {
       report_connect_errors: report_connect_errors,
       max_latency_ms: max_latency_ms,
       name: name,
+      tls_pin: if tls_pin.is_empty() { None } else { Some(tls_pin) },
     })
   }
 }
@@ -236,6 +294,15 @@ impl <'source> cpython::FromPyObject<'source> for config::ServerProtocol {
   }
 }
 
+impl <'source> cpython::FromPyObject<'source> for config::EvictionPolicy {
+  fn extract(py: Python, obj: &'source cpython::PyObject) -> PyResult<Self> {
+    let py_str: PyString = obj.extract(py)?;
+    Ok(config::EvictionPolicy::from_str(
+      format!("{}", py_str.to_string(py).unwrap_or(std::borrow::Cow::Borrowed(&String::new())))
+    ))
+  }
+}
+
 impl cpython::ToPyObject for config::Config {
   type ObjectType = PyDict;
   fn to_py_object(&self, py: Python) -> Self::ObjectType {
@@ -249,6 +316,10 @@ impl cpython::ToPyObject for config::Config {
     py_attr_map_dict!(py, py_dict, "client_http_custom_js", self.client_http_custom_js.clone());
     py_attr_map_dict!(py, py_dict, "client_http_custom_css", self.client_http_custom_css.clone());
     py_attr_map_dict!(py, py_dict, "client_use_sig", self.client_use_sig);
+    py_attr_map_dict!(py, py_dict, "client_encrypt_keys", self.client_encrypt_keys.clone());
+    py_attr_map_dict!(py, py_dict, "client_encrypt_recipients", self.client_encrypt_recipients.clone());
+    py_attr_map_dict!(py, py_dict, "client_match_mode", self.client_match_mode.to_spec());
+    py_attr_map_dict!(py, py_dict, "client_tls_ca_file", self.client_tls_ca_file.clone());
     py_attr_map_dict!(py, py_dict, "verbosity_level", self.verbosity_level);
     py_attr_map_dict!(py, py_dict, "servers", self.servers.clone());
     py_attr_map_dict!(py, py_dict, "rhai_scripts", self.rhai_scripts.clone());
@@ -257,11 +328,17 @@ impl cpython::ToPyObject for config::Config {
     py_attr_map_dict!(py, py_dict, "server_listen_unix", self.server_listen_unix);
     py_attr_map_dict!(py, py_dict, "server_listen_websocket", self.server_listen_websocket);
     py_attr_map_dict!(py, py_dict, "server_listen_multicast", self.server_listen_multicast);
+    py_attr_map_dict!(py, py_dict, "server_listen_tls", self.server_listen_tls);
+    py_attr_map_dict!(py, py_dict, "server_listen_wss", self.server_listen_wss);
     py_attr_map_dict!(py, py_dict, "server_extra_quiet", self.server_extra_quiet);
     py_attr_map_dict!(py, py_dict, "server_max_listeners", self.server_max_listeners);
     py_attr_map_dict!(py, py_dict, "server_pid_file", self.server_pid_file.clone());
     py_attr_map_dict!(py, py_dict, "server_port", self.server_port);
     py_attr_map_dict!(py, py_dict, "server_websocket_port", self.server_websocket_port);
+    py_attr_map_dict!(py, py_dict, "server_tls_port", self.server_tls_port);
+    py_attr_map_dict!(py, py_dict, "server_wss_port", self.server_wss_port);
+    py_attr_map_dict!(py, py_dict, "server_tls_cert_file", self.server_tls_cert_file.clone());
+    py_attr_map_dict!(py, py_dict, "server_tls_key_file", self.server_tls_key_file.clone());
     py_attr_map_dict!(py, py_dict, "server_ip", self.server_ip.clone());
     py_attr_map_dict!(py, py_dict, "server_unix_socket", self.server_unix_socket.clone());
     py_attr_map_dict!(py, py_dict, "server_multicast_group", self.server_multicast_group.clone());
@@ -270,8 +347,20 @@ impl cpython::ToPyObject for config::Config {
     py_attr_map_dict!(py, py_dict, "server_datastore_uri", self.server_datastore_uri.clone());
     py_attr_map_dict!(py, py_dict, "server_trusted_keys_file", self.server_trusted_keys_file.clone());
     py_attr_map_dict!(py, py_dict, "server_max_records", self.server_max_records);
+    py_attr_map_dict!(py, py_dict, "server_eviction_policy", format!("{:?}", self.server_eviction_policy));
+    py_attr_map_dict!(py, py_dict, "server_default_ttl_s", self.server_default_ttl_s);
     py_attr_map_dict!(py, py_dict, "server_max_unauth_websockets", self.server_max_unauth_websockets);
     py_attr_map_dict!(py, py_dict, "server_num_record_pools", self.server_num_record_pools);
+    py_attr_map_dict!(py, py_dict, "server_regex_size_limit", self.server_regex_size_limit);
+    py_attr_map_dict!(py, py_dict, "server_regex_dfa_size_limit", self.server_regex_dfa_size_limit);
+    py_attr_map_dict!(py, py_dict, "server_query_cache_size", self.server_query_cache_size);
+    py_attr_map_dict!(py, py_dict, "server_federate", self.server_federate);
+    py_attr_map_dict!(py, py_dict, "server_federation_max_hops", self.server_federation_max_hops);
+    py_attr_map_dict!(py, py_dict, "server_replication_peers", self.server_replication_peers.clone());
+    py_attr_map_dict!(py, py_dict, "server_replication_interval_ms", self.server_replication_interval_ms);
+    py_attr_map_dict!(py, py_dict, "server_tombstone_ttl_s", self.server_tombstone_ttl_s);
+    py_attr_map_dict!(py, py_dict, "server_name", self.server_name.clone());
+    py_attr_map_dict!(py, py_dict, "server_announce_interval_ms", self.server_announce_interval_ms);
     
     return py_dict;
   }
@@ -297,6 +386,14 @@ impl <'source> cpython::FromPyObject<'source> for config::Config {
       attr_from_py_dict!(py, py_dict, "client_http_custom_css", include_str!("http/example_custom_css.css").to_string(), String);
     let client_use_sig = 
       attr_from_py_dict!(py, py_dict, "client_use_sig", false, bool);
+    let client_encrypt_keys = 
+      attr_from_py_dict!(py, py_dict, "client_encrypt_keys", vec![], Vec<String>);
+    let client_encrypt_recipients = 
+      attr_from_py_dict!(py, py_dict, "client_encrypt_recipients", vec![], Vec<String>);
+    let client_match_mode = 
+      attr_from_py_dict!(py, py_dict, "client_match_mode", "regex".to_string(), String);
+    let client_tls_ca_file = 
+      attr_from_py_dict!(py, py_dict, "client_tls_ca_file", String::new(), String);
     let verbosity_level = 
       attr_from_py_dict!(py, py_dict, "verbosity_level", 0, u8);
     let servers = 
@@ -313,6 +410,10 @@ impl <'source> cpython::FromPyObject<'source> for config::Config {
       attr_from_py_dict!(py, py_dict, "server_listen_websocket", true, bool);
     let server_listen_multicast = 
       attr_from_py_dict!(py, py_dict, "server_listen_multicast", true, bool);
+    let server_listen_tls = 
+      attr_from_py_dict!(py, py_dict, "server_listen_tls", false, bool);
+    let server_listen_wss = 
+      attr_from_py_dict!(py, py_dict, "server_listen_wss", false, bool);
     let server_extra_quiet = 
       attr_from_py_dict!(py, py_dict, "server_extra_quiet", false, bool);
     let server_max_listeners = 
@@ -323,6 +424,14 @@ impl <'source> cpython::FromPyObject<'source> for config::Config {
       attr_from_py_dict!(py, py_dict, "server_port", config::DINDEX_DEF_PORT, u16);
     let server_websocket_port = 
       attr_from_py_dict!(py, py_dict, "server_websocket_port", config::DINDEX_DEF_WEBSOCKET_PORT, u16);
+    let server_tls_port = 
+      attr_from_py_dict!(py, py_dict, "server_tls_port", config::DINDEX_DEF_TLS_PORT, u16);
+    let server_wss_port = 
+      attr_from_py_dict!(py, py_dict, "server_wss_port", config::DINDEX_DEF_WSS_PORT, u16);
+    let server_tls_cert_file = 
+      attr_from_py_dict!(py, py_dict, "server_tls_cert_file", String::new(), String);
+    let server_tls_key_file = 
+      attr_from_py_dict!(py, py_dict, "server_tls_key_file", String::new(), String);
     let server_ip = 
       attr_from_py_dict!(py, py_dict, "server_ip", "0.0.0.0".to_string(), String);
     let server_unix_socket = 
@@ -339,10 +448,34 @@ impl <'source> cpython::FromPyObject<'source> for config::Config {
       attr_from_py_dict!(py, py_dict, "server_trusted_keys_file", "/tmp/dindex_trusted_keys".to_string(), String);
     let server_max_records = 
       attr_from_py_dict!(py, py_dict, "server_max_records", 4096, usize);
+    let server_eviction_policy = 
+      attr_from_py_dict!(py, py_dict, "server_eviction_policy", config::EvictionPolicy::Oldest, config::EvictionPolicy);
+    let server_default_ttl_s = 
+      attr_from_py_dict!(py, py_dict, "server_default_ttl_s", 0.0, f64);
     let server_max_unauth_websockets = 
       attr_from_py_dict!(py, py_dict, "server_max_unauth_websockets", 100, usize);
     let server_num_record_pools = 
       attr_from_py_dict!(py, py_dict, "server_num_record_pools", 8, usize);
+    let server_regex_size_limit = 
+      attr_from_py_dict!(py, py_dict, "server_regex_size_limit", 1 << 20, usize);
+    let server_regex_dfa_size_limit = 
+      attr_from_py_dict!(py, py_dict, "server_regex_dfa_size_limit", 1 << 20, usize);
+    let server_query_cache_size = 
+      attr_from_py_dict!(py, py_dict, "server_query_cache_size", 256, usize);
+    let server_federate = 
+      attr_from_py_dict!(py, py_dict, "server_federate", false, bool);
+    let server_federation_max_hops = 
+      attr_from_py_dict!(py, py_dict, "server_federation_max_hops", 3, u32);
+    let server_replication_peers = 
+      attr_from_py_dict!(py, py_dict, "server_replication_peers", vec![], Vec<config::Server>);
+    let server_replication_interval_ms = 
+      attr_from_py_dict!(py, py_dict, "server_replication_interval_ms", 30000, usize);
+    let server_tombstone_ttl_s = 
+      attr_from_py_dict!(py, py_dict, "server_tombstone_ttl_s", 7 * 24 * 60 * 60, usize);
+    let server_name = 
+      attr_from_py_dict!(py, py_dict, "server_name", config::default_server_name(), String);
+    let server_announce_interval_ms = 
+      attr_from_py_dict!(py, py_dict, "server_announce_interval_ms", 60000, usize);
     
     Ok(config::Config {
       ctypes: ctypes,
@@ -353,6 +486,10 @@ impl <'source> cpython::FromPyObject<'source> for config::Config {
       client_http_custom_js: client_http_custom_js,
       client_http_custom_css: client_http_custom_css,
       client_use_sig: client_use_sig,
+      client_encrypt_keys: client_encrypt_keys,
+      client_encrypt_recipients: client_encrypt_recipients,
+      client_match_mode: MatchMode::parse(&client_match_mode).unwrap_or_default(),
+      client_tls_ca_file: client_tls_ca_file,
       verbosity_level: verbosity_level,
       servers: servers,
       rhai_scripts: rhai_scripts,
@@ -361,11 +498,17 @@ impl <'source> cpython::FromPyObject<'source> for config::Config {
       server_listen_unix: server_listen_unix,
       server_listen_websocket: server_listen_websocket,
       server_listen_multicast: server_listen_multicast,
+      server_listen_tls: server_listen_tls,
+      server_listen_wss: server_listen_wss,
       server_extra_quiet: server_extra_quiet,
       server_max_listeners: server_max_listeners,
       server_pid_file: server_pid_file,
       server_port: server_port,
       server_websocket_port: server_websocket_port,
+      server_tls_port: server_tls_port,
+      server_wss_port: server_wss_port,
+      server_tls_cert_file: server_tls_cert_file,
+      server_tls_key_file: server_tls_key_file,
       server_ip: server_ip,
       server_unix_socket: server_unix_socket,
       server_multicast_group: server_multicast_group,
@@ -374,8 +517,20 @@ impl <'source> cpython::FromPyObject<'source> for config::Config {
       server_datastore_uri: server_datastore_uri,
       server_trusted_keys_file: server_trusted_keys_file,
       server_max_records: server_max_records,
+      server_eviction_policy: server_eviction_policy,
+      server_default_ttl_s: server_default_ttl_s,
       server_max_unauth_websockets: server_max_unauth_websockets,
       server_num_record_pools: server_num_record_pools,
+      server_regex_size_limit: server_regex_size_limit,
+      server_regex_dfa_size_limit: server_regex_dfa_size_limit,
+      server_query_cache_size: server_query_cache_size,
+      server_federate: server_federate,
+      server_federation_max_hops: server_federation_max_hops,
+      server_replication_peers: server_replication_peers,
+      server_replication_interval_ms: server_replication_interval_ms,
+      server_tombstone_ttl_s: server_tombstone_ttl_s,
+      server_name: server_name,
+      server_announce_interval_ms: server_announce_interval_ms,
     })
   }
 }
@@ -429,6 +584,34 @@ fn client_query_sync(_py: Python, config: Config, rec: Record) -> PyResult<Vec<R
   Ok(results)
 }
 
+fn client_query_sync_with_options(_py: Python, config: Config, rec: Record, limit: Option<usize>, offset: Option<usize>, sort_by: Option<String>, descending: bool, fields: Option<Vec<String>>) -> PyResult<Vec<Record>> {
+  let options = QueryOptions {
+    limit: limit,
+    offset: offset,
+    cursor: None,
+    sort_by: sort_by,
+    descending: descending,
+    fields: fields,
+    group_by: None,
+    replay: false,
+  };
+  let results = client::query_sync_with_options(&config, &rec, &options);
+  Ok(results)
+}
+
+// One record per server which answered, see wire::Counts::to_record
+fn client_count_sync(_py: Python, config: Config, rec: Record, group_by: Option<String>) -> PyResult<Vec<Record>> {
+  let mut results = vec![];
+  for (server, counts) in client::count_sync(&config, &rec, group_by.as_ref().map(|key| key.as_str())) {
+    if let Ok(counts) = counts {
+      let mut counts_rec = counts.to_record();
+      counts_rec.src_server = Some(server);
+      results.push(counts_rec);
+    }
+  }
+  Ok(results)
+}
+
 fn client_publish_sync(py: Python, config: Config, rec: Record) -> PyResult<cpython::PyObject> {
   client::publish_sync(&config, &rec);
   Ok(py.None())
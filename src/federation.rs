@@ -0,0 +1,309 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *  
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; version 2 of the License only.
+ * 
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ * 
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+/**
+ * Federation: servers with server_federate set forward the queries and
+ * listens of their clients to config.servers and send what those servers
+ * return back along with their own results, each record once.
+ * Forwarded requests carry a wire::Forwarded so loops between servers
+ * end after one round and chains end after server_federation_max_hops.
+ */
+
+use crossbeam_utils::thread;
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+use crate::actions::Action;
+use crate::client;
+use crate::config::Config;
+use crate::data::{Data, Listener};
+use crate::record::{Record, now_ms};
+use crate::wire::{WireData, QueryOptions, Forwarded};
+
+// Request ids Data remembers to recognise requests coming back around a loop
+pub const FORWARDED_REQUESTS_REMEMBERED: usize = 4096;
+// Record ids a federated listener remembers to avoid sending a record twice
+pub const LISTEN_IDS_REMEMBERED: usize = 4096;
+// How long a replaying listen waits for a federated server's end_of_replay,
+// servers which predate replay never send one
+pub const FEDERATED_REPLAY_WAIT_MS: u64 = 2000;
+
+// What a server does with a query or listen it received
+#[derive(Debug, Clone, PartialEq)]
+pub enum Route {
+  // Answered already, reply with no results
+  Seen,
+  // Answer from our own records only
+  Local,
+  // Answer from our own records and forward it with this to config.servers
+  Forward(Forwarded),
+}
+
+pub fn route(config: &Config, data: &Data, incoming: Option<&Forwarded>) -> Route {
+  let forwarded = match incoming {
+    Some(incoming) => {
+      if ! data.first_sight(&incoming.request_id) {
+        return Route::Seen;
+      }
+      Forwarded { request_id: incoming.request_id.clone(), hops: incoming.hops + 1 }
+    }
+    None => {
+      if ! config.server_federate || config.servers.is_empty() {
+        return Route::Local;
+      }
+      let forwarded = Forwarded { request_id: new_request_id(), hops: 1 };
+      data.first_sight(&forwarded.request_id);
+      forwarded
+    }
+  };
+  if ! config.server_federate || config.servers.is_empty() || forwarded.hops > config.server_federation_max_hops {
+    return Route::Local;
+  }
+  return Route::Forward(forwarded);
+}
+
+// 128 random bits as hex
+pub fn new_request_id() -> String {
+  let mut bytes = [0u8; 16];
+  if let Err(e) = openssl::rand::rand_bytes(&mut bytes) {
+    println!("Error generating a request id, falling back to the time: {}", e);
+    bytes[..8].copy_from_slice(&now_ms().to_be_bytes());
+  }
+  return bytes.iter().map(|b| format!("{:02x}", b)).collect();
+}
+
+// Sends query to every server in config.servers at once and calls
+// on_result with each record they return. Errors from them are ignored,
+// the client still gets our own results.
+pub fn query_sync<F: Fn(Record) + Sync>(config: &Config, query: &Record, options: &QueryOptions, forwarded: &Forwarded, on_result: F) {
+  let mut wire_data = WireData::new(Action::query, query.clone()).with_forwarded(forwarded.clone());
+  if ! options.is_empty() {
+    wire_data = wire_data.with_options(options.clone());
+  }
+  let wire_data = &wire_data;
+  let on_result = &on_result;
+  thread::scope(|s| {
+    let mut handlers = vec![];
+    
+    for server in &config.servers {
+      handlers.push(s.spawn(move |_| {
+        let res = client::request_server_sync(config, server, wire_data, || false, |wire_res| {
+          if wire_res.action == Action::result {
+            on_result(wire_res.record);
+          }
+          return true;
+        });
+        if let Err(e) = res {
+          if config.is_debug() && !config.server_extra_quiet {
+            println!("Error forwarding query to {}: {}", server.name, e);
+          }
+        }
+      }));
+    }
+    
+    for h in handlers {
+      h.join().unwrap();
+    }
+  }).unwrap();
+}
+
+// Listens to query on every server in config.servers in the background and
+// passes what they send on to listener, until listener is dropped or its
+// connection closes. listener.sent must be set so records arriving from
+// several servers are sent once.
+// options are forwarded too. With options.replay each server's replay is
+// sent before the end_of_replay of listener.replay_gate, see ReplayGate.
+pub fn listen(config: &Config, query: &Record, options: Option<&QueryOptions>, forwarded: &Forwarded, listener: &Listener) {
+  let mut wire_data = WireData::new(Action::listen, query.clone()).with_forwarded(forwarded.clone());
+  if let Some(options) = options {
+    wire_data = wire_data.with_options(options.clone());
+  }
+  for server in &config.servers {
+    let config = config.clone();
+    let server = server.clone();
+    let wire_data = wire_data.clone();
+    let id = listener.id;
+    let tx = listener.tx.clone();
+    let ended = listener.ended.clone();
+    let conn_is_valid = listener.conn_is_valid.clone();
+    let sent = listener.sent.clone();
+    let gate = listener.replay_gate.clone();
+    // Set once this server's replay is over, or we stopped waiting for it
+    let replayed = Arc::new(AtomicBool::new(gate.is_none()));
+    if let Some(gate) = &gate {
+      // Weak so a finished listen is not kept open until the wait is over
+      let (gate, replayed) = (Arc::downgrade(gate), replayed.clone());
+      std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(FEDERATED_REPLAY_WAIT_MS));
+        if let Some(gate) = gate.upgrade() {
+          if ! replayed.swap(true, Ordering::SeqCst) {
+            gate.finish();
+          }
+        }
+      });
+    }
+    std::thread::spawn(move || {
+      let is_listening = || {
+        if ended.load(Ordering::SeqCst) {
+          return false;
+        }
+        return conn_is_valid.lock().map(|valid| valid.load(Ordering::SeqCst)).unwrap_or(false);
+      };
+      let finish_replay = || {
+        if let Some(gate) = &gate {
+          if ! replayed.swap(true, Ordering::SeqCst) {
+            gate.finish();
+          }
+        }
+      };
+      let res = client::request_server_sync(&config, &server, &wire_data, is_listening, |wire_res| {
+        if ! is_listening() {
+          return false;
+        }
+        if wire_res.action == Action::end_of_replay {
+          finish_replay(); // Our own end_of_replay waits for it
+          return true;
+        }
+        if wire_res.action != Action::result && wire_res.action != Action::removed {
+          return true;
+        }
+        if wire_res.action == Action::result {
+          if let Some(sent) = &sent {
+            if let Ok(mut sent) = sent.lock() {
+              if ! sent.insert(&wire_res.record.id()) {
+                return true;
+              }
+            }
+          }
+        }
+        let wire_data = WireData::new(wire_res.action, wire_res.record).with_id(id);
+        return match (&gate, replayed.load(Ordering::SeqCst)) {
+          (Some(gate), true) => gate.send_live(wire_data),
+          _ => tx.send(wire_data).is_ok(),
+        };
+      });
+      if let Err(e) = res {
+        if config.is_debug() && !config.server_extra_quiet {
+          println!("Error forwarding listen to {}: {}", server.name, e);
+        }
+      }
+      finish_replay();
+    });
+  }
+}
+
+// Keeps end_of_replay meaningful for a federated listen with replay: it is
+// sent once we and every federated server have replayed, and records
+// published meanwhile are held back until after it.
+pub struct ReplayGate {
+  // The listener's client and request id
+  tx: Sender<WireData>,
+  id: Option<u64>,
+  // Parties still replaying (us and each federated server), and what
+  // they held back meanwhile
+  state: Mutex<(usize, Vec<WireData>)>,
+}
+
+impl ReplayGate {
+  pub fn new(parties: usize, tx: Sender<WireData>, id: Option<u64>) -> ReplayGate {
+    ReplayGate {
+      tx: tx,
+      id: id,
+      state: Mutex::new((parties, vec![])),
+    }
+  }
+  // Sends a record which is not part of a replay, unless the replay is
+  // still going. False if the client is gone.
+  pub fn send_live(&self, wire_data: WireData) -> bool {
+    match self.state.lock() {
+      Ok(mut state) if state.0 > 0 => {
+        state.1.push(wire_data);
+        return true;
+      }
+      _ => return self.tx.send(wire_data).is_ok(),
+    }
+  }
+  // Called once by each party when its replay is over. The last one sends
+  // end_of_replay followed by everything held back.
+  pub fn finish(&self) {
+    if let Ok(mut state) = self.state.lock() {
+      if state.0 == 0 {
+        return;
+      }
+      state.0 -= 1;
+      if state.0 > 0 {
+        return;
+      }
+      if let Err(e) = self.tx.send(WireData::end_of_replay().with_id(self.id)) {
+        println!("Error sending data to listener: {}", e);
+        return;
+      }
+      for wire_data in state.1.drain(..) {
+        if self.tx.send(wire_data).is_err() {
+          return;
+        }
+      }
+    }
+  }
+}
+
+// The last capacity ids inserted
+pub struct RecentIds {
+  capacity: usize,
+  order: VecDeque<String>,
+  ids: HashSet<String>,
+}
+
+impl RecentIds {
+  pub fn new(capacity: usize) -> RecentIds {
+    RecentIds {
+      capacity: capacity,
+      order: VecDeque::new(),
+      ids: HashSet::new(),
+    }
+  }
+  // True if id was not already held
+  pub fn insert(&mut self, id: &str) -> bool {
+    if self.ids.contains(id) {
+      return false;
+    }
+    self.ids.insert(id.to_string());
+    self.order.push_back(id.to_string());
+    while self.order.len() > self.capacity {
+      if let Some(oldest) = self.order.pop_front() {
+        self.ids.remove(&oldest);
+      }
+    }
+    return true;
+  }
+  pub fn contains(&self, id: &str) -> bool {
+    self.ids.contains(id)
+  }
+  pub fn remove(&mut self, id: &str) {
+    if self.ids.remove(id) {
+      self.order.retain(|held| held != id);
+    }
+  }
+  pub fn len(&self) -> usize {
+    self.ids.len()
+  }
+}
@@ -0,0 +1,263 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; version 2 of the License only.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use std::fmt;
+use std::io::prelude::*;
+
+use crate::wire::WireData;
+
+// Stream transports (tcp and unix sockets) can carry WireData in two ways:
+//
+//  - Legacy: a CBOR WireData object followed by a single 0xff byte.
+//    0xff may legitimately occur inside CBOR (byte strings, floats, UTF-8),
+//    so legacy messages are only split where the bytes before a 0xff parse.
+//
+//  - Length: FRAME_MAGIC, a version byte, the payload length as a
+//    big-endian u32 and then exactly that many bytes of CBOR.
+//
+// WireData always serializes as a CBOR map, so a legacy message never
+// begins with 0xff. Servers use the first byte a client sends to pick the
+// framing for the rest of the connection, and old servers reject a framed
+// request outright which lets new clients fall back to Legacy.
+
+pub const FRAME_MAGIC: [u8; 2] = [0xff, b'D'];
+pub const FRAME_VERSION: u8 = 1;
+// magic (2) + version (1) + length (4)
+pub const FRAME_HEADER_LEN: usize = 7;
+// Anything larger is treated as a corrupt or hostile stream
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+  Legacy, Length
+}
+
+impl Framing {
+  // The names used in Capabilities::framing
+  pub fn from_name(name: &str) -> Option<Framing> {
+    match name {
+      "length" => Some(Framing::Length),
+      "legacy" => Some(Framing::Legacy),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+  // The stream began with 0xff but not the rest of FRAME_MAGIC
+  BadMagic,
+  UnsupportedVersion(u8),
+  TooLarge(usize),
+  Cbor(serde_cbor::error::Error),
+  Io(std::io::Error),
+  // The stream closed with this many bytes of an incomplete message buffered
+  Truncated(usize),
+}
+
+impl fmt::Display for FrameError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      FrameError::BadMagic => write!(f, "stream does not begin with a known frame header"),
+      FrameError::UnsupportedVersion(v) => write!(f, "unsupported frame version {}", v),
+      FrameError::TooLarge(len) => write!(f, "frame of {} bytes exceeds limit of {} bytes", len, MAX_FRAME_LEN),
+      FrameError::Cbor(e) => write!(f, "invalid CBOR in frame: {}", e),
+      FrameError::Io(e) => write!(f, "{}", e),
+      FrameError::Truncated(len) => write!(f, "stream closed with {} bytes of an incomplete frame", len),
+    }
+  }
+}
+
+impl FrameError {
+  // True when the error is a read/write timeout, which listeners ignore
+  pub fn is_timeout(&self) -> bool {
+    if let FrameError::Io(e) = self {
+      return e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut;
+    }
+    return false;
+  }
+}
+
+pub fn encode(wire_data: &WireData, framing: Framing) -> Result<Vec<u8>, FrameError> {
+  let payload = serde_cbor::to_vec(wire_data).map_err(FrameError::Cbor)?;
+  match framing {
+    Framing::Legacy => {
+      let mut bytes = payload;
+      bytes.push(0xff);
+      return Ok(bytes);
+    }
+    Framing::Length => {
+      if payload.len() > MAX_FRAME_LEN {
+        return Err(FrameError::TooLarge(payload.len()));
+      }
+      let mut bytes = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+      bytes.extend_from_slice(&FRAME_MAGIC);
+      bytes.push(FRAME_VERSION);
+      bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+      bytes.extend_from_slice(&payload);
+      return Ok(bytes);
+    }
+  }
+}
+
+pub fn write_frame<W: Write>(stream: &mut W, wire_data: &WireData, framing: Framing) -> Result<(), FrameError> {
+  let bytes = encode(wire_data, framing)?;
+  stream.write_all(&bytes).map_err(FrameError::Io)?;
+  stream.flush().map_err(FrameError::Io)?;
+  return Ok(());
+}
+
+// Reads from stream until decoder yields a complete WireData.
+// Returns Ok(None) when the stream closes cleanly between messages.
+pub fn read_frame<R: Read>(stream: &mut R, decoder: &mut FrameDecoder) -> Result<Option<WireData>, FrameError> {
+  let mut buff = [0; 16 * 1024];
+  loop {
+    if let Some(wire_data) = decoder.next_frame()? {
+      return Ok(Some(wire_data));
+    }
+    match stream.read(&mut buff) {
+      Ok(0) => {
+        decoder.finish()?;
+        return Ok(None);
+      }
+      Ok(num_read) => {
+        decoder.push(&buff[0..num_read]);
+      }
+      Err(e) => {
+        return Err(FrameError::Io(e));
+      }
+    }
+  }
+}
+
+// Incrementally turns bytes from a stream into WireData objects.
+// The same decoder is used by servers and clients for both framings.
+pub struct FrameDecoder {
+  // None until the first byte arrives, at which point it is detected
+  framing: Option<Framing>,
+  buff: Vec<u8>,
+  // Legacy only: index to resume searching for a 0xff delimiter from
+  scan_from: usize,
+}
+
+impl FrameDecoder {
+  pub fn new() -> FrameDecoder {
+    FrameDecoder {
+      framing: None,
+      buff: vec![],
+      scan_from: 0,
+    }
+  }
+  pub fn with_framing(framing: Framing) -> FrameDecoder {
+    FrameDecoder {
+      framing: Some(framing),
+      buff: vec![],
+      scan_from: 0,
+    }
+  }
+  pub fn framing(&self) -> Option<Framing> {
+    self.framing
+  }
+  pub fn push(&mut self, bytes: &[u8]) {
+    if self.framing.is_none() && bytes.len() > 0 && self.buff.is_empty() {
+      self.framing = Some(if bytes[0] == FRAME_MAGIC[0] { Framing::Length } else { Framing::Legacy });
+    }
+    self.buff.extend_from_slice(bytes);
+  }
+  pub fn next_frame(&mut self) -> Result<Option<WireData>, FrameError> {
+    match self.framing {
+      None => Ok(None),
+      Some(Framing::Length) => self.next_length_frame(),
+      Some(Framing::Legacy) => self.next_legacy_frame(),
+    }
+  }
+  // Call once the stream has closed; errors if a partial message is buffered
+  pub fn finish(&self) -> Result<(), FrameError> {
+    if self.buff.is_empty() {
+      return Ok(());
+    }
+    // A lone trailing 0xff is how legacy UDP peers terminate their datagrams
+    if self.framing == Some(Framing::Legacy) && self.buff == [0xff] {
+      return Ok(());
+    }
+    return Err(FrameError::Truncated(self.buff.len()));
+  }
+
+  fn next_length_frame(&mut self) -> Result<Option<WireData>, FrameError> {
+    if self.buff.len() >= 2 && self.buff[0..2] != FRAME_MAGIC {
+      return Err(FrameError::BadMagic);
+    }
+    if self.buff.len() < FRAME_HEADER_LEN {
+      return Ok(None);
+    }
+    if self.buff[2] != FRAME_VERSION {
+      return Err(FrameError::UnsupportedVersion(self.buff[2]));
+    }
+    let mut len_bytes = [0u8; 4];
+    len_bytes.copy_from_slice(&self.buff[3..FRAME_HEADER_LEN]);
+    let payload_len = u32::from_be_bytes(len_bytes) as usize;
+    if payload_len > MAX_FRAME_LEN {
+      return Err(FrameError::TooLarge(payload_len));
+    }
+    if self.buff.len() < FRAME_HEADER_LEN + payload_len {
+      return Ok(None);
+    }
+    let frame_end = FRAME_HEADER_LEN + payload_len;
+    let parsed = serde_cbor::from_slice::<WireData>(&self.buff[FRAME_HEADER_LEN..frame_end]);
+    // The frame is consumed whether or not it parsed, the next one is still readable
+    self.buff.drain(0..frame_end);
+    return parsed.map(Some).map_err(FrameError::Cbor);
+  }
+
+  fn next_legacy_frame(&mut self) -> Result<Option<WireData>, FrameError> {
+    // Legacy UDP clients send their terminating 0xff as a separate datagram
+    // and some servers did the same, so skip empty messages.
+    while self.buff.first() == Some(&0xff) {
+      self.buff.remove(0);
+    }
+    let mut last_err = None;
+    let mut i = self.scan_from;
+    while i < self.buff.len() {
+      if self.buff[i] == 0xff {
+        match serde_cbor::from_slice::<WireData>(&self.buff[0..i]) {
+          Ok(wire_data) => {
+            self.buff.drain(0..i+1);
+            self.scan_from = 0;
+            return Ok(Some(wire_data));
+          }
+          Err(e) => {
+            // Most likely this 0xff is inside the CBOR object, keep looking.
+            last_err = Some(e);
+          }
+        }
+      }
+      i += 1;
+    }
+    self.scan_from = self.buff.len();
+    if self.buff.len() > MAX_FRAME_LEN {
+      let len = self.buff.len();
+      self.buff.clear();
+      self.scan_from = 0;
+      return match last_err {
+        Some(e) => Err(FrameError::Cbor(e)),
+        None => Err(FrameError::TooLarge(len)),
+      };
+    }
+    return Ok(None);
+  }
+}
@@ -90,8 +90,9 @@ fn run_websocket_sync(config: &config::Config) {
             let args: Vec<String> = msg.split_whitespace()
                                        .map(|s| s.to_string().clone())
                                        .collect();
-            let query_rec = crate::args::parse_record(&args, config.verbosity_level, config);
-            let results = crate::client::query_sync(config, &query_rec);
+            // Plain words become a full-text query, ranked best first
+            let query_rec = crate::args::parse_query_record(&args, config.verbosity_level, config);
+            let results = crate::client::query_sync_with_options(config, &query_rec, &crate::wire::QueryOptions::default());
             
             let payload = serde_json::to_string(&BrowserCmd::replace(results)).unwrap_or(String::new());
             
@@ -19,6 +19,7 @@
 pub mod config;
 pub mod args;
 pub mod record;
+pub mod query;
 pub mod actions;
 
 pub mod ext;
@@ -28,12 +29,22 @@ pub mod ext_py;
 
 pub mod server;
 pub mod server_data_io;
+pub mod record_log;
+pub mod record_sqlite;
+pub mod record_index;
 
 pub mod client;
+pub mod connection;
+pub mod federation;
+pub mod replication;
+pub mod discovery;
+pub mod tls;
 pub mod http_client;
 pub mod data;
 pub mod wire;
+pub mod framing;
 pub mod signing;
+pub mod encryption;
 pub mod disp;
 pub mod scripting;
 
@@ -29,14 +29,17 @@ use dindex::args;
 //use dindex::record;
 //use dindex::actions;
 use dindex::actions::Action;
+use dindex::query::QUERY_TEXT_KEY;
 
 use dindex::http_client;
 use dindex::server;
 use dindex::client;
+use dindex::discovery;
 //use dindex::data;
 //use dindex::wire;
 use dindex::disp;
 use dindex::signing;
+use dindex::encryption;
 
 use dindex::web_scan;
 
@@ -49,27 +52,154 @@ fn main() {
   
   match args.action {
     Action::query => {
-      let res = client::query_sync(&conf, &args.get_record(&conf));
-      disp::print_results(&conf, &res);
+      let options = args.get_query_options();
+      let rec = args.get_record(&conf);
+      // Full-text results are merged by score like paged ones
+      if options.is_empty() && !rec.p.contains_key(QUERY_TEXT_KEY) {
+        let mut res = client::query_sync(&conf, &rec);
+        encryption::decrypt_results(&conf, &mut res);
+        disp::print_results(&conf, &res);
+      }
+      else {
+        // A cursor only means something to the server which made it
+        let mut conf = conf.clone();
+        if args.cursor.is_some() {
+          match args.get_cursor() {
+            Some((server_name, _cursor)) => conf.servers.retain(|server| server.name == server_name),
+            None => conf.servers.clear(),
+          }
+          if conf.servers.is_empty() {
+            println!("Error: --cursor was not printed by a query to one of the configured servers");
+            return;
+          }
+        }
+        let pages = client::query_pages_sync(&conf, &rec, &options);
+        let cursors: Vec<(String, String)> = pages.iter()
+          .filter_map(|(server, page)| page.next_cursor.clone().map(|c| (server.name.clone(), c)))
+          .collect();
+        let mut res = client::merge_pages(&options, pages);
+        encryption::decrypt_results(&conf, &mut res);
+        disp::print_results(&conf, &res);
+        for (server_name, cursor) in cursors {
+          println!("{}: more results with --cursor {}", server_name, args::cli_cursor(&server_name, &cursor));
+        }
+      }
+    }
+    Action::count => {
+      let group_by = args.get_query_options().group_by;
+      let counts = client::count_sync(&conf, &args.get_record(&conf), group_by.as_ref().map(|key| key.as_str()));
+      disp::print_counts(&conf, &counts);
     }
+    
     Action::publish => {
       let rec = args.get_record(&conf);
       if rec.is_empty() {
         println!("Error: refusing to publish empty record!");
       }
       else {
-        client::publish_sync(&conf, &rec);
+        for (server, res) in client::publish_sync(&conf, &rec) {
+          match res {
+            Ok(record_id) => {
+              println!("{}: stored as {}", server.name, record_id);
+            }
+            Err(e) => {
+              println!("{}: Error publishing: {}", server.name, e);
+            }
+          }
+        }
       }
     }
     
     Action::listen => {
       let rec = args.get_record(&conf);
-      client::listen_sync(&conf, &rec, |result| {
-        disp::print_results(&conf, &vec![result]);
+      client::listen_events_sync_with_options(&conf, &rec, &args.get_query_options(), |event| {
+        match event {
+          client::ListenEvent::Published(result) => {
+            let mut res = vec![result];
+            encryption::decrypt_results(&conf, &mut res);
+            disp::print_results(&conf, &res);
+          }
+          client::ListenEvent::Removed(result) => {
+            println!("removed = {:?}", result.p);
+          }
+          client::ListenEvent::EndOfReplay => {
+            println!("=== end of replay ===");
+          }
+        }
         return client::ListenAction::Continue;
       });
     }
     
+    Action::delete => {
+      let target = args.get_target(&conf);
+      if target.is_empty() {
+        println!("Error: refusing to delete without an --id or query!");
+      }
+      else {
+        for (server, res) in client::delete_sync(&conf, &target) {
+          match res {
+            Ok(num_removed) => {
+              println!("{}: removed {} records", server.name, num_removed);
+            }
+            Err(e) => {
+              println!("{}: Error deleting: {}", server.name, e);
+            }
+          }
+        }
+      }
+    }
+    
+    Action::update => {
+      let target = args.get_target(&conf);
+      let rec = args.get_record(&conf);
+      if target.is_empty() {
+        println!("Error: update needs an --id or --target-query!");
+      }
+      else if rec.is_empty() {
+        println!("Error: refusing to publish empty record!");
+      }
+      else {
+        for (server, res) in client::update_sync(&conf, &target, &rec) {
+          match res {
+            Ok(record_id) => {
+              println!("{}: stored as {}", server.name, record_id);
+            }
+            Err(e) => {
+              println!("{}: Error updating: {}", server.name, e);
+            }
+          }
+        }
+      }
+    }
+    
+    Action::hello => {
+      let ours = dindex::wire::Capabilities::ours();
+      for (server, caps) in client::hello_sync(&conf) {
+        println!("=== {} ===", server.name);
+        match caps {
+          Some(caps) => {
+            println!("server = {:?}", caps);
+            println!("negotiated = {:?}", ours.negotiate(&caps));
+          }
+          None => {
+            println!("No hello reply, server speaks protocol version 1 or is unreachable");
+          }
+        }
+      }
+    }
+    
+    Action::discover => {
+      let discovered = discovery::discover_sync(&conf);
+      if discovered.is_empty() {
+        println!("No servers answered");
+      }
+      for found in discovered {
+        println!("=== {} ===", found.server.name);
+        println!("server = {}://{}:{}", format!("{:?}", found.server.protocol).to_lowercase(), found.server.host, found.server.port);
+        println!("caps = {:?}", found.caps);
+      }
+    }
+    
     Action::run_server => {
       server::run_sync(&conf);
     }
@@ -105,8 +235,16 @@ fn main() {
     Action::gen_identity => {
       let dev_stderr = "/dev/stderr".to_string();
       let output_path = args.rec_args.get(0).unwrap_or(&dev_stderr);
-      signing::gen_identity(output_path);
-      println!("Wrote new identity to {}", output_path);
+      let key_type_name = args.key_type.clone().unwrap_or("rsa".to_string());
+      match signing::KeyType::from_str(&key_type_name) {
+        Some(key_type) => {
+          signing::gen_identity_of_type(output_path, key_type);
+          println!("Wrote new {} identity to {}", key_type.name(), output_path);
+        }
+        None => {
+          println!("Error: unknown key type {}, expected rsa or ed25519", key_type_name);
+        }
+      }
     }
     
     Action::print_identity => {
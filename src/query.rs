@@ -0,0 +1,1066 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; version 2 of the License only.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use serde;
+use serde_json;
+use regex::{Regex, RegexBuilder};
+use chrono::DateTime;
+use base64;
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::record::{Record, now_ms};
+use crate::config::Config;
+use crate::signing;
+use crate::record_index::MAX_TERM_CHARS;
+
+// A plain query record matches records sharing at least one of its keys
+// when every shared key's regex matches (see Record::matches). Anything
+// more involved is written as a QueryExpr and sent JSON encoded under
+// QUERY_EXPRESSION_KEY in the query record, so queries still fit in a
+// Record wherever one is expected (query, listen, delete/update targets).
+//
+// The JSON form is externally tagged:
+//   {"and": [expr, ...]}   {"or": [expr, ...]}   {"not": expr}
+//   {"match": {"key": "regex", ...}}   {"exists": ["key", ...]}   {"absent": ["key", ...]}
+//   {"lt": {"key": operand, ...}} and likewise "le", "gt" and "ge"
+//   {"between": {"key": [low operand, high operand], ...}}   (inclusive)
+//
+// Values are compared as numbers when the operand is a number, eg
+// {"gt": {"temperature": 30}}, and as RFC 3339 timestamps when it is a
+// string, eg {"ge": {"published": "now-1h"}} (see Operand).
+//
+// The compact text form (see QueryExpr::from_text) reads like:
+//   title ~ '.*rust.*' and (url ~ ^https:// or not exists(author))
+//   temperature > 30 and published between 2019-06-01T00:00:00Z and now
+
+// Reserved key, holds a JSON QueryExpr which records must satisfy
+// in addition to any other keys in the query record.
+pub const QUERY_EXPRESSION_KEY: &str = "QUERY:expression";
+
+// Reserved key, holds words for a full-text search. Matching records hold at
+// least one of the words in a text key (see is_text_key), in any case, and
+// are ranked by their Okapi BM25 score which servers add under TEXT_SCORE_KEY.
+pub const QUERY_TEXT_KEY: &str = "QUERY:text";
+pub const TEXT_SCORE_KEY: &str = "QUERY:score";
+
+// Reserved key, holds a MatchMode spec such as "glob,ignore-case" which
+// says how the patterns in the query record are read. Without it they
+// are regexes.
+pub const QUERY_MATCH_KEY: &str = "QUERY:match";
+// Every reserved query key above starts with this
+pub const QUERY_KEY_PREFIX: &str = "QUERY:";
+
+// BM25 parameters, the usual defaults
+const BM25_K1: f64 = 1.2;
+const BM25_B: f64 = 0.75;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryExpr {
+  // Every sub-expression holds (true when empty)
+  And(Vec<QueryExpr>),
+  // At least one sub-expression holds (false when empty)
+  Or(Vec<QueryExpr>),
+  Not(Box<QueryExpr>),
+  // Every key is present and its pattern (a regex unless the query has a
+  // QUERY_MATCH_KEY) matches the value
+  Match(BTreeMap<String, String>),
+  // Every key is present
+  Exists(Vec<String>),
+  // None of the keys are present
+  Absent(Vec<String>),
+  // Every key is present and its value is less than (or equal to) the operand
+  Lt(BTreeMap<String, Operand>),
+  Le(BTreeMap<String, Operand>),
+  // Every key is present and its value is greater than (or equal to) the operand
+  Gt(BTreeMap<String, Operand>),
+  Ge(BTreeMap<String, Operand>),
+  // Every key is present and low <= value <= high
+  Between(BTreeMap<String, (Operand, Operand)>),
+}
+
+// The right hand side of a comparison
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Operand {
+  // Record values are parsed as numbers
+  Number(f64),
+  // An RFC 3339 timestamp, or "now" optionally followed by + or - and a
+  // duration with a unit of s, m, h or d (eg now-1h, now+1.5d).
+  // Record values are parsed as RFC 3339 timestamps.
+  Time(String),
+}
+
+impl QueryExpr {
+  pub fn from_json(json: &str) -> Result<QueryExpr, String> {
+    return serde_json::from_str(json).map_err(|e| format!("{}", e));
+  }
+  pub fn to_json(&self) -> String {
+    return serde_json::to_string(self).unwrap_or(String::new());
+  }
+  // Parses the compact text syntax:
+  //   key ~ regex        key is present and regex matches its value
+  //   exists(key)        key is present
+  //   absent(key)        key is not present
+  //   a and b, a && b    both hold
+  //   a or b, a || b     either holds
+  //   not a, !a          a does not hold
+  //   ( a )              grouping, and binds tighter than or
+  //   key < x, key <= x, key > x, key >= x, key between x and y
+  //                      comparisons, x and y are numbers or times (see Operand)
+  // Keys, regexes and operands may be quoted with ' or " (a backslash before
+  // the quote character escapes it). Quote them if they contain whitespace,
+  // parentheses, ~, < or >, or are one of the words and, or and not.
+  pub fn from_text(text: &str) -> Result<QueryExpr, String> {
+    let tokens = tokenize(text)?;
+    let mut parser = Parser { tokens: tokens, pos: 0 };
+    let expr = parser.parse_or()?;
+    if let Some(token) = parser.tokens.get(parser.pos) {
+      return Err(format!("Unexpected {:?} after the end of the query", token));
+    }
+    return Ok(expr);
+  }
+  // A query record holding only this expression
+  pub fn to_record(&self) -> Record {
+    let mut rec = Record::empty();
+    rec.p.insert(QUERY_EXPRESSION_KEY.to_string(), self.to_json());
+    return rec;
+  }
+}
+
+// A QueryExpr with its regexes compiled
+#[derive(Debug, Clone)]
+pub enum CompiledExpr {
+  And(Vec<CompiledExpr>),
+  Or(Vec<CompiledExpr>),
+  Not(Box<CompiledExpr>),
+  Match(Vec<(String, Matcher)>),
+  Exists(Vec<String>),
+  Absent(Vec<String>),
+  // Every range holds
+  Range(Vec<Range>),
+}
+
+// A comparison with its operands resolved
+#[derive(Debug, Clone)]
+pub struct Range {
+  pub key: String,
+  // Values are RFC 3339 timestamps compared in milliseconds, otherwise numbers
+  pub is_time: bool,
+  // Bounds and whether they are inclusive
+  pub min: Option<(Bound, bool)>,
+  pub max: Option<(Bound, bool)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Bound {
+  Number(f64),
+  // Milliseconds since the unix epoch
+  Time(i64),
+  // Milliseconds from the time of the match, so listeners keep up with the clock
+  SinceNow(i64),
+}
+
+impl CompiledExpr {
+  pub fn new(expr: &QueryExpr) -> Result<CompiledExpr, String> {
+    return CompiledExpr::compile(expr, &MatchMode::default(), &RegexLimits::default());
+  }
+  pub fn compile(expr: &QueryExpr, mode: &MatchMode, limits: &RegexLimits) -> Result<CompiledExpr, String> {
+    let compile_all = |items: &Vec<QueryExpr>| -> Result<Vec<CompiledExpr>, String> {
+      items.iter().map(|item| CompiledExpr::compile(item, mode, limits)).collect()
+    };
+    return Ok(match expr {
+      QueryExpr::And(items) => CompiledExpr::And(compile_all(items)?),
+      QueryExpr::Or(items) => CompiledExpr::Or(compile_all(items)?),
+      QueryExpr::Not(item) => CompiledExpr::Not(Box::new(CompiledExpr::compile(item, mode, limits)?)),
+      QueryExpr::Match(pairs) => {
+        let mut compiled = vec![];
+        for (key, pattern) in pairs {
+          compiled.push((key.clone(), mode.matcher(key, pattern, limits)?));
+        }
+        CompiledExpr::Match(compiled)
+      }
+      QueryExpr::Exists(keys) => CompiledExpr::Exists(keys.clone()),
+      QueryExpr::Absent(keys) => CompiledExpr::Absent(keys.clone()),
+      QueryExpr::Lt(pairs) => Range::compile(pairs, None, Some(false))?,
+      QueryExpr::Le(pairs) => Range::compile(pairs, None, Some(true))?,
+      QueryExpr::Gt(pairs) => Range::compile(pairs, Some(false), None)?,
+      QueryExpr::Ge(pairs) => Range::compile(pairs, Some(true), None)?,
+      QueryExpr::Between(pairs) => {
+        let mut ranges = vec![];
+        for (key, (low, high)) in pairs {
+          let (low_is_time, low) = low.to_bound()?;
+          let (high_is_time, high) = high.to_bound()?;
+          if low_is_time != high_is_time {
+            return Err(format!("Cannot compare {} between a number and a time", key));
+          }
+          ranges.push(Range {
+            key: key.clone(),
+            is_time: low_is_time,
+            min: Some((low, true)),
+            max: Some((high, true)),
+          });
+        }
+        CompiledExpr::Range(ranges)
+      }
+    });
+  }
+  pub fn matches(&self, rec: &Record) -> bool {
+    match self {
+      CompiledExpr::And(items) => items.iter().all(|e| e.matches(rec)),
+      CompiledExpr::Or(items) => items.iter().any(|e| e.matches(rec)),
+      CompiledExpr::Not(item) => !item.matches(rec),
+      CompiledExpr::Match(pairs) => pairs.iter().all(|(key, re)| {
+        rec.p.get(key).map(|val| re.is_match(val)).unwrap_or(false)
+      }),
+      CompiledExpr::Exists(keys) => keys.iter().all(|key| rec.p.contains_key(key)),
+      CompiledExpr::Absent(keys) => keys.iter().all(|key| !rec.p.contains_key(key)),
+      CompiledExpr::Range(ranges) => ranges.iter().all(|range| range.matches(rec)),
+    }
+  }
+}
+
+impl Range {
+  // One-sided comparisons, min_inclusive or max_inclusive is set for the bounded side
+  fn compile(pairs: &BTreeMap<String, Operand>, min_inclusive: Option<bool>, max_inclusive: Option<bool>) -> Result<CompiledExpr, String> {
+    let mut ranges = vec![];
+    for (key, operand) in pairs {
+      let (is_time, bound) = operand.to_bound()?;
+      ranges.push(Range {
+        key: key.clone(),
+        is_time: is_time,
+        min: min_inclusive.map(|inclusive| (bound, inclusive)),
+        max: max_inclusive.map(|inclusive| (bound, inclusive)),
+      });
+    }
+    return Ok(CompiledExpr::Range(ranges));
+  }
+  pub fn matches(&self, rec: &Record) -> bool {
+    let val = match rec.p.get(&self.key) {
+      Some(val) => val,
+      None => return false,
+    };
+    // Values which can't be read as the operand's type never match
+    let val = if self.is_time {
+      match parse_time_ms(val) {
+        Some(ms) => ms as f64,
+        None => return false,
+      }
+    }
+    else {
+      match val.trim().parse::<f64>() {
+        Ok(n) if !n.is_nan() => n,
+        _ => return false,
+      }
+    };
+    if let Some((bound, inclusive)) = self.min {
+      let min = bound.resolve();
+      if val < min || (!inclusive && val == min) {
+        return false;
+      }
+    }
+    if let Some((bound, inclusive)) = self.max {
+      let max = bound.resolve();
+      if val > max || (!inclusive && val == max) {
+        return false;
+      }
+    }
+    return true;
+  }
+}
+
+impl Bound {
+  fn resolve(&self) -> f64 {
+    match self {
+      Bound::Number(n) => *n,
+      Bound::Time(ms) => *ms as f64,
+      Bound::SinceNow(offset_ms) => (now_ms() as i64).saturating_add(*offset_ms) as f64,
+    }
+  }
+}
+
+impl Operand {
+  // Returns whether this is a time along with its bound
+  fn to_bound(&self) -> Result<(bool, Bound), String> {
+    match self {
+      Operand::Number(n) if n.is_nan() => Err("NaN cannot be compared".to_string()),
+      Operand::Number(n) => Ok((false, Bound::Number(*n))),
+      Operand::Time(s) => {
+        if let Some(offset_ms) = parse_since_now_ms(s) {
+          return Ok((true, Bound::SinceNow(offset_ms)));
+        }
+        match parse_time_ms(s) {
+          Some(ms) => Ok((true, Bound::Time(ms))),
+          None => Err(format!("{:?} is not an RFC 3339 time or now[+-]duration", s)),
+        }
+      }
+    }
+  }
+}
+
+// Milliseconds since the unix epoch of an RFC 3339 timestamp
+pub fn parse_time_ms(s: &str) -> Option<i64> {
+  return DateTime::parse_from_rfc3339(s.trim()).ok().map(|t| t.timestamp_millis());
+}
+
+// Longest offset from now a query may name, about a thousand years
+const MAX_SINCE_NOW_MS: f64 = 1000.0 * 365.25 * 24.0 * 60.0 * 60.0 * 1000.0;
+
+// Parses now, now-1h, now+30m etc into milliseconds from now
+fn parse_since_now_ms(s: &str) -> Option<i64> {
+  let s = s.trim();
+  if !s.starts_with("now") {
+    return None;
+  }
+  let rest = &s["now".len()..];
+  if rest.is_empty() {
+    return Some(0);
+  }
+  let sign = match rest.chars().next()? {
+    '+' => 1.0,
+    '-' => -1.0,
+    _ => return None,
+  };
+  let rest = &rest[1..];
+  let unit_ms = match rest.chars().last()? {
+    's' => 1000.0,
+    'm' => 60.0 * 1000.0,
+    'h' => 60.0 * 60.0 * 1000.0,
+    'd' => 24.0 * 60.0 * 60.0 * 1000.0,
+    _ => return None,
+  };
+  let amount = rest[..rest.len()-1].parse::<f64>().ok()?;
+  if !amount.is_finite() || amount < 0.0 || amount * unit_ms > MAX_SINCE_NOW_MS {
+    return None;
+  }
+  return Some((sign * amount * unit_ms) as i64);
+}
+
+// A query record ready to be run against records, see Record::create_query
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+  // Patterns for the query record's plain keys
+  pub keys: HashMap<String, Matcher>,
+  // From QUERY_EXPRESSION_KEY, if the query record has one
+  pub expr: Option<CompiledExpr>,
+  // The distinct terms of QUERY_TEXT_KEY, if the query record has one
+  pub text: Option<Vec<String>>,
+}
+
+impl Query {
+  // A query which cannot be compiled matches nothing rather than everything
+  pub fn new(query: &Record) -> Query {
+    return Query::compile(query, &RegexLimits::default()).unwrap_or(Query {
+      keys: HashMap::new(),
+      expr: Some(CompiledExpr::Or(vec![])),
+      text: None,
+    });
+  }
+  // Err describes the first regex or expression in query which cannot be
+  // compiled within limits. Servers send it back to the client.
+  pub fn compile(query: &Record, limits: &RegexLimits) -> Result<Query, String> {
+    let mut plain = query.clone();
+    let mode = match plain.p.remove(QUERY_MATCH_KEY) {
+      Some(spec) => MatchMode::parse(&spec)?,
+      None => MatchMode::default(),
+    };
+    let expr = match plain.p.remove(QUERY_EXPRESSION_KEY) {
+      Some(json) => {
+        let expr = QueryExpr::from_json(&json)
+          .map_err(|e| format!("Error: {} is not a query expression: {}", QUERY_EXPRESSION_KEY, e))?;
+        Some(CompiledExpr::compile(&expr, &mode, limits)?)
+      }
+      None => None,
+    };
+    let text = plain.p.remove(QUERY_TEXT_KEY).map(|words| {
+      let mut terms: Vec<String> = vec![];
+      for term in text_terms(&words) {
+        if !terms.contains(&term) {
+          terms.push(term);
+        }
+      }
+      terms
+    });
+    let mut keys = HashMap::new();
+    for (key, pattern) in &plain.p {
+      match mode.matcher(key, pattern, limits) {
+        Ok(matcher) => {
+          keys.insert(key.clone(), matcher);
+        }
+        // Signed queries carry base64 signature keys which were never meant
+        // as patterns, they are only matched when they happen to compile.
+        Err(_) if signing::key_is_used_in_signing(key) => { }
+        Err(e) => return Err(e),
+      }
+    }
+    return Ok(Query {
+      keys: keys,
+      expr: expr,
+      text: text,
+    });
+  }
+  pub fn matches(&self, rec: &Record) -> bool {
+    if self.keys.is_empty() && self.expr.is_none() && self.text.is_none() {
+      return false; // Nothing in common with any record
+    }
+    if !self.keys.is_empty() && !rec.matches(&self.keys) {
+      return false;
+    }
+    if let Some(terms) = &self.text {
+      let rec_terms = record_text_terms(rec);
+      if !terms.iter().any(|term| rec_terms.contains(term)) {
+        return false;
+      }
+    }
+    match &self.expr {
+      Some(expr) => expr.matches(rec),
+      None => true,
+    }
+  }
+  // rec's Okapi BM25 score for this query's text, 0 without text.
+  // Only comparable between scores made with the same stats.
+  pub fn text_score(&self, rec: &Record, stats: &TextStats) -> f64 {
+    let terms = match &self.text {
+      Some(terms) => terms,
+      None => return 0.0,
+    };
+    let rec_terms = record_text_terms(rec);
+    let num_docs = stats.docs as f64;
+    let avg_len = if stats.docs > 0 { stats.tokens as f64 / num_docs } else { 0.0 };
+    let len_norm = if avg_len > 0.0 { rec_terms.len() as f64 / avg_len } else { 1.0 };
+    let mut score = 0.0;
+    for (i, term) in terms.iter().enumerate() {
+      let tf = rec_terms.iter().filter(|t| *t == term).count() as f64;
+      if tf == 0.0 {
+        continue;
+      }
+      let df = (stats.doc_freqs.get(i).cloned().unwrap_or(0) as f64).min(num_docs);
+      let idf = (1.0 + (num_docs - df + 0.5) / (df + 0.5)).ln();
+      score += idf * tf * (BM25_K1 + 1.0) / (tf + BM25_K1 * (1.0 - BM25_B + BM25_B * len_norm));
+    }
+    return score;
+  }
+}
+
+// What BM25 needs to know about the records a query runs against
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TextStats {
+  // Records searched
+  pub docs: usize,
+  // Terms in the text keys of all of them
+  pub tokens: u64,
+  // For each of Query::text, the records holding that term
+  pub doc_freqs: Vec<usize>,
+}
+
+impl TextStats {
+  // Sums the stats of several pools
+  pub fn merge(&mut self, other: &TextStats) {
+    self.docs += other.docs;
+    self.tokens += other.tokens;
+    if self.doc_freqs.len() < other.doc_freqs.len() {
+      self.doc_freqs.resize(other.doc_freqs.len(), 0);
+    }
+    for (i, df) in other.doc_freqs.iter().enumerate() {
+      self.doc_freqs[i] += df;
+    }
+  }
+}
+
+// Keys searched by full-text queries, everything but reserved keys such
+// as signatures and expiry times
+pub fn is_text_key(key: &str) -> bool {
+  return !key.starts_with("SIGNING:") && !key.starts_with("TTL:") && !key.starts_with("QUERY:");
+}
+
+// Lowercased runs of alphanumeric characters. Runs longer than
+// record_index::MAX_TERM_CHARS are not terms.
+pub fn text_terms<'a>(text: &'a str) -> impl Iterator<Item=String> + 'a {
+  return text.split(|c: char| !c.is_alphanumeric())
+    .filter(|t| !t.is_empty() && t.chars().count() <= MAX_TERM_CHARS)
+    .map(|t| t.to_lowercase());
+}
+
+// Every term in rec's text keys, repeats included
+pub fn record_text_terms(rec: &Record) -> Vec<String> {
+  let mut terms = vec![];
+  for (key, val) in &rec.p {
+    if is_text_key(key) {
+      terms.extend(text_terms(val));
+    }
+  }
+  return terms;
+}
+
+// How the patterns of a query are read. Every kind but fuzzy is turned
+// into a regex, so the index can still narrow searches by its literals.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MatchMode {
+  pub kind: MatchKind,
+  // Upper and lower case letters are the same
+  pub ignore_case: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchKind {
+  // Found anywhere in the value, see the regex crate for the syntax
+  Regex,
+  // Found anywhere in the value, character for character
+  Literal,
+  // The whole value, where * is any run of characters, ? any one
+  // character and [abc] or [!abc] a character class
+  Glob,
+  // The whole value, or as many consecutive words of it as the pattern has,
+  // is within this many single character edits of the pattern. None picks
+  // by the pattern's length: 0 up to 2 characters, 1 up to 5 and 2 beyond.
+  Fuzzy(Option<usize>),
+}
+
+// Caps on fuzzy patterns, which come from any network client. Matching
+// costs the pattern's length times each value's length, for every window
+// of num_words words in the value.
+pub const FUZZY_MAX_EDITS: usize = 4;
+pub const FUZZY_MAX_PATTERN_CHARS: usize = 64;
+pub const FUZZY_MAX_PATTERN_WORDS: usize = 8;
+
+impl Default for MatchMode {
+  fn default() -> MatchMode {
+    MatchMode {
+      kind: MatchKind::Regex,
+      ignore_case: false,
+    }
+  }
+}
+
+impl MatchMode {
+  // Comma separated words: one of regex, literal, glob, fuzzy or fuzzy=N
+  // (N edits) optionally with ignore-case, eg "glob,ignore-case"
+  pub fn parse(spec: &str) -> Result<MatchMode, String> {
+    let mut mode = MatchMode::default();
+    for word in spec.split(',').map(|w| w.trim().to_lowercase()) {
+      match word.as_str() {
+        "" => { }
+        "regex" => mode.kind = MatchKind::Regex,
+        "literal" => mode.kind = MatchKind::Literal,
+        "glob" => mode.kind = MatchKind::Glob,
+        "fuzzy" => mode.kind = MatchKind::Fuzzy(None),
+        "ignore-case" | "case-insensitive" => mode.ignore_case = true,
+        _ if word.starts_with("fuzzy=") => {
+          let edits = word["fuzzy=".len()..].parse::<usize>()
+            .map_err(|_| format!("Error: {} needs a number of edits", word))?;
+          if edits > FUZZY_MAX_EDITS {
+            return Err(format!("Error: {} allows more than {} edits", word, FUZZY_MAX_EDITS));
+          }
+          mode.kind = MatchKind::Fuzzy(Some(edits));
+        }
+        _ => return Err(format!("Error: unknown match mode {:?}", word)),
+      }
+    }
+    return Ok(mode);
+  }
+  // The spec parse reads back as this mode
+  pub fn to_spec(&self) -> String {
+    let mut spec = match self.kind {
+      MatchKind::Regex => "regex".to_string(),
+      MatchKind::Literal => "literal".to_string(),
+      MatchKind::Glob => "glob".to_string(),
+      MatchKind::Fuzzy(None) => "fuzzy".to_string(),
+      MatchKind::Fuzzy(Some(edits)) => format!("fuzzy={}", edits),
+    };
+    if self.ignore_case {
+      spec.push_str(",ignore-case");
+    }
+    return spec;
+  }
+  pub fn is_default(&self) -> bool {
+    *self == MatchMode::default()
+  }
+  // key is only used to say which pattern was rejected
+  pub fn matcher(&self, key: &str, pattern: &str, limits: &RegexLimits) -> Result<Matcher, String> {
+    let regex = match self.kind {
+      MatchKind::Regex => pattern.to_string(),
+      MatchKind::Literal => regex::escape(pattern),
+      MatchKind::Glob => glob_to_regex(pattern),
+      MatchKind::Fuzzy(edits) => {
+        let pattern = if self.ignore_case { pattern.to_lowercase() } else { pattern.to_string() };
+        let num_chars = pattern.chars().count();
+        let num_words = pattern.split_whitespace().count();
+        if num_chars > FUZZY_MAX_PATTERN_CHARS || num_words > FUZZY_MAX_PATTERN_WORDS {
+          return Err(format!("Error: the fuzzy pattern for {} is over {} characters or {} words", key, FUZZY_MAX_PATTERN_CHARS, FUZZY_MAX_PATTERN_WORDS));
+        }
+        return Ok(Matcher::Fuzzy {
+          num_words: num_words,
+          max_edits: edits.unwrap_or(if num_chars <= 2 { 0 } else if num_chars <= 5 { 1 } else { 2 }),
+          ignore_case: self.ignore_case,
+          pattern: pattern,
+        });
+      }
+    };
+    // Written into the pattern rather than set on the builder so the
+    // index sees it in Regex::as_str
+    let regex = if self.ignore_case { format!("(?i){}", regex) } else { regex };
+    return Ok(Matcher::Regex(limits.compile(key, &regex)?));
+  }
+}
+
+// Anything which decides whether a record value matches, see Record::matches
+pub trait ValueMatcher {
+  fn is_match(&self, val: &str) -> bool;
+}
+
+impl ValueMatcher for Regex {
+  fn is_match(&self, val: &str) -> bool {
+    Regex::is_match(self, val)
+  }
+}
+
+// A compiled pattern in some MatchMode
+#[derive(Debug, Clone)]
+pub enum Matcher {
+  Regex(Regex),
+  Fuzzy {
+    // Lowercased when ignore_case is set
+    pattern: String,
+    num_words: usize,
+    max_edits: usize,
+    ignore_case: bool,
+  },
+}
+
+impl Matcher {
+  // The regex this matcher runs, which the index can look for literals in
+  pub fn as_regex(&self) -> Option<&Regex> {
+    match self {
+      Matcher::Regex(re) => Some(re),
+      Matcher::Fuzzy { .. } => None,
+    }
+  }
+}
+
+impl ValueMatcher for Matcher {
+  fn is_match(&self, val: &str) -> bool {
+    match self {
+      Matcher::Regex(re) => re.is_match(val),
+      Matcher::Fuzzy { pattern, num_words, max_edits, ignore_case } => {
+        let val = if *ignore_case { val.to_lowercase() } else { val.to_string() };
+        if edit_distance(pattern, &val, *max_edits).is_some() {
+          return true;
+        }
+        let words: Vec<&str> = val.split_whitespace().collect();
+        if *num_words == 0 || words.len() <= *num_words {
+          return false; // Already compared the whole value
+        }
+        return words.windows(*num_words).any(|w| edit_distance(pattern, &w.join(" "), *max_edits).is_some());
+      }
+    }
+  }
+}
+
+// The Levenshtein distance from a to b, or None if it is over max
+pub fn edit_distance(a: &str, b: &str, max: usize) -> Option<usize> {
+  let a: Vec<char> = a.chars().collect();
+  let b: Vec<char> = b.chars().collect();
+  if (a.len() as isize - b.len() as isize).abs() as usize > max {
+    return None;
+  }
+  let mut prev: Vec<usize> = (0..b.len() + 1).collect();
+  let mut row = vec![0; b.len() + 1];
+  for i in 1..a.len() + 1 {
+    row[0] = i;
+    let mut row_min = row[0];
+    for j in 1..b.len() + 1 {
+      let substitution = prev[j - 1] + if a[i - 1] == b[j - 1] { 0 } else { 1 };
+      row[j] = substitution.min(prev[j] + 1).min(row[j - 1] + 1);
+      row_min = row_min.min(row[j]);
+    }
+    if row_min > max {
+      return None; // Every path through the rest is longer still
+    }
+    std::mem::swap(&mut prev, &mut row);
+  }
+  let distance = prev[b.len()];
+  return if distance <= max { Some(distance) } else { None };
+}
+
+// An anchored regex matching the same values as glob
+fn glob_to_regex(glob: &str) -> String {
+  let mut regex = String::from("^");
+  let mut chars = glob.chars().peekable();
+  while let Some(c) = chars.next() {
+    match c {
+      '*' => regex.push_str(".*"),
+      '?' => regex.push('.'),
+      '[' => {
+        // Copy the class through when it is closed, otherwise [ is literal
+        let class: String = chars.clone().take_while(|c| *c != ']').collect();
+        let closed = chars.clone().nth(class.chars().count()) == Some(']');
+        if closed && !class.is_empty() && class != "!" {
+          regex.push('[');
+          let mut class_chars = class.chars();
+          if class.starts_with('!') {
+            regex.push('^');
+            class_chars.next();
+          }
+          for class_char in class_chars {
+            if class_char == '\\' || class_char == '[' || class_char == '^' || class_char == '&' || class_char == '~' {
+              regex.push('\\');
+            }
+            regex.push(class_char);
+          }
+          regex.push(']');
+          for _ in 0..class.chars().count() + 1 {
+            chars.next();
+          }
+        }
+        else {
+          regex.push_str("\\[");
+        }
+      }
+      c => regex.push_str(&regex::escape(&c.to_string())),
+    }
+  }
+  regex.push('$');
+  return regex;
+}
+
+// Caps on the regexes in a query, which come from any network client.
+// See regex::RegexBuilder::size_limit and dfa_size_limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegexLimits {
+  // Bytes a compiled regex may use
+  pub size_limit: usize,
+  // Bytes of lazy DFA cache each regex may use while matching
+  pub dfa_size_limit: usize,
+}
+
+impl Default for RegexLimits {
+  // The regex crate's own limits
+  fn default() -> RegexLimits {
+    RegexLimits {
+      size_limit: 10 * (1 << 20),
+      dfa_size_limit: 2 * (1 << 20),
+    }
+  }
+}
+
+impl RegexLimits {
+  pub fn from_config(config: &Config) -> RegexLimits {
+    RegexLimits {
+      size_limit: config.server_regex_size_limit,
+      dfa_size_limit: config.server_regex_dfa_size_limit,
+    }
+  }
+  // key is only used to say which pattern was rejected
+  pub fn compile(&self, key: &str, pattern: &str) -> Result<Regex, String> {
+    return RegexBuilder::new(pattern)
+      .size_limit(self.size_limit)
+      .dfa_size_limit(self.dfa_size_limit)
+      .build()
+      .map_err(|e| format!("Error: the regex for {} was rejected: {}", key, e));
+  }
+}
+
+// Compiled queries by the query record they came from, so the identical
+// queries listeners and UIs repeat are only compiled once. Rejected queries
+// are remembered too. When full the least recently used entry is forgotten.
+pub struct QueryCache {
+  capacity: usize,
+  limits: RegexLimits,
+  // Incremented by every lookup, orders entries by last use
+  clock: u64,
+  entries: HashMap<Vec<(String, String)>, (Result<Query, String>, u64)>,
+}
+
+impl QueryCache {
+  // A capacity of 0 compiles every query
+  pub fn new(capacity: usize, limits: RegexLimits) -> QueryCache {
+    QueryCache {
+      capacity: capacity,
+      limits: limits,
+      clock: 0,
+      entries: HashMap::new(),
+    }
+  }
+  pub fn compile(&mut self, query: &Record) -> Result<Query, String> {
+    self.clock += 1;
+    let mut cache_key: Vec<(String, String)> = query.p.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+    cache_key.sort();
+    if let Some(entry) = self.entries.get_mut(&cache_key) {
+      entry.1 = self.clock;
+      return entry.0.clone();
+    }
+    let compiled = Query::compile(query, &self.limits);
+    if self.capacity > 0 {
+      if self.entries.len() >= self.capacity {
+        let oldest = self.entries.iter().min_by_key(|(_, entry)| entry.1).map(|(k, _)| k.clone());
+        if let Some(oldest) = oldest {
+          self.entries.remove(&oldest);
+        }
+      }
+      self.entries.insert(cache_key, (compiled.clone(), self.clock));
+    }
+    return compiled;
+  }
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+}
+
+// Orders values of a QueryOptions::sort_by key. Numbers compare numerically
+// and before other values, which compare as strings. Records without the
+// key (None) come last in either direction.
+pub fn cmp_sort_values(a: Option<&str>, b: Option<&str>, descending: bool) -> Ordering {
+  let (a, b) = match (a, b) {
+    (None, None) => return Ordering::Equal,
+    (None, Some(_)) => return Ordering::Greater,
+    (Some(_), None) => return Ordering::Less,
+    (Some(a), Some(b)) => (a, b),
+  };
+  let number = |s: &str| s.trim().parse::<f64>().ok().filter(|n| !n.is_nan());
+  let ordering = match (number(a), number(b)) {
+    (Some(x), Some(y)) => x.partial_cmp(&y).unwrap_or(Ordering::Equal),
+    (Some(_), None) => Ordering::Less,
+    (None, Some(_)) => Ordering::Greater,
+    (None, None) => a.cmp(b),
+  };
+  if descending {
+    return ordering.reverse();
+  }
+  return ordering;
+}
+
+// Where a page of results ended, handed to clients as an opaque string.
+// seq is the server's StoredRecord::seq, so cursors only make sense to
+// the server which made them, which checks instance and the ordering.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Cursor {
+  // The Data::instance which made the cursor
+  pub instance: String,
+  // The ordering of the pages, a cursor means nothing in any other
+  pub sort_by: Option<String>,
+  pub descending: bool,
+  // The sort_by value of the last result
+  pub value: Option<String>,
+  pub seq: u64,
+}
+
+impl Cursor {
+  pub fn encode(&self) -> String {
+    return base64::encode_config(&serde_json::to_vec(self).unwrap_or(vec![]), base64::URL_SAFE_NO_PAD);
+  }
+  pub fn decode(s: &str) -> Option<Cursor> {
+    let bytes = base64::decode_config(s, base64::URL_SAFE_NO_PAD).ok()?;
+    return serde_json::from_slice(&bytes).ok();
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+  LParen,
+  RParen,
+  Tilde,
+  Lt,
+  Le,
+  Gt,
+  Ge,
+  And,
+  Or,
+  Not,
+  Word(String),
+  // Quoted words are never keywords
+  Quoted(String),
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+  let mut tokens = vec![];
+  let mut chars = text.chars().peekable();
+  while let Some(&c) = chars.peek() {
+    if c.is_whitespace() {
+      chars.next();
+      continue;
+    }
+    match c {
+      '(' => { chars.next(); tokens.push(Token::LParen); }
+      ')' => { chars.next(); tokens.push(Token::RParen); }
+      '~' => { chars.next(); tokens.push(Token::Tilde); }
+      '!' => { chars.next(); tokens.push(Token::Not); }
+      '<' | '>' => {
+        chars.next();
+        let or_equal = chars.peek() == Some(&'=');
+        if or_equal {
+          chars.next();
+        }
+        tokens.push(match (c, or_equal) {
+          ('<', false) => Token::Lt,
+          ('<', true) => Token::Le,
+          ('>', false) => Token::Gt,
+          _ => Token::Ge,
+        });
+      }
+      '\'' | '"' => {
+        chars.next();
+        let mut word = String::new();
+        loop {
+          match chars.next() {
+            Some('\\') if chars.peek() == Some(&c) => {
+              word.push(c);
+              chars.next();
+            }
+            Some(q) if q == c => break,
+            Some(other) => word.push(other),
+            None => return Err(format!("Unterminated {} quoted string", c)),
+          }
+        }
+        tokens.push(Token::Quoted(word));
+      }
+      _ => {
+        let mut word = String::new();
+        while let Some(&c) = chars.peek() {
+          if c.is_whitespace() || "()~<>'\"".contains(c) {
+            break;
+          }
+          word.push(c);
+          chars.next();
+        }
+        tokens.push(match word.as_str() {
+          "and" | "&&" => Token::And,
+          "or" | "||" => Token::Or,
+          "not" => Token::Not,
+          _ => Token::Word(word),
+        });
+      }
+    }
+  }
+  return Ok(tokens);
+}
+
+struct Parser {
+  tokens: Vec<Token>,
+  pos: usize,
+}
+
+impl Parser {
+  fn peek(&self) -> Option<&Token> {
+    self.tokens.get(self.pos)
+  }
+  fn next(&mut self) -> Option<Token> {
+    let token = self.tokens.get(self.pos).cloned();
+    self.pos += 1;
+    return token;
+  }
+  fn expect(&mut self, expected: Token) -> Result<(), String> {
+    match self.next() {
+      Some(ref token) if *token == expected => Ok(()),
+      Some(token) => Err(format!("Expected {:?} but found {:?}", expected, token)),
+      None => Err(format!("Expected {:?} but the query ended", expected)),
+    }
+  }
+  fn expect_string(&mut self) -> Result<String, String> {
+    match self.next() {
+      Some(Token::Word(s)) | Some(Token::Quoted(s)) => Ok(s),
+      Some(token) => Err(format!("Expected a key or regex but found {:?}", token)),
+      None => Err("Expected a key or regex but the query ended".to_string()),
+    }
+  }
+  // Numbers become Operand::Number, anything else is taken as a time
+  fn expect_operand(&mut self) -> Result<Operand, String> {
+    let s = self.expect_string()?;
+    match s.trim().parse::<f64>() {
+      Ok(n) if n.is_finite() => Ok(Operand::Number(n)),
+      _ => Ok(Operand::Time(s)),
+    }
+  }
+  fn parse_or(&mut self) -> Result<QueryExpr, String> {
+    let mut items = vec![self.parse_and()?];
+    while self.peek() == Some(&Token::Or) {
+      self.next();
+      items.push(self.parse_and()?);
+    }
+    if items.len() == 1 {
+      return Ok(items.remove(0));
+    }
+    return Ok(QueryExpr::Or(items));
+  }
+  fn parse_and(&mut self) -> Result<QueryExpr, String> {
+    let mut items = vec![self.parse_unary()?];
+    while self.peek() == Some(&Token::And) {
+      self.next();
+      items.push(self.parse_unary()?);
+    }
+    if items.len() == 1 {
+      return Ok(items.remove(0));
+    }
+    return Ok(QueryExpr::And(items));
+  }
+  fn parse_unary(&mut self) -> Result<QueryExpr, String> {
+    match self.next() {
+      Some(Token::Not) => {
+        return Ok(QueryExpr::Not(Box::new(self.parse_unary()?)));
+      }
+      Some(Token::LParen) => {
+        let expr = self.parse_or()?;
+        self.expect(Token::RParen)?;
+        return Ok(expr);
+      }
+      Some(Token::Word(ref w)) if (w == "exists" || w == "absent") && self.peek() == Some(&Token::LParen) => {
+        self.next();
+        let key = self.expect_string()?;
+        self.expect(Token::RParen)?;
+        if w == "exists" {
+          return Ok(QueryExpr::Exists(vec![key]));
+        }
+        return Ok(QueryExpr::Absent(vec![key]));
+      }
+      Some(Token::Word(key)) | Some(Token::Quoted(key)) => {
+        let op = self.next();
+        match op {
+          Some(Token::Tilde) => {
+            let pattern = self.expect_string()?;
+            let mut pairs = BTreeMap::new();
+            pairs.insert(key, pattern);
+            return Ok(QueryExpr::Match(pairs));
+          }
+          Some(Token::Lt) | Some(Token::Le) | Some(Token::Gt) | Some(Token::Ge) => {
+            let mut pairs = BTreeMap::new();
+            pairs.insert(key, self.expect_operand()?);
+            return Ok(match op {
+              Some(Token::Lt) => QueryExpr::Lt(pairs),
+              Some(Token::Le) => QueryExpr::Le(pairs),
+              Some(Token::Gt) => QueryExpr::Gt(pairs),
+              _ => QueryExpr::Ge(pairs),
+            });
+          }
+          Some(Token::Word(ref w)) if w == "between" => {
+            let low = self.expect_operand()?;
+            self.expect(Token::And)?;
+            let high = self.expect_operand()?;
+            let mut pairs = BTreeMap::new();
+            pairs.insert(key, (low, high));
+            return Ok(QueryExpr::Between(pairs));
+          }
+          Some(token) => Err(format!("Expected ~, <, <=, >, >= or between after {} but found {:?}", key, token)),
+          None => Err(format!("Expected ~, <, <=, >, >= or between after {} but the query ended", key)),
+        }
+      }
+      Some(token) => Err(format!("Unexpected {:?}", token)),
+      None => Err("The query ended early".to_string()),
+    }
+  }
+}
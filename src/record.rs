@@ -20,10 +20,22 @@ use serde;
 use regex::Regex;
 
 use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::signing;
 use crate::config::Config;
 use crate::config::Server;
+use crate::query::{Query, ValueMatcher, TEXT_SCORE_KEY};
+
+// Reserved key, set by publishers to the number of seconds
+// (fractions allowed) servers should keep the record for.
+pub const TTL_SECONDS_KEY: &str = "TTL:seconds";
+// Reserved key, set by servers when storing a record which expires.
+// Holds the expiry time in milliseconds since the unix epoch.
+// It is not covered by signatures or record ids.
+pub const TTL_EXPIRES_AT_KEY: &str = "TTL:expires-at";
+// Longest TTL servers honour (ten years), longer ones are clamped to it
+pub const MAX_TTL_S: f64 = 10.0 * 365.0 * 24.0 * 60.0 * 60.0;
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct Record {
@@ -69,12 +81,67 @@ impl Record {
   pub fn is_auth_by_server(&self, config: &Config) -> bool {
     signing::is_auth_by_server(self, config)
   }
+  // A stable identifier derived from the record's contents, which servers
+  // return when it is published. Hex encoded SHA-256 of the sorted keys and
+  // values, each prefixed by its length so distinct records cannot collide.
+  // Keys servers add to records they store or return are left out.
+  pub fn id(&self) -> String {
+    let mut keys: Vec<&String> = self.p.keys().filter(|k| k.as_str() != TTL_EXPIRES_AT_KEY && k.as_str() != TEXT_SCORE_KEY).collect();
+    keys.sort();
+    let mut bytes = vec![];
+    for key in keys {
+      let val = &self.p[key];
+      bytes.extend_from_slice(&(key.len() as u64).to_be_bytes());
+      bytes.extend_from_slice(key.as_bytes());
+      bytes.extend_from_slice(&(val.len() as u64).to_be_bytes());
+      bytes.extend_from_slice(val.as_bytes());
+    }
+    let digest = openssl::sha::sha256(&bytes);
+    return digest.iter().map(|b| format!("{:02x}", b)).collect();
+  }
+  // Milliseconds since the unix epoch after which the record should be
+  // forgotten, or None if it never expires.
+  pub fn expires_at_ms(&self) -> Option<u64> {
+    return self.p.get(TTL_EXPIRES_AT_KEY).and_then(|v| v.parse::<u64>().ok());
+  }
+  pub fn is_expired(&self, now_ms: u64) -> bool {
+    match self.expires_at_ms() {
+      Some(expires_at) => expires_at <= now_ms,
+      None => false,
+    }
+  }
+  // Turns TTL:seconds (or default_ttl_s when the record has no TTL:seconds
+  // and it is > 0) into an absolute TTL:expires-at. An expires-at the record
+  // already carries (eg a copy from a peer) is not signed, so it may only
+  // bring the expiry forward, never push it back.
+  // Returns false if TTL:seconds is not a finite number above 0.
+  pub fn stamp_expiry(&mut self, now_ms: u64, default_ttl_s: f64) -> bool {
+    let ttl_s = match self.p.get(TTL_SECONDS_KEY) {
+      Some(ttl_s) => match ttl_s.trim().parse::<f64>() {
+        Ok(ttl_s) if ttl_s.is_finite() && ttl_s > 0.0 => Some(ttl_s),
+        _ => return false,
+      },
+      None if default_ttl_s.is_finite() && default_ttl_s > 0.0 => Some(default_ttl_s),
+      None => None,
+    };
+    let computed = ttl_s.map(|ttl_s| now_ms.saturating_add((ttl_s.min(MAX_TTL_S) * 1000.0) as u64));
+    let supplied = self.p.get(TTL_EXPIRES_AT_KEY).map(|v| v.parse::<u64>().unwrap_or(0));
+    let expires_at = match (computed, supplied) {
+      (Some(computed), Some(supplied)) => Some(computed.min(supplied)),
+      (computed, supplied) => computed.or(supplied),
+    };
+    if let Some(expires_at) = expires_at {
+      self.p.insert(TTL_EXPIRES_AT_KEY.to_string(), format!("{}", expires_at));
+    }
+    return true;
+  }
   pub fn pub_key(&self) -> String {
     let empty_str = String::new();
     let pub_key_val = self.p.get("public-key").unwrap_or(&empty_str);
     return format!("{}", pub_key_val);
   }
-  pub fn matches(&self, query: &HashMap<String, Regex>) -> bool {
+  // query holds a pattern per key, see query::MatchMode
+  pub fn matches<M: ValueMatcher>(&self, query: &HashMap<String, M>) -> bool {
     let mut common_keys = vec![];
     for key in self.p.keys() {
       if query.contains_key(key) {
@@ -95,6 +162,8 @@ impl Record {
     // All shared key regexes matched, this is a match
     return true;
   }
+  // Patterns which do not compile are left out, see Query::compile for
+  // the checked form servers use on queries from clients.
   pub fn create_regex_map(&self) -> HashMap<String, Regex> {
     let mut map = HashMap::new();
     for (key, val) in &self.p {
@@ -104,4 +173,15 @@ impl Record {
     }
     return map;
   }
+  // Compiles this query record, including any QUERY:expression key
+  pub fn create_query(&self) -> Query {
+    Query::new(self)
+  }
+}
+
+pub fn now_ms() -> u64 {
+  match SystemTime::now().duration_since(UNIX_EPOCH) {
+    Ok(d) => d.as_secs() * 1000 + d.subsec_millis() as u64,
+    Err(_) => 0,
+  }
 }
@@ -0,0 +1,469 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; version 2 of the License only.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use regex_syntax;
+use regex_syntax::hir::{Hir, HirKind, Anchor, RepetitionKind, RepetitionRange};
+use regex_syntax::hir::literal::Literals;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::data::StoredRecord;
+use crate::query::{Query, CompiledExpr, TextStats, is_text_key, text_terms};
+
+// Records with a key are only a match if that key's regex matches, so each
+// pool indexes its records three ways to avoid running every regex against
+// every record:
+//
+//  - by key, since a record must share at least one key with the query
+//  - by the first VALUE_PREFIX_CHARS of each value, for patterns like ^http://
+//  - by the alphanumeric terms in each value, for patterns like .*dindex.*
+//
+// A QueryPlan holds the literals every match of each query regex must contain
+// (found by regex_syntax). RecordPool::candidates uses them to pick a superset
+// of the matching records which is then checked with the real regexes.
+// Query expressions are narrowed by intersecting the candidates of "and"
+// and uniting those of "or"; "not" and "absent" can't be narrowed at all.
+//
+// Full-text queries use a fourth index of lowercased terms across all text
+// keys, which also counts the records holding each term for BM25.
+
+// Values are indexed by at most this many leading characters
+pub const VALUE_PREFIX_CHARS: usize = 32;
+// Terms longer than this are not indexed; records holding them are always
+// candidates for term lookups on that key instead.
+pub const MAX_TERM_CHARS: usize = 64;
+// Regexes with more alternative literals than this are not worth looking up
+pub const MAX_LITERALS: usize = 64;
+
+// Literals at least one of which must appear in any value the regex matches
+#[derive(Debug, Clone)]
+pub struct Constraint {
+  // The literals must begin the value rather than appear anywhere in it
+  pub anchored: bool,
+  pub literals: Vec<String>,
+}
+
+impl Constraint {
+  // Returns None if nothing useful can be said about pattern
+  pub fn from_regex(pattern: &str) -> Option<Constraint> {
+    let hir = regex_syntax::Parser::new().parse(pattern).ok()?;
+    let mut items: Vec<Hir> = match hir.kind() {
+      HirKind::Concat(items) => items.clone(),
+      _ => vec![hir],
+    };
+    let mut anchored = false;
+    if let Some(HirKind::Anchor(Anchor::StartText)) = items.first().map(|h| h.kind()) {
+      anchored = true;
+      items.remove(0);
+    }
+    // Searches are unanchored, so optional leading items like the .* in .*foo.*
+    // change nothing about which values match. Dropping them lets the literal
+    // after them be found, but it then may appear anywhere in the value.
+    while items.first().map(|h| can_match_empty_prefix(h)).unwrap_or(false) {
+      items.remove(0);
+      anchored = false;
+    }
+    if items.is_empty() {
+      return None;
+    }
+
+    let prefixes = Literals::prefixes(&Hir::concat(items));
+    if prefixes.literals().is_empty() || prefixes.contains_empty() || prefixes.literals().len() > MAX_LITERALS {
+      return None;
+    }
+    let mut literals = vec![];
+    for lit in prefixes.literals() {
+      // Literals may be cut in the middle of a UTF-8 sequence, any prefix
+      // of a required prefix is still required.
+      let bytes: &[u8] = &lit;
+      let valid = match std::str::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(e) => std::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or(""),
+      };
+      if valid.is_empty() {
+        return None;
+      }
+      literals.push(valid.to_string());
+    }
+    return Some(Constraint {
+      anchored: anchored,
+      literals: literals,
+    });
+  }
+}
+
+// True for zero-width assertions and repetitions which may match nothing
+fn can_match_empty_prefix(hir: &Hir) -> bool {
+  match hir.kind() {
+    HirKind::Anchor(Anchor::StartText) => false,
+    HirKind::Anchor(_) | HirKind::WordBoundary(_) | HirKind::Empty => true,
+    HirKind::Repetition(rep) => match &rep.kind {
+      RepetitionKind::ZeroOrOne | RepetitionKind::ZeroOrMore => true,
+      RepetitionKind::Range(RepetitionRange::AtLeast(0)) => true,
+      RepetitionKind::Range(RepetitionRange::Bounded(0, _)) => true,
+      _ => false,
+    },
+    _ => false,
+  }
+}
+
+// What a query needs from the index, built once per search
+pub struct QueryPlan {
+  // The query record's plain keys, a record must share at least one
+  pub keys: Vec<(String, Option<Constraint>)>,
+  pub expr: Option<PlanExpr>,
+  // Full-text terms, a record must hold at least one
+  pub text: Option<Vec<String>>,
+}
+
+// CompiledExpr reduced to what the index can look up
+pub enum PlanExpr {
+  And(Vec<PlanExpr>),
+  Or(Vec<PlanExpr>),
+  Match(String, Option<Constraint>),
+  Exists(String),
+  // Could be any record
+  Any,
+}
+
+impl QueryPlan {
+  pub fn new(query: &Query) -> QueryPlan {
+    let mut keys = vec![];
+    for (key, matcher) in &query.keys {
+      keys.push((key.clone(), matcher.as_regex().and_then(|re| Constraint::from_regex(re.as_str()))));
+    }
+    return QueryPlan {
+      keys: keys,
+      expr: query.expr.as_ref().map(PlanExpr::new),
+      text: query.text.clone(),
+    };
+  }
+}
+
+impl PlanExpr {
+  pub fn new(expr: &CompiledExpr) -> PlanExpr {
+    match expr {
+      CompiledExpr::And(items) => PlanExpr::And(items.iter().map(PlanExpr::new).collect()),
+      CompiledExpr::Or(items) => PlanExpr::Or(items.iter().map(PlanExpr::new).collect()),
+      CompiledExpr::Match(pairs) => PlanExpr::And(pairs.iter().map(|(key, matcher)| {
+        PlanExpr::Match(key.clone(), matcher.as_regex().and_then(|re| Constraint::from_regex(re.as_str())))
+      }).collect()),
+      CompiledExpr::Exists(keys) => PlanExpr::And(keys.iter().map(|key| PlanExpr::Exists(key.clone())).collect()),
+      CompiledExpr::Range(ranges) => PlanExpr::And(ranges.iter().map(|range| PlanExpr::Exists(range.key.clone())).collect()),
+      CompiledExpr::Not(_) | CompiledExpr::Absent(_) => PlanExpr::Any,
+    }
+  }
+}
+
+#[derive(Default)]
+pub struct RecordIndex {
+  // key -> records with that key
+  keys: HashMap<String, HashSet<u64>>,
+  // key -> leading VALUE_PREFIX_CHARS of value -> records
+  value_prefixes: HashMap<String, BTreeMap<String, HashSet<u64>>>,
+  // key -> term -> records
+  terms: HashMap<String, HashMap<String, HashSet<u64>>>,
+  // key -> records with a term longer than MAX_TERM_CHARS in that key
+  long_terms: HashMap<String, HashSet<u64>>,
+  // lowercased term -> records holding it in any text key
+  text_terms: HashMap<String, HashSet<u64>>,
+  // Terms in the text keys of every record, repeats included
+  text_tokens: u64,
+}
+
+impl RecordIndex {
+  pub fn add(&mut self, stored: &StoredRecord) {
+    let seq = stored.seq;
+    for (key, val) in &stored.rec.p {
+      self.keys.entry(key.clone()).or_insert_with(HashSet::new).insert(seq);
+      self.value_prefixes.entry(key.clone()).or_insert_with(BTreeMap::new)
+        .entry(value_prefix(val)).or_insert_with(HashSet::new).insert(seq);
+      for term in terms(val) {
+        if term.chars().count() > MAX_TERM_CHARS {
+          self.long_terms.entry(key.clone()).or_insert_with(HashSet::new).insert(seq);
+        }
+        else {
+          self.terms.entry(key.clone()).or_insert_with(HashMap::new)
+            .entry(term.to_string()).or_insert_with(HashSet::new).insert(seq);
+        }
+      }
+      if is_text_key(key) {
+        for term in text_terms(val) {
+          self.text_tokens += 1;
+          self.text_terms.entry(term).or_insert_with(HashSet::new).insert(seq);
+        }
+      }
+    }
+  }
+  pub fn remove(&mut self, stored: &StoredRecord) {
+    let seq = stored.seq;
+    for (key, val) in &stored.rec.p {
+      remove_posting(&mut self.keys, key, seq);
+      if let Some(prefixes) = self.value_prefixes.get_mut(key) {
+        remove_posting(prefixes, &value_prefix(val), seq);
+        if prefixes.is_empty() {
+          self.value_prefixes.remove(key);
+        }
+      }
+      for term in terms(val) {
+        if term.chars().count() > MAX_TERM_CHARS {
+          remove_posting(&mut self.long_terms, key, seq);
+        }
+        else if let Some(key_terms) = self.terms.get_mut(key) {
+          remove_posting(key_terms, term, seq);
+          if key_terms.is_empty() {
+            self.terms.remove(key);
+          }
+        }
+      }
+      if is_text_key(key) {
+        for term in text_terms(val) {
+          self.text_tokens -= 1;
+          remove_posting(&mut self.text_terms, &term, seq);
+        }
+      }
+    }
+  }
+  // A superset of the records which can match plan, in no particular
+  // order, or None if any record might match.
+  pub fn candidates(&self, plan: &QueryPlan) -> Option<HashSet<u64>> {
+    if plan.keys.is_empty() && plan.expr.is_none() && plan.text.is_none() {
+      return Some(HashSet::new()); // Query::matches rejects everything
+    }
+    let mut narrowed = vec![];
+    if !plan.keys.is_empty() {
+      let mut candidates = HashSet::new();
+      for (key, constraint) in &plan.keys {
+        self.add_key_candidates(key, constraint, &mut candidates);
+      }
+      narrowed.push(candidates);
+    }
+    if let Some(from_expr) = plan.expr.as_ref().and_then(|expr| self.expr_candidates(expr)) {
+      narrowed.push(from_expr);
+    }
+    if let Some(terms) = &plan.text {
+      let mut candidates = HashSet::new();
+      for term in terms {
+        if let Some(seqs) = self.text_terms.get(term) {
+          candidates.extend(seqs);
+        }
+      }
+      narrowed.push(candidates);
+    }
+    if narrowed.is_empty() {
+      return None;
+    }
+    return Some(intersect(narrowed));
+  }
+  // How many records hold each of terms, and the total terms in this index
+  pub fn text_stats(&self, terms: &[String]) -> (Vec<usize>, u64) {
+    let doc_freqs = terms.iter().map(|term| self.text_terms.get(term).map(|seqs| seqs.len()).unwrap_or(0)).collect();
+    return (doc_freqs, self.text_tokens);
+  }
+  fn expr_candidates(&self, expr: &PlanExpr) -> Option<HashSet<u64>> {
+    match expr {
+      PlanExpr::And(items) => {
+        let narrowed: Vec<HashSet<u64>> = items.iter().filter_map(|e| self.expr_candidates(e)).collect();
+        if narrowed.is_empty() {
+          return None;
+        }
+        return Some(intersect(narrowed));
+      }
+      PlanExpr::Or(items) => {
+        let mut candidates = HashSet::new();
+        for item in items {
+          candidates.extend(self.expr_candidates(item)?);
+        }
+        return Some(candidates);
+      }
+      PlanExpr::Match(key, constraint) => {
+        let mut candidates = HashSet::new();
+        self.add_key_candidates(key, constraint, &mut candidates);
+        return Some(candidates);
+      }
+      PlanExpr::Exists(key) => {
+        return Some(self.keys.get(key).cloned().unwrap_or_default());
+      }
+      PlanExpr::Any => None,
+    }
+  }
+  fn add_key_candidates(&self, key: &str, constraint: &Option<Constraint>, candidates: &mut HashSet<u64>) {
+    match constraint {
+      None => {
+        if let Some(with_key) = self.keys.get(key) {
+          candidates.extend(with_key);
+        }
+      }
+      Some(constraint) if constraint.anchored => {
+        self.add_prefix_candidates(key, constraint, candidates);
+      }
+      Some(constraint) => {
+        self.add_term_candidates(key, constraint, candidates);
+      }
+    }
+  }
+  fn add_prefix_candidates(&self, key: &str, constraint: &Constraint, candidates: &mut HashSet<u64>) {
+    let prefixes = match self.value_prefixes.get(key) {
+      Some(prefixes) => prefixes,
+      None => return,
+    };
+    for literal in &constraint.literals {
+      // Indexed prefixes are truncated so only compare that much of the literal
+      let literal = value_prefix(literal);
+      for (prefix, seqs) in prefixes.range(literal.clone()..) {
+        if !prefix.starts_with(&literal) {
+          break;
+        }
+        candidates.extend(seqs);
+      }
+    }
+  }
+  fn add_term_candidates(&self, key: &str, constraint: &Constraint, candidates: &mut HashSet<u64>) {
+    // A value containing a literal contains the literal's longest alphanumeric
+    // run, which must then be inside one of the value's terms.
+    let mut fragments = vec![];
+    for literal in &constraint.literals {
+      match terms(literal).max_by_key(|t| t.len()) {
+        Some(fragment) => fragments.push(fragment),
+        None => {
+          // Eg a literal of only punctuation, fall back to the key alone
+          if let Some(with_key) = self.keys.get(key) {
+            candidates.extend(with_key);
+          }
+          return;
+        }
+      }
+    }
+    if let Some(key_terms) = self.terms.get(key) {
+      for (term, seqs) in key_terms {
+        if fragments.iter().any(|f| term.contains(f)) {
+          candidates.extend(seqs);
+        }
+      }
+    }
+    if let Some(long) = self.long_terms.get(key) {
+      candidates.extend(long);
+    }
+  }
+}
+
+// Records in every one of sets
+fn intersect(mut sets: Vec<HashSet<u64>>) -> HashSet<u64> {
+  sets.sort_by_key(|set| set.len());
+  let mut smallest = sets.remove(0);
+  smallest.retain(|seq| sets.iter().all(|set| set.contains(seq)));
+  return smallest;
+}
+
+fn value_prefix(val: &str) -> String {
+  return val.chars().take(VALUE_PREFIX_CHARS).collect();
+}
+
+// Maximal runs of alphanumeric characters
+fn terms<'a>(val: &'a str) -> impl Iterator<Item=&'a str> {
+  return val.split(|c: char| !c.is_alphanumeric()).filter(|t| !t.is_empty());
+}
+
+// Shared by the HashMap and BTreeMap postings above
+trait Postings {
+  fn postings_mut(&mut self, key: &str) -> Option<&mut HashSet<u64>>;
+  fn remove_key(&mut self, key: &str);
+}
+
+impl Postings for HashMap<String, HashSet<u64>> {
+  fn postings_mut(&mut self, key: &str) -> Option<&mut HashSet<u64>> {
+    self.get_mut(key)
+  }
+  fn remove_key(&mut self, key: &str) {
+    self.remove(key);
+  }
+}
+
+impl Postings for BTreeMap<String, HashSet<u64>> {
+  fn postings_mut(&mut self, key: &str) -> Option<&mut HashSet<u64>> {
+    self.get_mut(key)
+  }
+  fn remove_key(&mut self, key: &str) {
+    self.remove(key);
+  }
+}
+
+fn remove_posting<P: Postings>(postings: &mut P, key: &str, seq: u64) {
+  let now_empty = match postings.postings_mut(key) {
+    Some(seqs) => {
+      seqs.remove(&seq);
+      seqs.is_empty()
+    }
+    None => false,
+  };
+  if now_empty {
+    postings.remove_key(key);
+  }
+}
+
+// The records of one of Data::record_pools along with their index
+#[derive(Default)]
+pub struct RecordPool {
+  // Keyed by StoredRecord::seq, so iteration is in insertion order
+  records: BTreeMap<u64, StoredRecord>,
+  index: RecordIndex,
+}
+
+impl RecordPool {
+  pub fn new() -> RecordPool {
+    RecordPool::default()
+  }
+  pub fn push(&mut self, stored: StoredRecord) {
+    self.index.add(&stored);
+    self.records.insert(stored.seq, stored);
+  }
+  pub fn len(&self) -> usize {
+    self.records.len()
+  }
+  pub fn iter(&self) -> impl Iterator<Item=&StoredRecord> {
+    self.records.values()
+  }
+  // Keeps only the records for which keep returns true
+  pub fn retain<F: FnMut(&StoredRecord) -> bool>(&mut self, mut keep: F) {
+    let doomed: Vec<u64> = self.records.values().filter(|s| !keep(s)).map(|s| s.seq).collect();
+    for seq in doomed {
+      if let Some(stored) = self.records.remove(&seq) {
+        self.index.remove(&stored);
+      }
+    }
+  }
+  // This pool's share of the TextStats for terms
+  pub fn text_stats(&self, terms: &[String]) -> TextStats {
+    let (doc_freqs, tokens) = self.index.text_stats(terms);
+    TextStats {
+      docs: self.records.len(),
+      tokens: tokens,
+      doc_freqs: doc_freqs,
+    }
+  }
+  // Records which may match plan, in insertion order. Callers
+  // must still check each one against the query's regexes.
+  pub fn candidates(&self, plan: &QueryPlan) -> Vec<&StoredRecord> {
+    let mut seqs: Vec<u64> = match self.index.candidates(plan) {
+      Some(seqs) => seqs.into_iter().collect(),
+      None => return self.records.values().collect(),
+    };
+    seqs.sort_unstable();
+    return seqs.iter().filter_map(|seq| self.records.get(seq)).collect();
+  }
+}
@@ -0,0 +1,230 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; version 2 of the License only.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use serde;
+use crc32fast;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*, BufWriter};
+use std::collections::HashMap;
+
+use crate::record::Record;
+use crate::server_data_io::RecordStore;
+
+// An append-only RecordStore selected with a server_datastore_uri like
+// file:///tmp/dindex_db.log
+//
+// The file begins with LOG_MAGIC and is followed by entries of:
+//   payload length (big-endian u32), CRC32 of payload (big-endian u32), CBOR LogOp
+//
+// Every insert and removal appends one entry, so a publish costs one small
+// synced write instead of rewriting every record. If the process dies
+// mid-write the torn entry at the end is truncated away on the next startup,
+// entries damaged elsewhere fail their checksum and are skipped over.
+// Removals leave dead entries behind; compact() rewrites the file with only
+// the live records and atomically renames it over the old log.
+
+pub const LOG_MAGIC: &[u8] = b"dIndexLog1\n";
+// Logs are not compacted until they hold at least this many entries
+pub const COMPACT_MIN_OPS: usize = 1024;
+// ...and at least this many entries per live record
+pub const COMPACT_RATIO: usize = 2;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub enum LogOp {
+  Insert(Record),
+  // Removes the earliest remaining record with this Record::id()
+  Remove(String),
+}
+
+pub struct RecordLog {
+  pub path: String,
+  // Opened in append mode
+  file: File,
+  // Entries in the file, live or dead
+  num_ops: usize,
+}
+
+impl RecordLog {
+  // Opens or creates the log at path and returns it along with the
+  // records it holds, in the order they were inserted.
+  pub fn open(path: &str) -> io::Result<(RecordLog, Vec<Record>)> {
+    let mut bytes = vec![];
+    OpenOptions::new().read(true).write(true).create(true).open(path)?.read_to_end(&mut bytes)?;
+
+    if bytes.is_empty() {
+      fs::write(path, LOG_MAGIC)?;
+      bytes.extend_from_slice(LOG_MAGIC);
+    }
+    if !bytes.starts_with(LOG_MAGIC) {
+      return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a dindex record log", path)));
+    }
+
+    let mut ops = vec![];
+    let mut num_corrupt = 0;
+    let mut offset = LOG_MAGIC.len();
+    while offset < bytes.len() {
+      match read_entry(&bytes[offset..]) {
+        Entry::Op(op, entry_len) => {
+          ops.push(op);
+          offset += entry_len;
+        }
+        Entry::Corrupt(entry_len) => {
+          println!("Skipping corrupt entry of {} bytes at offset {} in {}", entry_len, offset, path);
+          num_corrupt += 1;
+          offset += entry_len;
+        }
+        Entry::Incomplete if num_corrupt > 0 => {
+          // A corrupt length may have sent us into the middle of good entries,
+          // so nothing is truncated when the log is damaged before its tail
+          return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} has corrupt entries before offset {}", path, offset)));
+        }
+        Entry::Incomplete => {
+          // Only the last append can be torn by dying mid-write
+          println!("Discarding {} bytes of an incomplete entry at the end of {}", bytes.len() - offset, path);
+          OpenOptions::new().write(true).open(path)?.set_len(offset as u64)?;
+          break;
+        }
+      }
+    }
+
+    let log = RecordLog {
+      path: path.to_string(),
+      file: OpenOptions::new().append(true).open(path)?,
+      num_ops: ops.len() + num_corrupt,
+    };
+    return Ok((log, replay(ops)));
+  }
+  pub fn append(&mut self, op: &LogOp) {
+    match encode_entry(op) {
+      Ok(entry) => {
+        // Synced so an acknowledged publish survives a crash
+        if let Err(e) = self.file.write_all(&entry).and_then(|_| self.file.flush()).and_then(|_| self.file.sync_data()) {
+          println!("Error appending to record log {}: {}", self.path, e);
+          return;
+        }
+        self.num_ops += 1;
+      }
+      Err(e) => {
+        println!("Error encoding record log entry: {}", e);
+      }
+    }
+  }
+  pub fn num_ops(&self) -> usize {
+    return self.num_ops;
+  }
+}
+
+impl RecordStore for RecordLog {
+  fn insert(&mut self, rec: &Record) {
+    self.append(&LogOp::Insert(rec.clone()));
+  }
+  fn remove(&mut self, rec: &Record) {
+    self.append(&LogOp::Remove(rec.id()));
+  }
+  fn should_compact(&self, num_live_records: usize) -> bool {
+    return self.num_ops >= COMPACT_MIN_OPS && self.num_ops >= num_live_records * COMPACT_RATIO;
+  }
+  // Replaces the log with one inserting exactly records
+  fn compact(&mut self, records: &[Record]) -> io::Result<()> {
+    let tmp_path = format!("{}.compacting", self.path);
+    {
+      let mut tmp = BufWriter::new(File::create(&tmp_path)?);
+      tmp.write_all(LOG_MAGIC)?;
+      for rec in records {
+        let entry = encode_entry(&LogOp::Insert(rec.clone()))
+          .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        tmp.write_all(&entry)?;
+      }
+      tmp.flush()?;
+      tmp.get_ref().sync_all()?;
+    }
+    // rename() is atomic, a crash leaves either the old or the new log
+    fs::rename(&tmp_path, &self.path)?;
+    self.file = OpenOptions::new().append(true).open(&self.path)?;
+    self.num_ops = records.len();
+    return Ok(());
+  }
+  fn describe(&self) -> String {
+    return format!("record log {}", self.path);
+  }
+}
+
+fn encode_entry(op: &LogOp) -> Result<Vec<u8>, serde_cbor::error::Error> {
+  let payload = serde_cbor::to_vec(op)?;
+  let mut entry = Vec::with_capacity(8 + payload.len());
+  entry.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+  entry.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+  entry.extend_from_slice(&payload);
+  return Ok(entry);
+}
+
+enum Entry {
+  // The op and the entry's length in bytes
+  Op(LogOp, usize),
+  // Whole but failed its checksum or decoding, with its length in bytes
+  Corrupt(usize),
+  // Runs past the end of the file
+  Incomplete,
+}
+
+// Reads the first entry in bytes
+fn read_entry(bytes: &[u8]) -> Entry {
+  if bytes.len() < 8 {
+    return Entry::Incomplete;
+  }
+  let mut u32_bytes = [0u8; 4];
+  u32_bytes.copy_from_slice(&bytes[0..4]);
+  let payload_len = u32::from_be_bytes(u32_bytes) as usize;
+  u32_bytes.copy_from_slice(&bytes[4..8]);
+  let crc = u32::from_be_bytes(u32_bytes);
+  if bytes.len() < 8 + payload_len {
+    return Entry::Incomplete;
+  }
+  let payload = &bytes[8..8+payload_len];
+  if crc32fast::hash(payload) != crc {
+    return Entry::Corrupt(8 + payload_len);
+  }
+  match serde_cbor::from_slice::<LogOp>(payload) {
+    Ok(op) => Entry::Op(op, 8 + payload_len),
+    Err(_) => Entry::Corrupt(8 + payload_len),
+  }
+}
+
+// Applies ops in order, returning the records still inserted at the end
+fn replay(ops: Vec<LogOp>) -> Vec<Record> {
+  let mut records: Vec<Option<Record>> = vec![];
+  // Indexes into records of the live records with each id, oldest first
+  let mut positions: HashMap<String, Vec<usize>> = HashMap::new();
+  for op in ops {
+    match op {
+      LogOp::Insert(rec) => {
+        positions.entry(rec.id()).or_insert(vec![]).push(records.len());
+        records.push(Some(rec));
+      }
+      LogOp::Remove(id) => {
+        if let Some(same_id) = positions.get_mut(&id) {
+          if !same_id.is_empty() {
+            records[same_id.remove(0)] = None;
+          }
+        }
+      }
+    }
+  }
+  return records.into_iter().filter_map(|r| r).collect();
+}
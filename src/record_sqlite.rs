@@ -0,0 +1,186 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; version 2 of the License only.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use rusqlite::{Connection, NO_PARAMS};
+use rusqlite::types::ToSql;
+
+use std::collections::HashMap;
+use std::io;
+
+use crate::record::Record;
+use crate::server_data_io::RecordStore;
+
+// A RecordStore kept in an embedded SQLite database, selected with a
+// server_datastore_uri like sqlite:///tmp/dindex_db.sqlite
+//
+// Each record is a row in `records` and each of its keys a row in
+// `record_keys`, so stored data can be inspected with the sqlite3 tool:
+//
+//   sqlite3 /tmp/dindex_db.sqlite "SELECT key, value FROM record_keys WHERE key = 'url'"
+//
+// Every insert and removal is its own transaction.
+
+const SCHEMA: &str = "
+  PRAGMA foreign_keys = ON;
+  PRAGMA journal_mode = WAL;
+  PRAGMA synchronous = NORMAL;
+  CREATE TABLE IF NOT EXISTS records (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    id TEXT NOT NULL,
+    -- TTL:expires-at of the record, if it has one
+    expires_at INTEGER
+  );
+  CREATE INDEX IF NOT EXISTS records_id ON records (id);
+  CREATE TABLE IF NOT EXISTS record_keys (
+    record_seq INTEGER NOT NULL REFERENCES records (seq) ON DELETE CASCADE,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL
+  );
+  CREATE INDEX IF NOT EXISTS record_keys_seq ON record_keys (record_seq);
+  CREATE INDEX IF NOT EXISTS record_keys_key_value ON record_keys (key, value);
+";
+
+pub struct SqliteStore {
+  pub path: String,
+  conn: Connection,
+}
+
+impl SqliteStore {
+  // Opens or creates the database at path. Records are not read
+  // until for_each_record is called.
+  pub fn open(path: &str) -> rusqlite::Result<SqliteStore> {
+    let conn = Connection::open(path)?;
+    conn.execute_batch(SCHEMA)?;
+    return Ok(SqliteStore {
+      path: path.to_string(),
+      conn: conn,
+    });
+  }
+  // Deletes records which expired before now_ms, then streams the rest to
+  // on_record in the order they were inserted without loading them all at once.
+  pub fn for_each_record<F: FnMut(Record)>(&self, now_ms: u64, mut on_record: F) -> rusqlite::Result<()> {
+    self.conn.execute(
+      "DELETE FROM records WHERE expires_at IS NOT NULL AND expires_at <= ?1",
+      &[&(now_ms as i64) as &dyn ToSql]
+    )?;
+    let mut stmt = self.conn.prepare(
+      "SELECT record_seq, key, value FROM record_keys ORDER BY record_seq"
+    )?;
+    let mut rows = stmt.query(NO_PARAMS)?;
+    let mut current: Option<(i64, Record)> = None;
+    while let Some(row) = rows.next()? {
+      let seq: i64 = row.get(0)?;
+      let key: String = row.get(1)?;
+      let value: String = row.get(2)?;
+      match &mut current {
+        Some((current_seq, rec)) if *current_seq == seq => {
+          rec.p.insert(key, value);
+        }
+        _ => {
+          if let Some((_, rec)) = current.take() {
+            on_record(rec);
+          }
+          let mut rec = Record::empty();
+          rec.p.insert(key, value);
+          current = Some((seq, rec));
+        }
+      }
+    }
+    if let Some((_, rec)) = current.take() {
+      on_record(rec);
+    }
+    return Ok(());
+  }
+  fn try_insert(&mut self, rec: &Record) -> rusqlite::Result<()> {
+    let tx = self.conn.transaction()?;
+    let expires_at = rec.expires_at_ms().map(|e| e as i64);
+    tx.execute(
+      "INSERT INTO records (id, expires_at) VALUES (?1, ?2)",
+      &[&rec.id() as &dyn ToSql, &expires_at]
+    )?;
+    let seq = tx.last_insert_rowid();
+    {
+      let mut stmt = tx.prepare_cached("INSERT INTO record_keys (record_seq, key, value) VALUES (?1, ?2, ?3)")?;
+      for (key, value) in &rec.p {
+        stmt.execute(&[&seq as &dyn ToSql, key, value])?;
+      }
+    }
+    return tx.commit();
+  }
+  // Deletes the rows of every record not in records, keeping the oldest
+  // row for each copy that is
+  fn try_compact(&mut self, records: &[Record]) -> rusqlite::Result<()> {
+    let mut num_live: HashMap<String, usize> = HashMap::new();
+    for rec in records {
+      *num_live.entry(rec.id()).or_insert(0) += 1;
+    }
+    let tx = self.conn.transaction()?;
+    let mut dead = vec![];
+    {
+      let mut stmt = tx.prepare("SELECT seq, id FROM records ORDER BY seq")?;
+      let mut rows = stmt.query(NO_PARAMS)?;
+      while let Some(row) = rows.next()? {
+        let seq: i64 = row.get(0)?;
+        let id: String = row.get(1)?;
+        match num_live.get_mut(&id) {
+          Some(n) if *n > 0 => {
+            *n -= 1;
+          }
+          _ => {
+            dead.push(seq);
+          }
+        }
+      }
+    }
+    {
+      let mut stmt = tx.prepare_cached("DELETE FROM records WHERE seq = ?1")?;
+      for seq in dead {
+        stmt.execute(&[&seq as &dyn ToSql])?;
+      }
+    }
+    return tx.commit();
+  }
+  fn try_remove(&mut self, rec: &Record) -> rusqlite::Result<()> {
+    // Data removes each copy of a duplicated record separately, so only remove one
+    self.conn.execute(
+      "DELETE FROM records WHERE seq = (SELECT seq FROM records WHERE id = ?1 ORDER BY seq LIMIT 1)",
+      &[&rec.id() as &dyn ToSql]
+    )?;
+    return Ok(());
+  }
+}
+
+impl RecordStore for SqliteStore {
+  fn insert(&mut self, rec: &Record) {
+    if let Err(e) = self.try_insert(rec) {
+      println!("Error inserting record into {}: {}", self.describe(), e);
+    }
+  }
+  fn remove(&mut self, rec: &Record) {
+    if let Err(e) = self.try_remove(rec) {
+      println!("Error removing record from {}: {}", self.describe(), e);
+    }
+  }
+  // Records evicted or refused while loading still have rows
+  fn compact(&mut self, records: &[Record]) -> io::Result<()> {
+    return self.try_compact(records).map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)));
+  }
+  fn describe(&self) -> String {
+    return format!("sqlite database {}", self.path);
+  }
+}
@@ -0,0 +1,365 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *  
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; version 2 of the License only.
+ * 
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ * 
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+/**
+ * Replication: servers copy the records of their server_replication_peers
+ * so every peer converges on the same records without clients publishing
+ * to each one. A sync pulls in three steps, each a sync request:
+ *  - a digest, which summarises the record ids in each of 256 buckets
+ *  - the record ids in the buckets whose summaries differ from ours
+ *  - the records with the ids we lack
+ * Both sides only count unexpired records. Records keep their
+ * TTL:expires-at as they are copied, so they expire everywhere at once,
+ * and records with bad signatures are never stored.
+ *
+ * Deletes and updates leave a tombstone for each record they remove,
+ * which is synced like a record until it expires server_tombstone_ttl_s
+ * later. Removing a signed record takes an authorization by its key, so
+ * its tombstone carries that authorization and peers check it before they
+ * remove their copy (see covers). Evicted records get no tombstone, peers
+ * keep them and we only avoid copying them back for a while.
+ */
+
+use std::collections::{HashSet, HashMap, BTreeMap};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Sender;
+
+use crate::actions::Action;
+use crate::client;
+use crate::config::{Config, Server};
+use crate::data::Data;
+use crate::query::Query;
+use crate::record::{Record, TTL_EXPIRES_AT_KEY};
+use crate::server_data_io::write_stored_records;
+use crate::signing::SIGNING_PUB_KEY_KEY;
+use crate::wire::{WireData, SyncRequest, Target, AUTH_ACTION_KEY, AUTH_TARGET_KEY};
+
+// Digest keys are this followed by the bucket
+pub const SYNC_BUCKET_PREFIX: &str = "SYNC:bucket:";
+// Keys listing the ids in a bucket are this followed by the bucket
+pub const SYNC_IDS_PREFIX: &str = "SYNC:ids:";
+// Ids of evicted records Data remembers, so evictions are not undone
+// by copying the record back from a peer
+pub const FORGOTTEN_IDS_REMEMBERED: usize = 4096;
+// Tombstones hold the Record::id of the record they remove in this key
+pub const TOMBSTONE_RECORD_ID_KEY: &str = "TOMBSTONE:record-id";
+// The JSON Target of the delete or update which removed a signed record
+pub const TOMBSTONE_TARGET_KEY: &str = "TOMBSTONE:target";
+// The JSON authorization of that request, see Target::authorization
+pub const TOMBSTONE_AUTHORIZATION_KEY: &str = "TOMBSTONE:authorization";
+// Records asked for in one request
+const PULL_BATCH: usize = 256;
+
+// The tombstone of rec, removed by a request for target. Signed records
+// were removed with an authorization, which the tombstone keeps.
+pub fn tombstone(rec: &Record, target: &Target, authorization: Option<&Record>, expires_at_ms: u64) -> Record {
+  let mut tombstone = Record::empty();
+  tombstone.p.insert(TOMBSTONE_RECORD_ID_KEY.to_string(), rec.id());
+  if rec.p.contains_key(SIGNING_PUB_KEY_KEY) {
+    if let (Ok(target), Some(Ok(authorization))) = (serde_json::to_string(target), authorization.map(serde_json::to_string)) {
+      tombstone.p.insert(TOMBSTONE_TARGET_KEY.to_string(), target);
+      tombstone.p.insert(TOMBSTONE_AUTHORIZATION_KEY.to_string(), authorization);
+    }
+  }
+  tombstone.p.insert(TTL_EXPIRES_AT_KEY.to_string(), format!("{}", expires_at_ms));
+  return tombstone;
+}
+
+pub fn is_tombstone(rec: &Record) -> bool {
+  return rec.p.contains_key(TOMBSTONE_RECORD_ID_KEY);
+}
+
+// True if tombstone may remove rec. Like a delete request, anyone may
+// remove unsigned records; signed ones need an authorization for a target
+// matching them, signed by their key or one data trusts.
+pub fn covers(tombstone: &Record, rec: &Record, data: &Data) -> bool {
+  if tombstone.p.get(TOMBSTONE_RECORD_ID_KEY) != Some(&rec.id()) {
+    return false;
+  }
+  let owner_key = match rec.p.get(SIGNING_PUB_KEY_KEY) {
+    Some(owner_key) => owner_key,
+    None => return true,
+  };
+  let target = tombstone.p.get(TOMBSTONE_TARGET_KEY).and_then(|target| serde_json::from_str::<Target>(target).ok());
+  let auth = tombstone.p.get(TOMBSTONE_AUTHORIZATION_KEY).and_then(|auth| serde_json::from_str::<Record>(auth).ok());
+  let (target, auth) = match (target, auth) {
+    (Some(target), Some(auth)) => (target, auth),
+    _ => return false,
+  };
+  let get = |key: &str| auth.p.get(key).map(|s| s.as_str()).unwrap_or("");
+  let action = get(AUTH_ACTION_KEY);
+  if action != format!("{}", Action::delete) && action != format!("{}", Action::update) {
+    return false;
+  }
+  if get(AUTH_TARGET_KEY) != target.describe() || ! auth.is_signed() {
+    return false;
+  }
+  let query = match &target.query {
+    Some(query) => match data.compile_query(query) {
+      Ok(query) => query,
+      Err(_) => return false,
+    },
+    None => Query::default(),
+  };
+  if ! target.matches(rec, &query) {
+    return false;
+  }
+  let auth_key = get(SIGNING_PUB_KEY_KEY);
+  return auth_key == owner_key || data.trusts_key(auth_key);
+}
+
+// Unexpired tombstones by their own Record::id
+pub struct Tombstones {
+  by_id: BTreeMap<String, Record>,
+  // Tombstone ids by the id of the record they remove
+  by_record: HashMap<String, Vec<String>>,
+}
+
+impl Tombstones {
+  pub fn new() -> Tombstones {
+    Tombstones {
+      by_id: BTreeMap::new(),
+      by_record: HashMap::new(),
+    }
+  }
+  // True if tombstone was not already held
+  pub fn insert(&mut self, tombstone: Record) -> bool {
+    let id = tombstone.id();
+    if self.by_id.contains_key(&id) {
+      return false;
+    }
+    let record_id = tombstone.p.get(TOMBSTONE_RECORD_ID_KEY).cloned().unwrap_or_default();
+    self.by_record.entry(record_id).or_insert(vec![]).push(id.clone());
+    self.by_id.insert(id, tombstone);
+    return true;
+  }
+  pub fn remove_expired(&mut self, now_ms: u64) -> usize {
+    let expired: Vec<String> = self.by_id.iter()
+      .filter(|(_id, tombstone)| tombstone.is_expired(now_ms))
+      .map(|(id, _tombstone)| id.clone())
+      .collect();
+    for id in &expired {
+      if let Some(tombstone) = self.by_id.remove(id) {
+        let record_id = tombstone.p.get(TOMBSTONE_RECORD_ID_KEY).cloned().unwrap_or_default();
+        if let Some(ids) = self.by_record.get_mut(&record_id) {
+          ids.retain(|held| held != id);
+          if ids.is_empty() {
+            self.by_record.remove(&record_id);
+          }
+        }
+      }
+    }
+    return expired.len();
+  }
+  // The unexpired tombstones which may remove a record with this Record::id
+  pub fn for_record(&self, record_id: &str, now_ms: u64) -> Vec<Record> {
+    return self.by_record.get(record_id).into_iter().flatten()
+      .filter_map(|id| self.by_id.get(id))
+      .filter(|tombstone| !tombstone.is_expired(now_ms))
+      .cloned()
+      .collect();
+  }
+  // Unexpired tombstones as (id, tombstone)
+  pub fn iter(&self, now_ms: u64) -> impl Iterator<Item=(&String, &Record)> {
+    return self.by_id.iter().filter(move |(_id, tombstone)| !tombstone.is_expired(now_ms));
+  }
+  pub fn len(&self) -> usize {
+    return self.by_id.len();
+  }
+}
+
+// The leading 2 hex characters of a record id
+pub fn bucket_of(id: &str) -> &str {
+  return &id[..id.len().min(2)];
+}
+
+// Summarises the ids in each bucket as their count and the XOR of their
+// bytes, which does not depend on the order records were stored in
+pub fn digest(ids: &BTreeMap<String, Vec<String>>) -> BTreeMap<String, String> {
+  let mut digest = BTreeMap::new();
+  for (bucket, bucket_ids) in ids {
+    let mut xor = [0u8; 32];
+    for id in bucket_ids {
+      for (i, byte) in hex_bytes(id).iter().take(32).enumerate() {
+        xor[i] ^= byte;
+      }
+    }
+    let xor: String = xor.iter().map(|b| format!("{:02x}", b)).collect();
+    digest.insert(bucket.clone(), format!("{}:{}", bucket_ids.len(), xor));
+  }
+  return digest;
+}
+
+fn hex_bytes(hex: &str) -> Vec<u8> {
+  return (0..hex.len() / 2).filter_map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()).collect();
+}
+
+// Buckets the peer holds which do not match ours
+pub fn differing_buckets(ours: &BTreeMap<String, String>, theirs: &BTreeMap<String, String>) -> Vec<String> {
+  return theirs.iter()
+    .filter(|(bucket, summary)| ours.get(*bucket) != Some(*summary))
+    .map(|(bucket, _summary)| bucket.clone())
+    .collect();
+}
+
+// Replies to a sync request from a peer
+pub fn answer(wire_data: &WireData, to_client: &Sender<WireData>, data: &Data) {
+  let id = wire_data.id;
+  let request = wire_data.sync.clone().unwrap_or_default();
+  let mut replies = vec![];
+  if ! request.ids.is_empty() {
+    let ids: HashSet<String> = request.ids.into_iter().collect();
+    for rec in data.records_with_ids(&ids) {
+      replies.push(WireData::result(rec));
+    }
+  }
+  else if ! request.buckets.is_empty() {
+    let buckets: HashSet<String> = request.buckets.into_iter().collect();
+    let mut rec = Record::empty();
+    for (bucket, ids) in data.ids_by_bucket(Some(&buckets)) {
+      rec.p.insert(format!("{}{}", SYNC_IDS_PREFIX, bucket), ids.join(","));
+    }
+    replies.push(WireData::result(rec));
+  }
+  else {
+    let mut rec = Record::empty();
+    for (bucket, summary) in digest(&data.ids_by_bucket(None)) {
+      rec.p.insert(format!("{}{}", SYNC_BUCKET_PREFIX, bucket), summary);
+    }
+    replies.push(WireData::result(rec));
+  }
+  replies.push(WireData::end_of_results());
+  for reply in replies {
+    if let Err(e) = to_client.send(reply.with_id(id)) {
+      println!("e = {}", e);
+      return;
+    }
+  }
+}
+
+// Copies the records and tombstones peer has and data lacks, returning
+// how many were stored
+pub fn sync_from_peer(config: &Config, data: &Data, peer: &Server) -> Result<usize, String> {
+  let theirs = keys_with_prefix(&request_records(config, peer, SyncRequest::default())?, SYNC_BUCKET_PREFIX);
+  let ours = digest(&data.ids_by_bucket(None));
+  let buckets = differing_buckets(&ours, &theirs);
+  if buckets.is_empty() {
+    return Ok(0);
+  }
+  
+  let their_ids = keys_with_prefix(&request_records(config, peer, SyncRequest { buckets: buckets.clone(), ..SyncRequest::default() })?, SYNC_IDS_PREFIX);
+  let bucket_set: HashSet<String> = buckets.into_iter().collect();
+  let our_ids: HashSet<String> = data.ids_by_bucket(Some(&bucket_set)).into_iter().flat_map(|(_bucket, ids)| ids).collect();
+  let missing: Vec<String> = their_ids.values()
+    .flat_map(|ids| ids.split(','))
+    .filter(|id| !id.is_empty() && !our_ids.contains(*id) && !data.was_forgotten(id))
+    .map(|id| id.to_string())
+    .collect();
+  
+  let mut num_stored = 0;
+  for batch in missing.chunks(PULL_BATCH) {
+    let wanted: HashSet<&String> = batch.iter().collect();
+    for rec in request_records(config, peer, SyncRequest { ids: batch.to_vec(), ..SyncRequest::default() })? {
+      // Peers only get to add the records we asked for, and only intact ones
+      if rec.is_empty() || rec.is_imposter() || !wanted.contains(&rec.id()) {
+        continue;
+      }
+      if is_tombstone(&rec) {
+        if data.add_tombstone(rec) {
+          num_stored += 1;
+        }
+        continue;
+      }
+      // Deleted here, the peer has yet to hear of it
+      if data.is_tombstoned(&rec) {
+        continue;
+      }
+      if data.insert(rec) {
+        num_stored += 1;
+      }
+    }
+  }
+  if num_stored > 0 {
+    write_stored_records(config, data);
+  }
+  return Ok(num_stored);
+}
+
+// The results of one sync request
+fn request_records(config: &Config, peer: &Server, request: SyncRequest) -> Result<Vec<Record>, String> {
+  let wire_data = WireData::new(Action::sync, Record::empty()).with_sync(request);
+  let mut records = vec![];
+  let mut error = None;
+  let res = client::request_server_sync(config, peer, &wire_data, || false, |wire_res| {
+    match wire_res.action {
+      Action::result => records.push(wire_res.record),
+      Action::error => error = Some(wire_res.record.p.get("error-message").cloned().unwrap_or_default()),
+      _ => { }
+    }
+    return true;
+  });
+  if let Err(e) = res {
+    return Err(format!("Error syncing with {}: {}", peer.name, e));
+  }
+  if let Some(error) = error {
+    return Err(format!("Error syncing with {}: {}", peer.name, error));
+  }
+  return Ok(records);
+}
+
+// The values of keys beginning with prefix across records, keyed by the rest
+fn keys_with_prefix(records: &[Record], prefix: &str) -> BTreeMap<String, String> {
+  let mut values = BTreeMap::new();
+  for rec in records {
+    for (key, val) in rec.p.iter() {
+      if key.starts_with(prefix) {
+        values.insert(key[prefix.len()..].to_string(), val.clone());
+      }
+    }
+  }
+  return values;
+}
+
+// Syncs with every peer every server_replication_interval_ms
+// until data.exit_flag is set
+pub fn run_replication_sync(config: &Config, data: &Data) {
+  let sync_every = std::time::Duration::from_millis(config.server_replication_interval_ms as u64);
+  let check_exit_every = std::time::Duration::from_millis(100);
+  let mut last_sync: Option<std::time::Instant> = None;
+  while !data.exit_flag.load(Ordering::Relaxed) {
+    if last_sync.map(|last_sync| last_sync.elapsed() < sync_every).unwrap_or(false) {
+      std::thread::sleep(check_exit_every);
+      continue;
+    }
+    last_sync = Some(std::time::Instant::now());
+    for peer in &config.server_replication_peers {
+      match sync_from_peer(config, data, peer) {
+        Ok(num_stored) => {
+          if num_stored > 0 && config.is_debug() && !config.server_extra_quiet {
+            println!("Copied {} records from {}", num_stored, peer.name);
+          }
+        }
+        Err(e) => {
+          if peer.report_connect_errors && !config.server_extra_quiet {
+            println!("{}", e);
+          }
+        }
+      }
+    }
+  }
+}
@@ -24,17 +24,28 @@ use std::sync::atomic::Ordering;
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::AtomicBool;
+use std::collections::HashSet;
 use std::process;
 use std::fs;
 
 use crate::config::Config;
 use crate::data::{Data, Listener};
-use crate::record::Record;
-use crate::wire::WireData;
+use crate::record::{Record, now_ms};
+use crate::wire::{WireData, Capabilities, QueryOptions, Forwarded, Target, AUTH_ACTION_KEY, AUTH_TARGET_KEY, AUTH_RECORD_ID_KEY, AUTH_ISSUED_AT_KEY, AUTH_NONCE_KEY, AUTH_MAX_AGE_MS};
+use crate::federation::{self, Route, RecentIds, ReplayGate, LISTEN_IDS_REMEMBERED};
+use crate::replication;
+use crate::discovery;
+use crate::tls::{self, TlsStream};
+use openssl::ssl::SslAcceptor;
+use crate::query::Query;
+use crate::client;
 use crate::actions::Action;
+use crate::framing::{Framing, FrameDecoder, FrameError, FRAME_MAGIC, encode, read_frame, write_frame};
 
 use crate::server_data_io::*;
 
+use crate::signing::SIGNING_PUB_KEY_KEY;
+
 use crate::h_map;
 
 pub fn run_sync(config: &Config) {
@@ -76,19 +87,117 @@ pub fn run_sync(config: &Config) {
       }));
     }
     
+    if config.server_listen_tls {
+      handlers.push(s.spawn(|_| {
+        run_tls_sync(config, &data);
+      }));
+    }
+    
+    if config.server_listen_wss {
+      handlers.push(s.spawn(|_| {
+        run_wss_sync(config, &data);
+      }));
+    }
+    
+    handlers.push(s.spawn(|_| {
+      run_maintenance_sync(config, &data);
+    }));
+    
+    if config.server_listen_udp && config.server_listen_multicast && config.server_announce_interval_ms > 0 {
+      handlers.push(s.spawn(|_| {
+        discovery::run_announce_sync(config, &data);
+      }));
+    }
+    
+    if !config.server_replication_peers.is_empty() && config.server_replication_interval_ms > 0 {
+      handlers.push(s.spawn(|_| {
+        replication::run_replication_sync(config, &data);
+      }));
+    }
+    
     for h in handlers {
       h.join().unwrap();
     }
   }).unwrap();
 }
 
+// Periodically forgets records whose TTL has passed and compacts
+// the record log until data.exit_flag is set.
+pub fn run_maintenance_sync(config: &Config, data: &Data) {
+  let sweep_every = std::time::Duration::from_millis(1000);
+  let check_exit_every = std::time::Duration::from_millis(100);
+  let mut last_sweep = std::time::Instant::now();
+  while !data.exit_flag.load(Ordering::Relaxed) {
+    std::thread::sleep(check_exit_every);
+    if last_sweep.elapsed() < sweep_every {
+      continue;
+    }
+    last_sweep = std::time::Instant::now();
+    let expired = data.remove_expired();
+    if expired.len() > 0 {
+      if config.is_debug() && !config.server_extra_quiet {
+        println!("Expired {} records, {} remain", expired.len(), data.len());
+      }
+      write_stored_records(config, &data);
+    }
+    data.maybe_compact_store();
+  }
+}
+
 pub fn run_tcp_sync(config: &Config, data: &Data) {
+  serve_tcp_sync(config, data, config.server_port, "tcp", |stream| {
+    handle_tcp_conn(stream, config, data);
+  });
+}
+
+pub fn run_tls_sync(config: &Config, data: &Data) {
+  let acceptor = match tls::acceptor(config) {
+    Ok(acceptor) => acceptor,
+    Err(e) => {
+      println!("Error starting TLS server: {}", e);
+      return;
+    }
+  };
+  print_tls_pin(config);
+  serve_tcp_sync(config, data, config.server_tls_port, "tls", |stream| {
+    handle_tls_conn(stream, &acceptor, config, data);
+  });
+}
+
+pub fn run_wss_sync(config: &Config, data: &Data) {
+  let acceptor = match tls::acceptor(config) {
+    Ok(acceptor) => acceptor,
+    Err(e) => {
+      println!("Error starting WSS server: {}", e);
+      return;
+    }
+  };
+  print_tls_pin(config);
+  serve_tcp_sync(config, data, config.server_wss_port, "wss", |stream| {
+    handle_wss_conn(stream, &acceptor, config, data);
+  });
+}
+
+// Clients can pin our key instead of trusting a CA, see tls.rs
+fn print_tls_pin(config: &Config) {
+  if !config.server_extra_quiet {
+    match tls::cert_file_pin(&config.server_tls_cert_file) {
+      Ok(pin) => println!("tls_pin = \"{}\"", pin),
+      Err(e) => println!("Error reading TLS key pin: {}", e),
+    }
+  }
+}
+
+// Accepts connections on port, passing each to handle_conn on its own thread
+fn serve_tcp_sync<H>(config: &Config, data: &Data, port: u16, transport: &str, handle_conn: H)
+  where H: Fn(Result<std::net::TcpStream, std::io::Error>) + Sync
+{
   use std::net::TcpListener;
   use std::collections::VecDeque;
   
-  let ip_port = format!("{}:{}", config.server_ip, config.server_port);
+  let ip_port = format!("{}:{}", config.server_ip, port);
   if !config.server_extra_quiet {
-    println!("tcp starting on {}", &ip_port);
+    println!("{} starting on {}", transport, &ip_port);
   }
   
   match TcpListener::bind(&ip_port) {
@@ -98,8 +207,9 @@ pub fn run_tcp_sync(config: &Config, data: &Data) {
         handlers.reserve_exact(config.server_threads_in_flight + 4);
         
         for stream in listener.incoming() {
-          handlers.push_back(s.spawn(|_| {
-            handle_tcp_conn(stream, config, data);
+          let handle_conn = &handle_conn;
+          handlers.push_back(s.spawn(move |_| {
+            handle_conn(stream);
           }));
           // Housekeeping
           if handlers.len() > config.server_threads_in_flight {
@@ -123,7 +233,7 @@ pub fn run_tcp_sync(config: &Config, data: &Data) {
           if data.exit_flag.load(Ordering::Relaxed) {
             if config.is_debug() {
               if !config.server_extra_quiet {
-                println!("tcp exiting due to data.exit_flag");
+                println!("{} exiting due to data.exit_flag", transport);
               }
             }
             break;
@@ -139,7 +249,7 @@ pub fn run_tcp_sync(config: &Config, data: &Data) {
       }).unwrap();
     }
     Err(e) => {
-      println!("Error starting TCP server: {}", e);
+      println!("Error starting {} server: {}", transport.to_uppercase(), e);
     }
   }
 }
@@ -334,21 +444,36 @@ pub fn run_unix_sync(config: &Config, data: &Data) {
 
 fn handle_udp_conn(socket: &mut std::net::UdpSocket, src: std::net::SocketAddr, mut packet: Vec<u8>, config: &Config, data: &Data) {
   
-  // UDP assumes every packet is a single CBOR message which is allowed to end in 0xff
-  if packet.last().eq(&Some(&0xff)) {
-    packet.pop();
-  }
-  if packet.len() < 1 {
+  if packet.len() < 1 || packet == [0xff] {
     return; // Do nothing, likely a stray 0xff that got put in a 2nd packet
   }
   
+  // UDP assumes every packet is a single message, legacy clients may
+  // leave off the trailing 0xff and send it in a second packet.
+  let mut decoder = FrameDecoder::new();
+  if packet[0] != FRAME_MAGIC[0] && packet.last() != Some(&0xff) {
+    packet.push(0xff);
+  }
+  decoder.push(&packet[..]);
+  let framing = decoder.framing().unwrap_or(Framing::Legacy);
+  
   // Parse bytes to WireData
-  match serde_cbor::from_slice::<WireData>(&packet[..]) {
+  match decoder.next_frame() {
     Err(e) => {
       println!("Error reading WireData from UDP client: {}", e);
       return;
     }
-    Ok(wire_data) => {
+    Ok(None) => {
+      println!("Error reading WireData from UDP client: {}", FrameError::Truncated(packet.len()));
+      return;
+    }
+    Ok(Some(wire_data)) => {
+        
+        // Beacons are remembered with the address they came from and not answered
+        if wire_data.action == Action::announce {
+          discovery::hear(wire_data, src, data);
+          return;
+        }
         
         // Create channel to do business logic
         let (to_business_logic, from_us) = mpsc::channel();
@@ -370,17 +495,13 @@ fn handle_udp_conn(socket: &mut std::net::UdpSocket, src: std::net::SocketAddr,
             loop {
               match from_business_logic.recv() {
                 Ok(wire_data_to_client) => {
-                  if let Ok(bytes) = serde_cbor::to_vec(&wire_data_to_client) {
+                  if let Ok(bytes) = encode(&wire_data_to_client, framing) {
                     if let Ok(stream) = ts_socket.lock() {
+                      // One datagram per message, including the frame header or 0xff terminator
                       if let Err(e) = stream.send_to(&bytes, &src) {
                         println!("Error sending result to UDP client: {}", e);
                         break; // stop querying, client has likely exited
                       }
-                      // Write packet seperation byte
-                      if let Err(e) = stream.send_to(&[0xff], &src) {
-                        println!("Error sending result to UDP client: {}", e);
-                        break; // stop querying, client has likely exited
-                      }
                     }
                   }
                 }
@@ -413,197 +534,185 @@ fn handle_udp_conn(socket: &mut std::net::UdpSocket, src: std::net::SocketAddr,
 fn handle_tcp_conn(stream: Result<std::net::TcpStream, std::io::Error>, config: &Config, data: &Data) {
   use std::time::Duration;
   
-  if let Ok(mut stream) = stream {
+  if let Ok(stream) = stream {
     if let Err(e) = stream.set_read_timeout(Some(Duration::from_millis(256))) {
       println!("Error setting TCP read timeout: {}", e);
     }
     if let Err(e) = stream.set_write_timeout(Some(Duration::from_millis(256))) {
       println!("Error setting TCP write timeout: {}", e);
     }
-    // Clients will send a WireData object and then 0xff ("break" stop code in the CBOR spec (rfc 7049))
-    let mut complete_buff: Vec<u8> = vec![];
-    let mut buff = [0; 4 * 1024];
-    while ! complete_buff.contains(&0xff) {
-      match stream.read(&mut buff) {
-        Ok(num_read) => {
-          complete_buff.extend_from_slice(&buff[0..num_read]);
-        }
-        Err(e) => {
-          println!("Error reading TCP data from client: {}", e);
-          return;
-        }
+    handle_stream_conn(stream, "TCP", config, data);
+  }
+}
+
+fn handle_tls_conn(stream: Result<std::net::TcpStream, std::io::Error>, acceptor: &SslAcceptor, config: &Config, data: &Data) {
+  use std::time::Duration;
+  
+  if let Ok(stream) = stream {
+    match tls::accept(acceptor, stream, Some(Duration::from_millis(256))) {
+      Ok(stream) => {
+        handle_stream_conn(stream, "TLS", config, data);
       }
-    }
-    // Remove last 0xff byte from complete_buff
-    if complete_buff.last().eq(&Some(&0xff)) {
-      complete_buff.pop();
-    }
-    
-    // Parse bytes to WireData
-    match serde_cbor::from_slice::<WireData>(&complete_buff[..]) {
       Err(e) => {
-        println!("Error reading WireData from TCP client: {}", e);
-        return;
+        if config.is_debug() && !config.server_extra_quiet {
+          println!("Error accepting TLS client: {}", e);
+        }
       }
-      Ok(wire_data) => {
-        
-        // Create channel to do business logic
-        let (to_business_logic, from_us) = mpsc::channel();
-        let (to_us, from_business_logic) = mpsc::channel();
-        let validity_flag = Arc::new(Mutex::new(AtomicBool::new(true)));
-        
-        thread::scope(|s| {
-          let handler_validity_flag_c = validity_flag.clone();
-          let bt = s.spawn(|_| {
-            handle_conn(from_us, to_us, config, data, handler_validity_flag_c);
-          });
-          
-          let client_to_business_t = s.spawn(move |_| {
-            to_business_logic.send(wire_data).unwrap();
-          });
-          
-          let ts_stream = Arc::new(Mutex::new(stream));
-          let business_to_client_t = s.spawn(move |_| {
-            loop {
-              match from_business_logic.recv() {
-                Ok(wire_data_to_client) => {
-                  if let Ok(mut stream) = ts_stream.lock() {
-                    if let Ok(bytes) = serde_cbor::to_vec(&wire_data_to_client) {
-                      if let Err(e) = stream.write(&bytes) {
-                        println!("Error sending result to TCP client: {}", e);
-                        break; // stop sending, client has likely exited
-                      }
-                      // Write packet seperation byte
-                      if let Err(e) = stream.write(&[0xff]) {
-                        println!("Error sending result to TCP client: {}", e);
-                        break; // stop sending, client has likely exited
-                      }
-                    }
-                  }
-                }
-                Err(_e) => {
-                  //println!("Error in handle_tcp_conn looping business back to client: {}", e); // Always channel closed error
-                  break;
-                }
-              }
+    }
+  }
+}
+
+fn handle_wss_conn(stream: Result<std::net::TcpStream, std::io::Error>, acceptor: &SslAcceptor, config: &Config, data: &Data) {
+  use websocket::sync::server::IntoWs;
+  
+  if let Ok(stream) = stream {
+    match tls::accept(acceptor, stream, None) {
+      Ok(stream) => {
+        match stream.into_ws() {
+          Ok(upgrade) => match upgrade.accept() {
+            Ok(client) => {
+              handle_websocket_conn(client, config, data);
             }
-            // Any listener/future logic on this connection is now invalid
-            match validity_flag.lock() {
-              Ok(mut validity_flag) => {
-                *validity_flag.get_mut() = false;
-              }
-              Err(e) => {
-                println!("Error validity_flag.lock() = {}", e);
-              }
+            Err((_stream, e)) => {
+              println!("Error accepting websocket: {:?}", e);
             }
-            data.trim_invalid_listeners();
-          });
-          
-          if let Err(e) = bt.join() {
-            println!("Error joining thread: {:?}", e);
+          },
+          Err((_stream, _request, _buffer, e)) => {
+            println!("Error accepting websocket: {:?}", e);
           }
-          if let Err(e) = client_to_business_t.join() {
-            println!("Error joining thread: {:?}", e);
-          }
-          if let Err(e) = business_to_client_t.join() {
-            println!("Error joining thread: {:?}", e);
-          }
-        }).unwrap();
+        }
+      }
+      Err(e) => {
+        if config.is_debug() && !config.server_extra_quiet {
+          println!("Error accepting TLS client: {}", e);
+        }
       }
     }
-    
   }
 }
 
 #[cfg(unix)]
 fn handle_unix_conn(stream: Result<std::os::unix::net::UnixStream, std::io::Error>, config: &Config, data: &Data) {
-  if let Ok(mut stream) = stream {
-    // Clients will send a WireData object and then 0xff ("break" stop code in the CBOR spec (rfc 7049))
-    let mut complete_buff: Vec<u8> = vec![];
-    let mut buff = [0; 4 * 1024];
-    while ! complete_buff.contains(&0xff) {
-      match stream.read(&mut buff) {
-        Ok(num_read) => {
-          complete_buff.extend_from_slice(&buff[0..num_read]);
-        }
-        Err(e) => {
-          println!("Error reading TCP data from client: {}", e);
-          return;
-        }
-      }
+  if let Ok(stream) = stream {
+    handle_stream_conn(stream, "Unix", config, data);
+  }
+}
+
+// Streams which can be read from one thread while another writes to them
+trait CloneStream: Sized {
+  fn try_clone_stream(&self) -> std::io::Result<Self>;
+}
+
+impl CloneStream for std::net::TcpStream {
+  fn try_clone_stream(&self) -> std::io::Result<Self> {
+    self.try_clone()
+  }
+}
+
+impl CloneStream for TlsStream {
+  fn try_clone_stream(&self) -> std::io::Result<Self> {
+    Ok(self.clone())
+  }
+}
+
+#[cfg(unix)]
+impl CloneStream for std::os::unix::net::UnixStream {
+  fn try_clone_stream(&self) -> std::io::Result<Self> {
+    self.try_clone()
+  }
+}
+
+// Shared by every byte-stream transport. The first bytes a client sends
+// determine the framing we reply with (see framing.rs).
+fn handle_stream_conn<S: Read + Write + Send + CloneStream>(mut stream: S, transport: &str, config: &Config, data: &Data) {
+  let mut decoder = FrameDecoder::new();
+  let wire_data = match read_frame(&mut stream, &mut decoder) {
+    Ok(Some(wire_data)) => wire_data,
+    Ok(None) => {
+      return; // Client connected and left without sending anything
     }
-    // Remove last 0xff byte from complete_buff
-    if complete_buff.last().eq(&Some(&0xff)) {
-      complete_buff.pop();
+    Err(e) => {
+      println!("Error reading WireData from {} client: {}", transport, e);
+      return;
     }
-    
-    // Parse bytes to WireData
-    match serde_cbor::from_slice::<WireData>(&complete_buff[..]) {
+  };
+  let framing = decoder.framing().unwrap_or(Framing::Legacy);
+  
+  // Requests with an id keep the connection open for more requests
+  if wire_data.id.is_some() {
+    match stream.try_clone_stream() {
+      Ok(mut write_stream) => {
+        handle_session(
+          wire_data,
+          || read_frame(&mut stream, &mut decoder),
+          move |wire_data_to_client| {
+            write_frame(&mut write_stream, wire_data_to_client, framing).map_err(|e| format!("{}", e))
+          },
+          transport, config, data
+        );
+      }
       Err(e) => {
-        println!("Error reading WireData from TCP client: {}", e);
-        return;
+        println!("Error cloning {} stream: {}", transport, e);
       }
-      Ok(wire_data) => {
-        
-        // Create channel to do business logic
-        let (to_business_logic, from_us) = mpsc::channel();
-        let (to_us, from_business_logic) = mpsc::channel();
-        let validity_flag = Arc::new(Mutex::new(AtomicBool::new(true)));
-        
-        thread::scope(|s| {
-          let handler_validity_flag_c = validity_flag.clone();
-          let bt = s.spawn(|_| {
-            handle_conn(from_us, to_us, config, data, handler_validity_flag_c);
-          });
-          
-          let client_to_business_t = s.spawn(move |_| {
-            to_business_logic.send(wire_data).unwrap();
-          });
-          
-          let ts_stream = Arc::new(Mutex::new(stream));
-          let business_to_client_t = s.spawn(move |_| {
-            loop {
-              match from_business_logic.recv() {
-                Ok(wire_data_to_client) => {
-                  if let Ok(mut stream) = ts_stream.lock() {
-                    if let Ok(bytes) = serde_cbor::to_vec(&wire_data_to_client) {
-                      if let Err(e) = stream.write(&bytes) {
-                        println!("Error sending result to Unix client: {}", e);
-                        break; // stop sending, client has likely exited
-                      }
-                      // Write packet seperation byte
-                      if let Err(e) = stream.write(&[0xff]) {
-                        println!("Error sending result to Unix client: {}", e);
-                        break; // stop sending, client has likely exited
-                      }
-                    }
-                  }
-                }
-                Err(_e) => {
-                  //println!("Error in handle_unix_conn looping business back to client: {}", e); // Always channel closed error
-                  break;
-                }
-              }
-            }
-            // Any listener/future logic on this connection is now invalid
-            match validity_flag.lock() {
-              Ok(mut validity_flag) => {
-                *validity_flag.get_mut() = false;
-              }
-              Err(e) => {
-                println!("Error validity_flag.lock() = {}", e);
+    }
+    return;
+  }
+  
+  // Create channel to do business logic
+  let (to_business_logic, from_us) = mpsc::channel();
+  let (to_us, from_business_logic) = mpsc::channel();
+  let validity_flag = Arc::new(Mutex::new(AtomicBool::new(true)));
+  
+  thread::scope(|s| {
+    let handler_validity_flag_c = validity_flag.clone();
+    let bt = s.spawn(|_| {
+      handle_conn(from_us, to_us, config, data, handler_validity_flag_c);
+    });
+    
+    let client_to_business_t = s.spawn(move |_| {
+      to_business_logic.send(wire_data).unwrap();
+    });
+    
+    let ts_stream = Arc::new(Mutex::new(stream));
+    let business_to_client_t = s.spawn(move |_| {
+      loop {
+        match from_business_logic.recv() {
+          Ok(wire_data_to_client) => {
+            if let Ok(mut stream) = ts_stream.lock() {
+              if let Err(e) = write_frame(&mut *stream, &wire_data_to_client, framing) {
+                println!("Error sending result to {} client: {}", transport, e);
+                break; // stop sending, client has likely exited
               }
             }
-            data.trim_invalid_listeners();
-          });
-          
-          bt.join().unwrap();
-          client_to_business_t.join().unwrap();
-          business_to_client_t.join().unwrap();
-        }).unwrap();
+          }
+          Err(_e) => {
+            //println!("Error in handle_stream_conn looping business back to client: {}", e); // Always channel closed error
+            break;
+          }
+        }
+      }
+      // Any listener/future logic on this connection is now invalid
+      match validity_flag.lock() {
+        Ok(mut validity_flag) => {
+          *validity_flag.get_mut() = false;
+        }
+        Err(e) => {
+          println!("Error validity_flag.lock() = {}", e);
+        }
       }
+      data.trim_invalid_listeners();
+    });
+    
+    if let Err(e) = bt.join() {
+      println!("Error joining thread: {:?}", e);
     }
-  }
+    if let Err(e) = client_to_business_t.join() {
+      println!("Error joining thread: {:?}", e);
+    }
+    if let Err(e) = business_to_client_t.join() {
+      println!("Error joining thread: {:?}", e);
+    }
+  }).unwrap();
 }
 
 pub fn run_websocket_sync(config: &Config, data: &Data) {
@@ -670,7 +779,10 @@ pub fn run_websocket_sync(config: &Config, data: &Data) {
   }
 }
 
-fn handle_websocket_conn(client: websocket::client::sync::Client<std::net::TcpStream>, config: &Config, data: &Data) {
+fn handle_websocket_conn<S>(client: websocket::client::sync::Client<S>, config: &Config, data: &Data)
+  where S: websocket::sync::Stream + websocket::sync::stream::Splittable,
+        S::Reader: Send, S::Writer: Send
+{
   use websocket::message::OwnedMessage;
   
   let (mut receiver, sender) = client.split().unwrap();
@@ -689,6 +801,35 @@ fn handle_websocket_conn(client: websocket::client::sync::Client<std::net::TcpSt
             }
             Ok(wire_data) => {
               
+              // Requests with an id keep the connection open for more requests
+              if wire_data.id.is_some() {
+                let mut sender = sender;
+                handle_session(
+                  wire_data,
+                  || {
+                    loop {
+                      match receiver.recv_message() {
+                        Ok(OwnedMessage::Binary(buff)) => {
+                          return serde_cbor::from_slice::<WireData>(&buff[..]).map(Some).map_err(FrameError::Cbor);
+                        }
+                        Ok(OwnedMessage::Close(_)) | Err(_) => {
+                          return Ok(None); // Client disconnected
+                        }
+                        Ok(_) => {
+                          continue; // Pings and the like
+                        }
+                      }
+                    }
+                  },
+                  move |wire_data_to_client| {
+                    let bytes = serde_cbor::to_vec(wire_data_to_client).map_err(|e| format!("{}", e))?;
+                    sender.send_message(&OwnedMessage::Binary(bytes)).map_err(|e| format!("{}", e))
+                  },
+                  "WebSocket", config, data
+                );
+                return;
+              }
+              
               // Create channel to do business logic
               let (to_business_logic, from_us) = mpsc::channel();
               let (to_us, from_business_logic) = mpsc::channel();
@@ -760,6 +901,119 @@ fn handle_websocket_conn(client: websocket::client::sync::Client<std::net::TcpSt
   
 }
 
+// Runs a multiplexed connection. Requests are read until the client leaves and
+// are handled by a few worker threads so several can be in flight at once;
+// replies from all of them (and from listeners) share a single writer.
+fn handle_session<R, W>(first: WireData, mut read_next: R, mut write_next: W, transport: &str, config: &Config, data: &Data)
+  where R: FnMut() -> Result<Option<WireData>, FrameError>, W: FnMut(&WireData) -> Result<(), String> + Send
+{
+  let (to_client, from_business_logic) = mpsc::channel();
+  let (to_workers, from_reader) = mpsc::channel::<WireData>();
+  let from_reader = Arc::new(Mutex::new(from_reader));
+  let validity_flag = Arc::new(Mutex::new(AtomicBool::new(true)));
+  
+  thread::scope(|s| {
+    let writer_validity_flag = validity_flag.clone();
+    let writer_t = s.spawn(move |_| {
+      loop {
+        match from_business_logic.recv() {
+          Ok(wire_data_to_client) => {
+            if let Err(e) = write_next(&wire_data_to_client) {
+              println!("Error sending result to {} client: {}", transport, e);
+              break; // stop sending, client has likely exited
+            }
+          }
+          Err(_e) => {
+            break; // Every request and listener on this connection has finished
+          }
+        }
+      }
+      invalidate(&writer_validity_flag);
+    });
+    
+    let mut workers = vec![];
+    for _ in 0..num_cpus::get() {
+      let worker_from_reader = from_reader.clone();
+      let worker_to_client = to_client.clone();
+      let worker_validity_flag = validity_flag.clone();
+      workers.push(s.spawn(move |_| {
+        loop {
+          let next = match worker_from_reader.lock() {
+            Ok(from_reader) => from_reader.recv(),
+            Err(_e) => break,
+          };
+          match next {
+            Ok(wire_data) => {
+              handle_request(wire_data, &worker_to_client, config, data, &worker_validity_flag);
+            }
+            Err(_e) => {
+              break; // Reader has finished
+            }
+          }
+        }
+      }));
+    }
+    drop(to_client);
+    
+    if to_workers.send(first).is_ok() {
+      loop {
+        if data.exit_flag.load(Ordering::Relaxed) || ! is_valid(&validity_flag) {
+          break;
+        }
+        match read_next() {
+          Ok(Some(wire_data)) => {
+            if let Err(e) = to_workers.send(wire_data) {
+              println!("Error queueing request from {} client: {}", transport, e);
+              break;
+            }
+          }
+          Ok(None) => {
+            break; // Client closed the connection
+          }
+          Err(ref e) if e.is_timeout() => {
+            continue;
+          }
+          Err(e) => {
+            println!("Error reading WireData from {} client: {}", transport, e);
+            break;
+          }
+        }
+      }
+    }
+    drop(to_workers);
+    
+    for w in workers {
+      if let Err(e) = w.join() {
+        println!("Error joining thread: {:?}", e);
+      }
+    }
+    // Listeners on this connection go away with it, which lets the writer finish
+    invalidate(&validity_flag);
+    data.trim_invalid_listeners();
+    if let Err(e) = writer_t.join() {
+      println!("Error joining thread: {:?}", e);
+    }
+  }).unwrap();
+}
+
+fn invalidate(validity_flag: &Arc<Mutex<AtomicBool>>) {
+  match validity_flag.lock() {
+    Ok(mut validity_flag) => {
+      *validity_flag.get_mut() = false;
+    }
+    Err(e) => {
+      println!("Error validity_flag.lock() = {}", e);
+    }
+  }
+}
+
+fn is_valid(validity_flag: &Arc<Mutex<AtomicBool>>) -> bool {
+  match validity_flag.lock() {
+    Ok(validity_flag) => validity_flag.load(Ordering::SeqCst),
+    Err(_e) => false,
+  }
+}
+
 // This is a generic channel implementation so we can seperate business
 // logic from tcp/udp/unix connection details.
 fn handle_conn(from_client: mpsc::Receiver<WireData>, to_client: mpsc::Sender<WireData>, config: &Config, data: &Data, validity_flag: Arc<Mutex<AtomicBool>>) {
@@ -768,71 +1022,410 @@ fn handle_conn(from_client: mpsc::Receiver<WireData>, to_client: mpsc::Sender<Wi
       println!("Error receiving in handle_conn: {}", e);
     }
     Ok(wire_data) => {
-      if config.is_debug() && !config.server_extra_quiet {
-        println!("wire_data = {:?}", wire_data);
-      }
-      // Reject all queries and published records
-      // if the record appears signed (contains pub key || signature)
-      // but the signature is invalid.
-      if wire_data.record.is_imposter() {
-        // We _ought_ to at least let the user know.
-        // This gives visibility in the scenario where a valid user
-        // does not understand their tools.
-        let err_data = WireData {
-          action: Action::unsolicited_msg,
-          record: Record::new(h_map!{
-            "error-message".to_string() =>
-              "Error: The record received has signature keys but contains an invalid signature.".to_string()
-          }),
-        };
-        if let Err(e) = to_client.send(err_data) {
+      handle_request(wire_data, &to_client, config, data, &validity_flag);
+    }
+  }
+}
+
+// Performs a single request. Every reply is tagged with the request's id
+// so multiplexed connections can route it back to the right caller.
+fn handle_request(wire_data: WireData, to_client: &mpsc::Sender<WireData>, config: &Config, data: &Data, validity_flag: &Arc<Mutex<AtomicBool>>) {
+  if config.is_debug() && !config.server_extra_quiet {
+    println!("wire_data = {:?}", wire_data);
+  }
+  let id = wire_data.id;
+  // Reject all queries and published records
+  // if the record appears signed (contains pub key || signature)
+  // but the signature is invalid.
+  if wire_data.record.is_imposter() {
+    // We _ought_ to at least let the user know.
+    // This gives visibility in the scenario where a valid user
+    // does not understand their tools.
+    let err_data = WireData::error("bad-signature",
+      "Error: The record received has signature keys but contains an invalid signature.");
+    if let Err(e) = to_client.send(err_data.with_id(id)) {
+      println!("e = {}", e);
+    }
+    return;
+  }
+  let ts_to_client = Arc::new(Mutex::new(to_client.clone()));
+  match wire_data.action {
+    Action::query => {
+      let route = federation::route(config, data, wire_data.forwarded.as_ref());
+      if route == Route::Seen {
+        // We answered it when it first reached us
+        if let Err(e) = to_client.send(WireData::end_of_results().with_id(id)) {
           println!("e = {}", e);
         }
         return;
       }
-      let ts_to_client = Arc::new(Mutex::new(to_client.clone()));
-      match wire_data.action {
-        Action::query => {
-          data.search_callback(&wire_data.record.create_regex_map(), |result| {
-            let wire_data = WireData {
-              action: Action::result,
-              record: result.clone(),
-            };
-            if let Ok(to_client) = ts_to_client.lock() {
-              to_client.send(wire_data).unwrap();
+      let query = match compile_query(&wire_data.record, to_client, data, id) {
+        Some(query) => query,
+        None => return,
+      };
+      let options = wire_data.options.clone().unwrap_or_default();
+      let forwarded = match route {
+        Route::Forward(forwarded) => Some(forwarded),
+        _ => None,
+      };
+      // Full-text results are ranked, which needs all of them first
+      if options.is_paged() || query.text.is_some() {
+        if let (Some(forwarded), None) = (&forwarded, &options.cursor) {
+          send_federated_page(&wire_data, &query, &options, forwarded, to_client, config, data);
+          return;
+        }
+        match data.search_page(&query, &options) {
+          Ok((results, next_cursor)) => {
+            for result in results {
+              if let Err(e) = to_client.send(WireData::result(options.project(&result)).with_id(id)) {
+                println!("e = {}", e);
+                return;
+              }
             }
-            return true; // TODO limit when using UDP?
-          });
-          // Tell clients connection should be closed
-          let wire_data = WireData {
-            action: Action::end_of_results,
-            record: Record::empty()
-          };
+            if let Err(e) = to_client.send(WireData::end_of_page(next_cursor).with_id(id)) {
+              println!("e = {}", e);
+            }
+          }
+          Err(msg) => {
+            if let Err(e) = to_client.send(WireData::error("bad-cursor", &msg).with_id(id)) {
+              println!("e = {}", e);
+            }
+          }
+        }
+      }
+      else {
+        let options = &options;
+        // Ids of results sent, when federated servers may send them again
+        let sent = Mutex::new(HashSet::new());
+        let sent = &sent;
+        let federated = forwarded.is_some();
+        data.search_callback(&query, |result| {
+          if federated {
+            if let Ok(mut sent) = sent.lock() {
+              sent.insert(result.id());
+            }
+          }
+          let wire_data = WireData::result(options.project(result)).with_id(id);
           if let Ok(to_client) = ts_to_client.lock() {
             to_client.send(wire_data).unwrap();
           }
+          return true;
+        });
+        if let Some(forwarded) = &forwarded {
+          // Projected here so ids match those of our own results
+          let upstream_options = QueryOptions { fields: None, ..options.clone() };
+          federation::query_sync(config, &wire_data.record, &upstream_options, forwarded, |result| {
+            let is_new = sent.lock().map(|mut sent| sent.insert(result.id())).unwrap_or(false);
+            if is_new {
+              if let Ok(to_client) = ts_to_client.lock() {
+                if let Err(e) = to_client.send(WireData::result(options.project(&result)).with_id(id)) {
+                  println!("e = {}", e);
+                }
+              }
+            }
+          });
         }
-        Action::publish => {
-          if ! wire_data.record.is_empty() {
-            data.insert(wire_data.record);
-          }
-          // For now just dump entire Data to storage whenever something is added
-          // TODO optimize etc etc
-          write_stored_records(config, &data);
+        // Tell clients connection should be closed
+        let wire_data = WireData::end_of_results().with_id(id);
+        if let Ok(to_client) = ts_to_client.lock() {
+          to_client.send(wire_data).unwrap();
         }
-        Action::listen => {
-          data.listen(Listener::new(
-            &wire_data.record,
-            to_client,
-            validity_flag.clone()
-          ));
+      }
+    }
+    Action::count => {
+      let query = match compile_query(&wire_data.record, to_client, data, id) {
+        Some(query) => query,
+        None => return,
+      };
+      let group_by = wire_data.options.as_ref().and_then(|options| options.group_by.as_ref());
+      let counts = data.count(&query, group_by.map(|key| key.as_str()));
+      if let Err(e) = to_client.send(WireData::result(counts.to_record()).with_id(id)) {
+        println!("e = {}", e);
+        return;
+      }
+      if let Err(e) = to_client.send(WireData::end_of_results().with_id(id)) {
+        println!("e = {}", e);
+      }
+    }
+    Action::publish => {
+      if wire_data.record.is_empty() {
+        if let Err(e) = to_client.send(WireData::error("empty-record", "Error: refusing to store an empty record").with_id(id)) {
+          println!("e = {}", e);
         }
-        unk => {
-          println!("Error: unknown action {}", unk);
-          return;
+        return;
+      }
+      let record_id = wire_data.record.id();
+      if ! data.insert(wire_data.record) {
+        if let Err(e) = to_client.send(WireData::error("not-stored", "Error: the server did not store the record (it may have already expired)").with_id(id)) {
+          println!("e = {}", e);
         }
+        return;
+      }
+      // .json stores are rewritten entirely here, .log and sqlite://
+      // stores were already given the new record by Data::insert.
+      write_stored_records(config, &data);
+      // Clients using length-prefixed framing wait for a reply, which is how
+      // they tell us apart from servers that only understand 0xff framing.
+      if let Err(e) = to_client.send(WireData::ack(&record_id).with_id(id)) {
+        println!("e = {}", e);
       }
     }
+    Action::delete | Action::update => {
+      modify_records(wire_data, to_client, config, data);
+    }
+    Action::listen => {
+      let route = federation::route(config, data, wire_data.forwarded.as_ref());
+      if route == Route::Seen {
+        // Already listening for it through another path
+        if let Err(e) = to_client.send(WireData::end_of_results().with_id(id)) {
+          println!("e = {}", e);
+        }
+        return;
+      }
+      let query = match compile_query(&wire_data.record, to_client, data, id) {
+        Some(query) => query,
+        None => return,
+      };
+      let mut listener = Listener::new(
+        query,
+        id,
+        to_client.clone(),
+        validity_flag.clone()
+      );
+      let replay = wire_data.options.as_ref().map(|options| options.replay).unwrap_or(false);
+      if let Route::Forward(forwarded) = &route {
+        listener.sent = Some(Arc::new(Mutex::new(RecentIds::new(LISTEN_IDS_REMEMBERED))));
+        if replay {
+          // Us and each server we forward to
+          listener.replay_gate = Some(Arc::new(ReplayGate::new(config.servers.len() + 1, to_client.clone(), id)));
+        }
+        federation::listen(config, &wire_data.record, wire_data.options.as_ref(), forwarded, &listener);
+      }
+      if replay {
+        data.listen_with_replay(listener);
+      }
+      else {
+        data.listen(listener);
+      }
+    }
+    Action::cancel => {
+      // The listener replies with its own end_of_results
+      let cancelled = match id {
+        Some(id) => data.cancel_listener(validity_flag, id),
+        None => false,
+      };
+      if ! cancelled {
+        if let Err(e) = to_client.send(WireData::error("unknown-request", "Error: no request with that id to cancel").with_id(id)) {
+          println!("e = {}", e);
+        }
+      }
+    }
+    Action::hello => {
+      // Reply with everything we support, the client picks from it
+      if let Err(e) = to_client.send(WireData::hello(Capabilities::ours()).with_id(id)) {
+        println!("e = {}", e);
+        return;
+      }
+      if let Err(e) = to_client.send(WireData::end_of_results().with_id(id)) {
+        println!("e = {}", e);
+      }
+    }
+    Action::sync => {
+      replication::answer(&wire_data, to_client, data);
+    }
+    Action::discover => {
+      discovery::answer(&wire_data, to_client, config, data);
+    }
+    Action::announce => {
+      // Only UDP beacons are remembered, see handle_udp_conn
+    }
+    unk => {
+      // Unknown numbers arrive as no_action, see actions::action_from_u8
+      let msg = if unk == Action::no_action {
+        "Error: unknown action".to_string()
+      } else {
+        format!("Error: servers do not accept the action {}", unk)
+      };
+      if config.is_debug() && !config.server_extra_quiet {
+        println!("{}", msg);
+      }
+      if let Err(e) = to_client.send(WireData::error("unknown-action", &msg).with_id(id)) {
+        println!("e = {}", e);
+      }
+    }
+  }
+}
+
+// Answers a paged or full-text query from our records and those of the
+// servers we federate with, merged the way clients merge them. There is
+// no next-cursor as a cursor could only continue our share of the results.
+fn send_federated_page(wire_data: &WireData, query: &Query, options: &QueryOptions, forwarded: &Forwarded, to_client: &mpsc::Sender<WireData>, config: &Config, data: &Data) {
+  let id = wire_data.id;
+  let offset = options.offset.unwrap_or(0);
+  // The page is within the first offset + limit results of every server
+  let upstream_options = QueryOptions {
+    offset: None,
+    limit: options.limit.map(|limit| limit + offset),
+    fields: None,
+    ..options.clone()
+  };
+  let results = match data.search_page(query, &upstream_options) {
+    Ok((results, _next_cursor)) => results,
+    Err(_) => vec![], // Only cursors fail, and we have none
+  };
+  let results = Mutex::new(results);
+  federation::query_sync(config, &wire_data.record, &upstream_options, forwarded, |result| {
+    if let Ok(mut results) = results.lock() {
+      results.push(result);
+    }
+  });
+  // Our copy of a record comes first and is the one kept
+  let mut seen = HashSet::new();
+  let results: Vec<Record> = results.into_inner().unwrap_or(vec![]).into_iter()
+    .filter(|result| seen.insert(result.id()))
+    .collect();
+  for result in client::merge_records(&upstream_options, results).into_iter().skip(offset) {
+    if let Err(e) = to_client.send(WireData::result(options.project(&result)).with_id(id)) {
+      println!("e = {}", e);
+      return;
+    }
+  }
+  if let Err(e) = to_client.send(WireData::end_of_page(None).with_id(id)) {
+    println!("e = {}", e);
   }
 }
 
+// The compiled form of a query from a client, or None once the client has
+// been sent a bad-query error saying which regex or expression was rejected.
+fn compile_query(query: &Record, to_client: &mpsc::Sender<WireData>, data: &Data, id: Option<u64>) -> Option<crate::query::Query> {
+  match data.compile_query(query) {
+    Ok(query) => Some(query),
+    Err(msg) => {
+      if let Err(e) = to_client.send(WireData::error("bad-query", &msg).with_id(id)) {
+        println!("e = {}", e);
+      }
+      None
+    }
+  }
+}
+
+// The public key which signed auth, if it authorizes exactly wire_data
+fn check_authorization(auth: &Record, wire_data: &WireData, target: &Target, data: &Data) -> Result<String, (&'static str, &'static str)> {
+  if ! auth.is_signed() {
+    return Err(("bad-signature", "Error: the authorization is not validly signed"));
+  }
+  let get = |key: &str| auth.p.get(key).map(|s| s.as_str()).unwrap_or("");
+  if get(AUTH_ACTION_KEY) != format!("{}", wire_data.action) {
+    return Err(("bad-authorization", "Error: the authorization was signed for a different action"));
+  }
+  if get(AUTH_TARGET_KEY) != target.describe() {
+    return Err(("bad-authorization", "Error: the authorization was signed for a different target"));
+  }
+  if wire_data.action == Action::update && get(AUTH_RECORD_ID_KEY) != wire_data.record.id() {
+    return Err(("bad-authorization", "Error: the authorization was signed for a different record"));
+  }
+  let now = now_ms();
+  match get(AUTH_ISSUED_AT_KEY).parse::<u64>() {
+    Ok(issued_at) if issued_at.max(now) - issued_at.min(now) <= AUTH_MAX_AGE_MS => { }
+    _ => {
+      return Err(("expired-authorization", "Error: the authorization is too old, or from a clock too far from ours"));
+    }
+  }
+  if get(AUTH_NONCE_KEY).is_empty() || ! data.first_use_of_nonce(get(AUTH_NONCE_KEY)) {
+    return Err(("replayed-authorization", "Error: the authorization was already used"));
+  }
+  return Ok(get(SIGNING_PUB_KEY_KEY).to_string());
+}
+
+// Deletes or replaces the records selected by wire_data.target.
+// Unsigned records may be changed by anyone. Signed records may only be changed
+// by a request whose authorization is signed with the same public key, or with
+// a key listed in server_trusted_keys_file. See Target::authorization.
+fn modify_records(wire_data: WireData, to_client: &mpsc::Sender<WireData>, config: &Config, data: &Data) {
+  use crate::query::Query;
+  
+  let id = wire_data.id;
+  let reply = |reply: WireData| {
+    if let Err(e) = to_client.send(reply.with_id(id)) {
+      println!("e = {}", e);
+    }
+  };
+  
+  let target = match &wire_data.target {
+    Some(target) if ! target.is_empty() => target.clone(),
+    _ => {
+      reply(WireData::error("missing-target", "Error: delete and update need a target id or query"));
+      return;
+    }
+  };
+  let is_update = wire_data.action == Action::update;
+  if is_update && wire_data.record.is_empty() {
+    reply(WireData::error("empty-record", "Error: refusing to store an empty record"));
+    return;
+  }
+  
+  let (requester_key, requester_trusted) = match &wire_data.authorization {
+    None => (None, false),
+    Some(auth) => match check_authorization(auth, &wire_data, &target, data) {
+      Ok(key) => (Some(key), auth.is_auth_by_server(config)),
+      Err((code, msg)) => {
+        reply(WireData::error(code, msg));
+        return;
+      }
+    },
+  };
+  
+  let query = match &target.query {
+    Some(query) => match data.compile_query(query) {
+      Ok(query) => query,
+      Err(msg) => {
+        reply(WireData::error("bad-query", &msg));
+        return;
+      }
+    },
+    None => Query::default(),
+  };
+  let mut num_denied = 0;
+  let removed = data.remove(|rec| {
+    if ! target.matches(rec, &query) {
+      return false;
+    }
+    let may_modify = match rec.p.get(SIGNING_PUB_KEY_KEY) {
+      None => true,
+      Some(owner_key) => requester_trusted || requester_key.as_ref() == Some(owner_key),
+    };
+    if ! may_modify {
+      num_denied += 1;
+    }
+    return may_modify;
+  });
+  
+  if removed.is_empty() {
+    if num_denied > 0 {
+      reply(WireData::error("not-owner", &format!("Error: {} matching records are signed by another key", num_denied)));
+    }
+    else {
+      reply(WireData::error("not-found", "Error: no records matched the target"));
+    }
+    return;
+  }
+  
+  // Peers remove them too, unless an update stored the same record again
+  let deleted: Vec<Record> = removed.iter().filter(|rec| !is_update || rec.id() != wire_data.record.id()).cloned().collect();
+  data.remember_deletes(&deleted, &target, wire_data.authorization.as_ref());
+  
+  let mut ack_rec = Record::new(h_map!{
+    "removed".to_string() => format!("{}", removed.len()),
+    "denied".to_string() => format!("{}", num_denied)
+  });
+  if is_update {
+    ack_rec.p.insert("record-id".to_string(), wire_data.record.id());
+    if ! data.insert(wire_data.record) {
+      write_stored_records(config, &data);
+      reply(WireData::error("not-stored", &format!("Error: removed {} records but did not store their replacement", removed.len())));
+      return;
+    }
+  }
+  write_stored_records(config, &data);
+  reply(WireData::new(Action::ack, ack_rec));
+}
@@ -19,16 +19,40 @@
 use url::{Url};
 
 use std::fs::File;
-use std::io::prelude::*;
+use std::io::{self, prelude::*};
+use std::sync::Mutex;
 
 use crate::config::Config;
 use crate::data::Data;
-use crate::record::Record;
+use crate::record::{Record, now_ms};
+use crate::record_log::RecordLog;
+use crate::record_sqlite::SqliteStore;
+
+// A datastore which Data keeps up to date one change at a time, rather
+// than write_stored_records rewriting everything after each change.
+// Data calls these while holding the store's lock, in the order
+// records are inserted into and removed from memory.
+pub trait RecordStore: Send {
+  fn insert(&mut self, rec: &Record);
+  fn remove(&mut self, rec: &Record);
+  // Stores which accumulate dead entries may ask to be rewritten
+  fn should_compact(&self, _num_live_records: usize) -> bool {
+    return false;
+  }
+  fn compact(&mut self, _records: &[Record]) -> io::Result<()> {
+    return Ok(());
+  }
+  // Used in error messages
+  fn describe(&self) -> String;
+}
 
 pub fn read_stored_records(config: &Config, data: &mut Data) {
   let uri_s = &config.server_datastore_uri;
   if let Ok(uri) = Url::parse(uri_s) {
     match uri.scheme() {
+      "file" if uri.path().ends_with(".log") => {
+        read_stored_records_log_file(uri.path(), data);
+      }
       "file" => {
         let path = uri.path();
         // This function will return an error if path does not already exist.
@@ -41,6 +65,9 @@ pub fn read_stored_records(config: &Config, data: &mut Data) {
           }
         }
       }
+      "sqlite" => {
+        read_stored_records_sqlite(sqlite_path(uri_s), data);
+      }
       "memory" => {
         // This specifies that the server should not read anything in,
         // but instead use memory only to store records.
@@ -59,6 +86,9 @@ pub fn write_stored_records(config: &Config, data: &Data) {
   let uri_s = &config.server_datastore_uri;
   if let Ok(uri) = Url::parse(uri_s) {
     match uri.scheme() {
+      "file" if uri.path().ends_with(".log") => {
+        // Data appends every change to the log as it happens
+      }
       "file" => {
         let path = uri.path();
         // This function will create nonexisting files, and truncate existing files when data is written
@@ -71,6 +101,9 @@ pub fn write_stored_records(config: &Config, data: &Data) {
           }
         }
       }
+      "sqlite" => {
+        // Data writes each change to the database as it happens
+      }
       "memory" => {
         // This specifies that the server should not store records,
         // but instead use memory only to store records.
@@ -99,15 +132,66 @@ pub fn read_stored_records_json_file(mut json_f: File, data: &mut Data) {
   }
 }
 
+pub fn read_stored_records_log_file(path: &str, data: &mut Data) {
+  match RecordLog::open(path) {
+    Ok((log, records)) => {
+      // data.log is set afterwards so replayed records are not appended again
+      for rec in records {
+        data.insert(rec);
+      }
+      data.store = Some(Mutex::new(Box::new(log)));
+      // Records may have expired or been evicted during replay
+      data.compact_store();
+    }
+    Err(e) => {
+      println!("Error opening record log {}: {}", path, e);
+    }
+  }
+}
+
+// sqlite:///tmp/db.sqlite is an absolute path, sqlite://db.sqlite a relative one
+fn sqlite_path(uri_s: &str) -> &str {
+  return &uri_s["sqlite://".len()..];
+}
+
+pub fn read_stored_records_sqlite(path: &str, data: &mut Data) {
+  match SqliteStore::open(path) {
+    Ok(store) => {
+      // data.store is set afterwards so loaded records are not written back
+      let loaded = store.for_each_record(now_ms(), |rec| {
+        data.insert(rec);
+      });
+      data.store = Some(Mutex::new(Box::new(store)));
+      match loaded {
+        Ok(()) => {
+          // Records may have been evicted or refused during loading
+          data.compact_store();
+        }
+        Err(e) => {
+          // Compacting would delete the rows which were not read
+          println!("Error reading records from sqlite database {}: {}", path, e);
+        }
+      }
+    }
+    Err(e) => {
+      println!("Error opening sqlite database {}: {}", path, e);
+    }
+  }
+}
+
 pub fn write_stored_records_json_file(mut json_f: File, data: &Data) {
   // TODO can we serialize without cloning everything OR without locking everything?
   let mut records = vec![];
+  let now = now_ms();
   for pool in data.record_pools.iter() {
     let read_retries = 5;
     for _ in 0..read_retries {
       if let Ok(pool) = pool.try_read() {
-        for rec in pool.iter() {
-          records.push(rec.clone());
+        for stored in pool.iter() {
+          if stored.is_expired(now) {
+            continue;
+          }
+          records.push(stored.rec.clone());
         }
         break;
       }
@@ -19,26 +19,71 @@
 use openssl;
 use openssl::sign::{Signer, Verifier};
 use openssl::rsa::Rsa;
-use openssl::pkey::{PKey, Private, Public};
+use openssl::pkey::{PKey, Id, Private, Public};
 use openssl::hash::MessageDigest;
 
 use base64;
 
 use std::fs;
 use std::path::Path;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::SystemTime;
 
 use crate::config::Config;
-use crate::record::Record;
+use crate::record::{Record, TTL_EXPIRES_AT_KEY};
+use crate::query::TEXT_SCORE_KEY;
 
 // Reserved key, holds base64 public key
 pub const SIGNING_PUB_KEY_KEY: &str = "SIGNING:public-key";
 // Reserved key, holds base64 signature of non_sig_bytes() for a record
 pub const SIGNING_NON_SIG_BYTES_KEY: &str = "SIGNING:non-sig-bytes";
+// Reserved key, holds the KeyType name of SIGNING:public-key. Records without
+// it are RSA signed, which keeps them verifiable by servers predating Ed25519.
+pub const SIGNING_KEY_TYPE_KEY: &str = "SIGNING:key-type";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyType {
+  // SIGNING:public-key is base64 of a PEM public key,
+  // signatures are RSA_PKCS1_SHA256
+  Rsa,
+  // SIGNING:public-key is base64 of the raw 32 byte public key
+  Ed25519,
+}
+
+impl KeyType {
+  pub fn from_str(s: &str) -> Option<KeyType> {
+    match s.to_lowercase().as_str() {
+      "rsa" => Some(KeyType::Rsa),
+      "ed25519" => Some(KeyType::Ed25519),
+      _ => None,
+    }
+  }
+  pub fn name(&self) -> &'static str {
+    match self {
+      KeyType::Rsa => "rsa",
+      KeyType::Ed25519 => "ed25519",
+    }
+  }
+  // The type a signed record says its key is
+  pub fn of_record(rec: &Record) -> Option<KeyType> {
+    match rec.p.get(SIGNING_KEY_TYPE_KEY) {
+      Some(name) => KeyType::from_str(name),
+      None => Some(KeyType::Rsa),
+    }
+  }
+}
 
 pub fn gen_identity(output_file: &str) {
-  let rsa = Rsa::generate(2048).unwrap();
-  match rsa.private_key_to_pem() {
+  gen_identity_of_type(output_file, KeyType::Rsa);
+}
+
+pub fn gen_identity_of_type(output_file: &str, key_type: KeyType) {
+  let pem = match key_type {
+    KeyType::Rsa => Rsa::generate(2048).and_then(|rsa| rsa.private_key_to_pem()),
+    KeyType::Ed25519 => PKey::generate_ed25519().and_then(|key| key.private_key_to_pem_pkcs8()),
+  };
+  match pem {
     Ok(priv_pem_bytes) => {
       if let Err(e) = fs::write(output_file, priv_pem_bytes) {
         println!("Error writing identity file: {}", e);
@@ -58,18 +103,11 @@ pub fn maybe_sign_record(config: &Config, rec: &mut Record) {
   
   match read_file(&Path::new(&identity_file_path)) {
     Ok(identity_file_bytes) => {
-      match try_parse_rsa(&identity_file_bytes) {
-        Ok(rsa_pair) => {
-          match PKey::from_rsa(rsa_pair) {
-            Ok(generic_key_pair) => {
-              sign_rec(&generic_key_pair, rec);
-              if config.is_debug() && !config.server_extra_quiet {
-                println!("Record after signing: {:?}", rec.p);
-              }
-            }
-            Err(e) => {
-              println!("Error making RSA keys generic: {}", e);
-            }
+      match try_parse_private(&identity_file_bytes) {
+        Ok(key_pair) => {
+          sign_rec(&key_pair, rec);
+          if config.is_debug() && !config.server_extra_quiet {
+            println!("Record after signing: {:?}", rec.p);
           }
         }
         Err(e) => {
@@ -87,16 +125,9 @@ pub fn maybe_sign_record(config: &Config, rec: &mut Record) {
 pub fn read_pub_key_base64(identity_file_path: &str) -> String {
   match read_file(&Path::new(identity_file_path)) {
     Ok(identity_file_bytes) => {
-      match try_parse_rsa(&identity_file_bytes) {
-        Ok(rsa_pair) => {
-          match PKey::from_rsa(rsa_pair) {
-            Ok(generic_key_pair) => {
-              return base64::encode(&generic_key_pair.public_key_to_pem().unwrap_or(vec![]));
-            }
-            Err(e) => {
-              println!("Error making RSA keys generic: {}", e);
-            }
-          }
+      match try_parse_private(&identity_file_bytes) {
+        Ok(key_pair) => {
+          return public_key_base64(&key_pair);
         }
         Err(e) => {
           println!("Error parsing identity file: {:?}", e);
@@ -121,6 +152,42 @@ pub fn try_parse_rsa(bytes: &[u8]) -> Result<Rsa<Private>, ()> {
   Err(())
 }
 
+// An RSA or Ed25519 identity
+pub fn try_parse_private(bytes: &[u8]) -> Result<PKey<Private>, ()> {
+  if let Ok(rsa) = try_parse_rsa(bytes) {
+    return PKey::from_rsa(rsa).map_err(|_| ());
+  }
+  if let Ok(key) = PKey::private_key_from_pem(bytes) {
+    if key.id() == Id::ED25519 {
+      return Ok(key);
+    }
+  }
+  if let Ok(key) = PKey::private_key_from_der(bytes) {
+    if key.id() == Id::ED25519 {
+      return Ok(key);
+    }
+  }
+  
+  Err(())
+}
+
+pub fn key_type_of<T>(key: &PKey<T>) -> Option<KeyType> {
+  match key.id() {
+    Id::RSA => Some(KeyType::Rsa),
+    Id::ED25519 => Some(KeyType::Ed25519),
+    _ => None,
+  }
+}
+
+// The SIGNING:public-key value for key_pair
+pub fn public_key_base64(key_pair: &PKey<Private>) -> String {
+  let bytes = match key_type_of(key_pair) {
+    Some(KeyType::Ed25519) => key_pair.raw_public_key(),
+    _ => key_pair.public_key_to_pem(),
+  };
+  return base64::encode(&bytes.unwrap_or(vec![]));
+}
+
 pub fn try_parse_rsa_pub(bytes: &[u8]) -> Result<Rsa<Public>, ()> {
   if let Ok(ret) = Rsa::public_key_from_pem(bytes) {
     return Ok(ret);
@@ -138,8 +205,14 @@ pub fn try_parse_rsa_pub(bytes: &[u8]) -> Result<Rsa<Public>, ()> {
 fn sign_rec(keypair: &PKey<Private>, rec: &mut Record) {
   let mut signatures: HashMap<String, String> = HashMap::new();
   
-  signatures.insert(SIGNING_PUB_KEY_KEY.to_string(), base64::encode(&keypair.public_key_to_pem().unwrap()));
+  signatures.insert(SIGNING_PUB_KEY_KEY.to_string(), public_key_base64(keypair));
   signatures.insert(SIGNING_NON_SIG_BYTES_KEY.to_string(), sign_nonsig_bytes(keypair, rec));
+  if let Some(KeyType::Ed25519) = key_type_of(keypair) {
+    signatures.insert(SIGNING_KEY_TYPE_KEY.to_string(), KeyType::Ed25519.name().to_string());
+  }
+  else {
+    rec.p.remove(SIGNING_KEY_TYPE_KEY);
+  }
   
   for (sign_key, sign_val) in signatures {
     rec.p.insert(sign_key, sign_val);
@@ -147,11 +220,17 @@ fn sign_rec(keypair: &PKey<Private>, rec: &mut Record) {
 }
 
 fn sign_nonsig_bytes(keypair: &PKey<Private>, rec: &Record) -> String {
-  let mut signer = Signer::new(MessageDigest::sha256(), &keypair).unwrap();
-  
   // Signatures are done on the key concatinated with value, in that order (non-sig key value)
-  signer.update(&non_sig_bytes(rec)).unwrap();
-  let signature = signer.sign_to_vec().unwrap();
+  let signature = if let Some(KeyType::Ed25519) = key_type_of(keypair) {
+    // Ed25519 hashes internally, so it signs the whole message at once
+    let mut signer = Signer::new_without_digest(keypair).unwrap();
+    signer.sign_oneshot_to_vec(&non_sig_bytes(rec)).unwrap()
+  }
+  else {
+    let mut signer = Signer::new(MessageDigest::sha256(), &keypair).unwrap();
+    signer.update(&non_sig_bytes(rec)).unwrap();
+    signer.sign_to_vec().unwrap()
+  };
   
   return base64::encode(&signature);
 }
@@ -169,7 +248,7 @@ pub fn has_sig_fields(rec: &Record) -> bool {
   // the possibility of a badly-configured server publishing partial
   // invalid records which are then treated has "not having sig fields"
   // when a public key is listed.
-  return rec.p.contains_key(SIGNING_PUB_KEY_KEY) || rec.p.contains_key(SIGNING_NON_SIG_BYTES_KEY);
+  return rec.p.contains_key(SIGNING_PUB_KEY_KEY) || rec.p.contains_key(SIGNING_NON_SIG_BYTES_KEY) || rec.p.contains_key(SIGNING_KEY_TYPE_KEY);
 }
 
 pub fn is_valid_sig(rec: &Record) -> bool {
@@ -182,11 +261,28 @@ pub fn is_valid_sig(rec: &Record) -> bool {
   let empty_str = String::new();
   let pub_key_base64 = rec.p.get(SIGNING_PUB_KEY_KEY).unwrap_or(&empty_str);
   let pub_key_bytes = base64::decode(pub_key_base64).unwrap_or(pub_key_base64.as_bytes().to_vec());
+  let base64_unsigned_sig = rec.p.get(SIGNING_NON_SIG_BYTES_KEY).unwrap_or(&empty_str);
+  match KeyType::of_record(rec) {
+    Some(KeyType::Rsa) => { }
+    Some(KeyType::Ed25519) => {
+      match PKey::public_key_from_raw_bytes(&pub_key_bytes, Id::ED25519) {
+        Ok(pkey) => {
+          return check_nonsig_bytes(&pkey, rec, base64_unsigned_sig);
+        }
+        Err(e) => {
+          println!("Error parsing Ed25519 pub key: {}", e);
+          return false;
+        }
+      }
+    }
+    None => {
+      return false; // Unknown key type
+    }
+  }
   match try_parse_rsa_pub(&pub_key_bytes) {
     Ok(rsa_pub_key) => {
       match PKey::from_rsa(rsa_pub_key) {
         Ok(pkey) => {
-          let base64_unsigned_sig = rec.p.get(SIGNING_NON_SIG_BYTES_KEY).unwrap_or(&empty_str);
           if ! check_nonsig_bytes(&pkey, rec, base64_unsigned_sig) {
             return false;
           }
@@ -214,9 +310,13 @@ pub fn check_single_sig(pub_key: &PKey<Public>, value_key: &str, value: &str, si
 }
 
 pub fn check_nonsig_bytes(pub_key: &PKey<Public>, rec: &Record, sig_base64: &str) -> bool {
+  let sig = base64::decode(sig_base64).unwrap_or(vec![]);
+  if let Some(KeyType::Ed25519) = key_type_of(pub_key) {
+    let mut verifier = Verifier::new_without_digest(pub_key).unwrap();
+    return verifier.verify_oneshot(&sig, &non_sig_bytes(rec)).unwrap_or(false);
+  }
   let mut verifier = Verifier::new(MessageDigest::sha256(), &pub_key).unwrap();
   verifier.update(&non_sig_bytes(rec)).unwrap();
-  let sig = base64::decode(sig_base64).unwrap_or(vec![]);
   return verifier.verify(&sig).unwrap();
 }
 
@@ -243,10 +343,6 @@ pub fn non_sig_bytes(rec: &Record) -> Vec<u8> {
 }
 
 pub fn is_auth_by_server(rec: &Record, config: &Config) -> bool {
-  use std::io::BufReader;
-  use std::io::BufRead;
-  use std::fs::File;
-  
   if !is_valid_sig(rec) {
     return false; // Cannot be trusted by server if anon sigs aren't even correct
   }
@@ -258,17 +354,27 @@ pub fn is_auth_by_server(rec: &Record, config: &Config) -> bool {
     return false; // no pub key given
   }
   
-  match File::open(&config.server_trusted_keys_file) {
+  return is_trusted_key(rec_pub_key_s, &config.server_trusted_keys_file);
+}
+
+// Keys read from each trusted_keys_file, with the mtime they were read at
+static TRUSTED_KEYS: Mutex<BTreeMap<String, (Option<SystemTime>, HashSet<String>)>> = Mutex::new(BTreeMap::new());
+
+fn read_trusted_keys(trusted_keys_file: &str) -> HashSet<String> {
+  use std::io::BufReader;
+  use std::io::BufRead;
+  use std::fs::File;
+  
+  let mut keys = HashSet::new();
+  match File::open(trusted_keys_file) {
     Ok(f) => {
       let buff = BufReader::new(&f);
-      for (_num, line) in buff.lines().enumerate() {
+      for line in buff.lines() {
         if let Ok(line) = line {
           if line.starts_with("#") || line.trim().len() < 1 {
             continue;
           }
-          if &line == rec_pub_key_s {
-            return true;
-          }
+          keys.insert(line);
         }
       }
     }
@@ -276,13 +382,31 @@ pub fn is_auth_by_server(rec: &Record, config: &Config) -> bool {
       println!("Error opening server_trusted_keys_file: {}", e);
     }
   }
+  return keys;
+}
+
+// True if pub_key is listed in trusted_keys_file. Does not check any signature.
+// The file is only read again when its mtime changes.
+pub fn is_trusted_key(pub_key: &str, trusted_keys_file: &str) -> bool {
+  let mtime = fs::metadata(trusted_keys_file).and_then(|m| m.modified()).ok();
+  let mut cache = match TRUSTED_KEYS.lock() {
+    Ok(cache) => cache,
+    Err(_) => return false, // Fail safe
+  };
+  let stale = match cache.get(trusted_keys_file) {
+    Some((read_mtime, _)) => *read_mtime != mtime,
+    None => true,
+  };
+  if stale {
+    cache.insert(trusted_keys_file.to_string(), (mtime, read_trusted_keys(trusted_keys_file)));
+  }
   // Some error or nothing in auth file matches, fail safe
-  return false;
+  return cache[trusted_keys_file].1.contains(pub_key);
 }
 
 // As reserved keys pile up, this method tracks reserved
 // key patterns which are not considered user data when signing.
 pub fn key_is_used_in_signing(key: &str) -> bool {
-  key == SIGNING_PUB_KEY_KEY || key == SIGNING_NON_SIG_BYTES_KEY
+  key == SIGNING_PUB_KEY_KEY || key == SIGNING_NON_SIG_BYTES_KEY || key == SIGNING_KEY_TYPE_KEY || key == TTL_EXPIRES_AT_KEY || key == TEXT_SCORE_KEY
 }
 
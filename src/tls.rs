@@ -0,0 +1,219 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *  
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; version 2 of the License only.
+ * 
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ * 
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+/**
+ * TLS for the tls:// and wss:// transports, using openssl.
+ * Clients trust servers by client_tls_ca_file (or the system's certificates)
+ * and the host name they connect to, or when a server entry has a tls_pin,
+ * by the SHA-256 of the server's public key alone so self-signed servers
+ * can be used without a CA.
+ */
+
+use openssl::ssl::{SslAcceptor, SslConnector, SslMethod, SslFiletype, SslStream, SslVerifyMode, HandshakeError};
+use openssl::x509::{X509, X509Ref};
+
+use std::io::{self, Read, Write, Error, ErrorKind};
+use std::net::{TcpStream, Shutdown};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, Server};
+
+// Longest we wait for the other side to finish a handshake
+const HANDSHAKE_TIMEOUT_MS: u64 = 5000;
+
+// Longest a read holds the lock before letting writers in
+const READ_POLL_MS: u64 = 50;
+
+// A TLS connection which, like a TcpStream, may be cloned so one thread
+// reads while another writes. The socket only ever blocks for READ_POLL_MS,
+// reads wait for bytes without the lock and retry until their own
+// read_timeout, so a waiting reader does not hold up writers.
+#[derive(Clone)]
+pub struct TlsStream {
+  ssl: Arc<Mutex<SslStream<TcpStream>>>,
+  tcp: Arc<TcpStream>,
+  read_timeout: Arc<Mutex<Option<Duration>>>,
+}
+
+impl TlsStream {
+  fn lock(&self) -> io::Result<MutexGuard<'_, SslStream<TcpStream>>> {
+    return self.ssl.lock().map_err(|e| Error::new(ErrorKind::Other, format!("{}", e)));
+  }
+  // Public key pin of the certificate the other side presented
+  pub fn peer_pin(&self) -> Option<String> {
+    let ssl = self.lock().ok()?;
+    let cert = ssl.ssl().peer_certificate()?;
+    return key_pin(&cert).ok();
+  }
+  pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+    if timeout == Some(Duration::from_millis(0)) {
+      return Err(Error::new(ErrorKind::InvalidInput, "cannot set a 0 duration timeout"));
+    }
+    self.tcp.set_read_timeout(Some(Duration::from_millis(READ_POLL_MS)))?;
+    *self.read_timeout.lock().map_err(|e| Error::new(ErrorKind::Other, format!("{}", e)))? = timeout;
+    return Ok(());
+  }
+  fn new(ssl: SslStream<TcpStream>, tcp: TcpStream) -> TlsStream {
+    return TlsStream { ssl: Arc::new(Mutex::new(ssl)), tcp: Arc::new(tcp), read_timeout: Arc::new(Mutex::new(None)) };
+  }
+  pub fn shutdown(&self) -> io::Result<()> {
+    return self.tcp.shutdown(Shutdown::Both);
+  }
+}
+
+impl Read for TlsStream {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let read_timeout = *self.read_timeout.lock().map_err(|e| Error::new(ErrorKind::Other, format!("{}", e)))?;
+    let deadline = read_timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+      let res = if self.lock()?.ssl().pending() == 0 {
+        // Disconnects surface here as they would on TCP
+        self.tcp.peek(&mut [0u8; 1]).map(|_| ())
+      }
+      else {
+        Ok(())
+      };
+      // The lock is only held while bytes are there to read, a partial
+      // record gives up the lock after READ_POLL_MS and is resumed
+      let res = res.and_then(|_| self.lock()?.read(buf));
+      match res {
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+          if deadline.map(|deadline| Instant::now() >= deadline).unwrap_or(false) {
+            return res;
+          }
+        }
+        res => {
+          return res;
+        }
+      }
+    }
+  }
+}
+
+impl Write for TlsStream {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    return self.lock()?.write(buf);
+  }
+  fn flush(&mut self) -> io::Result<()> {
+    return self.lock()?.flush();
+  }
+}
+
+impl websocket::sync::stream::Splittable for TlsStream {
+  type Reader = TlsStream;
+  type Writer = TlsStream;
+  fn split(self) -> io::Result<(TlsStream, TlsStream)> {
+    return Ok((self.clone(), self));
+  }
+}
+
+// Hex SHA-256 of a certificate's DER encoded public key, what
+// Server::tls_pin holds
+pub fn key_pin(cert: &X509Ref) -> Result<String, String> {
+  let der = cert.public_key().and_then(|key| key.public_key_to_der()).map_err(|e| format!("{}", e))?;
+  let digest = openssl::sha::sha256(&der);
+  return Ok(digest.iter().map(|b| format!("{:02x}", b)).collect());
+}
+
+// The pin clients should use for the first certificate in a PEM file
+pub fn cert_file_pin(cert_file: &str) -> Result<String, String> {
+  let pem = std::fs::read(cert_file).map_err(|e| format!("{}: {}", cert_file, e))?;
+  let cert = X509::from_pem(&pem).map_err(|e| format!("{}: {}", cert_file, e))?;
+  return key_pin(&cert);
+}
+
+// Connects to a tls:// or wss:// server. read_timeout applies once the
+// handshake is done, None waits for bytes without ever holding the lock
+// for longer than READ_POLL_MS.
+pub fn connect(config: &Config, server: &Server, read_timeout: Option<Duration>) -> io::Result<TlsStream> {
+  let ip_and_port = format!("{}:{}", server.host, server.port);
+  let tcp = TcpStream::connect(&ip_and_port)?;
+  tcp.set_read_timeout(Some(Duration::from_millis(256)))?;
+  tcp.set_write_timeout(Some(Duration::from_millis(256)))?;
+  let peek_tcp = tcp.try_clone()?;
+  
+  let mut builder = SslConnector::builder(SslMethod::tls()).map_err(to_io_err)?;
+  if server.tls_pin.is_some() {
+    // The pin is checked below instead
+    builder.set_verify(SslVerifyMode::NONE);
+  }
+  else if !config.client_tls_ca_file.is_empty() {
+    builder.set_ca_file(&config.client_tls_ca_file).map_err(to_io_err)?;
+  }
+  let configuration = builder.build().configure().map_err(to_io_err)?
+    .verify_hostname(server.tls_pin.is_none());
+  let ssl = finish_handshake(configuration.connect(&server.host, tcp))?;
+  
+  let stream = TlsStream::new(ssl, peek_tcp);
+  if let Some(pin) = &server.tls_pin {
+    if stream.peer_pin().map(|peer_pin| peer_pin != pin.to_lowercase()).unwrap_or(true) {
+      let _ = stream.shutdown();
+      return Err(Error::new(ErrorKind::PermissionDenied, format!("{} does not have the pinned key", server.name)));
+    }
+  }
+  stream.set_read_timeout(read_timeout)?;
+  return Ok(stream);
+}
+
+// Reads server_tls_cert_file and server_tls_key_file
+pub fn acceptor(config: &Config) -> Result<SslAcceptor, String> {
+  if config.server_tls_cert_file.is_empty() || config.server_tls_key_file.is_empty() {
+    return Err("server_tls_cert_file and server_tls_key_file must be set".to_string());
+  }
+  let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).map_err(|e| format!("{}", e))?;
+  builder.set_certificate_chain_file(&config.server_tls_cert_file).map_err(|e| format!("{}: {}", config.server_tls_cert_file, e))?;
+  builder.set_private_key_file(&config.server_tls_key_file, SslFiletype::PEM).map_err(|e| format!("{}: {}", config.server_tls_key_file, e))?;
+  builder.check_private_key().map_err(|e| format!("{}", e))?;
+  return Ok(builder.build());
+}
+
+// Handshakes with a client which connected to a TLS listener. read_timeout
+// applies once the handshake is done.
+pub fn accept(acceptor: &SslAcceptor, tcp: TcpStream, read_timeout: Option<Duration>) -> io::Result<TlsStream> {
+  tcp.set_read_timeout(Some(Duration::from_millis(256)))?;
+  tcp.set_write_timeout(Some(Duration::from_millis(256)))?;
+  let peek_tcp = tcp.try_clone()?;
+  let ssl = finish_handshake(acceptor.accept(tcp))?;
+  let stream = TlsStream::new(ssl, peek_tcp);
+  stream.set_read_timeout(read_timeout)?;
+  return Ok(stream);
+}
+
+// Sockets have read timeouts, so handshakes are resumed until
+// HANDSHAKE_TIMEOUT_MS has passed
+fn finish_handshake(mut res: Result<SslStream<TcpStream>, HandshakeError<TcpStream>>) -> io::Result<SslStream<TcpStream>> {
+  let deadline = Instant::now() + Duration::from_millis(HANDSHAKE_TIMEOUT_MS);
+  loop {
+    match res {
+      Ok(ssl) => {
+        return Ok(ssl);
+      }
+      Err(HandshakeError::WouldBlock(mid)) if Instant::now() < deadline => {
+        res = mid.handshake();
+      }
+      Err(e) => {
+        return Err(Error::new(ErrorKind::Other, format!("TLS handshake failed: {}", e)));
+      }
+    }
+  }
+}
+
+fn to_io_err(e: openssl::error::ErrorStack) -> Error {
+  return Error::new(ErrorKind::Other, format!("{}", e));
+}
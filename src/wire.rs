@@ -17,29 +17,485 @@
  */
 
 use serde;
+use serde::Deserialize;
 
-use crate::actions::Action;
-use crate::record::Record;
+use std::collections::BTreeMap;
+
+use crate::actions::{Action, action_from_u8};
+use crate::record::{Record, now_ms};
+use crate::federation;
+use crate::query::Query;
+use crate::signing::{SIGNING_PUB_KEY_KEY, SIGNING_NON_SIG_BYTES_KEY, SIGNING_KEY_TYPE_KEY};
+
+use crate::h_map;
+
+// Version 1 was the original 0xff-delimited protocol without hello.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+// Key in the record of an end_of_results answering a query with QueryOptions,
+// present when the server has more results. See QueryOptions::cursor.
+pub const NEXT_CURSOR_KEY: &str = "next-cursor";
+
+// Keys of the record answering a count, see Counts::to_record
+pub const COUNT_TOTAL_KEY: &str = "COUNT:total";
+pub const COUNT_GROUP_BY_KEY: &str = "COUNT:group-by";
+pub const COUNT_GROUP_PREFIX: &str = "COUNT:group:";
 
 // This represents data send to/from servers and clients over
 // any tcp, udp, or unix socket connection.
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct WireData {
+  #[serde(deserialize_with = "deserialize_action")]
   pub action: Action,
   pub record: Record,
+  
+  // Set by clients on multiplexed connections and echoed on every reply
+  // to that request. A request with an id keeps the connection open.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub id: Option<u64>,
+  
+  // Selects the records a delete or update applies to
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub target: Option<Target>,
+  
+  // Only present on hello messages. Peers which predate hello ignore it.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub caps: Option<Capabilities>,
+  
+  // Limits and orders the results of a query
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub options: Option<QueryOptions>,
+  
+  // Only present on queries and listens a server forwards to the servers
+  // it federates with, see federation.rs
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub forwarded: Option<Forwarded>,
+  
+  // Only present on sync requests, which without it ask for a digest
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub sync: Option<SyncRequest>,
+  
+  // Only present on announce messages
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub announce: Option<Announcement>,
+  
+  // Signed proof that a delete or update comes from the owner of the
+  // records it changes, see Target::authorization
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub authorization: Option<Record>,
+}
+
+// The later steps of a sync, see replication::answer
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SyncRequest {
+  // Buckets to list the record ids of
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub buckets: Vec<String>,
+  // Ids of records to send
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub ids: Vec<String>,
+}
+
+// Who a server is and how to reach it, sent as an announce beacon to
+// server_multicast_group and in reply to discover, see discovery.rs
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Announcement {
+  // Random per server process, tells restarts and identically
+  // named servers apart
+  pub instance: String,
+  pub name: String,
+  // Left out by the server itself, receivers use the address it came from.
+  // Set when a server passes on an announcement it heard.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub host: Option<String>,
+  // Ports of the listeners the server runs
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub tcp_port: Option<u16>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub udp_port: Option<u16>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub websocket_port: Option<u16>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub tls_port: Option<u16>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub wss_port: Option<u16>,
+  pub caps: Capabilities,
+}
+
+// How a forwarded request has travelled so far
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Forwarded {
+  // Picked by the first server and kept on every hop, so a server which
+  // receives the request again knows it has answered it already
+  pub request_id: String,
+  // Servers the request has been forwarded from, including the sender
+  pub hops: u32,
+}
+
+// Results are ordered by the value of sort_by, as numbers when both values
+// are numbers (see query::cmp_sort_values), then by the order the server
+// stored them in. Servers apply offset and limit after that ordering.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct QueryOptions {
+  // Most results to return
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub limit: Option<usize>,
+  // Results to skip
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub offset: Option<usize>,
+  // The NEXT_CURSOR_KEY of the previous page, continues from the end of it.
+  // Cursors belong to the server which made them and to the same sort_by.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub cursor: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub sort_by: Option<String>,
+  #[serde(default, skip_serializing_if = "is_false")]
+  pub descending: bool,
+  // Keys to return from each result, all of them when None. Signatures
+  // cover every key, so projected results never carry the SIGNING: keys.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub fields: Option<Vec<String>>,
+  // Only read by count, which tallies matches by the value of this key
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub group_by: Option<String>,
+  // Only read by listen, which first sends the records already matching
+  // followed by an end_of_replay, see Data::listen_with_replay
+  #[serde(default, skip_serializing_if = "is_false")]
+  pub replay: bool,
+}
+
+impl QueryOptions {
+  pub fn is_empty(&self) -> bool {
+    *self == QueryOptions::default()
+  }
+  // True if results have to be collected and ordered before any are sent
+  pub fn is_paged(&self) -> bool {
+    self.limit.is_some() || self.offset.is_some() || self.cursor.is_some() || self.sort_by.is_some()
+  }
+  // rec with only the keys in fields, and so no longer verifiable
+  pub fn project(&self, rec: &Record) -> Record {
+    let fields = match &self.fields {
+      Some(fields) => fields,
+      None => return rec.clone(),
+    };
+    let mut projected = Record::empty();
+    for (key, val) in &rec.p {
+      let is_signature = key == SIGNING_PUB_KEY_KEY || key == SIGNING_NON_SIG_BYTES_KEY || key == SIGNING_KEY_TYPE_KEY;
+      if fields.contains(key) && !is_signature {
+        projected.p.insert(key.clone(), val.clone());
+      }
+    }
+    projected.src_server = rec.src_server.clone();
+    return projected;
+  }
+}
+
+fn is_false(b: &bool) -> bool {
+  !*b
+}
+
+// The answer to a count: how many records matched and, when the count has a
+// group_by key, how many matched with each value of it. Records without the
+// key are in total but in no group.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Counts {
+  pub total: usize,
+  pub group_by: Option<String>,
+  pub groups: BTreeMap<String, usize>,
+}
+
+impl Counts {
+  // Servers send counts as a single record so every transport can carry them
+  pub fn to_record(&self) -> Record {
+    let mut rec = Record::empty();
+    rec.p.insert(COUNT_TOTAL_KEY.to_string(), format!("{}", self.total));
+    if let Some(group_by) = &self.group_by {
+      rec.p.insert(COUNT_GROUP_BY_KEY.to_string(), group_by.clone());
+    }
+    for (value, n) in &self.groups {
+      rec.p.insert(format!("{}{}", COUNT_GROUP_PREFIX, value), format!("{}", n));
+    }
+    return rec;
+  }
+  pub fn from_record(rec: &Record) -> Counts {
+    let mut counts = Counts::default();
+    for (key, val) in &rec.p {
+      if key == COUNT_TOTAL_KEY {
+        counts.total = val.parse().unwrap_or(0);
+      }
+      else if key == COUNT_GROUP_BY_KEY {
+        counts.group_by = Some(val.clone());
+      }
+      else if key.starts_with(COUNT_GROUP_PREFIX) {
+        counts.groups.insert(key[COUNT_GROUP_PREFIX.len()..].to_string(), val.parse().unwrap_or(0));
+      }
+    }
+    return counts;
+  }
+  // Adds other to these counts, eg to combine the answers of several servers
+  pub fn merge(&mut self, other: &Counts) {
+    self.total += other.total;
+    if self.group_by.is_none() {
+      self.group_by = other.group_by.clone();
+    }
+    for (value, n) in &other.groups {
+      *self.groups.entry(value.clone()).or_insert(0) += n;
+    }
+  }
+}
+
+// Keys of the record authorizing a delete or update. Owners sign it so
+// it cannot be reused for another action, target or replacement record,
+// and servers refuse it once it is old or its nonce was already used.
+pub const AUTH_ACTION_KEY: &str = "modify-action";
+pub const AUTH_TARGET_KEY: &str = "modify-target";
+pub const AUTH_RECORD_ID_KEY: &str = "modify-record-id";
+pub const AUTH_ISSUED_AT_KEY: &str = "modify-issued-at";
+pub const AUTH_NONCE_KEY: &str = "modify-nonce";
+// Furthest an authorization's issued-at may be from the server's clock
+pub const AUTH_MAX_AGE_MS: u64 = 5 * 60 * 1000;
+
+// Records selected by id (see Record::id), by query, or by both.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct Target {
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub id: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub query: Option<Record>,
+}
+
+impl Target {
+  pub fn id(id: &str) -> Target {
+    Target {
+      id: Some(id.to_string()),
+      query: None,
+    }
+  }
+  pub fn query(query: &Record) -> Target {
+    Target {
+      id: None,
+      query: Some(query.clone()),
+    }
+  }
+  pub fn is_empty(&self) -> bool {
+    self.id.is_none() && self.query.as_ref().map(|q| q.is_empty()).unwrap_or(true)
+  }
+  // The unsigned authorization for action on this target, replacement
+  // is the new record of an update
+  pub fn authorization(&self, action: Action, replacement: Option<&Record>) -> Record {
+    let mut auth = Record::new(h_map!{
+      AUTH_ACTION_KEY.to_string() => format!("{}", action),
+      AUTH_TARGET_KEY.to_string() => self.describe(),
+      AUTH_ISSUED_AT_KEY.to_string() => format!("{}", now_ms()),
+      AUTH_NONCE_KEY.to_string() => federation::new_request_id()
+    });
+    if let Some(replacement) = replacement {
+      auth.p.insert(AUTH_RECORD_ID_KEY.to_string(), replacement.id());
+    }
+    return auth;
+  }
+  // A canonical string for this target. Authorizations sign it so
+  // they cannot be reused against other records.
+  pub fn describe(&self) -> String {
+    let mut s = String::new();
+    if let Some(id) = &self.id {
+      s.push_str(&format!("id={};", id));
+    }
+    if let Some(query) = &self.query {
+      let mut keys: Vec<&String> = query.p.keys().collect();
+      keys.sort();
+      for key in keys {
+        s.push_str(&format!("{}={:?};", key, query.p[key]));
+      }
+    }
+    return s;
+  }
+  // query is the compiled form of self.query
+  pub fn matches(&self, rec: &Record, query: &Query) -> bool {
+    if let Some(id) = &self.id {
+      if &rec.id() != id {
+        return false;
+      }
+    }
+    if self.query.is_some() && ! query.matches(rec) {
+      return false;
+    }
+    return true;
+  }
+}
+
+// What a peer understands. Every list is ordered by preference and
+// unknown entries are ignored, so newer peers can add to them freely.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Capabilities {
+  pub version: u32,
+  // "length" and/or "legacy", see framing.rs
+  #[serde(default)]
+  pub framing: Vec<String>,
+  // No compression schemes are implemented yet, an empty list means none
+  #[serde(default)]
+  pub compression: Vec<String>,
+  // Record signature schemes, see signing.rs
+  #[serde(default)]
+  pub signing: Vec<String>,
+  // Names of the actions this peer will accept
+  #[serde(default)]
+  pub actions: Vec<String>,
+  // Optional protocol behaviour such as "multiplex" (request ids on a persistent
+  // connection), "query-options" (queries honor WireData::options) and
+  // "listen-replay" (listens honor QueryOptions::replay)
+  #[serde(default)]
+  pub features: Vec<String>,
+}
+
+impl Capabilities {
+  // Everything this build of dIndex supports
+  pub fn ours() -> Capabilities {
+    Capabilities {
+      version: PROTOCOL_VERSION,
+      framing: vec!["length".to_string(), "legacy".to_string()],
+      compression: vec![],
+      signing: vec!["ed25519".to_string(), "rsa-sha256".to_string()],
+      // Requests servers accept, replies such as ack and removed are not listed
+      actions: vec![
+        "query".to_string(), "publish".to_string(), "listen".to_string(), "hello".to_string(),
+        "cancel".to_string(), "delete".to_string(), "update".to_string(),
+        "count".to_string(), "sync".to_string(), "discover".to_string()
+      ],
+      features: vec!["multiplex".to_string(), "query-options".to_string(), "listen-replay".to_string()],
+    }
+  }
+  // Peers which never answer hello speak version 1
+  pub fn legacy() -> Capabilities {
+    Capabilities {
+      version: 1,
+      framing: vec!["legacy".to_string()],
+      compression: vec![],
+      signing: vec!["rsa-sha256".to_string()],
+      actions: vec![
+        "query".to_string(), "publish".to_string(), "listen".to_string()
+      ],
+      features: vec![],
+    }
+  }
+  // The subset both peers support, in our order of preference
+  pub fn negotiate(&self, peer: &Capabilities) -> Capabilities {
+    let both = |ours: &Vec<String>, theirs: &Vec<String>| -> Vec<String> {
+      ours.iter().filter(|s| theirs.contains(s)).cloned().collect()
+    };
+    Capabilities {
+      version: std::cmp::min(self.version, peer.version),
+      framing: both(&self.framing, &peer.framing),
+      compression: both(&self.compression, &peer.compression),
+      signing: both(&self.signing, &peer.signing),
+      actions: both(&self.actions, &peer.actions),
+      features: both(&self.features, &peer.features),
+    }
+  }
+  pub fn supports_action(&self, action: &str) -> bool {
+    self.actions.iter().any(|a| a == action)
+  }
+  pub fn supports_feature(&self, feature: &str) -> bool {
+    self.features.iter().any(|f| f == feature)
+  }
+}
+
+fn deserialize_action<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Action, D::Error> {
+  let n = u8::deserialize(deserializer)?;
+  return Ok(action_from_u8(n));
 }
 
 impl WireData {
-  pub fn result(record: Record) -> WireData {
+  pub fn new(action: Action, record: Record) -> WireData {
     WireData {
-      action: Action::result,
+      action: action,
       record: record,
+      id: None,
+      target: None,
+      caps: None,
+      options: None,
+      forwarded: None,
+      sync: None,
+      announce: None,
+      authorization: None,
     }
   }
+  pub fn with_authorization(mut self, authorization: Record) -> WireData {
+    self.authorization = Some(authorization);
+    return self;
+  }
+  pub fn with_target(mut self, target: Target) -> WireData {
+    self.target = Some(target);
+    return self;
+  }
+  pub fn with_options(mut self, options: QueryOptions) -> WireData {
+    self.options = Some(options);
+    return self;
+  }
+  pub fn with_sync(mut self, sync: SyncRequest) -> WireData {
+    self.sync = Some(sync);
+    return self;
+  }
+  pub fn with_forwarded(mut self, forwarded: Forwarded) -> WireData {
+    self.forwarded = Some(forwarded);
+    return self;
+  }
+  // Tags a reply with the id of the request it answers
+  pub fn with_id(mut self, id: Option<u64>) -> WireData {
+    self.id = id;
+    return self;
+  }
+  pub fn result(record: Record) -> WireData {
+    WireData::new(Action::result, record)
+  }
   pub fn end_of_results() -> WireData {
+    WireData::new(Action::end_of_results, Record::empty())
+  }
+  pub fn end_of_replay() -> WireData {
+    WireData::new(Action::end_of_replay, Record::empty())
+  }
+  // Ends a page of results, telling the client how to fetch the next one
+  pub fn end_of_page(next_cursor: Option<String>) -> WireData {
+    let mut record = Record::empty();
+    if let Some(next_cursor) = next_cursor {
+      record.p.insert(NEXT_CURSOR_KEY.to_string(), next_cursor);
+    }
+    WireData::new(Action::end_of_results, record)
+  }
+  pub fn hello(caps: Capabilities) -> WireData {
     WireData {
-      action: Action::end_of_results,
+      action: Action::hello,
       record: Record::empty(),
+      id: None,
+      target: None,
+      caps: Some(caps),
+      options: None,
+      forwarded: None,
+      sync: None,
+      announce: None,
+      authorization: None,
+    }
+  }
+  pub fn announce(announcement: Announcement) -> WireData {
+    WireData {
+      announce: Some(announcement),
+      ..WireData::new(Action::announce, Record::empty())
     }
   }
+  pub fn ack(record_id: &str) -> WireData {
+    WireData::new(Action::ack, Record::new(h_map!{
+      "record-id".to_string() => record_id.to_string()
+    }))
+  }
+  // True for the last reply a server sends to a request
+  pub fn ends_request(&self) -> bool {
+    self.action == Action::end_of_results || self.action == Action::error || self.action == Action::ack
+  }
+  // error_code is a short machine-readable string such as "unknown-action"
+  pub fn error(error_code: &str, error_message: &str) -> WireData {
+    WireData::new(Action::error, Record::new(h_map!{
+      "error-code".to_string() => error_code.to_string(),
+      "error-message".to_string() => error_message.to_string()
+    }))
+  }
 }
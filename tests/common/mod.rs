@@ -0,0 +1,113 @@
+/*
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; either version 2 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+// Fixtures shared by the integration tests, each test file
+// uses a different subset of them.
+#![allow(dead_code)]
+
+use dindex;
+use dindex::config::{Config, Server, ServerProtocol};
+use dindex::data::Data;
+use dindex::record::Record;
+
+// A config read from no file and no environment
+pub fn empty_config() -> Config {
+  return dindex::config::get_config_detail(
+    // this is the method that reads from env, but we specify no env in the arguments
+    false, false, false, false,
+    Err(std::env::VarError::NotPresent),
+    &dindex::args::Args::empty()
+  );
+}
+
+// A quiet config keeping records in memory only
+pub fn mem_config() -> Config {
+  let mut config = empty_config();
+  config.server_extra_quiet = true;
+  config.server_datastore_uri = "memory://".to_string();
+  return config;
+}
+
+// A server on 127.0.0.1 the test configs below can listen as
+pub fn local_server(protocol: ServerProtocol, port: u16) -> Server {
+  Server {
+    protocol: protocol,
+    host: "127.0.0.1".to_string(),
+    port: port,
+    path: "/tmp/dindex.test.socket".to_string(),
+    max_latency_ms: 250,
+    report_connect_errors: true,
+    name: format!("Localhost Server {}", port),
+    tls_pin: None
+  }
+}
+
+// A mem_config serving server's port over its protocol only,
+// which forwards to no other servers
+pub fn server_config(server: &Server) -> Config {
+  let mut config = mem_config();
+  config.server_port = server.port;
+  config.server_ip = "127.0.0.1".to_string();
+  config.server_listen_tcp = server.protocol == ServerProtocol::TCP;
+  config.server_listen_udp = server.protocol == ServerProtocol::UDP;
+  config.server_listen_unix = false;
+  config.server_listen_websocket = false;
+  config.server_listen_multicast = false;
+  config.servers = vec![];
+  return config;
+}
+
+// Loads whatever the config's datastore already holds
+pub fn open_data(config: &Config) -> Data {
+  let mut data = Data::new(config);
+  dindex::server_data_io::read_stored_records(config, &mut data);
+  return data;
+}
+
+pub fn record(pairs: &[(&str, &str)]) -> Record {
+  let mut rec = Record::empty();
+  for (key, val) in pairs {
+    rec.p.insert(key.to_string(), val.to_string());
+  }
+  return rec;
+}
+
+pub fn pair(key: &str, val: &str) -> Record {
+  return record(&[(key, val)]);
+}
+
+pub fn named(name: &str) -> Record {
+  return pair("NAME", name);
+}
+
+// The NAME of each record, in order
+pub fn names(records: &[Record]) -> Vec<String> {
+  return records.iter().map(|rec| rec.p.get("NAME").cloned().unwrap_or_default()).collect();
+}
+
+pub fn sorted_names(records: &[Record]) -> Vec<String> {
+  let mut names = names(records);
+  names.sort();
+  return names;
+}
+
+// The sorted NAME of every record held by data
+pub fn stored_names(data: &Data) -> Vec<String> {
+  return sorted_names(&data.search(&named(".*").create_query()));
+}
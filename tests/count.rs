@@ -0,0 +1,147 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; either version 2 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use crossbeam_utils::thread;
+
+use std::time::Duration;
+
+use dindex;
+use dindex::wire::Counts;
+
+mod common;
+use common::{mem_config, record};
+
+#[test]
+fn count_groups_matches() {
+  let data = dindex::data::Data::new(&mem_config());
+  insert_pages(&data);
+  
+  let pages = record(&[("url", ".*")]).create_query();
+  let total = data.count(&pages, None);
+  assert_eq!(total.total, 6);
+  assert!(total.groups.is_empty());
+  
+  let by_host = data.count(&pages, Some("host"));
+  assert_eq!(by_host.total, 6);
+  assert_eq!(by_host.group_by, Some("host".to_string()));
+  assert_eq!(by_host.groups.get("a.example"), Some(&3));
+  assert_eq!(by_host.groups.get("b.example"), Some(&2));
+  // The page without a host is only in the total
+  assert_eq!(by_host.groups.len(), 2);
+  
+  let nothing = data.count(&record(&[("url", "^ftp:")]).create_query(), Some("host"));
+  assert_eq!(nothing, Counts { total: 0, group_by: Some("host".to_string()), ..Counts::default() });
+  
+  // Counts survive the trip through a record and add up across servers
+  let mut merged = Counts::from_record(&by_host.to_record());
+  assert_eq!(merged, by_host);
+  merged.merge(&by_host);
+  assert_eq!(merged.total, 12);
+  assert_eq!(merged.groups.get("a.example"), Some(&6));
+}
+
+#[test]
+fn count_waits_for_busy_pools() {
+  let data = dindex::data::Data::new(&mem_config());
+  insert_pages(&data);
+  let pages = record(&[("url", ".*")]).create_query();
+  
+  let guards: Vec<_> = data.record_pools.iter().map(|pool| pool.write().unwrap()).collect();
+  thread::scope(|s| {
+    let counter = s.spawn(|_| data.count(&pages, None).total);
+    std::thread::sleep(Duration::from_millis(50));
+    drop(guards);
+    assert_eq!(counter.join().unwrap(), 6);
+  }).unwrap();
+}
+
+#[test]
+fn tcp_count() {
+  let mut test_config = mem_config();
+  // Write details for temporary data
+  let port = 2008;
+  let localhost_server = dindex::config::Server {
+    protocol: dindex::config::ServerProtocol::TCP,
+    host: "127.0.0.1".to_string(),
+    port: port,
+    path: "/tmp/dindex.test.socket".to_string(),
+    max_latency_ms: 250,
+    report_connect_errors: true,
+    name: "Localhost Server".to_string(),
+    tls_pin: None
+  };
+  test_config.servers = vec![localhost_server.clone()];
+  test_config.server_port = port;
+  test_config.server_ip = "127.0.0.1".to_string();
+  test_config.server_listen_tcp = true;
+  test_config.server_listen_udp = false;
+  test_config.server_listen_unix = false;
+  test_config.server_listen_websocket = false;
+
+  // Create a data store
+  let mut data = dindex::data::Data::new(&test_config);
+  insert_pages(&data);
+  let exit_flag = data.exit_flag.clone();
+
+  // Spawn server and client threads to perform testing
+  thread::scope(|s| {
+    let mut handlers = vec![];
+
+    handlers.push(s.spawn(|_| {
+      dindex::server::run_tcp_sync(&test_config, &mut data);
+    }));
+
+    handlers.push(s.spawn(|_| {
+      // This is where client logic is tested
+      std::thread::sleep(Duration::from_millis(25));
+      // Server should have bound to ports within 25ms
+
+      let query = record(&[("url", ".*")]);
+      let counts = dindex::client::count_server_sync(&test_config, &localhost_server, &query, Some("host")).unwrap();
+      assert_eq!(counts.total, 6);
+      assert_eq!(counts.groups.get("a.example"), Some(&3));
+      assert_eq!(counts.groups.get("b.example"), Some(&2));
+      
+      let all = dindex::client::count_sync(&test_config, &query, None);
+      assert_eq!(all.len(), 1);
+      assert_eq!(all[0].1.as_ref().unwrap().total, 6);
+
+      // Instruct server to exit
+      exit_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+      // Send it network traffic to force eval of exit_flag
+      dindex::client::query_sync(&test_config, &query);
+
+    }));
+
+    for h in handlers {
+      h.join().unwrap();
+    }
+  }).unwrap();
+
+}
+
+fn insert_pages(data: &dindex::data::Data) {
+  for (url, host) in &[("http://a.example/1", "a.example"), ("http://a.example/2", "a.example"),
+                       ("http://a.example/3", "a.example"), ("http://b.example/1", "b.example"),
+                       ("http://b.example/2", "b.example")] {
+    data.insert(record(&[("url", url), ("host", host)]));
+  }
+  data.insert(record(&[("url", "http://unknown/")]));
+  data.insert(record(&[("title", "not a page")]));
+}
@@ -0,0 +1,92 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *  
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; either version 2 of the License, or
+ *  (at your option) any later version.
+ * 
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ * 
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use dindex;
+use dindex::config::EvictionPolicy;
+
+mod common;
+use common::{mem_config, named, stored_names};
+
+#[test]
+fn eviction_oldest() {
+  let data = dindex::data::Data::new(&test_config(EvictionPolicy::Oldest));
+  for i in 0..10 {
+    data.insert(named(&format!("{}", i)));
+  }
+  assert_eq!(data.len(), 4);
+  assert_eq!(data.num_evicted(), 6);
+  assert_eq!(stored_names(&data), vec!["6", "7", "8", "9"]);
+}
+
+#[test]
+fn eviction_least_recently_matched() {
+  let data = dindex::data::Data::new(&test_config(EvictionPolicy::LeastRecentlyMatched));
+  for i in 0..4 {
+    data.insert(named(&format!("{}", i)));
+  }
+  // 0 and 1 are the oldest but were just used
+  assert_eq!(data.search(&named("^[01]$").create_query()).len(), 2);
+  data.insert(named("4"));
+  data.insert(named("5"));
+  assert_eq!(stored_names(&data), vec!["0", "1", "4", "5"]);
+}
+
+#[test]
+fn eviction_prefer_trusted() {
+  let identity_f = "/tmp/dindex-test-eviction.identity";
+  let trusted_keys_f = "/tmp/dindex-test-eviction-trusted-keys";
+  dindex::signing::gen_identity(identity_f);
+  std::fs::write(trusted_keys_f, dindex::signing::read_pub_key_base64(identity_f)).unwrap();
+  
+  let mut config = test_config(EvictionPolicy::PreferTrusted);
+  config.server_trusted_keys_file = trusted_keys_f.to_string();
+  let mut signing_config = config.clone();
+  signing_config.client_use_sig = true;
+  signing_config.client_private_key_file = identity_f.to_string();
+  
+  let data = dindex::data::Data::new(&config);
+  let mut trusted_rec = named("trusted");
+  dindex::signing::maybe_sign_record(&signing_config, &mut trusted_rec);
+  data.insert(trusted_rec);
+  for i in 0..10 {
+    data.insert(named(&format!("{}", i)));
+  }
+  assert_eq!(stored_names(&data), vec!["7", "8", "9", "trusted"]);
+}
+
+#[test]
+fn trusted_keys_reload_on_change() {
+  let trusted_keys_f = "/tmp/dindex-test-trusted-keys-reload";
+  std::fs::write(trusted_keys_f, "# nobody yet\n").unwrap();
+  assert!(!dindex::signing::is_trusted_key("key-a", trusted_keys_f));
+  
+  // The cached keys are dropped once the file's mtime changes
+  std::fs::write(trusted_keys_f, "key-a\n").unwrap();
+  let f = std::fs::File::options().write(true).open(trusted_keys_f).unwrap();
+  f.set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(60)).unwrap();
+  assert!(dindex::signing::is_trusted_key("key-a", trusted_keys_f));
+  assert!(!dindex::signing::is_trusted_key("key-b", trusted_keys_f));
+}
+
+fn test_config(policy: EvictionPolicy) -> dindex::config::Config {
+  let mut test_config = mem_config();
+  test_config.server_max_records = 4;
+  test_config.server_eviction_policy = policy;
+  return test_config;
+}
@@ -0,0 +1,123 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *  
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; either version 2 of the License, or
+ *  (at your option) any later version.
+ * 
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ * 
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use dindex;
+use dindex::record::Record;
+use dindex::record_index::Constraint;
+
+mod common;
+use common::mem_config;
+
+const WORDS: &[&str] = &[
+  "dindex", "index", "Distributed", "organic", "mechanical", "everything",
+  "foo", "FOO", "bar", "foobar", "http://example.org/a", "https://example.org/b",
+  "héllo", "wörld", "a.c", "abc", "123", "x-y_z", "",
+];
+
+#[test]
+fn index_agrees_with_full_scan() {
+  let mut config = mem_config();
+  config.server_max_records = 100_000;
+  let data = dindex::data::Data::new(&config);
+  
+  let mut all = vec![];
+  for i in 0..WORDS.len() * 3 {
+    let mut rec = Record::empty();
+    rec.p.insert("title".to_string(), format!("{} {}", WORDS[i % WORDS.len()], WORDS[(i * 7) % WORDS.len()]));
+    if i % 2 == 0 {
+      rec.p.insert("url".to_string(), WORDS[(i * 3) % WORDS.len()].to_string());
+    }
+    if i % 5 == 0 {
+      rec.p.insert("long".to_string(), "z".repeat(100) + WORDS[i % WORDS.len()]);
+    }
+    data.insert(rec.clone());
+    all.push(rec);
+  }
+  
+  let patterns = &[
+    ".*", "foo", ".*foo.*", "^foo", "^foo$", "(?i)foo", "FOO|bar", "a.c", "a\\.c",
+    "^http://", "^https?://example", "example\\.org/[ab]", "\\d+", "(?m)^index",
+    "héllo", "(?i)WÖRLD", "^$", "x-y", "-y_", "\\bbar", "foo.*bar", "[a-c]bc", "z+dindex",
+  ];
+  for key in &["title", "url", "long"] {
+    for pattern in patterns {
+      check(&data, &all, &[(key, pattern)]);
+      check(&data, &all, &[(key, pattern), ("url", "^https")]);
+    }
+  }
+  
+  // The index must follow removals too
+  data.remove(|rec| rec.p.get("title").map(|t| t.contains("foo")).unwrap_or(false));
+  all.retain(|rec| !rec.p.get("title").map(|t| t.contains("foo")).unwrap_or(false));
+  for pattern in patterns {
+    check(&data, &all, &[("title", pattern)]);
+  }
+}
+
+#[test]
+fn constraints_from_regexes() {
+  let c = Constraint::from_regex("^http://").unwrap();
+  assert!(c.anchored);
+  assert_eq!(c.literals, vec!["http://"]);
+  let c = Constraint::from_regex(".*keyword.*").unwrap();
+  assert!(!c.anchored);
+  assert_eq!(c.literals, vec!["keyword"]);
+  assert!(Constraint::from_regex(".*").is_none());
+  assert!(Constraint::from_regex("a*b*").is_none());
+  assert!(Constraint::from_regex("foo|").is_none());
+}
+
+fn check(data: &dindex::data::Data, all: &Vec<Record>, query: &[(&str, &str)]) {
+  let mut query_rec = Record::empty();
+  for (key, pattern) in query {
+    query_rec.p.insert(key.to_string(), pattern.to_string());
+  }
+  let query_map = query_rec.create_regex_map();
+  let mut expected: Vec<String> = all.iter().filter(|r| r.matches(&query_map)).map(|r| format!("{:?}", sorted(r))).collect();
+  let mut found: Vec<String> = data.search(&query_rec.create_query()).iter().map(|r| format!("{:?}", sorted(r))).collect();
+  expected.sort();
+  found.sort();
+  assert_eq!(found, expected, "query {:?}", query);
+}
+
+#[test]
+fn insert_waits_for_busy_pools() {
+  let config = mem_config();
+  let data = dindex::data::Data::new(&config);
+  
+  let mut rec = Record::empty();
+  rec.p.insert("title".to_string(), "busy".to_string());
+  
+  // Hold every pool as a writer would, the insert must wait rather than drop the record
+  let guards: Vec<_> = data.record_pools.iter().map(|pool| pool.write().unwrap()).collect();
+  crossbeam_utils::thread::scope(|s| {
+    let inserter = s.spawn(|_| data.insert(rec.clone()));
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    drop(guards);
+    assert!(inserter.join().unwrap());
+  }).unwrap();
+  
+  assert_eq!(data.len(), 1);
+}
+
+fn sorted(rec: &Record) -> Vec<(String, String)> {
+  let mut pairs: Vec<(String, String)> = rec.p.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+  pairs.sort();
+  return pairs;
+}
@@ -0,0 +1,133 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *  
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; either version 2 of the License, or
+ *  (at your option) any later version.
+ * 
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ * 
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+
+use dindex;
+
+mod common;
+use common::{empty_config, open_data, named, stored_names};
+
+#[test]
+fn log_replays_inserts_and_removals() {
+  let path = "/tmp/dindex-test-replay.log";
+  let config = test_config(path);
+  {
+    let data = open_data(&config);
+    for i in 0..5 {
+      data.insert(named(&format!("{}", i)));
+    }
+    data.remove(|rec| rec.p["NAME"] == "1" || rec.p["NAME"] == "3");
+  }
+  let data = open_data(&config);
+  assert_eq!(stored_names(&data), vec!["0", "2", "4"]);
+}
+
+#[test]
+fn log_discards_torn_and_corrupt_entries() {
+  let path = "/tmp/dindex-test-torn.log";
+  let config = test_config(path);
+  {
+    let data = open_data(&config);
+    data.insert(named("a"));
+    data.insert(named("b"));
+  }
+  // Simulate dying half way through writing an entry
+  OpenOptions::new().append(true).open(path).unwrap().write_all(&[0, 0, 0, 99, 1, 2, 3]).unwrap();
+  {
+    let data = open_data(&config);
+    assert_eq!(stored_names(&data), vec!["a", "b"]);
+    // The torn bytes were truncated so new entries are readable
+    data.insert(named("c"));
+  }
+  assert_eq!(stored_names(&open_data(&config)), vec!["a", "b", "c"]);
+  
+  // Flip a byte inside the last entry, its checksum no longer matches
+  let mut bytes = std::fs::read(path).unwrap();
+  let last = bytes.len() - 2;
+  bytes[last] ^= 0xff;
+  std::fs::write(path, &bytes).unwrap();
+  assert_eq!(stored_names(&open_data(&config)), vec!["a", "b"]);
+}
+
+#[test]
+fn log_skips_corrupt_entries_before_the_tail() {
+  use dindex::record_log::{RecordLog, LogOp, LOG_MAGIC};
+  
+  let path = "/tmp/dindex-test-corrupt.log";
+  let _ = std::fs::remove_file(path);
+  {
+    let (mut log, _) = RecordLog::open(path).unwrap();
+    for name in &["a", "b", "c"] {
+      log.append(&LogOp::Insert(named(name)));
+    }
+  }
+  let names = |records: Vec<dindex::record::Record>| records.iter().map(|rec| rec.p["NAME"].clone()).collect::<Vec<String>>();
+  
+  // Flip a byte inside the middle entry, the entries around it survive
+  let good = std::fs::read(path).unwrap();
+  let a_len = 8 + u32::from_be_bytes([good[LOG_MAGIC.len()], good[LOG_MAGIC.len()+1], good[LOG_MAGIC.len()+2], good[LOG_MAGIC.len()+3]]) as usize;
+  let b_offset = LOG_MAGIC.len() + a_len;
+  let mut bytes = good.clone();
+  bytes[b_offset + 10] ^= 0xff;
+  std::fs::write(path, &bytes).unwrap();
+  let (log, records) = RecordLog::open(path).unwrap();
+  assert_eq!(names(records), vec!["a", "c"]);
+  assert_eq!(log.num_ops(), 3);
+  assert_eq!(std::fs::read(path).unwrap(), bytes);
+  
+  // A corrupt entry followed by one running past the end could be a bad
+  // length, the log is refused and left alone rather than truncated
+  bytes.extend_from_slice(&[0, 0, 0, 99, 1, 2, 3]);
+  std::fs::write(path, &bytes).unwrap();
+  assert!(RecordLog::open(path).is_err());
+  assert_eq!(std::fs::read(path).unwrap(), bytes);
+}
+
+#[test]
+fn log_compaction() {
+  let path = "/tmp/dindex-test-compact.log";
+  let config = test_config(path);
+  let data = open_data(&config);
+  for i in 0..2000 {
+    data.insert(named(&format!("{}", i)));
+  }
+  data.remove(|rec| rec.p["NAME"] != "7");
+  let size_before = std::fs::metadata(path).unwrap().len();
+  
+  data.maybe_compact_store();
+  assert!(std::fs::metadata(path).unwrap().len() < size_before / 100);
+  let (log, records) = dindex::record_log::RecordLog::open(path).unwrap();
+  assert_eq!(log.num_ops(), 1);
+  assert_eq!(records.len(), 1);
+  
+  // Appends after compaction go to the new file
+  data.insert(named("after"));
+  drop(data);
+  assert_eq!(stored_names(&open_data(&config)), vec!["7", "after"]);
+}
+
+fn test_config(path: &str) -> dindex::config::Config {
+  let _ = std::fs::remove_file(path);
+  let mut test_config = empty_config();
+  test_config.server_extra_quiet = true;
+  test_config.server_datastore_uri = format!("file://{}", path);
+  return test_config;
+}
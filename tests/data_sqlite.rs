@@ -0,0 +1,90 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *  
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; either version 2 of the License, or
+ *  (at your option) any later version.
+ * 
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ * 
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use std::time::Duration;
+
+use dindex;
+use dindex::record::TTL_SECONDS_KEY;
+
+mod common;
+use common::{empty_config, open_data, named, stored_names};
+
+#[test]
+fn sqlite_store() {
+  let path = "/tmp/dindex-test-store.sqlite";
+  let config = test_config(path);
+  {
+    let data = open_data(&config);
+    for i in 0..5 {
+      data.insert(named(&format!("{}", i)));
+    }
+    // Duplicates are stored as separate rows
+    data.insert(named("1"));
+    data.remove(|rec| rec.p["NAME"] == "3");
+    let mut short_lived = named("short lived");
+    short_lived.p.insert(TTL_SECONDS_KEY.to_string(), "0.05".to_string());
+    data.insert(short_lived);
+  }
+  std::thread::sleep(Duration::from_millis(100));
+  
+  {
+    let data = open_data(&config);
+    assert_eq!(stored_names(&data), vec!["0", "1", "1", "2", "4"]);
+    data.remove(|rec| rec.p["NAME"] == "0");
+  }
+  
+  // Records and their keys can be read with standard tools
+  let conn = rusqlite::Connection::open(path).unwrap();
+  let mut stmt = conn.prepare("SELECT value FROM record_keys WHERE key = 'NAME' ORDER BY value").unwrap();
+  let stored: Vec<String> = stmt.query_map(rusqlite::NO_PARAMS, |row| row.get(0)).unwrap()
+    .map(|v| v.unwrap()).collect();
+  assert_eq!(stored, vec!["1", "1", "2", "4"]);
+}
+
+#[test]
+fn sqlite_drops_rows_evicted_while_loading() {
+  let path = "/tmp/dindex-test-evict-load.sqlite";
+  let mut config = test_config(path);
+  {
+    let data = open_data(&config);
+    for i in 0..5 {
+      data.insert(named(&format!("{}", i)));
+    }
+  }
+  config.server_max_records = 2;
+  let kept = stored_names(&open_data(&config));
+  assert_eq!(kept.len(), 2);
+  
+  // Raising the limit again does not bring back what was evicted
+  config.server_max_records = 4096;
+  assert_eq!(stored_names(&open_data(&config)), kept);
+  let conn = rusqlite::Connection::open(path).unwrap();
+  let num_rows: i64 = conn.query_row("SELECT COUNT(*) FROM records", rusqlite::NO_PARAMS, |row| row.get(0)).unwrap();
+  assert_eq!(num_rows, 2);
+}
+
+fn test_config(path: &str) -> dindex::config::Config {
+  for suffix in &["", "-wal", "-shm"] {
+    let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+  }
+  let mut test_config = empty_config();
+  test_config.server_extra_quiet = true;
+  test_config.server_datastore_uri = format!("sqlite://{}", path);
+  return test_config;
+}
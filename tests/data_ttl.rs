@@ -0,0 +1,142 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *  
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; either version 2 of the License, or
+ *  (at your option) any later version.
+ * 
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ * 
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use std::time::Duration;
+
+use dindex;
+use dindex::record::{TTL_SECONDS_KEY, TTL_EXPIRES_AT_KEY};
+
+mod common;
+use common::{mem_config, named};
+
+#[test]
+fn ttl_expiry() {
+  let data = dindex::data::Data::new(&test_config(0.0));
+  
+  let mut short_lived = named("ping");
+  short_lived.p.insert(TTL_SECONDS_KEY.to_string(), "0.1".to_string());
+  let short_lived_id = short_lived.id();
+  data.insert(short_lived);
+  data.insert(named("permanent"));
+  
+  let results = data.search(&named(".*").create_query());
+  assert_eq!(results.len(), 2);
+  // Servers stamp an absolute expiry which does not change the record id
+  let stamped = results.iter().find(|r| r.p["NAME"] == "ping").unwrap();
+  assert!(stamped.p.contains_key(TTL_EXPIRES_AT_KEY));
+  assert_eq!(stamped.id(), short_lived_id);
+  
+  std::thread::sleep(Duration::from_millis(150));
+  
+  // Expired records are hidden from searches before the sweep runs
+  let results = data.search(&named(".*").create_query());
+  assert_eq!(results.len(), 1);
+  assert_eq!(results[0].p["NAME"], "permanent");
+  assert_eq!(data.len(), 2);
+  
+  let expired = data.remove_expired();
+  assert_eq!(expired.len(), 1);
+  assert_eq!(data.len(), 1);
+  
+  // Records which arrive already expired (eg from storage) are not kept
+  let mut stale = named("stale");
+  stale.p.insert(TTL_EXPIRES_AT_KEY.to_string(), "1".to_string());
+  data.insert(stale);
+  assert_eq!(data.len(), 1);
+}
+
+#[test]
+fn ttl_server_default() {
+  let data = dindex::data::Data::new(&test_config(0.1));
+  data.insert(named("default ttl"));
+  let mut kept = named("explicit ttl");
+  kept.p.insert(TTL_SECONDS_KEY.to_string(), "60".to_string());
+  data.insert(kept);
+  
+  std::thread::sleep(Duration::from_millis(150));
+  let results = data.search(&named(".*").create_query());
+  assert_eq!(results.len(), 1);
+  assert_eq!(results[0].p["NAME"], "explicit ttl");
+}
+
+#[test]
+fn ttl_keeps_signature_valid() {
+  let identity_f = "/tmp/dindex-test-ttl.identity";
+  dindex::signing::gen_identity(identity_f);
+  let mut config = test_config(0.0);
+  config.client_use_sig = true;
+  config.client_private_key_file = identity_f.to_string();
+  
+  let data = dindex::data::Data::new(&config);
+  let mut rec = named("signed ping");
+  rec.p.insert(TTL_SECONDS_KEY.to_string(), "30".to_string());
+  dindex::signing::maybe_sign_record(&config, &mut rec);
+  data.insert(rec);
+  
+  let results = data.search(&named(".*").create_query());
+  assert!(results[0].p.contains_key(TTL_EXPIRES_AT_KEY));
+  assert!(results[0].is_signed());
+}
+
+#[test]
+fn ttl_rejects_and_clamps() {
+  let data = dindex::data::Data::new(&test_config(0.0));
+  for bad_ttl in &["inf", "-inf", "NaN", "0", "-5", "soon"] {
+    let mut rec = named(bad_ttl);
+    rec.p.insert(TTL_SECONDS_KEY.to_string(), bad_ttl.to_string());
+    assert!(!data.insert(rec), "TTL:seconds={}", bad_ttl);
+  }
+  assert_eq!(data.len(), 0);
+  
+  // Huge TTLs are clamped rather than overflowing
+  let mut huge = named("huge");
+  huge.p.insert(TTL_SECONDS_KEY.to_string(), "1e300".to_string());
+  assert!(data.insert(huge));
+  let max_expiry = dindex::record::now_ms() + (dindex::record::MAX_TTL_S * 1000.0) as u64;
+  let stamped = data.search(&named("huge").create_query())[0].expires_at_ms().unwrap();
+  assert!(stamped <= max_expiry);
+}
+
+#[test]
+fn ttl_supplied_expiry_only_shortens() {
+  let data = dindex::data::Data::new(&test_config(0.0));
+  let now = dindex::record::now_ms();
+  
+  // A far off expires-at cannot outlive TTL:seconds
+  let mut extended = named("extended");
+  extended.p.insert(TTL_SECONDS_KEY.to_string(), "60".to_string());
+  extended.p.insert(TTL_EXPIRES_AT_KEY.to_string(), format!("{}", u64::MAX));
+  assert!(data.insert(extended));
+  let stamped = data.search(&named("extended").create_query())[0].expires_at_ms().unwrap();
+  assert!(stamped <= dindex::record::now_ms() + 60_000);
+  
+  // An earlier one is kept, as replicated copies need
+  let mut copied = named("copied");
+  copied.p.insert(TTL_SECONDS_KEY.to_string(), "60".to_string());
+  copied.p.insert(TTL_EXPIRES_AT_KEY.to_string(), format!("{}", now + 5_000));
+  assert!(data.insert(copied));
+  let stamped = data.search(&named("copied").create_query())[0].expires_at_ms().unwrap();
+  assert_eq!(stamped, now + 5_000);
+}
+
+fn test_config(default_ttl_s: f64) -> dindex::config::Config {
+  let mut test_config = mem_config();
+  test_config.server_default_ttl_s = default_ttl_s;
+  return test_config;
+}
@@ -0,0 +1,156 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; either version 2 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use crossbeam_utils::thread;
+
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use dindex;
+use dindex::config::ServerProtocol;
+use dindex::data::Data;
+use dindex::discovery;
+use dindex::framing::{Framing, encode};
+use dindex::record::Record;
+use dindex::wire::{Announcement, Capabilities, WireData};
+
+mod common;
+use common::{mem_config, local_server, server_config};
+
+#[test]
+fn announcements_become_servers() {
+  let mut announcement = beacon("alpha", Some(2099));
+  announcement.udp_port = Some(2098);
+  // Without an address there is nothing to connect to
+  assert!(discovery::to_discovered(&announcement).is_none());
+  
+  announcement.host = Some("192.0.2.7".to_string());
+  let found = discovery::to_discovered(&announcement).unwrap();
+  assert_eq!(found.server.protocol, ServerProtocol::TCP);
+  assert_eq!(found.server.host, "192.0.2.7");
+  assert_eq!(found.server.port, 2099);
+  assert_eq!(found.server.name, "alpha");
+  
+  announcement.tcp_port = None;
+  let found = discovery::to_discovered(&announcement).unwrap();
+  assert_eq!(found.server.protocol, ServerProtocol::UDP);
+  assert_eq!(found.server.port, 2098);
+  
+  announcement.tls_port = Some(2097);
+  let found = discovery::to_discovered(&announcement).unwrap();
+  assert_eq!(found.server.protocol, ServerProtocol::TLS);
+  assert_eq!(found.server.port, 2097);
+}
+
+#[test]
+fn announcements_list_tls_listeners() {
+  let mut config = mem_config();
+  let ours = discovery::ours(&config, &Data::new(&config));
+  assert_eq!((ours.tls_port, ours.wss_port), (None, None));
+  
+  config.server_listen_tls = true;
+  config.server_listen_wss = true;
+  let ours = discovery::ours(&config, &Data::new(&config));
+  assert_eq!(ours.tls_port, Some(config.server_tls_port));
+  assert_eq!(ours.wss_port, Some(config.server_wss_port));
+}
+
+#[test]
+fn discover_over_tcp_and_udp() {
+  let port = 2018;
+  let mut config = server_config(&local_server(ServerProtocol::TCP, port));
+  config.server_listen_udp = true;
+  config.server_name = "alpha".to_string();
+  let mut client_config = mem_config();
+  client_config.servers = vec![local_server(ServerProtocol::TCP, port), local_server(ServerProtocol::UDP, port)];
+  
+  let data = Data::new(&config);
+  let exit_flag = data.exit_flag.clone();
+  
+  thread::scope(|s| {
+    let mut handlers = vec![];
+    
+    handlers.push(s.spawn(|_| {
+      dindex::server::run_tcp_sync(&config, &data);
+    }));
+    handlers.push(s.spawn(|_| {
+      dindex::server::run_udp_sync(&config, &data);
+    }));
+    
+    handlers.push(s.spawn(|_| {
+      std::thread::sleep(Duration::from_millis(25));
+      
+      // Another server's beacon, and our own looping back
+      let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+      let mut ours = discovery::ours(&config, &data);
+      ours.name = "looped back".to_string();
+      // Beacons cannot point clients at another host
+      let mut beta = beacon("beta", Some(2099));
+      beta.host = Some("192.0.2.99".to_string());
+      for announcement in vec![beta, ours] {
+        let bytes = encode(&WireData::announce(announcement), Framing::Legacy).unwrap();
+        socket.send_to(&bytes, ("127.0.0.1", port)).unwrap();
+      }
+      std::thread::sleep(Duration::from_millis(50));
+      let heard: Vec<String> = data.heard().iter().map(|a| a.name.clone()).collect();
+      assert_eq!(heard, vec!["beta"]);
+      
+      let over_tcp = discovery::discover_server_sync(&client_config, &client_config.servers[0]);
+      let mut names: Vec<String> = over_tcp.iter().map(|a| a.name.clone()).collect();
+      names.sort();
+      assert_eq!(names, vec!["alpha", "beta"]);
+      // beta's address is where its beacon came from
+      assert!(over_tcp.iter().all(|a| a.host.as_ref().map(|h| h.as_str()) == Some("127.0.0.1")));
+      
+      // Both servers in the client config answer, each server is listed once
+      let mut discovered = discovery::discover_sync(&client_config);
+      discovered.sort_by(|a, b| a.server.name.cmp(&b.server.name));
+      assert_eq!(discovered.len(), 2);
+      let alpha = &discovered[0];
+      assert_eq!(alpha.server.name, "alpha");
+      assert_eq!(alpha.server.protocol, ServerProtocol::TCP);
+      assert_eq!(alpha.server.port, port);
+      assert!(alpha.caps.actions.contains(&"discover".to_string()));
+      assert_eq!(discovered[1].server.port, 2099);
+      
+      // Instruct servers to exit
+      exit_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+      // Send them network traffic to force eval of exit_flag
+      dindex::client::query_sync(&client_config, &Record::empty());
+    }));
+    
+    for h in handlers {
+      h.join().unwrap();
+    }
+  }).unwrap();
+}
+
+fn beacon(name: &str, tcp_port: Option<u16>) -> Announcement {
+  Announcement {
+    instance: format!("instance of {}", name),
+    name: name.to_string(),
+    host: None,
+    tcp_port: tcp_port,
+    udp_port: None,
+    websocket_port: None,
+    tls_port: None,
+    wss_port: None,
+    caps: Capabilities::ours(),
+  }
+}
@@ -0,0 +1,205 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; either version 2 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use crossbeam_utils::thread;
+
+use std::time::Duration;
+
+use dindex;
+use dindex::encryption;
+use dindex::record::Record;
+
+mod common;
+use common::empty_config;
+
+fn test_config() -> dindex::config::Config {
+  let mut test_config = empty_config();
+  test_config.server_extra_quiet = true;
+  return test_config;
+}
+
+fn identity(name: &str) -> String {
+  let path = format!("/tmp/dindex-test-encryption-{}.identity", name);
+  dindex::signing::gen_identity(&path);
+  return path;
+}
+
+fn secret_record() -> Record {
+  let mut rec = Record::empty();
+  rec.p.insert("title".to_string(), "Quarterly numbers".to_string());
+  rec.p.insert("url".to_string(), "https://example.org/q3".to_string());
+  rec.p.insert("notes".to_string(), "Do not share".to_string());
+  return rec;
+}
+
+#[test]
+fn seal_and_open_records() {
+  let alice = identity("alice");
+  let bob = identity("bob");
+  let eve = identity("eve");
+  
+  let mut config = test_config();
+  config.client_private_key_file = alice.clone();
+  config.client_encrypt_keys = vec!["url".to_string(), "notes".to_string()];
+  // Bob is given as the base64 key print_identity shows
+  config.client_encrypt_recipients = vec![dindex::signing::read_pub_key_base64(&bob)];
+  
+  let mut rec = secret_record();
+  encryption::maybe_encrypt_record(&config, &mut rec).unwrap();
+  assert!(encryption::is_encrypted(&rec));
+  assert_eq!(rec.p.get("title").map(|s| s.as_str()), Some("Quarterly numbers"));
+  assert!(!rec.p.contains_key("url"));
+  assert!(!rec.p.contains_key("notes"));
+  assert!(!rec.p.values().any(|v| v.contains("Do not share")));
+  // One wrapped key each for bob and the publisher
+  assert_eq!(rec.p.keys().filter(|k| k.starts_with(encryption::ENCRYPTED_RECIPIENT_PREFIX)).count(), 2);
+  
+  for reader in &[&alice, &bob] {
+    let mut opened = rec.clone();
+    encryption::open_record(&mut opened, &encryption::read_identity(reader).unwrap()).unwrap();
+    assert_eq!(opened.p, secret_record().p);
+  }
+  
+  let mut opened = rec.clone();
+  assert!(encryption::open_record(&mut opened, &encryption::read_identity(&eve).unwrap()).is_err());
+  assert_eq!(opened.p, rec.p);
+  
+  // Values are bound to their key
+  let mut swapped = rec.clone();
+  let url = swapped.p.remove("ENCRYPTED:value:url").unwrap();
+  let notes = swapped.p.insert("ENCRYPTED:value:notes".to_string(), url.clone()).unwrap();
+  swapped.p.insert("ENCRYPTED:value:url".to_string(), notes);
+  assert!(encryption::open_record(&mut swapped, &encryption::read_identity(&bob).unwrap()).is_err());
+  
+  // Keys servers read are left in the clear even when asked for
+  config.client_encrypt_keys = vec![
+    "notes".to_string(), dindex::record::TTL_SECONDS_KEY.to_string(),
+    dindex::record::TTL_EXPIRES_AT_KEY.to_string(), dindex::query::QUERY_TEXT_KEY.to_string()
+  ];
+  let mut rec = secret_record();
+  rec.p.insert(dindex::record::TTL_SECONDS_KEY.to_string(), "60".to_string());
+  rec.p.insert(dindex::record::TTL_EXPIRES_AT_KEY.to_string(), "1000".to_string());
+  rec.p.insert(dindex::query::QUERY_TEXT_KEY.to_string(), "numbers".to_string());
+  encryption::maybe_encrypt_record(&config, &mut rec).unwrap();
+  assert!(!rec.p.contains_key("notes"));
+  assert_eq!(rec.p.get(dindex::record::TTL_SECONDS_KEY).map(|s| s.as_str()), Some("60"));
+  assert_eq!(rec.p.get(dindex::record::TTL_EXPIRES_AT_KEY).map(|s| s.as_str()), Some("1000"));
+  assert_eq!(rec.p.get(dindex::query::QUERY_TEXT_KEY).map(|s| s.as_str()), Some("numbers"));
+  
+  // Nobody to encrypt for is an error, not a plaintext record
+  let mut config = test_config();
+  config.client_private_key_file = "/tmp/dindex-test-encryption-missing.identity".to_string();
+  config.client_encrypt_keys = vec!["notes".to_string()];
+  let mut rec = secret_record();
+  assert!(encryption::maybe_encrypt_record(&config, &mut rec).is_err());
+  
+  // An Ed25519 identity cannot be sealed to, so the publisher is not quietly left out
+  let signer = "/tmp/dindex-test-encryption-ed25519.identity";
+  dindex::signing::gen_identity_of_type(signer, dindex::signing::KeyType::Ed25519);
+  let e = encryption::read_identity(signer).unwrap_err();
+  assert!(e.contains("ed25519"), "{}", e);
+  config.client_private_key_file = signer.to_string();
+  config.client_encrypt_recipients = vec![dindex::signing::read_pub_key_base64(&bob)];
+  let mut rec = secret_record();
+  assert!(encryption::maybe_encrypt_record(&config, &mut rec).is_err());
+  assert_eq!(rec.p, secret_record().p);
+}
+
+#[test]
+fn query_encrypted_records() {
+  let publisher = identity("publisher");
+  let reader = identity("reader");
+  
+  let port = 2021;
+  let mut test_config = test_config();
+  test_config.servers = vec![dindex::config::Server {
+    protocol: dindex::config::ServerProtocol::TCP,
+    host: "127.0.0.1".to_string(),
+    port: port,
+    path: "/tmp/dindex.test.socket".to_string(),
+    max_latency_ms: 250,
+    report_connect_errors: true,
+    name: "Localhost Server".to_string(),
+    tls_pin: None
+  }];
+  test_config.server_port = port;
+  test_config.server_ip = "127.0.0.1".to_string();
+  test_config.server_listen_tcp = true;
+  test_config.server_listen_udp = false;
+  test_config.server_listen_unix = false;
+  test_config.server_listen_websocket = false;
+  test_config.server_datastore_uri = "memory://".to_string();
+  
+  let mut publisher_config = test_config.clone();
+  publisher_config.client_private_key_file = publisher.clone();
+  publisher_config.client_use_sig = true;
+  publisher_config.client_encrypt_keys = vec!["notes".to_string()];
+  publisher_config.client_encrypt_recipients = vec![reader.clone()];
+  
+  let mut reader_config = test_config.clone();
+  reader_config.client_private_key_file = reader.clone();
+  
+  let mut data = dindex::data::Data::new(&test_config);
+  let exit_flag = data.exit_flag.clone();
+  
+  thread::scope(|s| {
+    let mut handlers = vec![];
+    
+    handlers.push(s.spawn(|_| {
+      dindex::server::run_tcp_sync(&test_config, &mut data);
+    }));
+    
+    handlers.push(s.spawn(|_| {
+      std::thread::sleep(Duration::from_millis(25));
+      
+      // Encrypted before signing, so servers still check the signature
+      let mut rec = secret_record();
+      encryption::maybe_encrypt_record(&publisher_config, &mut rec).unwrap();
+      dindex::signing::maybe_sign_record(&publisher_config, &mut rec);
+      assert!(dindex::client::publish_sync(&publisher_config, &rec)[0].1.is_ok());
+      
+      // Plaintext keys are still queryable
+      let query = {
+        let mut rec = Record::empty();
+        rec.p.insert("title".to_string(), "Quarterly.*".to_string());
+        rec
+      };
+      let mut results = dindex::client::query_sync(&reader_config, &query);
+      assert_eq!(results.len(), 1);
+      assert!(dindex::signing::is_valid_sig(&results[0]));
+      assert!(!results[0].p.contains_key("notes"));
+      
+      encryption::decrypt_results(&reader_config, &mut results);
+      assert_eq!(results[0].p.get("notes").map(|s| s.as_str()), Some("Do not share"));
+      
+      // Others see the record still sealed
+      let mut results = dindex::client::query_sync(&test_config, &query);
+      encryption::decrypt_results(&test_config, &mut results);
+      assert!(encryption::is_encrypted(&results[0]));
+      assert!(!results[0].p.contains_key("notes"));
+      
+      exit_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+      dindex::client::query_sync(&test_config, &query);
+    }));
+    
+    for h in handlers {
+      h.join().unwrap();
+    }
+  }).unwrap();
+}
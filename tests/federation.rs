@@ -0,0 +1,181 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; either version 2 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use crossbeam_utils::thread;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use dindex;
+use dindex::client::{ListenAction, ListenEvent};
+use dindex::config::ServerProtocol;
+use dindex::data::Data;
+use dindex::federation::{Route, RecentIds};
+use dindex::wire::{Forwarded, QueryOptions};
+
+mod common;
+use common::{mem_config, local_server, server_config, pair, names};
+
+#[test]
+fn hop_limits_and_loops() {
+  let mut config = mem_config();
+  config.servers = vec![local_server(ServerProtocol::TCP, 2013)];
+  let data = Data::new(&config);
+  // Without server_federate requests are answered locally
+  assert_eq!(dindex::federation::route(&config, &data, None), Route::Local);
+  
+  config.server_federate = true;
+  config.server_federation_max_hops = 2;
+  let first = match dindex::federation::route(&config, &data, None) {
+    Route::Forward(forwarded) => forwarded,
+    other => panic!("expected to forward, got {:?}", other),
+  };
+  assert_eq!(first.hops, 1);
+  // Coming back around a loop
+  assert_eq!(dindex::federation::route(&config, &data, Some(&first)), Route::Seen);
+  
+  let second = Forwarded { request_id: "abc".to_string(), hops: 1 };
+  assert_eq!(dindex::federation::route(&config, &data, Some(&second)), Route::Forward(Forwarded { hops: 2, ..second.clone() }));
+  let last = Forwarded { request_id: "def".to_string(), hops: 2 };
+  assert_eq!(dindex::federation::route(&config, &data, Some(&last)), Route::Local);
+  assert_eq!(dindex::federation::route(&config, &data, Some(&last)), Route::Seen);
+}
+
+#[test]
+fn recent_ids_are_bounded() {
+  let mut ids = RecentIds::new(2);
+  assert!(ids.insert("a"));
+  assert!(! ids.insert("a"));
+  assert!(ids.insert("b"));
+  assert!(ids.insert("c"));
+  assert_eq!(ids.len(), 2);
+  assert!(ids.insert("a")); // Forgotten
+}
+
+#[test]
+fn tcp_federation() {
+  // a <-> b -> c, a loop between a and b and a chain on to c
+  let (server_a, server_b, server_c) = (local_server(ServerProtocol::TCP, 2013), local_server(ServerProtocol::TCP, 2014), local_server(ServerProtocol::TCP, 2015));
+  let mut config_a = server_config(&server_a);
+  config_a.server_federate = true;
+  config_a.servers = vec![server_b.clone()];
+  let mut config_b = server_config(&server_b);
+  config_b.server_federate = true;
+  config_b.servers = vec![server_a.clone(), server_c.clone()];
+  let mut config_c = server_config(&server_c);
+  config_c.server_federate = true;
+  config_c.servers = vec![];
+  let mut client_config = mem_config();
+  client_config.servers = vec![server_a.clone()];
+  
+  let mut data_a = Data::new(&config_a);
+  let mut data_b = Data::new(&config_b);
+  let mut data_c = Data::new(&config_c);
+  data_a.insert(pair("NAME", "a"));
+  data_a.insert(pair("NAME", "shared"));
+  data_b.insert(pair("NAME", "b"));
+  data_b.insert(pair("NAME", "shared"));
+  data_c.insert(pair("NAME", "c"));
+  let exit_flags = vec![data_a.exit_flag.clone(), data_b.exit_flag.clone(), data_c.exit_flag.clone()];
+  
+  // Spawn server and client threads to perform testing
+  thread::scope(|s| {
+    let mut handlers = vec![];
+    
+    handlers.push(s.spawn(|_| {
+      dindex::server::run_tcp_sync(&config_a, &mut data_a);
+    }));
+    handlers.push(s.spawn(|_| {
+      dindex::server::run_tcp_sync(&config_b, &mut data_b);
+    }));
+    handlers.push(s.spawn(|_| {
+      dindex::server::run_tcp_sync(&config_c, &mut data_c);
+    }));
+    
+    handlers.push(s.spawn(|_| {
+      // This is where client logic is tested
+      std::thread::sleep(Duration::from_millis(25));
+      // Servers should have bound to ports within 25ms
+      
+      let mut everything = names(&dindex::client::query_server_sync(&client_config, &server_a, &pair("NAME", ".*")));
+      everything.sort();
+      assert_eq!(everything, vec!["a", "b", "c", "shared"]);
+      
+      // Paged queries are merged across servers before the page is cut
+      let options = QueryOptions { sort_by: Some("NAME".to_string()), offset: Some(1), limit: Some(2), ..QueryOptions::default() };
+      let page = dindex::client::query_server_page_sync(&client_config, &server_a, &pair("NAME", ".*"), &options);
+      assert_eq!(names(&page.records), vec!["b", "c"]);
+      assert!(page.next_cursor.is_none());
+      
+      // A server which does not federate answers alone
+      assert_eq!(names(&dindex::client::query_server_sync(&client_config, &server_c, &pair("NAME", ".*"))), vec!["c"]);
+      
+      // Listens see records published anywhere downstream, once each
+      let conn = dindex::connection::Connection::open(&client_config, &server_a).unwrap();
+      let heard = Arc::new(Mutex::new(vec![]));
+      let t_heard = heard.clone();
+      conn.listen_events(&pair("NAME", "^new"), move |event| {
+        if let ListenEvent::Published(rec) = event {
+          t_heard.lock().unwrap().push(rec.p.get("NAME").unwrap().clone());
+        }
+        return ListenAction::Continue;
+      }).unwrap();
+      std::thread::sleep(Duration::from_millis(100));
+      dindex::client::publish_server_sync(&client_config, &server_c, &pair("NAME", "new c")).unwrap();
+      dindex::client::publish_server_sync(&client_config, &server_a, &pair("NAME", "new twice")).unwrap();
+      dindex::client::publish_server_sync(&client_config, &server_b, &pair("NAME", "new twice")).unwrap();
+      std::thread::sleep(Duration::from_millis(100));
+      let mut heard = heard.lock().unwrap().clone();
+      heard.sort();
+      assert_eq!(heard, vec!["new c", "new twice"]);
+      
+      // Replays cover every server and end after all of them
+      let replayed = Arc::new(Mutex::new(vec![]));
+      let t_replayed = replayed.clone();
+      let replay = QueryOptions { replay: true, ..QueryOptions::default() };
+      conn.listen_events_with_options(&pair("NAME", "^(a|b|c|shared|later)$"), &replay, move |event| {
+        t_replayed.lock().unwrap().push(match event {
+          ListenEvent::Published(rec) => rec.p.get("NAME").unwrap().clone(),
+          ListenEvent::Removed(rec) => format!("removed {}", rec.p.get("NAME").unwrap()),
+          ListenEvent::EndOfReplay => "end of replay".to_string(),
+        });
+        return ListenAction::Continue;
+      }).unwrap();
+      std::thread::sleep(Duration::from_millis(100));
+      dindex::client::publish_server_sync(&client_config, &server_c, &pair("NAME", "later")).unwrap();
+      std::thread::sleep(Duration::from_millis(100));
+      let replayed = replayed.lock().unwrap().clone();
+      let mut before: Vec<String> = replayed[..4].to_vec();
+      before.sort();
+      assert_eq!(before, vec!["a", "b", "c", "shared"]);
+      assert_eq!(replayed[4..].to_vec(), vec!["end of replay", "later"]);
+      
+      // Instruct servers to exit
+      for (exit_flag, server) in exit_flags.iter().zip(&[&server_a, &server_b, &server_c]) {
+        exit_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+        // Send it network traffic to force eval of exit_flag
+        dindex::client::query_server_sync(&client_config, server, &pair("NAME", ".*"));
+      }
+    }));
+    
+    for h in handlers {
+      h.join().unwrap();
+    }
+  }).unwrap();
+}
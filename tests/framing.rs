@@ -0,0 +1,170 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *  
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; either version 2 of the License, or
+ *  (at your option) any later version.
+ * 
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ * 
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use crossbeam_utils::thread;
+
+use std::io::prelude::*;
+use std::time::Duration;
+
+use dindex;
+use dindex::framing::{Framing, FrameDecoder, FrameError, encode};
+
+mod common;
+use common::empty_config;
+
+fn wire_with(key: &str, val: &str) -> dindex::wire::WireData {
+  let mut rec = dindex::record::Record::empty();
+  rec.p.insert(key.to_string(), val.to_string());
+  dindex::wire::WireData::new(dindex::actions::Action::publish, rec)
+}
+
+#[test]
+fn framing_round_trip() {
+  // ÿ is U+00FF which puts 0xff bytes inside the CBOR payload
+  let messages = vec![wire_with("NAME", "ÿÿÿ"), wire_with("URL", "https://lipsum.com/")];
+  
+  for &framing in &[Framing::Length, Framing::Legacy] {
+    let mut bytes = vec![];
+    for m in &messages {
+      bytes.extend_from_slice(&encode(m, framing).unwrap());
+    }
+    
+    // Feed one byte at a time to exercise partial reads
+    let mut decoder = FrameDecoder::new();
+    let mut decoded = vec![];
+    for b in &bytes {
+      decoder.push(&[*b]);
+      while let Some(wire_data) = decoder.next_frame().unwrap() {
+        decoded.push(wire_data);
+      }
+    }
+    assert_eq!(decoder.framing(), Some(framing));
+    assert!(decoder.finish().is_ok());
+    assert_eq!(decoded.len(), 2);
+    assert_eq!(decoded[0].record.p.get("NAME").unwrap(), "ÿÿÿ");
+    assert_eq!(decoded[1].record.p.get("URL").unwrap(), "https://lipsum.com/");
+  }
+}
+
+#[test]
+fn framing_errors() {
+  let mut decoder = FrameDecoder::new();
+  decoder.push(&[0xff, b'X', 1, 0, 0, 0, 0]);
+  match decoder.next_frame() {
+    Err(FrameError::BadMagic) => { }
+    other => panic!("expected BadMagic, got {:?}", other.map(|_| ())),
+  }
+  
+  let mut decoder = FrameDecoder::new();
+  decoder.push(&[0xff, b'D', 9, 0, 0, 0, 0]);
+  match decoder.next_frame() {
+    Err(FrameError::UnsupportedVersion(9)) => { }
+    other => panic!("expected UnsupportedVersion, got {:?}", other.map(|_| ())),
+  }
+  
+  let mut decoder = FrameDecoder::new();
+  decoder.push(&[0xff, b'D', 1, 0xff, 0xff, 0xff, 0xff]);
+  match decoder.next_frame() {
+    Err(FrameError::TooLarge(_)) => { }
+    other => panic!("expected TooLarge, got {:?}", other.map(|_| ())),
+  }
+  
+  let bytes = encode(&wire_with("NAME", "truncated"), Framing::Length).unwrap();
+  let mut decoder = FrameDecoder::new();
+  decoder.push(&bytes[0..bytes.len()-1]);
+  assert!(decoder.next_frame().unwrap().is_none());
+  match decoder.finish() {
+    Err(FrameError::Truncated(_)) => { }
+    other => panic!("expected Truncated, got {:?}", other),
+  }
+}
+
+#[test]
+fn framing_legacy_client() {
+  let mut test_config = empty_config();
+  let port = 2002;
+  let localhost_server = dindex::config::Server {
+    protocol: dindex::config::ServerProtocol::TCP,
+    host: "127.0.0.1".to_string(),
+    port: port,
+    path: "/tmp/dindex.test.socket".to_string(),
+    max_latency_ms: 250,
+    report_connect_errors: true,
+    name: "Localhost Server".to_string(),
+    tls_pin: None
+  };
+  test_config.servers = vec![localhost_server];
+  test_config.server_port = port;
+  test_config.server_ip = "127.0.0.1".to_string();
+  test_config.server_listen_tcp = true;
+  test_config.server_listen_udp = false;
+  test_config.server_listen_unix = false;
+  test_config.server_listen_websocket = false;
+  test_config.server_extra_quiet = true;
+  test_config.server_datastore_uri = "memory://".to_string();
+  
+  let mut data = dindex::data::Data::new(&test_config);
+  let exit_flag = data.exit_flag.clone();
+  
+  thread::scope(|s| {
+    let mut handlers = vec![];
+    
+    handlers.push(s.spawn(|_| {
+      dindex::server::run_tcp_sync(&test_config, &mut data);
+    }));
+    
+    handlers.push(s.spawn(|_| {
+      std::thread::sleep(Duration::from_millis(25));
+      
+      // Publish with the current client, which uses length-prefixed frames
+      dindex::client::publish_sync(&test_config, &wire_with("NAME", "ÿ legacy").record);
+      
+      // Query the way clients did before framing existed: CBOR followed by 0xff
+      let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+      stream.set_read_timeout(Some(Duration::from_millis(256))).unwrap();
+      let mut query = wire_with("NAME", ".*");
+      query.action = dindex::actions::Action::query;
+      stream.write_all(&encode(&query, Framing::Legacy).unwrap()).unwrap();
+      
+      let mut decoder = FrameDecoder::with_framing(Framing::Legacy);
+      let mut results = vec![];
+      loop {
+        match dindex::framing::read_frame(&mut stream, &mut decoder) {
+          Ok(Some(wire_data)) => {
+            if wire_data.action == dindex::actions::Action::end_of_results {
+              break;
+            }
+            results.push(wire_data.record);
+          }
+          Ok(None) => break,
+          Err(e) => panic!("legacy read failed: {}", e),
+        }
+      }
+      assert_eq!(results.len(), 1);
+      assert_eq!(results[0].p.get("NAME").unwrap(), "ÿ legacy");
+      
+      exit_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+      dindex::client::query_sync(&test_config, &query.record);
+    }));
+    
+    for h in handlers {
+      h.join().unwrap();
+    }
+  }).unwrap();
+}
@@ -0,0 +1,201 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; either version 2 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use crossbeam_utils::thread;
+
+use std::time::Duration;
+
+use dindex;
+use dindex::record::Record;
+use dindex::wire::QueryOptions;
+use dindex::query::{QUERY_TEXT_KEY, TEXT_SCORE_KEY};
+
+mod common;
+use common::{mem_config, names};
+
+#[test]
+fn ranked_by_relevance() {
+  let data = dindex::data::Data::new(&mem_config());
+  insert_pages(&data);
+  
+  let query = text_query("Rust web").create_query();
+  let (results, next_cursor) = data.search_page(&query, &QueryOptions::default()).unwrap();
+  assert!(next_cursor.is_none());
+  // Both words beat one, the same word in a short page beats it in a long one,
+  // and pages without either word (or only in a signature) are left out
+  let ranked = names(&results);
+  assert_eq!(ranked.len(), 4);
+  assert_eq!(ranked[0], "both");
+  let position = |name: &str| ranked.iter().position(|n| n == name).unwrap();
+  assert!(position("rust title") < position("rust description"));
+  assert!(position("web only") > 0);
+  let scores: Vec<f64> = results.iter().map(|r| r.p[TEXT_SCORE_KEY].parse().unwrap()).collect();
+  assert!(scores.windows(2).all(|w| w[0] >= w[1]), "{:?}", scores);
+  assert!(scores.iter().all(|s| *s > 0.0));
+  
+  // Rarer words count for more
+  let (results, _) = data.search_page(&text_query("web framework").create_query(), &QueryOptions::default()).unwrap();
+  assert_eq!(names(&results)[0], "both");
+  
+  // sort_by overrides the ranking, limit and cursors page through it
+  let by_name = QueryOptions { sort_by: Some("NAME".to_string()), ..QueryOptions::default() };
+  assert_eq!(names(&data.search_page(&query, &by_name).unwrap().0), vec!["both", "rust description", "rust title", "web only"]);
+  let mut page_options = QueryOptions { limit: Some(3), ..QueryOptions::default() };
+  let (first, cursor) = data.search_page(&query, &page_options).unwrap();
+  page_options.cursor = cursor;
+  let (second, cursor) = data.search_page(&query, &page_options).unwrap();
+  assert!(cursor.is_none());
+  assert_eq!(names(&first), ranked[..3].to_vec());
+  assert_eq!(names(&second), ranked[3..].to_vec());
+  
+  // Plain matching needs any one word, in any case
+  assert!(text_query("RUST").create_query().matches(&page("x", "Learning rust", "")));
+  assert!(!text_query("rust").create_query().matches(&page("x", "Learning rusty", "")));
+  assert!(!text_query("").create_query().matches(&page("x", "Learning rust", "")));
+}
+
+#[test]
+fn text_stats_follow_removals() {
+  let data = dindex::data::Data::new(&mem_config());
+  insert_pages(&data);
+  let terms = vec!["rust".to_string(), "web".to_string(), "nothing".to_string()];
+  let before = data.text_stats(&terms);
+  assert_eq!(before.docs, 6);
+  assert_eq!(before.doc_freqs, vec![3, 2, 0]);
+  
+  data.remove(|rec| rec.p.get("NAME").map(|n| n.starts_with("rust")).unwrap_or(false));
+  let after = data.text_stats(&terms);
+  assert_eq!(after.docs, 4);
+  assert_eq!(after.doc_freqs, vec![1, 2, 0]);
+  assert!(after.tokens < before.tokens);
+}
+
+#[test]
+fn words_become_text_queries() {
+  let config = mem_config();
+  let args = |s: &str| -> Vec<String> { s.split_whitespace().map(|w| w.to_string()).collect() };
+  let words = dindex::args::parse_query_record(&args("rust web framework"), 0, &config);
+  assert_eq!(words.p.get(QUERY_TEXT_KEY), Some(&"rust web framework".to_string()));
+  // Records and expressions are still understood as before
+  let json = dindex::args::parse_query_record(&args("{\"title\": \"rust\"}"), 0, &config);
+  assert_eq!(json.p.get("title"), Some(&"rust".to_string()));
+  let expr = dindex::args::parse_query_record(&args("title ~ rust"), 0, &config);
+  assert!(expr.p.contains_key(dindex::query::QUERY_EXPRESSION_KEY));
+  // Publishing never turns words into a query
+  assert!(dindex::args::parse_record(&args("rust web"), 0, &config).is_empty());
+}
+
+#[test]
+fn tcp_text_query() {
+  let mut test_config = mem_config();
+  // Write details for temporary data
+  let port = 2010;
+  let localhost_server = dindex::config::Server {
+    protocol: dindex::config::ServerProtocol::TCP,
+    host: "127.0.0.1".to_string(),
+    port: port,
+    path: "/tmp/dindex.test.socket".to_string(),
+    max_latency_ms: 250,
+    report_connect_errors: true,
+    name: "Localhost Server".to_string(),
+    tls_pin: None
+  };
+  test_config.servers = vec![localhost_server.clone()];
+  test_config.server_port = port;
+  test_config.server_ip = "127.0.0.1".to_string();
+  test_config.server_listen_tcp = true;
+  test_config.server_listen_udp = false;
+  test_config.server_listen_unix = false;
+  test_config.server_listen_websocket = false;
+
+  // Create a data store
+  let mut data = dindex::data::Data::new(&test_config);
+  insert_pages(&data);
+  let identity_f = "/tmp/dindex-test-full-text.identity";
+  dindex::signing::gen_identity(identity_f);
+  let mut signing_config = test_config.clone();
+  signing_config.client_use_sig = true;
+  signing_config.client_private_key_file = identity_f.to_string();
+  let mut signed = page("signed", "Rust signed", "");
+  dindex::signing::maybe_sign_record(&signing_config, &mut signed);
+  let signed_id = signed.id();
+  data.insert(signed);
+  let exit_flag = data.exit_flag.clone();
+
+  // Spawn server and client threads to perform testing
+  thread::scope(|s| {
+    let mut handlers = vec![];
+
+    handlers.push(s.spawn(|_| {
+      dindex::server::run_tcp_sync(&test_config, &mut data);
+    }));
+
+    handlers.push(s.spawn(|_| {
+      // This is where client logic is tested
+      std::thread::sleep(Duration::from_millis(25));
+      // Server should have bound to ports within 25ms
+
+      let results = dindex::client::query_sync_with_options(&test_config, &text_query("rust web"), &QueryOptions::default());
+      assert_eq!(names(&results)[0], "both");
+      assert_eq!(results.len(), 5);
+      // The score is not part of the signature or id
+      let signed = results.iter().find(|r| r.p["NAME"] == "signed").unwrap();
+      assert!(signed.p.contains_key(TEXT_SCORE_KEY));
+      assert!(dindex::signing::is_valid_sig(signed));
+      assert_eq!(signed.id(), signed_id);
+      
+      let count = dindex::client::count_server_sync(&test_config, &localhost_server, &text_query("web"), None).unwrap();
+      assert_eq!(count.total, 2);
+
+      // Instruct server to exit
+      exit_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+      // Send it network traffic to force eval of exit_flag
+      dindex::client::query_sync(&test_config, &text_query("rust"));
+
+    }));
+
+    for h in handlers {
+      h.join().unwrap();
+    }
+  }).unwrap();
+
+}
+
+fn insert_pages(data: &dindex::data::Data) {
+  data.insert(page("both", "Rust web framework", "A web framework for Rust"));
+  data.insert(page("rust title", "Rust", "A systems language"));
+  data.insert(page("rust description", "Programming languages", "A long description which mentions rust once among many other words about languages"));
+  data.insert(page("web only", "The web", "Browsers and servers"));
+  data.insert(page("neither", "Cooking", "Recipes for bread"));
+  let mut signature_only = page("signature only", "Gardening", "Tomatoes");
+  signature_only.p.insert(dindex::signing::SIGNING_NON_SIG_BYTES_KEY.to_string(), "rust web".to_string());
+  data.insert(signature_only);
+}
+
+fn page(name: &str, title: &str, description: &str) -> Record {
+  let mut rec = Record::empty();
+  rec.p.insert("NAME".to_string(), name.to_string());
+  rec.p.insert("title".to_string(), title.to_string());
+  rec.p.insert("description".to_string(), description.to_string());
+  rec
+}
+
+fn text_query(words: &str) -> Record {
+  return dindex::args::text_query_record(words);
+}
@@ -0,0 +1,129 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *  
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; either version 2 of the License, or
+ *  (at your option) any later version.
+ * 
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ * 
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use crossbeam_utils::thread;
+
+use std::io::prelude::*;
+use std::time::Duration;
+
+use dindex;
+use dindex::framing::{Framing, FrameDecoder, FRAME_MAGIC, FRAME_VERSION};
+
+mod common;
+use common::empty_config;
+
+// Lets us put an action number on the wire that dindex does not know about
+#[derive(serde::Serialize)]
+struct RawWireData {
+  action: u8,
+  record: dindex::record::Record,
+}
+
+#[test]
+fn hello_and_unknown_action() {
+  let mut test_config = empty_config();
+  // Write details for temporary data
+  let port = 2003;
+  let localhost_server = dindex::config::Server {
+    protocol: dindex::config::ServerProtocol::TCP,
+    host: "127.0.0.1".to_string(),
+    port: port,
+    path: "/tmp/dindex.test.socket".to_string(),
+    max_latency_ms: 250,
+    report_connect_errors: true,
+    name: "Localhost Server".to_string(),
+    tls_pin: None
+  };
+  test_config.servers = vec![localhost_server.clone()];
+  test_config.server_port = port;
+  test_config.server_ip = "127.0.0.1".to_string();
+  test_config.server_listen_tcp = true;
+  test_config.server_listen_udp = false;
+  test_config.server_listen_unix = false;
+  test_config.server_listen_websocket = false;
+  test_config.server_extra_quiet = true;
+  
+  // Tell server not to store records outside this process's memory
+  test_config.server_datastore_uri = "memory://".to_string();
+  
+  // Create a data store
+  let mut data = dindex::data::Data::new(&test_config);
+  let exit_flag = data.exit_flag.clone();
+  
+  // Spawn server and client threads to perform testing
+  thread::scope(|s| {
+    let mut handlers = vec![];
+    
+    handlers.push(s.spawn(|_| {
+      dindex::server::run_tcp_sync(&test_config, &mut data);
+    }));
+    
+    handlers.push(s.spawn(|_| {
+      // This is where client logic is tested
+      std::thread::sleep(Duration::from_millis(25));
+      // Server should have bound to ports within 25ms
+      
+      assert_eq!(dindex::client::negotiated_framing(&localhost_server), None);
+      let caps = dindex::client::hello_server_sync(&test_config, &localhost_server).unwrap();
+      assert_eq!(caps, dindex::wire::Capabilities::ours());
+      // Later requests skip straight to the framing both sides prefer
+      assert_eq!(dindex::client::negotiated_framing(&localhost_server), Some(Framing::Length));
+      
+      // Peers drop entries they do not know about
+      let mut peer = caps.clone();
+      peer.framing = vec!["legacy".to_string(), "future-framing".to_string()];
+      let negotiated = dindex::wire::Capabilities::ours().negotiate(&peer);
+      assert_eq!(negotiated.framing, vec!["legacy".to_string()]);
+      assert!(negotiated.supports_action("hello"));
+      // Replies are not requests the server accepts
+      assert!(! caps.supports_action("ack"));
+      assert!(! caps.supports_action("removed"));
+      
+      // An action number from the future gets a structured error
+      let payload = serde_cbor::to_vec(&RawWireData {
+        action: 200,
+        record: dindex::record::Record::empty(),
+      }).unwrap();
+      let mut bytes = FRAME_MAGIC.to_vec();
+      bytes.push(FRAME_VERSION);
+      bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+      bytes.extend_from_slice(&payload);
+      
+      let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+      stream.set_read_timeout(Some(Duration::from_millis(256))).unwrap();
+      stream.write_all(&bytes).unwrap();
+      
+      let mut decoder = FrameDecoder::with_framing(Framing::Length);
+      let reply = dindex::framing::read_frame(&mut stream, &mut decoder).unwrap().unwrap();
+      assert_eq!(reply.action, dindex::actions::Action::error);
+      assert_eq!(reply.record.p.get("error-code").unwrap(), "unknown-action");
+      
+      // Instruct server to exit
+      exit_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+      // Send it network traffic to force eval of exit_flag
+      dindex::client::query_sync(&test_config, &dindex::record::Record::empty());
+      
+    }));
+    
+    for h in handlers {
+      h.join().unwrap();
+    }
+  }).unwrap();
+  
+}
@@ -0,0 +1,167 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; either version 2 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use crossbeam_utils::thread;
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use dindex;
+use dindex::actions::Action;
+use dindex::client::{ListenAction, ListenEvent};
+use dindex::data::{Data, Listener};
+use dindex::wire::QueryOptions;
+
+mod common;
+use common::{mem_config, record};
+
+#[test]
+fn replay_then_live() {
+  let config = mem_config();
+  let data = Data::new(&config);
+  data.insert(record(&[("n", "0"), ("kind", "old")]));
+  data.insert(record(&[("n", "1"), ("kind", "old")]));
+  data.insert(record(&[("other", "x")]));
+  
+  let (tx, rx) = mpsc::channel();
+  let query = record(&[("n", ".*")]).create_query();
+  data.listen_with_replay(Listener::new(query, Some(7), tx, Arc::new(Mutex::new(AtomicBool::new(true)))));
+  data.insert(record(&[("n", "2"), ("kind", "new")]));
+  
+  let replies: Vec<_> = rx.try_iter().collect();
+  let actions: Vec<Action> = replies.iter().map(|r| r.action).collect();
+  assert_eq!(actions, vec![Action::result, Action::result, Action::end_of_replay, Action::result]);
+  assert!(replies.iter().all(|r| r.id == Some(7)));
+  assert!(replies[..2].iter().all(|r| r.record.p.get("kind").unwrap() == "old"));
+  assert_eq!(replies[3].record.p.get("kind").unwrap(), "new");
+}
+
+#[test]
+fn no_gap_or_duplicate_under_inserts() {
+  let config = mem_config();
+  let data = Data::new(&config);
+  let num_records = 2000;
+  for n in 0..num_records / 2 {
+    data.insert(record(&[("n", &n.to_string())]));
+  }
+  let (tx, rx) = mpsc::channel();
+  thread::scope(|s| {
+    let data = &data;
+    // Inserts race the replay
+    s.spawn(move |_| {
+      for n in num_records / 2..num_records {
+        data.insert(record(&[("n", &n.to_string())]));
+      }
+    });
+    s.spawn(move |_| {
+      let query = record(&[("n", ".*")]).create_query();
+      data.listen_with_replay(Listener::new(query, None, tx, Arc::new(Mutex::new(AtomicBool::new(true)))));
+    });
+  }).unwrap();
+  
+  let mut seen = HashSet::new();
+  let mut num_markers = 0;
+  for reply in rx.try_iter() {
+    match reply.action {
+      Action::result => assert!(seen.insert(reply.record.p.get("n").unwrap().clone()), "sent twice: {:?}", reply.record.p),
+      Action::end_of_replay => num_markers += 1,
+      other => panic!("unexpected {}", other),
+    }
+  }
+  assert_eq!(num_markers, 1);
+  assert_eq!(seen.len(), num_records);
+}
+
+#[test]
+fn tcp_listen_replay() {
+  let mut test_config = mem_config();
+  // Write details for temporary data
+  let port = 2012;
+  let localhost_server = dindex::config::Server {
+    protocol: dindex::config::ServerProtocol::TCP,
+    host: "127.0.0.1".to_string(),
+    port: port,
+    path: "/tmp/dindex.test.socket".to_string(),
+    max_latency_ms: 250,
+    report_connect_errors: true,
+    name: "Localhost Server".to_string(),
+    tls_pin: None
+  };
+  test_config.servers = vec![localhost_server.clone()];
+  test_config.server_port = port;
+  test_config.server_ip = "127.0.0.1".to_string();
+  test_config.server_listen_tcp = true;
+  test_config.server_listen_udp = false;
+  test_config.server_listen_unix = false;
+  test_config.server_listen_websocket = false;
+
+  // Create a data store
+  let mut data = Data::new(&test_config);
+  data.insert(record(&[("NAME", "before")]));
+  let exit_flag = data.exit_flag.clone();
+
+  // Spawn server and client threads to perform testing
+  thread::scope(|s| {
+    let mut handlers = vec![];
+
+    handlers.push(s.spawn(|_| {
+      dindex::server::run_tcp_sync(&test_config, &mut data);
+    }));
+
+    handlers.push(s.spawn(|_| {
+      // This is where client logic is tested
+      std::thread::sleep(Duration::from_millis(25));
+      // Server should have bound to ports within 25ms
+
+      let conn = dindex::connection::Connection::open(&test_config, &localhost_server).unwrap();
+      let events = Arc::new(Mutex::new(vec![]));
+      let t_events = events.clone();
+      let options = QueryOptions { replay: true, ..QueryOptions::default() };
+      conn.listen_events_with_options(&record(&[("NAME", ".*")]), &options, move |event| {
+        t_events.lock().unwrap().push(match event {
+          ListenEvent::Published(rec) => rec.p.get("NAME").unwrap().clone(),
+          ListenEvent::Removed(rec) => format!("removed {}", rec.p.get("NAME").unwrap()),
+          ListenEvent::EndOfReplay => "end of replay".to_string(),
+        });
+        return ListenAction::Continue;
+      }).unwrap();
+      std::thread::sleep(Duration::from_millis(50));
+      conn.publish(&record(&[("NAME", "after")])).unwrap();
+      std::thread::sleep(Duration::from_millis(50));
+      assert_eq!(*events.lock().unwrap(), vec!["before", "end of replay", "after"]);
+      
+      let caps = dindex::client::hello_server_sync(&test_config, &localhost_server).unwrap();
+      assert!(caps.supports_feature("listen-replay"));
+
+      // Instruct server to exit
+      exit_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+      // Send it network traffic to force eval of exit_flag
+      dindex::client::query_sync(&test_config, &record(&[("NAME", ".*")]));
+
+    }));
+
+    for h in handlers {
+      h.join().unwrap();
+    }
+  }).unwrap();
+
+}
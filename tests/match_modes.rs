@@ -0,0 +1,221 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; either version 2 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use crossbeam_utils::thread;
+
+use std::time::Duration;
+
+use dindex;
+use dindex::record::Record;
+use dindex::query::{Query, MatchMode, MatchKind, RegexLimits, QUERY_MATCH_KEY, edit_distance};
+
+mod common;
+use common::{mem_config, record};
+
+#[test]
+fn match_mode_specs() {
+  assert_eq!(MatchMode::parse("").unwrap(), MatchMode::default());
+  assert!(MatchMode::default().is_default());
+  let mode = MatchMode::parse("Glob, ignore-case").unwrap();
+  assert_eq!(mode, MatchMode { kind: MatchKind::Glob, ignore_case: true });
+  assert_eq!(mode.to_spec(), "glob,ignore-case");
+  for spec in &["regex", "literal", "fuzzy", "fuzzy=3", "literal,ignore-case"] {
+    assert_eq!(MatchMode::parse(spec).unwrap().to_spec(), spec.to_string());
+  }
+  assert_eq!(MatchMode::parse("case-insensitive").unwrap().to_spec(), "regex,ignore-case");
+  assert!(MatchMode::parse("fuzzy=many").is_err());
+  assert!(MatchMode::parse("soundex").is_err());
+}
+
+#[test]
+fn literal_and_glob_patterns() {
+  let price = record(&[("title", "Lamp (brass) $40.00")]);
+  assert!(matches("literal", "title", "(brass) $40", &price));
+  assert!(! matches("literal", "title", "(brass) $40.0.", &price));
+  assert!(! matches("literal", "title", "(BRASS)", &price));
+  assert!(matches("literal,ignore-case", "title", "(BRASS)", &price));
+  
+  let file = record(&[("path", "src/query.rs")]);
+  assert!(matches("glob", "path", "src/*.rs", &file));
+  assert!(matches("glob", "path", "src/quer?.rs", &file));
+  assert!(matches("glob", "path", "src/[pq]uery.[!c]s", &file));
+  assert!(! matches("glob", "path", "src/[!q]uery.rs", &file));
+  assert!(! matches("glob", "path", "*.RS", &file)); // Globs match the whole value
+  assert!(matches("glob,ignore-case", "path", "*.RS", &file));
+  assert!(! matches("glob", "path", "query", &file));
+  assert!(matches("glob", "path", "src/query.rs", &file));
+  // Unclosed classes and regex syntax are just characters
+  assert!(matches("glob", "path", "a[b+(c", &record(&[("path", "a[b+(c")])));
+  assert!(matches("glob", "path", "[!]", &record(&[("path", "[!]")])));
+  
+  // Expressions are read in the same mode
+  let mut expr_query = dindex::query::QueryExpr::from_text("path ~ 'src/*' and not path ~ '*.py'").unwrap().to_record();
+  expr_query.p.insert(QUERY_MATCH_KEY.to_string(), "glob".to_string());
+  assert!(Query::compile(&expr_query, &RegexLimits::default()).unwrap().matches(&file));
+}
+
+#[test]
+fn fuzzy_patterns() {
+  assert_eq!(edit_distance("kitten", "sitting", 3), Some(3));
+  assert_eq!(edit_distance("kitten", "sitting", 2), None);
+  assert_eq!(edit_distance("", "abc", 3), Some(3));
+  assert_eq!(edit_distance("same", "same", 0), Some(0));
+  
+  let title = record(&[("title", "Getting started with Rust programming")]);
+  assert!(matches("fuzzy", "title", "programing", &title));
+  assert!(matches("fuzzy", "title", "startd with", &title));
+  assert!(! matches("fuzzy", "title", "python", &title));
+  assert!(! matches("fuzzy", "title", "RUST", &title));
+  assert!(matches("fuzzy,ignore-case", "title", "RUST", &title));
+  // Short patterns must match exactly unless told otherwise
+  assert!(! matches("fuzzy", "title", "ru", &record(&[("title", "rs")])));
+  assert!(matches("fuzzy=1", "title", "ru", &record(&[("title", "rs")])));
+  assert!(! matches("fuzzy=0", "title", "programing", &title));
+}
+
+#[test]
+fn search_agrees_with_matches() {
+  let config = mem_config();
+  let data = dindex::data::Data::new(&config);
+  let recs = vec![
+    record(&[("title", "Rust Book"), ("path", "books/rust.pdf")]),
+    record(&[("title", "rust cookbook"), ("path", "books/rust-cookbook.PDF")]),
+    record(&[("title", "The Go Programming Language"), ("path", "books/go.pdf")]),
+  ];
+  for rec in &recs {
+    data.insert(rec.clone());
+  }
+  let queries = vec![
+    ("regex,ignore-case", "title", "RUST"),
+    ("literal,ignore-case", "title", "rust b"),
+    ("glob", "path", "books/*.pdf"),
+    ("glob,ignore-case", "path", "BOOKS/RUST*"),
+    ("fuzzy,ignore-case", "title", "cokbook"),
+  ];
+  for (spec, key, pattern) in queries {
+    let mut query_rec = record(&[(key, pattern)]);
+    query_rec.p.insert(QUERY_MATCH_KEY.to_string(), spec.to_string());
+    let query = Query::compile(&query_rec, &RegexLimits::default()).unwrap();
+    let expected = recs.iter().filter(|r| query.matches(r)).count();
+    assert!(expected > 0, "{} {}", spec, pattern);
+    assert_eq!(data.search(&query).len(), expected, "{} {}", spec, pattern);
+  }
+}
+
+#[test]
+fn client_match_mode_is_sent() {
+  let mut config = mem_config();
+  let args = vec![r#"{"title": "rust*"}"#.to_string()];
+  let plain = dindex::args::parse_query_record(&args, 0, &config);
+  assert!(! plain.p.contains_key(QUERY_MATCH_KEY));
+  
+  config.client_match_mode = MatchMode::parse("glob,ignore-case").unwrap();
+  let globbed = dindex::args::parse_query_record(&args, 0, &config);
+  assert_eq!(globbed.p.get(QUERY_MATCH_KEY).map(|s| s.as_str()), Some("glob,ignore-case"));
+  
+  // --match wins over the config
+  let mut cli = dindex::args::Args::empty();
+  cli.action = dindex::actions::Action::query;
+  cli.rec_args = args.clone();
+  cli.match_mode = Some("regex".to_string());
+  assert!(! cli.get_record(&config).p.contains_key(QUERY_MATCH_KEY));
+  cli.match_mode = Some("literal".to_string());
+  assert_eq!(cli.get_record(&config).p.get(QUERY_MATCH_KEY).map(|s| s.as_str()), Some("literal"));
+  
+  // Full-text queries have no patterns to read
+  let text = dindex::args::parse_query_record(&vec!["rust".to_string(), "web".to_string()], 0, &config);
+  assert!(! text.p.contains_key(QUERY_MATCH_KEY));
+}
+
+#[test]
+fn tcp_match_modes() {
+  let mut test_config = mem_config();
+  // Write details for temporary data
+  let port = 2011;
+  let localhost_server = dindex::config::Server {
+    protocol: dindex::config::ServerProtocol::TCP,
+    host: "127.0.0.1".to_string(),
+    port: port,
+    path: "/tmp/dindex.test.socket".to_string(),
+    max_latency_ms: 250,
+    report_connect_errors: true,
+    name: "Localhost Server".to_string(),
+    tls_pin: None
+  };
+  test_config.servers = vec![localhost_server.clone()];
+  test_config.server_port = port;
+  test_config.server_ip = "127.0.0.1".to_string();
+  test_config.server_listen_tcp = true;
+  test_config.server_listen_udp = false;
+  test_config.server_listen_unix = false;
+  test_config.server_listen_websocket = false;
+
+  // Create a data store
+  let mut data = dindex::data::Data::new(&test_config);
+  data.insert(record(&[("title", "C++ Primer (5th edition)")]));
+  data.insert(record(&[("title", "Effective Modern C++")]));
+  let exit_flag = data.exit_flag.clone();
+
+  // Spawn server and client threads to perform testing
+  thread::scope(|s| {
+    let mut handlers = vec![];
+
+    handlers.push(s.spawn(|_| {
+      dindex::server::run_tcp_sync(&test_config, &mut data);
+    }));
+
+    handlers.push(s.spawn(|_| {
+      // This is where client logic is tested
+      std::thread::sleep(Duration::from_millis(25));
+      // Server should have bound to ports within 25ms
+
+      let mut literal = record(&[("title", "c++ primer (")]);
+      literal.p.insert(QUERY_MATCH_KEY.to_string(), "literal,ignore-case".to_string());
+      let results = dindex::client::query_server_sync(&test_config, &localhost_server, &literal);
+      assert_eq!(results.len(), 1);
+      assert!(! results[0].p.contains_key(QUERY_MATCH_KEY));
+      
+      let mut glob = record(&[("title", "*C++")]);
+      glob.p.insert(QUERY_MATCH_KEY.to_string(), "glob".to_string());
+      assert_eq!(dindex::client::count_server_sync(&test_config, &localhost_server, &glob, None).unwrap().total, 1);
+      
+      let mut bad_mode = record(&[("title", "C")]);
+      bad_mode.p.insert(QUERY_MATCH_KEY.to_string(), "soundex".to_string());
+      let err = dindex::client::count_server_sync(&test_config, &localhost_server, &bad_mode, None).unwrap_err();
+      assert!(err.contains("soundex"), "{}", err);
+
+      // Instruct server to exit
+      exit_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+      // Send it network traffic to force eval of exit_flag
+      dindex::client::query_sync(&test_config, &glob);
+
+    }));
+
+    for h in handlers {
+      h.join().unwrap();
+    }
+  }).unwrap();
+
+}
+
+fn matches(spec: &str, key: &str, pattern: &str, rec: &Record) -> bool {
+  let mut query = record(&[(key, pattern)]);
+  query.p.insert(QUERY_MATCH_KEY.to_string(), spec.to_string());
+  return Query::compile(&query, &RegexLimits::default()).unwrap().matches(rec);
+}
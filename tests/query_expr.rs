@@ -0,0 +1,254 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; either version 2 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use crossbeam_utils::thread;
+
+use std::time::Duration;
+
+use dindex;
+use dindex::record::Record;
+use dindex::query::{QueryExpr, QUERY_EXPRESSION_KEY};
+
+mod common;
+use common::{empty_config, mem_config};
+
+#[test]
+fn text_and_json_forms() {
+  let expr = QueryExpr::from_text("title ~ '.*rust.*' and (url ~ ^https:// or not exists(author))").unwrap();
+  let json = r#"{"and":[{"match":{"title":".*rust.*"}},{"or":[{"match":{"url":"^https://"}},{"not":{"exists":["author"]}}]}]}"#;
+  assert_eq!(expr.to_json(), json);
+  assert_eq!(QueryExpr::from_json(json).unwrap(), expr);
+
+  // and binds tighter than or, symbols work like words
+  assert_eq!(
+    QueryExpr::from_text("a ~ 1 || b ~ 2 && !absent(c)").unwrap(),
+    QueryExpr::from_text("a ~ 1 or (b ~ 2 and not absent(c))").unwrap()
+  );
+  // Quoting keeps keywords, spaces and escaped quotes literal
+  assert_eq!(
+    QueryExpr::from_text(r#"'and' ~ "say \"hi\" (x)""#).unwrap().to_json(),
+    r#"{"match":{"and":"say \"hi\" (x)"}}"#
+  );
+
+  for bad in &["", "title", "title ~", "(a ~ b", "a ~ b)", "a ~ b c ~ d", "exists(", "'unterminated"] {
+    assert!(QueryExpr::from_text(bad).is_err(), "{:?} should not parse", bad);
+  }
+}
+
+#[test]
+fn evaluation() {
+  let rust = rec(&[("title", "learning rust"), ("url", "https://example.org")]);
+  let authored = rec(&[("title", "rust book"), ("url", "http://example.org"), ("author", "someone")]);
+  let untitled = rec(&[("url", "http://example.com")]);
+
+  let matching = |text: &str| -> Vec<usize> {
+    let query = QueryExpr::from_text(text).unwrap().to_record().create_query();
+    return [&rust, &authored, &untitled].iter().enumerate()
+      .filter(|(_, r)| query.matches(r)).map(|(i, _)| i).collect();
+  };
+  assert_eq!(matching("title ~ rust"), vec![0, 1]);
+  assert_eq!(matching("title ~ rust and not exists(author)"), vec![0]);
+  assert_eq!(matching("url ~ ^https or exists(author)"), vec![0, 1]);
+  // Records sharing no keys with the query can match now
+  assert_eq!(matching("absent(title)"), vec![2]);
+  assert_eq!(matching("not title ~ book"), vec![0, 2]);
+  assert_eq!(matching("exists(title) and exists(author)"), vec![1]);
+
+  // Plain keys alongside an expression must match too
+  let mut query_rec = QueryExpr::from_text("exists(author) or url ~ https").unwrap().to_record();
+  query_rec.p.insert("title".to_string(), "learning".to_string());
+  let query = query_rec.create_query();
+  assert!(query.matches(&rust));
+  assert!(!query.matches(&authored));
+
+  // Broken expressions match nothing
+  let mut broken = Record::empty();
+  broken.p.insert(QUERY_EXPRESSION_KEY.to_string(), "{\"or\": ".to_string());
+  assert!(!broken.create_query().matches(&rust));
+  let bad_regex = QueryExpr::from_text("not title ~ '('").unwrap().to_record().create_query();
+  assert!(!bad_regex.matches(&untitled));
+}
+
+#[test]
+fn comparisons() {
+  let hot = rec(&[("temperature", "31.5"), ("published", "2019-06-01T12:00:00Z")]);
+  let cold = rec(&[("temperature", "-4"), ("published", "2019-06-01T14:00:00+02:00")]);
+  let broken = rec(&[("temperature", "warm"), ("published", "yesterday")]);
+  let recent = rec(&[("published", &chrono::Utc::now().to_rfc3339())]);
+
+  let matching = |text: &str| -> Vec<usize> {
+    let query = QueryExpr::from_text(text).unwrap().to_record().create_query();
+    return [&hot, &cold, &broken, &recent].iter().enumerate()
+      .filter(|(_, r)| query.matches(r)).map(|(i, _)| i).collect();
+  };
+  assert_eq!(matching("temperature > 30"), vec![0]);
+  assert_eq!(matching("temperature < 31.5"), vec![1]);
+  assert_eq!(matching("temperature <= 31.5"), vec![0, 1]);
+  assert_eq!(matching("temperature >= -4 and temperature < 0"), vec![1]);
+  assert_eq!(matching("temperature between -10 and 40"), vec![0, 1]);
+  assert_eq!(matching("not temperature > 30"), vec![1, 2, 3]);
+  // 14:00+02:00 is noon UTC
+  assert_eq!(matching("published >= 2019-06-01T12:00:00Z and published <= 2019-06-01T12:00:00Z"), vec![0, 1]);
+  assert_eq!(matching("published < 2019-06-01T12:00:00Z"), Vec::<usize>::new());
+  assert_eq!(matching("published > now-1h"), vec![3]);
+  assert_eq!(matching("published between 2019-01-01T00:00:00Z and now"), vec![0, 1, 3]);
+
+  // JSON takes numbers for numeric comparisons and strings for times
+  let json = r#"{"and":[{"gt":{"temperature":30.0}},{"between":{"published":["2019-06-01T00:00:00Z","now+1d"]}}]}"#;
+  let expr = QueryExpr::from_json(json).unwrap();
+  assert_eq!(expr, QueryExpr::from_text("temperature > 30 and published between 2019-06-01T00:00:00Z and now+1d").unwrap());
+  assert_eq!(expr.to_json(), json);
+
+  // Operands which are neither numbers nor times match nothing
+  assert!(!QueryExpr::from_text("temperature > warm").unwrap().to_record().create_query().matches(&broken));
+  assert!(!QueryExpr::from_text("not published > now-1x").unwrap().to_record().create_query().matches(&hot));
+  // Offsets too far from now to be times are not clamped into one
+  assert!(!QueryExpr::from_text("published < now+1e300d").unwrap().to_record().create_query().matches(&hot));
+  assert!(!QueryExpr::from_text("not published > now-1e300d").unwrap().to_record().create_query().matches(&hot));
+  assert_eq!(matching("published < now+300000d"), vec![0, 1, 3]);
+  assert!(!QueryExpr::from_text("temperature between 1 and now").unwrap().to_record().create_query().matches(&hot));
+}
+
+#[test]
+fn search_agrees_with_full_scan() {
+  let config = mem_config();
+  let data = dindex::data::Data::new(&config);
+  let mut all = vec![];
+  for i in 0..60 {
+    let mut pairs = vec![
+      ("title", format!("item{} {}", i % 7, if i % 3 == 0 { "rust" } else { "other" })),
+      ("n", format!("{}", i)),
+    ];
+    if i % 2 == 0 {
+      pairs.push(("url", format!("http{}://example.org/{}", if i % 4 == 0 { "s" } else { "" }, i)));
+    }
+    if i % 5 == 0 {
+      pairs.push(("author", format!("author{}", i % 3)));
+    }
+    let r = Record::new(pairs.into_iter().map(|(k, v)| (k.to_string(), v)).collect());
+    data.insert(r.clone());
+    all.push(r);
+  }
+
+  for text in &[
+    "title ~ rust", "title ~ ^item3", "exists(author)", "absent(url)", "not title ~ rust",
+    "title ~ rust and url ~ ^https", "title ~ item1 or author ~ 2", "title ~ rust and not exists(url)",
+    "(title ~ item2 or title ~ item4) and (url ~ https or absent(url))", "url ~ '^https?://example'",
+    "exists(author) and absent(author)", "not (exists(url) or exists(author))",
+    "n > 20", "n between 10 and 19 or title ~ rust", "not n <= 50",
+  ] {
+    let query = QueryExpr::from_text(text).unwrap().to_record().create_query();
+    let expected = all.iter().filter(|r| query.matches(r)).count();
+    assert_eq!(data.search(&query).len(), expected, "query {}", text);
+  }
+}
+
+#[test]
+fn parse_record_accepts_expressions() {
+  let config = empty_config();
+  let parse = |args: &[&str]| dindex::args::parse_record(&args.iter().map(|a| a.to_string()).collect(), 0, &config);
+
+  // Plain JSON records are unchanged
+  let plain = parse(&[r#"{"title": ".*rust.*"}"#]);
+  assert_eq!(plain.p.get("title").unwrap(), ".*rust.*");
+  assert!(!plain.p.contains_key(QUERY_EXPRESSION_KEY));
+
+  let json = parse(&[r#"{"or": [{"exists": ["a"]}, {"match": {"b": "c"}}]}"#]);
+  let text = parse(&["exists(a)", "or", "b", "~", "c"]);
+  assert_eq!(json.p.get(QUERY_EXPRESSION_KEY), text.p.get(QUERY_EXPRESSION_KEY));
+  assert!(json.p.get(QUERY_EXPRESSION_KEY).is_some());
+
+  assert!(parse(&["not", "a", "query"]).is_empty());
+}
+
+#[test]
+fn tcp_query_expr() {
+  let mut test_config = empty_config();
+  // Write details for temporary data
+  let port = 2006;
+  let localhost_server = dindex::config::Server {
+    protocol: dindex::config::ServerProtocol::TCP,
+    host: "127.0.0.1".to_string(),
+    port: port,
+    path: "/tmp/dindex.test.socket".to_string(),
+    max_latency_ms: 250,
+    report_connect_errors: true,
+    name: "Localhost Server".to_string(),
+    tls_pin: None
+  };
+  test_config.servers = vec![localhost_server];
+  test_config.server_port = port;
+  test_config.server_ip = "127.0.0.1".to_string();
+  test_config.server_listen_tcp = true;
+  test_config.server_listen_udp = false;
+  test_config.server_listen_unix = false;
+  test_config.server_listen_websocket = false;
+  test_config.server_extra_quiet = true;
+
+  // Tell server not to store records outside this process's memory
+  test_config.server_datastore_uri = "memory://".to_string();
+
+  // Create a data store
+  let mut data = dindex::data::Data::new(&test_config);
+  let exit_flag = data.exit_flag.clone();
+
+  // Spawn server and client threads to perform testing
+  thread::scope(|s| {
+    let mut handlers = vec![];
+
+    handlers.push(s.spawn(|_| {
+      dindex::server::run_tcp_sync(&test_config, &mut data);
+    }));
+
+    handlers.push(s.spawn(|_| {
+      // This is where client logic is tested
+      std::thread::sleep(Duration::from_millis(25));
+      // Server should have bound to ports within 25ms
+
+      dindex::client::publish_sync(&test_config, &rec(&[("NAME", "a"), ("tag", "x")]));
+      dindex::client::publish_sync(&test_config, &rec(&[("NAME", "b")]));
+      dindex::client::publish_sync(&test_config, &rec(&[("other", "c")]));
+
+      let query = QueryExpr::from_text("NAME ~ . and absent(tag) or other ~ c").unwrap().to_record();
+      let mut names: Vec<String> = dindex::client::query_sync(&test_config, &query).iter()
+        .map(|r| r.p.get("NAME").or(r.p.get("other")).unwrap().to_string()).collect();
+      names.sort();
+      assert_eq!(names, vec!["b", "c"]);
+
+      // Instruct server to exit
+      exit_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+      // Send it network traffic to force eval of exit_flag
+      dindex::client::query_sync(&test_config, &rec(&[("NAME", ".*")]));
+
+    }));
+
+    for h in handlers {
+      h.join().unwrap();
+    }
+  }).unwrap();
+
+}
+
+fn rec(pairs: &[(&str, &str)]) -> Record {
+  let mut rec = Record::empty();
+  for (key, val) in pairs {
+    rec.p.insert(key.to_string(), val.to_string());
+  }
+  rec
+}
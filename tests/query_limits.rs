@@ -0,0 +1,160 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; either version 2 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use crossbeam_utils::thread;
+
+use std::time::Duration;
+
+use dindex;
+use dindex::record::Record;
+use dindex::query::{Query, QueryCache, RegexLimits, QueryExpr};
+use dindex::client::PublishError;
+use dindex::wire::Target;
+
+mod common;
+use common::{mem_config, record};
+
+#[test]
+fn rejected_regexes() {
+  let small = RegexLimits { size_limit: 4096, dfa_size_limit: 4096 };
+  assert!(Query::compile(&record(&[("title", "^rust")]), &small).is_ok());
+  
+  let err = Query::compile(&record(&[("title", "a{2000}")]), &small).unwrap_err();
+  assert!(err.contains("title"), "{}", err);
+  let err = Query::compile(&record(&[("title", "rust"), ("url", "(")]), &small).unwrap_err();
+  assert!(err.contains("url"), "{}", err);
+  assert!(Query::compile(&QueryExpr::from_text("not body ~ 'a{2000}'").unwrap().to_record(), &small).is_err());
+  let mut broken = Record::empty();
+  broken.p.insert(dindex::query::QUERY_EXPRESSION_KEY.to_string(), "{\"or\": ".to_string());
+  assert!(Query::compile(&broken, &small).is_err());
+  
+  // A pattern which fails no longer widens the query to the keys that compiled
+  let rust = record(&[("title", "rust"), ("url", "https://rust-lang.org")]);
+  assert!(! record(&[("title", "rust"), ("url", "(")]).create_query().matches(&rust));
+  
+  // Signature keys are not patterns and never cause a rejection
+  let signed_query = record(&[("title", "rust"), (dindex::signing::SIGNING_NON_SIG_BYTES_KEY, "+ab/c==")]);
+  assert!(Query::compile(&record(&[("title", "+ab/c==")]), &small).is_err());
+  let query = Query::compile(&signed_query, &small).unwrap();
+  assert!(query.matches(&rust));
+}
+
+#[test]
+fn rejected_fuzzy_patterns() {
+  use dindex::query::{QUERY_MATCH_KEY, FUZZY_MAX_EDITS, FUZZY_MAX_PATTERN_CHARS, FUZZY_MAX_PATTERN_WORDS};
+  let fuzzy = |mode: &str, pattern: &str| Query::compile(&record(&[("title", pattern), (QUERY_MATCH_KEY, mode)]), &RegexLimits::default());
+  assert!(fuzzy(&format!("fuzzy={}", FUZZY_MAX_EDITS), "rust").is_ok());
+  assert!(fuzzy(&format!("fuzzy={}", FUZZY_MAX_EDITS + 1), "rust").is_err());
+  
+  assert!(fuzzy("fuzzy", &"a".repeat(FUZZY_MAX_PATTERN_CHARS)).is_ok());
+  let err = fuzzy("fuzzy", &"a".repeat(FUZZY_MAX_PATTERN_CHARS + 1)).unwrap_err();
+  assert!(err.contains("title"), "{}", err);
+  assert!(fuzzy("fuzzy", &vec!["a"; FUZZY_MAX_PATTERN_WORDS].join(" ")).is_ok());
+  assert!(fuzzy("fuzzy", &vec!["a"; FUZZY_MAX_PATTERN_WORDS + 1].join(" ")).is_err());
+}
+
+#[test]
+fn query_cache_is_bounded() {
+  let mut cache = QueryCache::new(2, RegexLimits::default());
+  let rust = record(&[("title", "rust")]);
+  for pattern in &["^r", "^ru", "^rus", "^r"] {
+    assert!(cache.compile(&record(&[("title", pattern)])).unwrap().matches(&rust));
+    assert!(cache.len() <= 2);
+  }
+  // Rejections are cached and stay rejections
+  let bad = record(&[("title", "(")]);
+  assert!(cache.compile(&bad).is_err());
+  assert!(cache.compile(&bad).is_err());
+  assert_eq!(cache.len(), 2);
+  
+  let mut uncached = QueryCache::new(0, RegexLimits::default());
+  assert!(uncached.compile(&rust).is_ok());
+  assert_eq!(uncached.len(), 0);
+}
+
+#[test]
+fn tcp_bad_query() {
+  let mut test_config = mem_config();
+  // Write details for temporary data
+  let port = 2009;
+  let localhost_server = dindex::config::Server {
+    protocol: dindex::config::ServerProtocol::TCP,
+    host: "127.0.0.1".to_string(),
+    port: port,
+    path: "/tmp/dindex.test.socket".to_string(),
+    max_latency_ms: 250,
+    report_connect_errors: true,
+    name: "Localhost Server".to_string(),
+    tls_pin: None
+  };
+  test_config.servers = vec![localhost_server.clone()];
+  test_config.server_port = port;
+  test_config.server_ip = "127.0.0.1".to_string();
+  test_config.server_listen_tcp = true;
+  test_config.server_listen_udp = false;
+  test_config.server_listen_unix = false;
+  test_config.server_listen_websocket = false;
+  test_config.server_regex_size_limit = 4096;
+
+  // Create a data store
+  let mut data = dindex::data::Data::new(&test_config);
+  data.insert(record(&[("title", "rust")]));
+  let exit_flag = data.exit_flag.clone();
+
+  // Spawn server and client threads to perform testing
+  thread::scope(|s| {
+    let mut handlers = vec![];
+
+    handlers.push(s.spawn(|_| {
+      dindex::server::run_tcp_sync(&test_config, &mut data);
+    }));
+
+    handlers.push(s.spawn(|_| {
+      // This is where client logic is tested
+      std::thread::sleep(Duration::from_millis(25));
+      // Server should have bound to ports within 25ms
+
+      let too_big = record(&[("title", "a{2000}")]);
+      let err = dindex::client::count_server_sync(&test_config, &localhost_server, &too_big, None).unwrap_err();
+      assert!(err.contains("title"), "{}", err);
+      assert_eq!(dindex::client::query_server_sync(&test_config, &localhost_server, &too_big).len(), 0);
+      
+      match dindex::client::delete_server_sync(&test_config, &localhost_server, &Target::query(&too_big)) {
+        Err(PublishError::Rejected { code, .. }) => assert_eq!(code, "bad-query"),
+        other => panic!("expected bad-query, got {:?}", other),
+      }
+      
+      // Within the limits the same server answers as usual
+      let fine = record(&[("title", "^rust$")]);
+      assert_eq!(dindex::client::count_server_sync(&test_config, &localhost_server, &fine, None).unwrap().total, 1);
+      assert_eq!(dindex::client::query_server_sync(&test_config, &localhost_server, &fine).len(), 1);
+
+      // Instruct server to exit
+      exit_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+      // Send it network traffic to force eval of exit_flag
+      dindex::client::query_sync(&test_config, &fine);
+
+    }));
+
+    for h in handlers {
+      h.join().unwrap();
+    }
+  }).unwrap();
+
+}
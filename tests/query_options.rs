@@ -0,0 +1,251 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; either version 2 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use crossbeam_utils::thread;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use dindex;
+use dindex::wire::QueryOptions;
+
+mod common;
+use common::{mem_config, record, names};
+
+#[test]
+fn pages_are_exact_and_ordered() {
+  let data = dindex::data::Data::new(&mem_config());
+  // n is inserted out of order, with ties, and missing from two records
+  for n in &["5", "10", "2", "10", "7", "-1", "3.5", "9", "0", "8"] {
+    data.insert(record(&[("NAME", &format!("n={}", n)), ("n", n)]));
+  }
+  data.insert(record(&[("NAME", "none a")]));
+  data.insert(record(&[("NAME", "none b")]));
+  let query = record(&[("NAME", ".*")]).create_query();
+
+  let options = QueryOptions { sort_by: Some("n".to_string()), ..QueryOptions::default() };
+  let (all, next_cursor) = data.search_page(&query, &options).unwrap();
+  assert_eq!(names(&all), vec![
+    "n=-1", "n=0", "n=2", "n=3.5", "n=5", "n=7", "n=8", "n=9", "n=10", "n=10", "none a", "none b"
+  ]);
+  assert!(next_cursor.is_none());
+
+  let desc = QueryOptions { sort_by: Some("n".to_string()), descending: true, limit: Some(3), ..QueryOptions::default() };
+  assert_eq!(names(&data.search_page(&query, &desc).unwrap().0), vec!["n=10", "n=10", "n=9"]);
+
+  let window = QueryOptions { offset: Some(4), limit: Some(2), ..options.clone() };
+  assert_eq!(names(&data.search_page(&query, &window).unwrap().0), vec!["n=5", "n=7"]);
+
+  // Following cursors visits every record exactly once, in order
+  for page_size in 1..6 {
+    let mut paged = vec![];
+    let mut page_options = QueryOptions { limit: Some(page_size), ..options.clone() };
+    loop {
+      let (page, next_cursor) = data.search_page(&query, &page_options).unwrap();
+      assert!(page.len() <= page_size);
+      paged.extend(page);
+      match next_cursor {
+        Some(cursor) => page_options.cursor = Some(cursor),
+        None => break,
+      }
+    }
+    assert_eq!(names(&paged), names(&all), "page size {}", page_size);
+  }
+
+  // Without sort_by results come in the order they were stored
+  let first_two = QueryOptions { limit: Some(2), ..QueryOptions::default() };
+  assert_eq!(names(&data.search_page(&query, &first_two).unwrap().0), vec!["n=5", "n=10"]);
+
+  let bad = QueryOptions { cursor: Some("not a cursor".to_string()), ..QueryOptions::default() };
+  assert!(data.search_page(&query, &bad).is_err());
+
+  // Cursors are tied to the instance and ordering which made them
+  let two = QueryOptions { limit: Some(2), ..options.clone() };
+  let cursor = data.search_page(&query, &two).unwrap().1;
+  assert!(cursor.is_some());
+  let other_data = dindex::data::Data::new(&mem_config());
+  other_data.insert(record(&[("NAME", "n=1"), ("n", "1")]));
+  assert!(other_data.search_page(&query, &QueryOptions { cursor: cursor.clone(), ..two.clone() }).is_err());
+  assert!(data.search_page(&query, &QueryOptions { cursor: cursor.clone(), descending: true, ..two.clone() }).is_err());
+  assert!(data.search_page(&query, &QueryOptions { cursor: cursor.clone(), sort_by: Some("NAME".to_string()), ..two.clone() }).is_err());
+  assert!(data.search_page(&query, &QueryOptions { cursor: cursor.clone(), ..two.clone() }).is_ok());
+  
+  // The CLI only sends a cursor back to the server which made it
+  let mut args = dindex::args::Args::empty();
+  args.cursor = Some(dindex::args::cli_cursor("Default localhost TCP Connection", cursor.as_ref().unwrap()));
+  assert_eq!(args.get_cursor(), Some(("Default localhost TCP Connection".to_string(), cursor.clone().unwrap())));
+  assert_eq!(args.get_query_options().cursor, cursor);
+  args.cursor = cursor;
+  assert_eq!(args.get_cursor(), None);
+}
+
+#[test]
+fn pages_wait_for_busy_pools() {
+  let data = dindex::data::Data::new(&mem_config());
+  for i in 0..20 {
+    data.insert(record(&[("NAME", &format!("{}", i))]));
+  }
+  let query = record(&[("NAME", ".*")]).create_query();
+  
+  // A page taken while every pool is being written to still holds every match
+  let guards: Vec<_> = data.record_pools.iter().map(|pool| pool.write().unwrap()).collect();
+  thread::scope(|s| {
+    let pager = s.spawn(|_| data.search_page(&query, &QueryOptions::default()).unwrap().0.len());
+    std::thread::sleep(Duration::from_millis(50));
+    drop(guards);
+    assert_eq!(pager.join().unwrap(), 20);
+  }).unwrap();
+}
+
+#[test]
+fn search_callback_stops_every_pool() {
+  let mut config = mem_config();
+  config.server_num_record_pools = 8 * (num_cpus::get() + 1);
+  let data = dindex::data::Data::new(&config);
+  let mut seq = 0;
+  for pool in data.record_pools.iter() {
+    for _ in 0..20 {
+      seq += 1;
+      let stored = dindex::data::StoredRecord::new(record(&[("NAME", &format!("{}", seq))]), seq, false);
+      pool.write().unwrap().push(stored);
+    }
+  }
+  let calls = AtomicUsize::new(0);
+  let calls_ref = &calls;
+  data.search_callback(&record(&[("NAME", ".*")]).create_query(), move |_rec| {
+    calls_ref.fetch_add(1, Ordering::SeqCst);
+    return false;
+  });
+  // Each search thread may get one result in before it sees the others stopped
+  assert!(calls.load(Ordering::SeqCst) <= num_cpus::get() + 1);
+}
+
+#[test]
+fn projection_keeps_requested_keys() {
+  let identity_f = "/tmp/dindex-test-projection.identity";
+  dindex::signing::gen_identity(identity_f);
+  let mut signing_config = mem_config();
+  signing_config.client_use_sig = true;
+  signing_config.client_private_key_file = identity_f.to_string();
+  
+  let mut rec = record(&[("NAME", "projected"), ("url", "https://example.com"), ("description", "long text")]);
+  dindex::signing::maybe_sign_record(&signing_config, &mut rec);
+  assert!(dindex::signing::is_valid_sig(&rec));
+  
+  let all = QueryOptions::default();
+  assert_eq!(all.project(&rec).p, rec.p);
+  
+  let fields = QueryOptions { fields: Some(vec!["NAME".to_string(), "missing".to_string()]), ..QueryOptions::default() };
+  assert_eq!(fields.project(&rec).p, record(&[("NAME", "projected")]).p);
+  
+  // Signatures cover every key, so projections never carry them even
+  // when every key or a signature key is asked for
+  let every_field = QueryOptions {
+    fields: Some(vec!["NAME".to_string(), "url".to_string(), "description".to_string(), dindex::signing::SIGNING_PUB_KEY_KEY.to_string()]),
+    ..QueryOptions::default()
+  };
+  let projected = every_field.project(&rec);
+  assert_eq!(projected.p.len(), 3);
+  assert!(!dindex::signing::has_sig_fields(&projected));
+  assert!(!dindex::signing::is_valid_sig(&projected));
+}
+
+#[test]
+fn tcp_query_options() {
+  let mut test_config = mem_config();
+  // Write details for temporary data
+  let port = 2007;
+  let localhost_server = dindex::config::Server {
+    protocol: dindex::config::ServerProtocol::TCP,
+    host: "127.0.0.1".to_string(),
+    port: port,
+    path: "/tmp/dindex.test.socket".to_string(),
+    max_latency_ms: 250,
+    report_connect_errors: true,
+    name: "Localhost Server".to_string(),
+    tls_pin: None
+  };
+  test_config.servers = vec![localhost_server.clone()];
+  test_config.server_port = port;
+  test_config.server_ip = "127.0.0.1".to_string();
+  test_config.server_listen_tcp = true;
+  test_config.server_listen_udp = false;
+  test_config.server_listen_unix = false;
+  test_config.server_listen_websocket = false;
+
+  // Create a data store
+  let mut data = dindex::data::Data::new(&test_config);
+  let exit_flag = data.exit_flag.clone();
+
+  // Spawn server and client threads to perform testing
+  thread::scope(|s| {
+    let mut handlers = vec![];
+
+    handlers.push(s.spawn(|_| {
+      dindex::server::run_tcp_sync(&test_config, &mut data);
+    }));
+
+    handlers.push(s.spawn(|_| {
+      // This is where client logic is tested
+      std::thread::sleep(Duration::from_millis(25));
+      // Server should have bound to ports within 25ms
+
+      for n in 0..10 {
+        dindex::client::publish_sync(&test_config, &record(&[("NAME", &format!("rec{}", n)), ("n", &format!("{}", n))]));
+      }
+      let query = record(&[("NAME", ".*")]);
+
+      let top = QueryOptions { limit: Some(3), sort_by: Some("n".to_string()), descending: true, ..QueryOptions::default() };
+      assert_eq!(names(&dindex::client::query_sync_with_options(&test_config, &query, &top)), vec!["rec9", "rec8", "rec7"]);
+
+      let mut options = QueryOptions { limit: Some(4), sort_by: Some("n".to_string()), ..QueryOptions::default() };
+      let mut pages = vec![];
+      loop {
+        let page = dindex::client::query_server_page_sync(&test_config, &localhost_server, &query, &options);
+        pages.push(names(&page.records));
+        match page.next_cursor {
+          Some(cursor) => options.cursor = Some(cursor),
+          None => break,
+        }
+      }
+      assert_eq!(pages, vec![
+        vec!["rec0", "rec1", "rec2", "rec3"], vec!["rec4", "rec5", "rec6", "rec7"], vec!["rec8", "rec9"]
+      ]);
+
+      let fields = QueryOptions { fields: Some(vec!["n".to_string()]), ..QueryOptions::default() };
+      let projected = dindex::client::query_sync_with_options(&test_config, &query, &fields);
+      assert_eq!(projected.len(), 10);
+      assert!(projected.iter().all(|r| r.p.len() == 1 && r.p.contains_key("n")));
+      
+      // Plain queries still get everything
+      assert_eq!(dindex::client::query_sync(&test_config, &query).len(), 10);
+
+      // Instruct server to exit
+      exit_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+      // Send it network traffic to force eval of exit_flag
+      dindex::client::query_sync(&test_config, &query);
+
+    }));
+
+    for h in handlers {
+      h.join().unwrap();
+    }
+  }).unwrap();
+
+}
@@ -0,0 +1,186 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; either version 2 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use crossbeam_utils::thread;
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use dindex;
+use dindex::config::{Config, ServerProtocol};
+use dindex::actions::Action;
+use dindex::data::Data;
+use dindex::replication;
+use dindex::wire::Target;
+
+mod common;
+use common::{mem_config, local_server, server_config, pair, stored_names};
+
+#[test]
+fn digests_ignore_order() {
+  let mut ids: BTreeMap<String, Vec<String>> = BTreeMap::new();
+  ids.insert("ab".to_string(), vec!["ab01".to_string(), "ab02".to_string()]);
+  let mut reordered: BTreeMap<String, Vec<String>> = BTreeMap::new();
+  reordered.insert("ab".to_string(), vec!["ab02".to_string(), "ab01".to_string()]);
+  assert_eq!(replication::digest(&ids), replication::digest(&reordered));
+  
+  let mut fewer: BTreeMap<String, Vec<String>> = BTreeMap::new();
+  fewer.insert("ab".to_string(), vec!["ab01".to_string()]);
+  let ours = replication::digest(&fewer);
+  assert_eq!(replication::differing_buckets(&ours, &replication::digest(&ids)), vec!["ab"]);
+  assert!(replication::differing_buckets(&ours, &ours).is_empty());
+}
+
+#[test]
+fn tcp_replication() {
+  let (server_a, server_b) = (local_server(ServerProtocol::TCP, 2016), local_server(ServerProtocol::TCP, 2017));
+  let mut config_a = server_config(&server_a);
+  config_a.server_replication_peers = vec![server_b.clone()];
+  let mut config_b = server_config(&server_b);
+  config_b.server_replication_peers = vec![server_a.clone()];
+  let client_config = mem_config();
+  
+  let data_a = Data::new(&config_a);
+  let data_b = Data::new(&config_b);
+  data_a.insert(pair("NAME", "a"));
+  data_a.insert(pair("NAME", "shared"));
+  data_b.insert(pair("NAME", "b"));
+  data_b.insert(pair("NAME", "shared"));
+  data_b.insert(pair("NAME", "deleted"));
+  // Carries signature fields that do not verify
+  let mut imposter = pair("NAME", "imposter");
+  imposter.p.insert(dindex::signing::SIGNING_PUB_KEY_KEY.to_string(), "not a key".to_string());
+  data_b.insert(imposter);
+  let mut expiring = pair("NAME", "expiring");
+  expiring.p.insert(dindex::record::TTL_SECONDS_KEY.to_string(), "0.05".to_string());
+  data_b.insert(expiring);
+  let mut lasting = pair("NAME", "lasting");
+  lasting.p.insert(dindex::record::TTL_SECONDS_KEY.to_string(), "60".to_string());
+  data_b.insert(lasting);
+  // a deleted its copy, b should not give it back but delete its own
+  data_a.insert(pair("NAME", "deleted"));
+  let deleted = data_a.remove(|rec| rec.p.get("NAME").map(|name| name == "deleted").unwrap_or(false));
+  data_a.remember_deletes(&deleted, &Target::query(&pair("NAME", "deleted")), None);
+  let exit_flags = vec![data_a.exit_flag.clone(), data_b.exit_flag.clone()];
+  
+  thread::scope(|s| {
+    let mut handlers = vec![];
+    
+    handlers.push(s.spawn(|_| {
+      dindex::server::run_tcp_sync(&config_a, &data_a);
+    }));
+    handlers.push(s.spawn(|_| {
+      dindex::server::run_tcp_sync(&config_b, &data_b);
+    }));
+    
+    handlers.push(s.spawn(|_| {
+      std::thread::sleep(Duration::from_millis(100));
+      // Servers should have bound to ports and "expiring" expired
+      
+      let copied = replication::sync_from_peer(&config_a, &data_a, &server_b).unwrap();
+      assert_eq!(copied, 2);
+      assert_eq!(stored_names(&data_a), vec!["a", "b", "lasting", "shared"]);
+      // "a" and the tombstone of "deleted"
+      let copied = replication::sync_from_peer(&config_b, &data_b, &server_a).unwrap();
+      assert_eq!(copied, 2);
+      assert_eq!(stored_names(&data_b), vec!["a", "b", "imposter", "lasting", "shared"]);
+      assert_eq!(data_b.num_tombstones(), 1);
+      
+      // Nothing left to copy once converged
+      assert_eq!(replication::sync_from_peer(&config_a, &data_a, &server_b).unwrap(), 0);
+      
+      // Copies keep their expiry rather than starting a new TTL
+      let expiry = |data: &Data| {
+        let query = data.compile_query(&pair("NAME", "lasting")).unwrap();
+        data.search(&query)[0].p.get(dindex::record::TTL_EXPIRES_AT_KEY).cloned()
+      };
+      assert!(expiry(&data_a).is_some());
+      assert_eq!(expiry(&data_a), expiry(&data_b));
+      
+      // Instruct servers to exit
+      for (exit_flag, server) in exit_flags.iter().zip(&[&server_a, &server_b]) {
+        exit_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+        // Send it network traffic to force eval of exit_flag
+        dindex::client::query_server_sync(&client_config, server, &pair("NAME", ".*"));
+      }
+    }));
+    
+    for h in handlers {
+      h.join().unwrap();
+    }
+  }).unwrap();
+}
+
+#[test]
+fn tombstones_need_the_owners_authorization() {
+  let owner_identity_f = "/tmp/dindex-test-tombstone-owner.identity";
+  let other_identity_f = "/tmp/dindex-test-tombstone-other.identity";
+  dindex::signing::gen_identity(owner_identity_f);
+  dindex::signing::gen_identity(other_identity_f);
+  let signing_config = |identity_f: &str| {
+    let mut config = mem_config();
+    config.client_use_sig = true;
+    config.client_private_key_file = identity_f.to_string();
+    return config;
+  };
+  let (owner_config, other_config) = (signing_config(owner_identity_f), signing_config(other_identity_f));
+  let signed_by = |config: &Config, mut rec: dindex::record::Record| {
+    dindex::signing::maybe_sign_record(config, &mut rec);
+    return rec;
+  };
+  let later = dindex::record::now_ms() + 60_000;
+  
+  let data = Data::new(&mem_config());
+  let signed = signed_by(&owner_config, pair("NAME", "signed"));
+  let target = Target::id(&signed.id());
+  data.insert(signed.clone());
+  data.insert(pair("NAME", "unsigned"));
+  
+  // Without an authorization, or with one by another key, the tombstone
+  // is passed on but the signed record stays
+  assert!(data.add_tombstone(replication::tombstone(&signed, &target, None, later)));
+  let others_auth = signed_by(&other_config, target.authorization(Action::delete, None));
+  assert!(data.add_tombstone(replication::tombstone(&signed, &target, Some(&others_auth), later)));
+  assert!(!data.is_tombstoned(&signed));
+  assert_eq!(stored_names(&data), vec!["signed", "unsigned"]);
+  
+  let owners_auth = signed_by(&owner_config, target.authorization(Action::delete, None));
+  assert!(data.add_tombstone(replication::tombstone(&signed, &target, Some(&owners_auth), later)));
+  assert!(data.is_tombstoned(&signed));
+  assert_eq!(stored_names(&data), vec!["unsigned"]);
+  
+  // Anyone may remove unsigned records, expired tombstones are ignored
+  let unsigned = pair("NAME", "unsigned");
+  let expired = dindex::record::now_ms() - 1;
+  assert!(!data.add_tombstone(replication::tombstone(&unsigned, &Target::id(&unsigned.id()), None, expired)));
+  assert_eq!(stored_names(&data), vec!["unsigned"]);
+  assert!(data.add_tombstone(replication::tombstone(&unsigned, &Target::id(&unsigned.id()), None, later)));
+  assert!(stored_names(&data).is_empty());
+  assert_eq!(data.num_tombstones(), 4);
+  
+  // Evictions leave no tombstone, they are only kept from being copied back
+  let mut config = mem_config();
+  config.server_max_records = 1;
+  let data = Data::new(&config);
+  data.insert(pair("NAME", "evicted"));
+  data.insert(pair("NAME", "kept"));
+  assert_eq!(stored_names(&data), vec!["kept"]);
+  assert_eq!(data.num_tombstones(), 0);
+  assert!(data.was_forgotten(&pair("NAME", "evicted").id()));
+}
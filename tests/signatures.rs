@@ -122,6 +122,52 @@ fn sign_records() {
   
 }
 
+#[test]
+fn sign_records_ed25519() {
+  let test_identity_f = "/tmp/dindex-test.identity.ed25519";
+  dindex::signing::gen_identity_of_type(test_identity_f, dindex::signing::KeyType::Ed25519);
+  
+  let mut test_config = dindex::config::get_config_detail(
+    false, false, false, false,
+    Err(std::env::VarError::NotPresent),
+    &dindex::args::Args::empty()
+  );
+  test_config.client_use_sig = true;
+  test_config.client_private_key_file = test_identity_f.to_string();
+  test_config.server_extra_quiet = true;
+  
+  let mut known_record = gen_rand_record();
+  dindex::signing::maybe_sign_record(&test_config, &mut known_record);
+  assert!(dindex::signing::is_valid_sig(&known_record));
+  assert_eq!(known_record.p.get(dindex::signing::SIGNING_KEY_TYPE_KEY).unwrap(), "ed25519");
+  // A raw 32 byte key in base64, not a PEM
+  let pub_key = known_record.p.get(dindex::signing::SIGNING_PUB_KEY_KEY).unwrap().to_string();
+  assert_eq!(pub_key.len(), 44);
+  assert_eq!(pub_key, dindex::signing::read_pub_key_base64(test_identity_f));
+  
+  let mut imposter_record = known_record.clone();
+  imposter_record.p.insert("NAME".to_string(), "Ipsum Lorem".to_string());
+  assert!(!dindex::signing::is_valid_sig(&imposter_record));
+  
+  // Without its key type the record reads as RSA signed, which cannot verify
+  let mut untyped_record = known_record.clone();
+  untyped_record.p.remove(dindex::signing::SIGNING_KEY_TYPE_KEY);
+  assert!(!dindex::signing::is_valid_sig(&untyped_record));
+  
+  let mut unknown_type_record = known_record.clone();
+  unknown_type_record.p.insert(dindex::signing::SIGNING_KEY_TYPE_KEY.to_string(), "dsa".to_string());
+  assert!(!dindex::signing::is_valid_sig(&unknown_type_record));
+  
+  // RSA identities still sign without a key type, as they always have
+  let rsa_identity_f = "/tmp/dindex-test.identity.rsa";
+  dindex::signing::gen_identity(rsa_identity_f);
+  test_config.client_private_key_file = rsa_identity_f.to_string();
+  let mut rsa_record = known_record.clone();
+  dindex::signing::maybe_sign_record(&test_config, &mut rsa_record);
+  assert!(dindex::signing::is_valid_sig(&rsa_record));
+  assert!(!rsa_record.p.contains_key(dindex::signing::SIGNING_KEY_TYPE_KEY));
+}
+
 
 fn gen_rand_record() -> dindex::record::Record {
   use rand::{thread_rng, Rng};
@@ -0,0 +1,208 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *  
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; either version 2 of the License, or
+ *  (at your option) any later version.
+ * 
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ * 
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use crossbeam_utils::thread;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use dindex;
+use dindex::client::PublishError;
+use dindex::wire::Target;
+
+mod common;
+use common::{empty_config, pair};
+
+#[test]
+fn tcp_delete_update() {
+  let owner_identity_f = "/tmp/dindex-test-owner.identity";
+  let other_identity_f = "/tmp/dindex-test-other.identity";
+  let trusted_keys_f = "/tmp/dindex-test-delete-trusted-keys";
+  dindex::signing::gen_identity(owner_identity_f);
+  dindex::signing::gen_identity(other_identity_f);
+  std::fs::write(trusted_keys_f, "# nobody yet\n").unwrap();
+  
+  let mut test_config = empty_config();
+  // Write details for temporary data
+  let port = 2005;
+  let localhost_server = dindex::config::Server {
+    protocol: dindex::config::ServerProtocol::TCP,
+    host: "127.0.0.1".to_string(),
+    port: port,
+    path: "/tmp/dindex.test.socket".to_string(),
+    max_latency_ms: 250,
+    report_connect_errors: true,
+    name: "Localhost Server".to_string(),
+    tls_pin: None
+  };
+  test_config.servers = vec![localhost_server.clone()];
+  test_config.server_port = port;
+  test_config.server_ip = "127.0.0.1".to_string();
+  test_config.server_listen_tcp = true;
+  test_config.server_listen_udp = false;
+  test_config.server_listen_unix = false;
+  test_config.server_listen_websocket = false;
+  test_config.server_extra_quiet = true;
+  test_config.server_trusted_keys_file = trusted_keys_f.to_string();
+  
+  // Tell server not to store records outside this process's memory
+  test_config.server_datastore_uri = "memory://".to_string();
+  
+  let mut owner_config = test_config.clone();
+  owner_config.client_use_sig = true;
+  owner_config.client_private_key_file = owner_identity_f.to_string();
+  let mut other_config = test_config.clone();
+  other_config.client_use_sig = true;
+  other_config.client_private_key_file = other_identity_f.to_string();
+  
+  // Create a data store
+  let mut data = dindex::data::Data::new(&test_config);
+  let exit_flag = data.exit_flag.clone();
+  
+  // Spawn server and client threads to perform testing
+  thread::scope(|s| {
+    let mut handlers = vec![];
+    
+    handlers.push(s.spawn(|_| {
+      dindex::server::run_tcp_sync(&test_config, &mut data);
+    }));
+    
+    handlers.push(s.spawn(|_| {
+      // This is where client logic is tested
+      std::thread::sleep(Duration::from_millis(25));
+      // Server should have bound to ports within 25ms
+      
+      let conn = dindex::connection::Connection::open(&test_config, &localhost_server).unwrap();
+      let removed = Arc::new(Mutex::new(vec![]));
+      let t_removed = removed.clone();
+      conn.listen_events(&pair("NAME", ".*"), move |event| {
+        if let dindex::client::ListenEvent::Removed(rec) = event {
+          t_removed.lock().unwrap().push(rec);
+        }
+        return dindex::client::ListenAction::Continue;
+      }).unwrap();
+      
+      // Anyone may delete unsigned records
+      let unsigned_id = publish_one(&test_config, &pair("NAME", "unsigned"));
+      assert_eq!(dindex::client::delete_sync(&test_config, &Target::id(&unsigned_id))[0].1.as_ref().unwrap(), &1);
+      assert_eq!(dindex::client::query_sync(&test_config, &pair("NAME", ".*")).len(), 0);
+      match &dindex::client::delete_sync(&test_config, &Target::id(&unsigned_id))[0].1 {
+        Err(PublishError::Rejected { code, .. }) => assert_eq!(code, "not-found"),
+        other => panic!("expected not-found, got {:?}", other),
+      }
+      
+      // Signed records belong to their key
+      let mut signed_rec = pair("NAME", "signed");
+      dindex::signing::maybe_sign_record(&owner_config, &mut signed_rec);
+      let signed_id = publish_one(&test_config, &signed_rec);
+      
+      for config in &[&test_config, &other_config] {
+        match &dindex::client::delete_sync(config, &Target::query(&pair("NAME", "sig.*")))[0].1 {
+          Err(PublishError::Rejected { code, .. }) => assert_eq!(code, "not-owner"),
+          other => panic!("expected not-owner, got {:?}", other),
+        }
+      }
+      let mut imposter_update = pair("NAME", "taken over");
+      dindex::signing::maybe_sign_record(&other_config, &mut imposter_update);
+      assert!(dindex::client::update_sync(&test_config, &Target::id(&signed_id), &imposter_update)[0].1.is_err());
+      
+      // A signed record returned by a query is not an authorization,
+      // so it cannot be replayed as an update of everything its key owns
+      let queried = dindex::client::query_sync(&test_config, &pair("NAME", "signed"));
+      assert_eq!(queried.len(), 1);
+      match &dindex::client::update_sync(&test_config, &Target::query(&pair("NAME", ".*")), &queried[0])[0].1 {
+        Err(PublishError::Rejected { code, .. }) => assert_eq!(code, "not-owner"),
+        other => panic!("expected not-owner, got {:?}", other),
+      }
+      
+      // Owner authorizations only work once, for their action, target and record
+      let replacement = pair("NAME", "signed v2");
+      let owner_wire = dindex::client::maybe_authorize(&owner_config,
+        dindex::wire::WireData::new(dindex::actions::Action::update, replacement.clone()).with_target(Target::id(&signed_id)));
+      let mut other_record_wire = owner_wire.clone();
+      other_record_wire.record = pair("NAME", "something else");
+      assert_eq!(modify_error(&test_config, &localhost_server, &other_record_wire), "bad-authorization");
+      let mut other_target_wire = owner_wire.clone();
+      other_target_wire.target = Some(Target::query(&pair("NAME", ".*")));
+      assert_eq!(modify_error(&test_config, &localhost_server, &other_target_wire), "bad-authorization");
+      let mut delete_wire = owner_wire.clone();
+      delete_wire.action = dindex::actions::Action::delete;
+      assert_eq!(modify_error(&test_config, &localhost_server, &delete_wire), "bad-authorization");
+      let mut stale_auth = Target::id(&signed_id).authorization(dindex::actions::Action::update, Some(&replacement));
+      stale_auth.p.insert(dindex::wire::AUTH_ISSUED_AT_KEY.to_string(), "1".to_string());
+      dindex::signing::maybe_sign_record(&owner_config, &mut stale_auth);
+      let stale_wire = owner_wire.clone().with_authorization(stale_auth);
+      assert_eq!(modify_error(&test_config, &localhost_server, &stale_wire), "expired-authorization");
+      
+      // The owner may replace it
+      assert_eq!(modify_error(&test_config, &localhost_server, &owner_wire), "");
+      assert_eq!(modify_error(&test_config, &localhost_server, &owner_wire), "replayed-authorization");
+      
+      // And update it again through the client helpers
+      let signed_id = replacement.id();
+      let mut owner_update = pair("NAME", "signed v3");
+      dindex::signing::maybe_sign_record(&owner_config, &mut owner_update);
+      let new_id = dindex::client::update_sync(&owner_config, &Target::id(&signed_id), &owner_update);
+      assert_eq!(new_id[0].1.as_ref().unwrap(), &owner_update.id());
+      let results = dindex::client::query_sync(&test_config, &pair("NAME", ".*"));
+      assert_eq!(results.len(), 1);
+      assert_eq!(results[0].p.get("NAME").unwrap(), "signed v3");
+      
+      // Keys trusted by the server may remove anything
+      std::fs::write(trusted_keys_f, dindex::signing::read_pub_key_base64(other_identity_f)).unwrap();
+      assert_eq!(dindex::client::delete_sync(&other_config, &Target::query(&pair("NAME", "sig.*")))[0].1.as_ref().unwrap(), &1);
+      assert_eq!(dindex::client::query_sync(&test_config, &pair("NAME", ".*")).len(), 0);
+      
+      // Listener heard about the unsigned delete, the update, and the trusted delete
+      std::thread::sleep(Duration::from_millis(50));
+      let removed: Vec<String> = removed.lock().unwrap().iter().map(|r| r.p.get("NAME").unwrap().to_string()).collect();
+      assert_eq!(removed, vec!["unsigned", "signed", "signed v2", "signed v3"]);
+      
+      drop(conn);
+      
+      // Instruct server to exit
+      exit_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+      // Send it network traffic to force eval of exit_flag
+      dindex::client::query_sync(&test_config, &pair("NAME", ".*"));
+      
+    }));
+    
+    for h in handlers {
+      h.join().unwrap();
+    }
+  }).unwrap();
+  
+}
+
+// The error code a delete or update gets, empty when it is acknowledged
+fn modify_error(config: &dindex::config::Config, server: &dindex::config::Server, wire_data: &dindex::wire::WireData) -> String {
+  let mut code = String::new();
+  dindex::client::request_server_sync(config, server, wire_data, || false, |wire_res| {
+    if wire_res.action == dindex::actions::Action::error {
+      code = wire_res.record.p.get("error-code").cloned().unwrap_or_default();
+    }
+    return true;
+  }).unwrap();
+  return code;
+}
+
+fn publish_one(config: &dindex::config::Config, rec: &dindex::record::Record) -> String {
+  let acks = dindex::client::publish_sync(config, rec);
+  return acks[0].1.as_ref().unwrap().to_string();
+}
@@ -42,7 +42,8 @@ fn tcp_server_listen() {
     path: "/tmp/dindex.test.socket".to_string(),
     max_latency_ms: 250,
     report_connect_errors: true,
-    name: "Localhost Server".to_string()
+    name: "Localhost Server".to_string(),
+    tls_pin: None
   };
   test_config.servers = vec![localhost_server];
   test_config.server_port = port;
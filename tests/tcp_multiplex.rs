@@ -0,0 +1,134 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *  
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; either version 2 of the License, or
+ *  (at your option) any later version.
+ * 
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ * 
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use crossbeam_utils::thread;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use dindex;
+
+mod common;
+use common::{empty_config, pair};
+
+#[test]
+fn tcp_multiplex() {
+  let mut test_config = empty_config();
+  // Write details for temporary data
+  let port = 2004;
+  let localhost_server = dindex::config::Server {
+    protocol: dindex::config::ServerProtocol::TCP,
+    host: "127.0.0.1".to_string(),
+    port: port,
+    path: "/tmp/dindex.test.socket".to_string(),
+    max_latency_ms: 250,
+    report_connect_errors: true,
+    name: "Localhost Server".to_string(),
+    tls_pin: None
+  };
+  test_config.servers = vec![localhost_server.clone()];
+  test_config.server_port = port;
+  test_config.server_ip = "127.0.0.1".to_string();
+  test_config.server_listen_tcp = true;
+  test_config.server_listen_udp = false;
+  test_config.server_listen_unix = false;
+  test_config.server_listen_websocket = false;
+  test_config.server_extra_quiet = true;
+  
+  // Tell server not to store records outside this process's memory
+  test_config.server_datastore_uri = "memory://".to_string();
+  
+  // Create a data store
+  let mut data = dindex::data::Data::new(&test_config);
+  let exit_flag = data.exit_flag.clone();
+  
+  // Spawn server and client threads to perform testing
+  thread::scope(|s| {
+    let mut handlers = vec![];
+    
+    handlers.push(s.spawn(|_| {
+      dindex::server::run_tcp_sync(&test_config, &mut data);
+    }));
+    
+    handlers.push(s.spawn(|_| {
+      // This is where client logic is tested
+      std::thread::sleep(Duration::from_millis(25));
+      // Server should have bound to ports within 25ms
+      
+      let conn = dindex::connection::Connection::open(&test_config, &localhost_server).unwrap();
+      assert!(conn.caps.supports_feature("multiplex"));
+      
+      // Two listeners on the same socket
+      let heard_a = Arc::new(Mutex::new(vec![]));
+      let heard_b = Arc::new(Mutex::new(vec![]));
+      let t_heard_a = heard_a.clone();
+      let listen_a = conn.listen(&pair("NAME", "^a.*"), move |rec| {
+        t_heard_a.lock().unwrap().push(rec);
+        return dindex::client::ListenAction::Continue;
+      }).unwrap();
+      let t_heard_b = heard_b.clone();
+      conn.listen(&pair("NAME", "^b.*"), move |rec| {
+        t_heard_b.lock().unwrap().push(rec);
+        return dindex::client::ListenAction::Continue;
+      }).unwrap();
+      std::thread::sleep(Duration::from_millis(25));
+      
+      // Many publishes and queries in flight at once
+      thread::scope(|s| {
+        for i in 0..8 {
+          let conn = &conn;
+          s.spawn(move |_| {
+            conn.publish(&pair("NAME", &format!("a{}", i))).unwrap();
+            conn.publish(&pair("NAME", &format!("b{}", i))).unwrap();
+            let results = conn.query(&pair("NAME", &format!("^a{}$", i))).unwrap();
+            assert_eq!(results.len(), 1);
+          });
+        }
+      }).unwrap();
+      
+      assert_eq!(conn.query(&pair("NAME", ".*")).unwrap().len(), 16);
+      
+      std::thread::sleep(Duration::from_millis(50));
+      assert_eq!(heard_a.lock().unwrap().len(), 8);
+      assert_eq!(heard_b.lock().unwrap().len(), 8);
+      
+      // Cancelled listeners hear nothing more, others carry on
+      conn.cancel(listen_a).unwrap();
+      std::thread::sleep(Duration::from_millis(25));
+      conn.publish(&pair("NAME", "a8")).unwrap();
+      conn.publish(&pair("NAME", "b8")).unwrap();
+      std::thread::sleep(Duration::from_millis(50));
+      assert_eq!(heard_a.lock().unwrap().len(), 8);
+      assert_eq!(heard_b.lock().unwrap().len(), 9);
+      
+      drop(conn);
+      
+      // Instruct server to exit
+      exit_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+      // Send it network traffic to force eval of exit_flag
+      dindex::client::query_sync(&test_config, &pair("NAME", ".*"));
+      
+    }));
+    
+    for h in handlers {
+      h.join().unwrap();
+    }
+  }).unwrap();
+  
+}
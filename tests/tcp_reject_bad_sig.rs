@@ -40,7 +40,8 @@ fn tcp_reject_bad_sig() {
     path: "/tmp/dindex.test.socket".to_string(),
     max_latency_ms: 250,
     report_connect_errors: true,
-    name: "Localhost Server".to_string()
+    name: "Localhost Server".to_string(),
+    tls_pin: None
   };
   test_config.servers = vec![localhost_server];
   test_config.server_port = port;
@@ -89,7 +90,11 @@ fn tcp_reject_bad_sig() {
         rec.p.insert("SIGNING:non-sig-bytes".to_string(), "TQS8PqgyH/Ey9X1ZjdarWUcpSKpPcPVRhNwkC7F7ntt2IHfemz1hoLzhK+B35cpJmKqt7mrPBXw1FPfX3F8Xf3FGxpXDSbL+8oeOxG30AB0HIFNmGoC1jEFi1haLriTX1DyK9hPpviqHfcq/2WfeuqbBcjyf3mgXf8k2bR6ZdCMnNVrXv9yqsFLIUfgh13DdkMjAVOH6s+YAnFmCPh0HtrePyxe0gIsecDQC8tn9pKLPbww2i9staHej2e/VF/3K1lsWojZ8jxN5F62fraAB31EhuzrBLhrfuMGKnxYxp9bHdsICpVMKX0fMjBu6iG6Kt4WeK1S7SXLuxZ11IDC5NQ==".to_string());
         rec
       };
-      dindex::client::publish_sync(&test_config, &rec_1);
+      let acks = dindex::client::publish_sync(&test_config, &rec_1);
+      match &acks[0].1 {
+        Err(dindex::client::PublishError::Rejected { code, .. }) => assert_eq!(code, "bad-signature"),
+        other => panic!("expected bad-signature rejection, got {:?}", other),
+      }
       
       let results = dindex::client::query_sync(&test_config, &query_1);
       // Results must still be empty b/c server must reject imposter record
@@ -102,7 +107,7 @@ fn tcp_reject_bad_sig() {
         rec.p.insert("URL".to_string(), "https://lipsum.com/".to_string());
         rec
       };
-      dindex::client::publish_sync(&test_config, &rec_2);
+      assert!(dindex::client::publish_sync(&test_config, &rec_2)[0].1.is_ok());
       
       let results = dindex::client::query_sync(&test_config, &query_1);
       // Results must have the 1 unsigned record
@@ -40,7 +40,8 @@ fn tcp_server_store_retrieve() {
     path: "/tmp/dindex.test.socket".to_string(),
     max_latency_ms: 250,
     report_connect_errors: true,
-    name: "Localhost Server".to_string()
+    name: "Localhost Server".to_string(),
+    tls_pin: None
   };
   test_config.servers = vec![localhost_server];
   test_config.server_port = port;
@@ -87,7 +88,10 @@ fn tcp_server_store_retrieve() {
         rec.p.insert("URL".to_string(), "https://lipsum.com/".to_string());
         rec
       };
-      dindex::client::publish_sync(&test_config, &rec_1);
+      let acks = dindex::client::publish_sync(&test_config, &rec_1);
+      assert_eq!(acks.len(), 1);
+      // The server acknowledges with an id derived from the record's contents
+      assert_eq!(acks[0].1.as_ref().unwrap(), &rec_1.id());
       
       let results = dindex::client::query_sync(&test_config, &query_1);
       assert_eq!(results.len(), 1);
@@ -97,6 +101,20 @@ fn tcp_server_store_retrieve() {
       assert_eq!(rec_1_url, "https://lipsum.com/");
       // ^ now we know we got the same record back
       
+      // Records the server does not keep are not acknowledged as stored
+      let expired_rec = {
+        let mut rec = dindex::record::Record::empty();
+        rec.p.insert("NAME".to_string(), "Already gone".to_string());
+        rec.p.insert(dindex::record::TTL_SECONDS_KEY.to_string(), "30".to_string());
+        rec.p.insert(dindex::record::TTL_EXPIRES_AT_KEY.to_string(), "1".to_string());
+        rec
+      };
+      match &dindex::client::publish_sync(&test_config, &expired_rec)[0].1 {
+        Err(dindex::client::PublishError::Rejected { code, .. }) => assert_eq!(code, "not-stored"),
+        other => panic!("expected not-stored rejection, got {:?}", other),
+      }
+      assert_eq!(dindex::client::query_sync(&test_config, &query_1).len(), 1);
+      
       
       // Instruct server to exit
       exit_flag.store(true, std::sync::atomic::Ordering::Relaxed);
@@ -0,0 +1,237 @@
+/**
+ *  dIndex - a distributed, organic, mechanical index for everything
+ *  Copyright (C) 2019  Jeffrey McAteer <jeffrey.p.mcateer@gmail.com>
+ *
+ *  This program is free software; you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation; either version 2 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License along
+ *  with this program; if not, write to the Free Software Foundation, Inc.,
+ *  51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use crossbeam_utils::thread;
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::x509::{X509, X509NameBuilder};
+use openssl::x509::extension::SubjectAlternativeName;
+
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use dindex;
+use dindex::client::ListenAction;
+use dindex::config::{Server, ServerProtocol};
+use dindex::data::Data;
+
+mod common;
+use common::{mem_config, pair, sorted_names};
+
+// Writes a self-signed certificate for localhost and its key,
+// returning their paths
+fn self_signed_cert(name: &str) -> (String, String) {
+  let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+  let mut subject = X509NameBuilder::new().unwrap();
+  subject.append_entry_by_text("CN", "localhost").unwrap();
+  let subject = subject.build();
+  let mut serial = BigNum::new().unwrap();
+  serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+  
+  let mut cert = X509::builder().unwrap();
+  cert.set_version(2).unwrap();
+  cert.set_serial_number(&serial.to_asn1_integer().unwrap()).unwrap();
+  cert.set_subject_name(&subject).unwrap();
+  cert.set_issuer_name(&subject).unwrap();
+  cert.set_pubkey(&key).unwrap();
+  cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+  cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+  let san = SubjectAlternativeName::new().dns("localhost").ip("127.0.0.1").build(&cert.x509v3_context(None, None)).unwrap();
+  cert.append_extension(san).unwrap();
+  cert.sign(&key, MessageDigest::sha256()).unwrap();
+  
+  let cert_file = format!("/tmp/dindex.test.{}.crt", name);
+  let key_file = format!("/tmp/dindex.test.{}.key", name);
+  std::fs::write(&cert_file, cert.build().to_pem().unwrap()).unwrap();
+  std::fs::write(&key_file, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+  return (cert_file, key_file);
+}
+
+#[test]
+fn tls_and_wss() {
+  let (tls_port, wss_port) = (2019, 2020);
+  let (cert_file, key_file) = self_signed_cert("tls");
+  let mut config = mem_config();
+  config.server_ip = "127.0.0.1".to_string();
+  config.server_tls_port = tls_port;
+  config.server_wss_port = wss_port;
+  config.server_tls_cert_file = cert_file.clone();
+  config.server_tls_key_file = key_file.clone();
+  let mut client_config = mem_config();
+  client_config.client_tls_ca_file = cert_file.clone();
+  let tls_server = server(ServerProtocol::TLS, tls_port);
+  let wss_server = server(ServerProtocol::WSS, wss_port);
+  
+  let data = Data::new(&config);
+  let exit_flag = data.exit_flag.clone();
+  
+  thread::scope(|s| {
+    let mut handlers = vec![];
+    
+    handlers.push(s.spawn(|_| {
+      dindex::server::run_tls_sync(&config, &data);
+    }));
+    handlers.push(s.spawn(|_| {
+      dindex::server::run_wss_sync(&config, &data);
+    }));
+    
+    handlers.push(s.spawn(|_| {
+      std::thread::sleep(Duration::from_millis(100));
+      
+      // Trusted by the CA file and host name
+      dindex::client::publish_server_sync(&client_config, &tls_server, &pair("NAME", "over tls")).unwrap();
+      dindex::client::publish_server_sync(&client_config, &wss_server, &pair("NAME", "over wss")).unwrap();
+      assert_eq!(sorted_names(&dindex::client::query_server_sync(&client_config, &tls_server, &pair("NAME", ".*"))), vec!["over tls", "over wss"]);
+      assert_eq!(sorted_names(&dindex::client::query_server_sync(&client_config, &wss_server, &pair("NAME", ".*"))), vec!["over tls", "over wss"]);
+      
+      // Listening and querying at once over one connection
+      for server in &[&tls_server, &wss_server] {
+        let conn = dindex::connection::Connection::open(&client_config, server).unwrap();
+        let heard = Arc::new(Mutex::new(vec![]));
+        let t_heard = heard.clone();
+        conn.listen(&pair("NAME", "^heard"), move |rec| {
+          t_heard.lock().unwrap().push(rec.p.get("NAME").unwrap().clone());
+          return ListenAction::Continue;
+        }).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        conn.publish(&pair("NAME", &format!("heard {}", server.port))).unwrap();
+        assert_eq!(conn.query(&pair("NAME", "^over tls$")).unwrap().len(), 1);
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(*heard.lock().unwrap(), vec![format!("heard {}", server.port)]);
+      }
+      
+      // Listening with a timeout hears the idle ticks
+      for server in &[&tls_server, &wss_server] {
+        let ticks = Arc::new(Mutex::new(0));
+        dindex::client::listen_server_sync_with_timeout(&client_config, server, &pair("NAME", "^never$"), 100, |rec| {
+          assert!(rec.is_empty());
+          *ticks.lock().unwrap() += 1;
+          return ListenAction::EndListen;
+        });
+        assert_eq!(*ticks.lock().unwrap(), 1);
+      }
+      
+      // Self-signed servers are only trusted by their pin without the CA file
+      let untrusting_config = mem_config();
+      assert!(dindex::tls::connect(&untrusting_config, &tls_server, None).is_err());
+      let pin = dindex::tls::cert_file_pin(&cert_file).unwrap();
+      let pinned = Server { tls_pin: Some(pin.clone()), host: "127.0.0.1".to_string(), ..tls_server.clone() };
+      let stream = dindex::tls::connect(&untrusting_config, &pinned, None).unwrap();
+      assert_eq!(stream.peer_pin(), Some(pin));
+      assert_eq!(dindex::client::query_server_sync(&untrusting_config, &pinned, &pair("NAME", ".*")).len(), 4);
+      let wrong_pin = Server { tls_pin: Some("00".repeat(32)), ..pinned.clone() };
+      match dindex::tls::connect(&untrusting_config, &wrong_pin, None) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::PermissionDenied),
+        Ok(_) => panic!("connected to a server without the pinned key"),
+      }
+      
+      // Instruct servers to exit
+      exit_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+      // Connecting forces eval of exit_flag
+      for port in &[tls_port, wss_port] {
+        let _ = std::net::TcpStream::connect(("127.0.0.1", *port));
+      }
+    }));
+    
+    for h in handlers {
+      h.join().unwrap();
+    }
+  }).unwrap();
+}
+
+#[test]
+fn wss_multiplex() {
+  let port = 2022;
+  let (cert_file, key_file) = self_signed_cert("wss_multiplex");
+  let mut config = mem_config();
+  config.server_ip = "127.0.0.1".to_string();
+  config.server_wss_port = port;
+  config.server_tls_cert_file = cert_file.clone();
+  config.server_tls_key_file = key_file.clone();
+  let mut client_config = mem_config();
+  client_config.client_tls_ca_file = cert_file.clone();
+  let wss_server = server(ServerProtocol::WSS, port);
+  
+  let data = Data::new(&config);
+  let exit_flag = data.exit_flag.clone();
+  
+  thread::scope(|s| {
+    let mut handlers = vec![];
+    
+    handlers.push(s.spawn(|_| {
+      dindex::server::run_wss_sync(&config, &data);
+    }));
+    
+    handlers.push(s.spawn(|_| {
+      std::thread::sleep(Duration::from_millis(100));
+      
+      let conn = dindex::connection::Connection::open(&client_config, &wss_server).unwrap();
+      let heard = Arc::new(Mutex::new(vec![]));
+      let t_heard = heard.clone();
+      conn.listen(&pair("NAME", "^a"), move |rec| {
+        t_heard.lock().unwrap().push(rec);
+        return ListenAction::Continue;
+      }).unwrap();
+      std::thread::sleep(Duration::from_millis(50));
+      
+      // Many publishes and queries in flight while the reader waits
+      thread::scope(|s| {
+        for i in 0..8 {
+          let conn = &conn;
+          s.spawn(move |_| {
+            conn.publish(&pair("NAME", &format!("a{}", i))).unwrap();
+            conn.publish(&pair("NAME", &format!("b{}", i))).unwrap();
+            assert_eq!(conn.query(&pair("NAME", &format!("^a{}$", i))).unwrap().len(), 1);
+          });
+        }
+      }).unwrap();
+      
+      assert_eq!(conn.query(&pair("NAME", ".*")).unwrap().len(), 16);
+      std::thread::sleep(Duration::from_millis(100));
+      assert_eq!(heard.lock().unwrap().len(), 8);
+      drop(conn);
+      
+      // Instruct server to exit
+      exit_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+      // Connecting forces eval of exit_flag
+      let _ = std::net::TcpStream::connect(("127.0.0.1", port));
+    }));
+    
+    for h in handlers {
+      h.join().unwrap();
+    }
+  }).unwrap();
+}
+
+fn server(protocol: ServerProtocol, port: u16) -> Server {
+  Server {
+    protocol: protocol,
+    host: "localhost".to_string(),
+    port: port,
+    path: String::new(),
+    max_latency_ms: 250,
+    report_connect_errors: true,
+    name: format!("Localhost Server {}", port),
+    tls_pin: None,
+  }
+}